@@ -0,0 +1,342 @@
+use crate::registers::Registers;
+use crate::registers::IPName;
+use crate::registers::CRName;
+use crate::registers::SegmentName;
+use crate::registers::{CR0_PG, CR0_WP, CR4_LA57, CR4_SMEP, EFER_NXE};
+
+use crate::memory::Memory;
+use crate::memory::MemoryIO;
+use crate::memory::MemoryFault;
+
+use crate::paging::PagingMode;
+use crate::paging::PagingLevels;
+use crate::paging::PageFault;
+use crate::paging::AccessKind;
+use crate::paging::{PF_PRESENT, PF_WRITE, PF_USER, PF_INSTRUCTION};
+
+use crate::instructions;
+use crate::instructions::DecodeError;
+use crate::instructions::Instruction;
+
+// architectural exceptions an instruction can raise
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    // #DE
+    DivideError,
+    // #BP, raised by INT3 as a trap
+    Breakpoint,
+    // #UD
+    InvalidOpcode,
+    // #NP, a segment or system descriptor with P clear, the error code is its selector
+    SegmentNotPresent(u32),
+    // #SS, raised here only for a not-present stack segment descriptor
+    StackFault(u32),
+    // #GP with its error code
+    GeneralProtection(u32),
+    // #MF, an unmasked x87 exception left pending in the status word, delivered by the next waiting instruction
+    FloatingPointError,
+    // #PF, the address goes to CR2
+    PageFault { address: u64, error_code: u32 },
+    // #XM, an unmasked SIMD floating-point exception, the cause is in the MXCSR flags
+    SIMDFloatingPoint,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halt,
+    Breakpoint,
+    Fault(Exception),
+    StepLimit,
+    // SYSCALL executed, RIP is past it and the system call layer takes over
+    Syscall,
+    // the process exited with a status, reported by the system call layer
+    Exit(i32),
+}
+
+pub struct Cpu {
+    pub registers: Registers,
+    pub memory: Memory,
+    halted: bool,
+    syscall: bool,
+}
+
+impl From<PageFault> for Exception {
+    fn from(fault: PageFault) -> Self {
+        Exception::PageFault { address: fault.address, error_code: fault.error_code }
+    }
+}
+
+impl Cpu {
+    pub fn new(registers: Registers, memory: Memory) -> Self {
+        Cpu {
+            registers,
+            memory,
+            halted: false,
+            syscall: false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn halt(&mut self) {
+        self.halted = true;
+    }
+
+    // stop after the current instruction so the system call layer can run
+    pub fn request_syscall(&mut self) {
+        self.syscall = true;
+    }
+
+    // current privilege level, the RPL of CS; user-mode page checks apply at 3
+    pub fn cpl(&self) -> u8 {
+        (self.registers.get_segment(SegmentName::CS).selector & 3) as u8
+    }
+
+    // rebuild the memory translation after CR0, CR3, CR4 or EFER changed
+    pub fn update_paging(&mut self) {
+        let cr0 = self.registers.get_cr_value(CRName::CR0);
+        let cr4 = self.registers.get_cr_value(CRName::CR4);
+        let paging = if cr0 & CR0_PG != 0 {
+            let levels = if cr4 & CR4_LA57 != 0 { PagingLevels::Five } else { PagingLevels::Four };
+            let mut mode = PagingMode::new(self.registers.get_cr_value(CRName::CR3), levels);
+            mode.no_execute = self.registers.get_efer() & EFER_NXE != 0;
+            mode.write_protect = cr0 & CR0_WP != 0;
+            mode.smep = cr4 & CR4_SMEP != 0;
+            Some(mode)
+        } else {
+            None
+        };
+        self.memory.set_paging(paging);
+    }
+
+    pub fn check_canonical(&self, address: u64) -> Result<(), Exception> {
+        match self.memory.paging() {
+            Some(paging) if !paging.is_canonical(address) => Err(Exception::GeneralProtection(0)),
+            _ => Ok(()),
+        }
+    }
+
+    // branch to target, a non-canonical target faults on the branch rather than on the fetch
+    pub fn jump(&mut self, target: u64) -> Result<(), Exception> {
+        self.check_canonical(target)?;
+        self.registers.set_ip_value(IPName::RIP, target);
+        Ok(())
+    }
+
+    // unbacked or protected memory is reported like the page fault a kernel would see
+    pub fn memory_fault(&self, fault: MemoryFault) -> Exception {
+        let error_code = |address: usize, access: AccessKind, present: bool| {
+            let mut error_code = if present { PF_PRESENT } else { 0 };
+            match access {
+                AccessKind::Write => error_code |= PF_WRITE,
+                AccessKind::Execute => error_code |= PF_INSTRUCTION,
+                AccessKind::Read => {}
+            }
+            if self.cpl() == 3 {
+                error_code |= PF_USER;
+            }
+            Exception::PageFault { address: address as u64, error_code }
+        };
+        match fault {
+            MemoryFault::Page(fault) => fault.into(),
+            MemoryFault::Unmapped { address, access } => error_code(address, access, false),
+            MemoryFault::Permission { address, access } => error_code(address, access, true),
+            MemoryFault::OutOfRange { .. } => Exception::GeneralProtection(0),
+        }
+    }
+
+    pub fn read_memory<T: MemoryIO>(&self, address: u64) -> Result<T, Exception> {
+        self.check_canonical(address)?;
+        self.memory.read_linear::<T>(address as usize, self.cpl() == 3).map_err(|fault| self.memory_fault(fault))
+    }
+
+    pub fn write_memory<T: MemoryIO>(&mut self, address: u64, value: T) -> Result<(), Exception> {
+        self.check_canonical(address)?;
+        let user = self.cpl() == 3;
+        self.memory.write_linear::<T>(address as usize, value, user).map_err(|fault| self.memory_fault(fault))
+    }
+
+    // implicit supervisor accesses to descriptor tables, which CPL 3 code makes as well
+    pub fn read_system<T: MemoryIO>(&self, address: u64) -> Result<T, Exception> {
+        self.check_canonical(address)?;
+        self.memory.read_linear::<T>(address as usize, false).map_err(|fault| self.memory_fault(fault))
+    }
+
+    pub fn write_system<T: MemoryIO>(&mut self, address: u64, value: T) -> Result<(), Exception> {
+        self.check_canonical(address)?;
+        self.memory.write_linear::<T>(address as usize, value, false).map_err(|fault| self.memory_fault(fault))
+    }
+
+    // a range of bytes, for system calls and other bulk accesses
+    pub fn read_bytes(&self, address: u64, buffer: &mut [u8]) -> Result<(), Exception> {
+        self.check_canonical(address)?;
+        self.check_canonical(address.wrapping_add(buffer.len().saturating_sub(1) as u64))?;
+        self.memory.read_bytes_linear(address as usize, buffer, self.cpl() == 3).map_err(|fault| self.memory_fault(fault))
+    }
+
+    pub fn write_bytes(&mut self, address: u64, bytes: &[u8]) -> Result<(), Exception> {
+        self.check_canonical(address)?;
+        self.check_canonical(address.wrapping_add(bytes.len().saturating_sub(1) as u64))?;
+        let user = self.cpl() == 3;
+        self.memory.write_bytes_linear(address as usize, bytes, user).map_err(|fault| self.memory_fault(fault))
+    }
+
+    fn fetch(&self, rip: u64) -> Result<Instruction, Exception> {
+        self.check_canonical(rip)?;
+        let user = self.cpl() == 3;
+        let memory = &self.memory;
+        Instruction::decode_with(|offset| {
+            memory.fetch_linear(rip.wrapping_add(offset as u64) as usize, user).map_err(DecodeError::Fault)
+        }).map_err(|error| match error {
+            DecodeError::InvalidOpcode => Exception::InvalidOpcode,
+            DecodeError::TooLong => Exception::GeneralProtection(0),
+            // only the test decoder runs out of bytes, a fetch past mapped memory faults instead
+            #[cfg(test)]
+            DecodeError::Truncated => Exception::GeneralProtection(0),
+            DecodeError::Fault(fault) => self.memory_fault(fault),
+        })
+    }
+
+    fn raise(&mut self, exception: Exception) {
+        if let Exception::PageFault { address, .. } = exception {
+            self.registers.set_cr_value(CRName::CR2, address);
+        }
+    }
+
+    // fetch, decode and execute the instruction at RIP
+    pub fn step(&mut self) -> Result<(), StopReason> {
+        if self.halted {
+            return Err(StopReason::Halt);
+        }
+        let rip = self.registers.get_ip_value(IPName::RIP);
+        let instruction = match self.fetch(rip) {
+            Ok(instruction) => instruction,
+            Err(exception) => {
+                self.raise(exception);
+                return Err(StopReason::Fault(exception));
+            }
+        };
+        // RIP points past the instruction while it executes, as RIP-relative addressing and branches expect
+        self.registers.set_ip_value(IPName::RIP, rip.wrapping_add(instruction.length as u64));
+        match instructions::execute(self, &instruction) {
+            Ok(()) if self.halted => Err(StopReason::Halt),
+            Ok(()) if self.syscall => {
+                self.syscall = false;
+                Err(StopReason::Syscall)
+            }
+            Ok(()) => Ok(()),
+            // INT3 is a trap, RIP stays after the instruction
+            Err(Exception::Breakpoint) => Err(StopReason::Breakpoint),
+            Err(exception) => {
+                // faults are restartable, RIP goes back to the faulting instruction
+                self.registers.set_ip_value(IPName::RIP, rip);
+                self.raise(exception);
+                Err(StopReason::Fault(exception))
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn run(&mut self, max_steps: usize) -> StopReason {
+        for _ in 0..max_steps {
+            if let Err(reason) = self.step() {
+                return reason;
+            }
+        }
+        StopReason::StepLimit
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    pub(crate) const CODE: usize = 0x1000;
+
+    pub(crate) fn cpu_with_code(code: &[u8]) -> Cpu {
+        let mut memory = Memory::new(0);
+        memory.set_lazy_allocation(true);
+        memory.write_vec::<u8>(CODE, code.to_vec());
+        let mut registers = Registers::new();
+        registers.set_ip_value(IPName::RIP, CODE as u64);
+        Cpu::new(registers, memory)
+    }
+
+    #[test]
+    fn step_advances_rip() {
+        let mut cpu = cpu_with_code(&[0x90, 0x0F, 0x1F, 0x40, 0x00]);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 1);
+        assert_eq!(cpu.step(), Ok(()));
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 5);
+    }
+
+    #[test]
+    fn run_stops_on_halt() {
+        let mut cpu = cpu_with_code(&[0x90, 0x90, 0xF4, 0x90]);
+        assert_eq!(cpu.run(100), StopReason::Halt);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 3);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.run(100), StopReason::Halt);
+    }
+
+    #[test]
+    fn run_stops_on_breakpoint_after_int3() {
+        let mut cpu = cpu_with_code(&[0x90, 0xCC, 0x90]);
+        assert_eq!(cpu.run(100), StopReason::Breakpoint);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 2);
+        assert_eq!(cpu.run(1), StopReason::StepLimit);
+    }
+
+    #[test]
+    fn run_reports_fault_at_faulting_instruction() {
+        let mut cpu = cpu_with_code(&[0x90, 0x0F, 0x0B]);
+        assert_eq!(cpu.run(100), StopReason::Fault(Exception::InvalidOpcode));
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 1);
+    }
+
+    #[test]
+    fn page_faults_set_cr2() {
+        // mov eax, [0x7000] under identity-mapped paging where 0x7000 is not present
+        let mut cpu = cpu_with_code(&[0x8B, 0x04, 0x25, 0x00, 0x70, 0x00, 0x00]);
+        cpu.memory.write::<u64>(0x10000, 0x11000 | 7);
+        cpu.memory.write::<u64>(0x11000, 0x12000 | 7);
+        cpu.memory.write::<u64>(0x12000, 0x13000 | 7);
+        cpu.memory.write::<u64>(0x13000 + 8, 0x1000 | 7);
+        cpu.registers.set_cr_value(CRName::CR3, 0x10000);
+        cpu.registers.set_cr_value(CRName::CR0, CR0_PG);
+        cpu.update_paging();
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::PageFault { address: 0x7000, error_code: 0 }));
+        assert_eq!(cpu.registers.get_cr_value(CRName::CR2), 0x7000);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64);
+
+        // map 0x7000 and the load goes through
+        cpu.memory.write::<u64>(0x13000 + 7 * 8, 0x2000 | 7);
+        cpu.memory.write::<u32>(0x2000, 0xCAFE);
+        assert_eq!(cpu.run(1), StopReason::StepLimit);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xCAFE);
+    }
+
+    #[test]
+    fn unmapped_memory_faults() {
+        // mov [0x9000], eax with lazy allocation off
+        let mut cpu = cpu_with_code(&[0x89, 0x04, 0x25, 0x00, 0x90, 0x00, 0x00, 0xEB, 0x00]);
+        cpu.memory.set_lazy_allocation(false);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::PageFault { address: 0x9000, error_code: PF_WRITE }));
+        // jumping off the end of the mapped code faults on the fetch
+        cpu.registers.set_ip_value(IPName::RIP, 0x1FFF);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::PageFault { address: 0x2000, error_code: PF_INSTRUCTION }));
+    }
+
+    #[test]
+    fn run_honors_step_limit() {
+        let mut cpu = cpu_with_code(&[0x90; 8]);
+        assert_eq!(cpu.run(3), StopReason::StepLimit);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 3);
+    }
+}
@@ -0,0 +1,324 @@
+// ELF64 executable loader for statically linked x86-64 Linux binaries
+// reference: System V ABI, "Object Files" and the x86-64 psABI supplement
+
+use std::collections::BTreeMap;
+
+use crate::registers::IPName;
+
+use crate::memory::MemoryFault;
+use crate::memory::Perms;
+
+use crate::paging::PAGE_SIZE;
+
+use crate::cpu::Cpu;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const PT_LOAD: u32 = 1;
+const PT_INTERP: u32 = 3;
+const PT_PHDR: u32 = 6;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+const EHDR_SIZE: usize = 64;
+const PHDR_SIZE: usize = 56;
+
+// where position independent executables get loaded, the same bias Linux uses without ASLR
+pub const DYN_BASE: u64 = 0x5555_5555_4000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    // the file ends inside a header or segment
+    Truncated,
+    BadMagic,
+    // not a little-endian ELF64 x86-64 executable
+    Unsupported,
+    // needs a dynamic linker
+    Interpreter,
+    // a segment with memsz < filesz, misaligned or wrapping the address space
+    BadSegment,
+    Memory(MemoryFault),
+}
+
+impl From<MemoryFault> for ElfError {
+    fn from(fault: MemoryFault) -> Self {
+        ElfError::Memory(fault)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+// what the process setup needs to know about a loaded image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedElf {
+    pub entry: u64,
+    // load bias, 0 for ET_EXEC
+    pub base: u64,
+    // address of the program headers in memory, for AT_PHDR
+    pub phdr: u64,
+    pub phent: u64,
+    pub phnum: u64,
+    // first page after the highest segment, the initial brk
+    pub program_break: u64,
+}
+
+fn field<const N: usize>(image: &[u8], offset: usize) -> Result<[u8; N], ElfError> {
+    image.get(offset..offset + N)
+        .map(|bytes| bytes.try_into().unwrap())
+        .ok_or(ElfError::Truncated)
+}
+
+fn read_u16(image: &[u8], offset: usize) -> Result<u16, ElfError> {
+    field(image, offset).map(u16::from_le_bytes)
+}
+
+fn read_u32(image: &[u8], offset: usize) -> Result<u32, ElfError> {
+    field(image, offset).map(u32::from_le_bytes)
+}
+
+fn read_u64(image: &[u8], offset: usize) -> Result<u64, ElfError> {
+    field(image, offset).map(u64::from_le_bytes)
+}
+
+fn page_down(address: u64) -> u64 {
+    address & !(PAGE_SIZE as u64 - 1)
+}
+
+fn page_up(address: u64) -> Option<u64> {
+    address.checked_add(PAGE_SIZE as u64 - 1).map(page_down)
+}
+
+fn segment_perms(flags: u32) -> Perms {
+    let mut perms = Perms::NONE;
+    if flags & PF_R != 0 {
+        perms = perms | Perms::READ;
+    }
+    if flags & PF_W != 0 {
+        perms = perms | Perms::WRITE;
+    }
+    if flags & PF_X != 0 {
+        perms = perms | Perms::EXECUTE;
+    }
+    perms
+}
+
+fn program_headers(image: &[u8]) -> Result<(u16, u64, u64, Vec<ProgramHeader>), ElfError> {
+    let identity: [u8; 16] = field(image, 0)?;
+    if identity[..4] != ELF_MAGIC {
+        return Err(ElfError::BadMagic);
+    }
+    let kind = read_u16(image, 16)?;
+    if identity[4] != ELFCLASS64 || identity[5] != ELFDATA2LSB || read_u16(image, 18)? != EM_X86_64
+        || (kind != ET_EXEC && kind != ET_DYN) {
+        return Err(ElfError::Unsupported);
+    }
+    let entry = read_u64(image, 24)?;
+    let phoff = read_u64(image, 32)?;
+    let phentsize = read_u16(image, 54)? as usize;
+    let phnum = read_u16(image, 56)? as usize;
+    if phentsize < PHDR_SIZE {
+        return Err(ElfError::Unsupported);
+    }
+
+    let mut headers = Vec::with_capacity(phnum);
+    for index in 0..phnum {
+        let offset = usize::try_from(phoff).ok()
+            .and_then(|phoff| phoff.checked_add(index * phentsize))
+            .ok_or(ElfError::Truncated)?;
+        field::<PHDR_SIZE>(image, offset)?;
+        headers.push(ProgramHeader {
+            kind: read_u32(image, offset)?,
+            flags: read_u32(image, offset + 4)?,
+            offset: read_u64(image, offset + 8)?,
+            vaddr: read_u64(image, offset + 16)?,
+            filesz: read_u64(image, offset + 32)?,
+            memsz: read_u64(image, offset + 40)?,
+        });
+    }
+    Ok((kind, entry, phoff, headers))
+}
+
+// map the PT_LOAD segments of `image` into the CPU memory and point RIP at the entry
+pub fn load(cpu: &mut Cpu, image: &[u8]) -> Result<LoadedElf, ElfError> {
+    if image.len() < EHDR_SIZE {
+        return Err(ElfError::Truncated);
+    }
+    let (kind, entry, phoff, headers) = program_headers(image)?;
+    if headers.iter().any(|header| header.kind == PT_INTERP) {
+        return Err(ElfError::Interpreter);
+    }
+    let base = if kind == ET_DYN { DYN_BASE } else { 0 };
+
+    // segments may share a page, which then gets the union of their permissions
+    let mut pages: BTreeMap<u64, Perms> = BTreeMap::new();
+    let mut program_break = 0;
+    for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        let start = header.vaddr.checked_add(base).ok_or(ElfError::BadSegment)?;
+        let end = start.checked_add(header.memsz).and_then(page_up).ok_or(ElfError::BadSegment)?;
+        if header.filesz > header.memsz {
+            return Err(ElfError::BadSegment);
+        }
+        let perms = segment_perms(header.flags);
+        for page in (page_down(start)..end).step_by(PAGE_SIZE) {
+            let entry = pages.entry(page).or_insert(Perms::NONE);
+            *entry = *entry | perms;
+        }
+        program_break = program_break.max(end);
+    }
+
+    // map writable to copy the file contents in, everything past filesz stays zero
+    for page in pages.keys() {
+        cpu.memory.map(*page as usize, PAGE_SIZE, Perms::RW)?;
+    }
+    for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+        let contents = usize::try_from(header.offset).ok()
+            .zip(usize::try_from(header.filesz).ok())
+            .and_then(|(offset, size)| image.get(offset..offset.checked_add(size)?))
+            .ok_or(ElfError::Truncated)?;
+        cpu.memory.write_bytes((header.vaddr + base) as usize, contents)?;
+    }
+    for (page, perms) in &pages {
+        cpu.memory.protect(*page as usize, PAGE_SIZE, *perms)?;
+    }
+
+    // prefer PT_PHDR, otherwise find the load segment covering the header table
+    let mut phdr = headers.iter().find(|header| header.kind == PT_PHDR).map(|header| header.vaddr);
+    if phdr.is_none() {
+        for header in headers.iter().filter(|header| header.kind == PT_LOAD) {
+            let file_end = header.offset.checked_add(header.filesz).ok_or(ElfError::BadSegment)?;
+            if header.offset <= phoff && phoff < file_end {
+                phdr = Some(header.vaddr.checked_add(phoff - header.offset).ok_or(ElfError::BadSegment)?);
+                break;
+            }
+        }
+    }
+    let phdr = match phdr {
+        Some(phdr) => phdr.checked_add(base).ok_or(ElfError::BadSegment)?,
+        None => 0,
+    };
+
+    let entry = entry.wrapping_add(base);
+    cpu.registers.set_ip_value(IPName::RIP, entry);
+    Ok(LoadedElf {
+        entry,
+        base,
+        phdr,
+        phent: PHDR_SIZE as u64,
+        phnum: headers.len() as u64,
+        program_break,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::Registers;
+    use crate::registers::GPRName;
+
+    use crate::memory::Memory;
+
+    use crate::cpu::StopReason;
+
+    // program headers: text RX at 0x401000, data RW at 0x402000 with 0x1800 bytes of .bss
+    fn test_image() -> Vec<u8> {
+        let mut image = vec![0u8; 0x1010];
+        image[..4].copy_from_slice(&ELF_MAGIC);
+        image[4] = ELFCLASS64;
+        image[5] = ELFDATA2LSB;
+        image[6] = 1;
+        image[16..18].copy_from_slice(&ET_EXEC.to_le_bytes());
+        image[18..20].copy_from_slice(&EM_X86_64.to_le_bytes());
+        image[20..24].copy_from_slice(&1u32.to_le_bytes());
+        image[24..32].copy_from_slice(&0x401000u64.to_le_bytes());
+        image[32..40].copy_from_slice(&(EHDR_SIZE as u64).to_le_bytes());
+        image[52..54].copy_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+        image[54..56].copy_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+        image[56..58].copy_from_slice(&2u16.to_le_bytes());
+
+        let segments = [
+            (PF_R | PF_X, 0x0u64, 0x400000u64, 0x1008u64, 0x1008u64),
+            (PF_R | PF_W, 0x1008, 0x402008, 0x8, 0x1800),
+        ];
+        for (index, (flags, offset, vaddr, filesz, memsz)) in segments.iter().enumerate() {
+            let header = EHDR_SIZE + index * PHDR_SIZE;
+            image[header..header + 4].copy_from_slice(&PT_LOAD.to_le_bytes());
+            image[header + 4..header + 8].copy_from_slice(&flags.to_le_bytes());
+            image[header + 8..header + 16].copy_from_slice(&offset.to_le_bytes());
+            image[header + 16..header + 24].copy_from_slice(&vaddr.to_le_bytes());
+            image[header + 24..header + 32].copy_from_slice(&vaddr.to_le_bytes());
+            image[header + 32..header + 40].copy_from_slice(&filesz.to_le_bytes());
+            image[header + 40..header + 48].copy_from_slice(&memsz.to_le_bytes());
+            image[header + 48..header + 56].copy_from_slice(&0x1000u64.to_le_bytes());
+        }
+        // mov rax, [rip+0x1001] (the data word at 0x402008) / hlt
+        image[0x1000..0x1008].copy_from_slice(&[0x48, 0x8B, 0x05, 0x01, 0x10, 0x00, 0x00, 0xF4]);
+        image[0x1008..0x1010].copy_from_slice(&0x1122334455667788u64.to_le_bytes());
+        image
+    }
+
+    #[test]
+    fn loads_segments_and_runs() {
+        let mut cpu = Cpu::new(Registers::new(), Memory::new(0));
+        let loaded = load(&mut cpu, &test_image()).unwrap();
+        assert_eq!(loaded.entry, 0x401000);
+        assert_eq!(loaded.phdr, 0x400040);
+        assert_eq!(loaded.phnum, 2);
+        assert_eq!(loaded.program_break, 0x404000);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), 0x401000);
+
+        assert_eq!(cpu.memory.perms(0x400000), Some(Perms::RX));
+        assert_eq!(cpu.memory.perms(0x401000), Some(Perms::RX));
+        assert_eq!(cpu.memory.perms(0x402000), Some(Perms::RW));
+        assert_eq!(cpu.memory.perms(0x403FFF), Some(Perms::RW));
+        assert_eq!(cpu.memory.perms(0x404000), None);
+        // .bss and the part of the data page before the segment read as zero
+        assert_eq!(cpu.memory.read::<u64>(0x402000), 0);
+        assert_eq!(cpu.memory.read::<u64>(0x402010), 0);
+        assert_eq!(cpu.memory.read::<u64>(0x403800), 0);
+        assert!(cpu.memory.try_write::<u8>(0x401000, 0).is_err());
+
+        assert_eq!(cpu.run(10), StopReason::Halt);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x1122334455667788);
+    }
+
+    #[test]
+    fn rejects_malformed_images() {
+        let mut cpu = Cpu::new(Registers::new(), Memory::new(0));
+        let image = test_image();
+        assert_eq!(load(&mut cpu, &image[..40]), Err(ElfError::Truncated));
+        assert_eq!(load(&mut cpu, &image[..0x1004]), Err(ElfError::Truncated));
+
+        let mut bad = image.clone();
+        bad[1] = b'X';
+        assert_eq!(load(&mut cpu, &bad), Err(ElfError::BadMagic));
+        let mut bad = image.clone();
+        bad[18] = 3;
+        assert_eq!(load(&mut cpu, &bad), Err(ElfError::Unsupported));
+        let mut bad = image.clone();
+        bad[EHDR_SIZE..EHDR_SIZE + 4].copy_from_slice(&PT_INTERP.to_le_bytes());
+        assert_eq!(load(&mut cpu, &bad), Err(ElfError::Interpreter));
+        // a PT_PHDR address that wraps once the ET_DYN load base is added
+        let mut bad = image;
+        let header = EHDR_SIZE + PHDR_SIZE;
+        bad[16..18].copy_from_slice(&ET_DYN.to_le_bytes());
+        bad[header..header + 4].copy_from_slice(&PT_PHDR.to_le_bytes());
+        bad[header + 16..header + 24].copy_from_slice(&(u64::MAX - 0x1000).to_le_bytes());
+        assert_eq!(load(&mut cpu, &bad), Err(ElfError::BadSegment));
+    }
+}
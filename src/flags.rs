@@ -0,0 +1,389 @@
+// arithmetic flag computation shared by the instruction implementations
+// undefined flags follow what Intel hardware (and Bochs) produce
+
+use crate::registers::Registers;
+use crate::registers::Flag;
+
+use crate::instructions::OperandSize;
+
+// the six status flags written by arithmetic instructions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ArithFlags {
+    pub cf: bool,
+    pub pf: bool,
+    pub af: bool,
+    pub zf: bool,
+    pub sf: bool,
+    pub of: bool,
+}
+
+impl ArithFlags {
+    pub fn apply(&self, registers: &mut Registers) {
+        registers.set_flag(Flag::CF, self.cf);
+        registers.set_flag(Flag::PF, self.pf);
+        registers.set_flag(Flag::AF, self.af);
+        registers.set_flag(Flag::ZF, self.zf);
+        registers.set_flag(Flag::SF, self.sf);
+        registers.set_flag(Flag::OF, self.of);
+    }
+
+    // INC and DEC leave CF alone
+    pub fn apply_except_cf(&self, registers: &mut Registers) {
+        let cf = registers.get_flag(Flag::CF);
+        ArithFlags { cf, ..*self }.apply(registers);
+    }
+}
+
+pub fn parity(value: u64) -> bool {
+    (value as u8).count_ones().is_multiple_of(2)
+}
+
+// the condition encoded in the low four bits of Jcc/SETcc/CMOVcc, odd codes negate the even ones
+pub fn condition(registers: &Registers, code: u8) -> bool {
+    let flag = |flag| registers.get_flag(flag);
+    let result = match (code >> 1) & 7 {
+        0 => flag(Flag::OF),
+        1 => flag(Flag::CF),
+        2 => flag(Flag::ZF),
+        3 => flag(Flag::CF) || flag(Flag::ZF),
+        4 => flag(Flag::SF),
+        5 => flag(Flag::PF),
+        6 => flag(Flag::SF) != flag(Flag::OF),
+        _ => flag(Flag::ZF) || flag(Flag::SF) != flag(Flag::OF),
+    };
+    result != (code & 1 != 0)
+}
+
+// ZF, SF and PF of a result, everything else cleared
+pub fn result_flags(size: OperandSize, result: u64) -> ArithFlags {
+    let result = result & size.mask();
+    ArithFlags {
+        zf: result == 0,
+        sf: result & size.sign_bit() != 0,
+        pf: parity(result),
+        ..ArithFlags::default()
+    }
+}
+
+// a + b + carry
+pub fn add(size: OperandSize, a: u64, b: u64, carry: bool) -> (u64, ArithFlags) {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let full = a as u128 + b as u128 + carry as u128;
+    let result = full as u64 & size.mask();
+    let flags = ArithFlags {
+        cf: full > size.mask() as u128,
+        af: (a ^ b ^ result) & 0x10 != 0,
+        of: (a ^ result) & (b ^ result) & size.sign_bit() != 0,
+        ..result_flags(size, result)
+    };
+    (result, flags)
+}
+
+// a - b - borrow
+pub fn sub(size: OperandSize, a: u64, b: u64, borrow: bool) -> (u64, ArithFlags) {
+    let (a, b) = (a & size.mask(), b & size.mask());
+    let result = a.wrapping_sub(b).wrapping_sub(borrow as u64) & size.mask();
+    let flags = ArithFlags {
+        cf: (a as u128) < b as u128 + borrow as u128,
+        af: (a ^ b ^ result) & 0x10 != 0,
+        of: (a ^ b) & (a ^ result) & size.sign_bit() != 0,
+        ..result_flags(size, result)
+    };
+    (result, flags)
+}
+
+// AND, OR, XOR and TEST clear CF and OF, AF is undefined and left clear
+pub fn logic(size: OperandSize, result: u64) -> (u64, ArithFlags) {
+    let result = result & size.mask();
+    (result, result_flags(size, result))
+}
+
+fn shift_count(size: OperandSize, count: u64) -> u32 {
+    let mask = if size == OperandSize::Qword { 0x3F } else { 0x1F };
+    (count & mask) as u32
+}
+
+// shifts return None when the masked count is zero, the flags must then stay untouched
+pub fn shl(size: OperandSize, value: u64, count: u64) -> Option<(u64, ArithFlags)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let value = value & size.mask();
+    let bits = size.bits();
+    let result = if count >= bits { 0 } else { (value << count) & size.mask() };
+    let cf = count <= bits && (value >> (bits - count)) & 1 != 0;
+    let flags = ArithFlags {
+        cf,
+        of: cf ^ (result & size.sign_bit() != 0),
+        ..result_flags(size, result)
+    };
+    Some((result, flags))
+}
+
+pub fn shr(size: OperandSize, value: u64, count: u64) -> Option<(u64, ArithFlags)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let value = value & size.mask();
+    let bits = size.bits();
+    let result = if count >= bits { 0 } else { value >> count };
+    let cf = count <= bits && (value >> (count - 1)) & 1 != 0;
+    // the two top result bits, which for a count of one is the original sign
+    let of = ((result << 1) ^ result) & size.sign_bit() != 0;
+    let flags = ArithFlags {
+        cf,
+        of,
+        ..result_flags(size, result)
+    };
+    Some((result, flags))
+}
+
+pub fn sar(size: OperandSize, value: u64, count: u64) -> Option<(u64, ArithFlags)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let signed = size.sign_extend(value) as i64;
+    let bits = size.bits();
+    let result = (signed >> count.min(bits - 1)) as u64 & size.mask();
+    let cf = (signed >> (count - 1).min(bits - 1)) & 1 != 0;
+    let flags = ArithFlags {
+        cf,
+        ..result_flags(size, result)
+    };
+    Some((result, flags))
+}
+
+// rotates only write CF and OF, they return (result, cf, of) or None for a masked count of zero
+pub fn rol(size: OperandSize, value: u64, count: u64) -> Option<(u64, bool, bool)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let value = value & size.mask();
+    let bits = size.bits();
+    let count = count % bits;
+    let result = if count == 0 { value } else { ((value << count) | (value >> (bits - count))) & size.mask() };
+    let cf = result & 1 != 0;
+    Some((result, cf, cf ^ (result & size.sign_bit() != 0)))
+}
+
+pub fn ror(size: OperandSize, value: u64, count: u64) -> Option<(u64, bool, bool)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let value = value & size.mask();
+    let bits = size.bits();
+    let count = count % bits;
+    let result = if count == 0 { value } else { ((value >> count) | (value << (bits - count))) & size.mask() };
+    // the two top result bits
+    Some((result, result & size.sign_bit() != 0, ((result << 1) ^ result) & size.sign_bit() != 0))
+}
+
+// rotates through CF work on bits + 1 bits, 8 and 16-bit counts are taken modulo 9 and 17
+pub fn rcl(size: OperandSize, value: u64, count: u64, carry: bool) -> Option<(u64, bool, bool)> {
+    let bits = size.bits();
+    let count = shift_count(size, count) % (bits + 1);
+    if count == 0 {
+        return None;
+    }
+    let wide = ((carry as u128) << bits) | (value & size.mask()) as u128;
+    let rotated = ((wide << count) | (wide >> (bits + 1 - count))) & ((1u128 << (bits + 1)) - 1);
+    let result = rotated as u64 & size.mask();
+    let cf = (rotated >> bits) & 1 != 0;
+    Some((result, cf, cf ^ (result & size.sign_bit() != 0)))
+}
+
+pub fn rcr(size: OperandSize, value: u64, count: u64, carry: bool) -> Option<(u64, bool, bool)> {
+    let bits = size.bits();
+    let count = shift_count(size, count) % (bits + 1);
+    if count == 0 {
+        return None;
+    }
+    let wide = ((carry as u128) << bits) | (value & size.mask()) as u128;
+    let rotated = ((wide >> count) | (wide << (bits + 1 - count))) & ((1u128 << (bits + 1)) - 1);
+    let result = rotated as u64 & size.mask();
+    let cf = (rotated >> bits) & 1 != 0;
+    Some((result, cf, ((result << 1) ^ result) & size.sign_bit() != 0))
+}
+
+// 16-bit double shifts by more than 16 shift dest:src:dest, as P6 and later do
+fn double_shift_source(size: OperandSize, destination: u64, source: u64) -> u64 {
+    let (destination, source) = (destination & size.mask(), source & size.mask());
+    if size == OperandSize::Word {
+        (destination << 32) | (source << 16) | destination
+    } else {
+        source
+    }
+}
+
+// SHLD shifts source bits in from the right
+pub fn shld(size: OperandSize, destination: u64, source: u64, count: u64) -> Option<(u64, ArithFlags)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let destination = destination & size.mask();
+    let (result, cf) = if size == OperandSize::Word {
+        let wide = double_shift_source(size, destination, source);
+        (((wide << count) >> 32) & 0xFFFF, (wide >> (48 - count)) & 1 != 0)
+    } else {
+        let bits = size.bits();
+        let source = source & size.mask();
+        (((destination << count) | (source >> (bits - count))) & size.mask(), (destination >> (bits - count)) & 1 != 0)
+    };
+    let flags = ArithFlags {
+        cf,
+        of: cf ^ (result & size.sign_bit() != 0),
+        ..result_flags(size, result)
+    };
+    Some((result, flags))
+}
+
+// SHRD shifts source bits in from the left
+pub fn shrd(size: OperandSize, destination: u64, source: u64, count: u64) -> Option<(u64, ArithFlags)> {
+    let count = shift_count(size, count);
+    if count == 0 {
+        return None;
+    }
+    let destination = destination & size.mask();
+    let result = if size == OperandSize::Word {
+        (double_shift_source(size, destination, source) >> count) & 0xFFFF
+    } else {
+        let bits = size.bits();
+        ((destination >> count) | ((source & size.mask()) << (bits - count))) & size.mask()
+    };
+    // past the destination width CF reads as zero, like Bochs
+    let cf = count <= size.bits() && (destination >> (count - 1)) & 1 != 0;
+    let flags = ArithFlags {
+        cf,
+        of: ((result << 1) ^ result) & size.sign_bit() != 0,
+        ..result_flags(size, result)
+    };
+    Some((result, flags))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_sets_carry_overflow_and_adjust() {
+        let (result, flags) = add(OperandSize::Byte, 0x7F, 0x01, false);
+        assert_eq!(result, 0x80);
+        assert!(flags.of && flags.sf && flags.af && !flags.cf && !flags.zf);
+
+        let (result, flags) = add(OperandSize::Dword, 0xFFFFFFFF, 0x00000001, false);
+        assert_eq!(result, 0);
+        assert!(flags.cf && flags.zf && flags.pf && !flags.of);
+
+        let (result, flags) = add(OperandSize::Qword, u64::MAX, 0, true);
+        assert_eq!(result, 0);
+        assert!(flags.cf && flags.zf);
+
+        let (result, flags) = add(OperandSize::Word, 0x8000, 0x8000, false);
+        assert_eq!(result, 0);
+        assert!(flags.cf && flags.of);
+    }
+
+    #[test]
+    fn sub_sets_borrow_and_overflow() {
+        let (result, flags) = sub(OperandSize::Byte, 0x00, 0x01, false);
+        assert_eq!(result, 0xFF);
+        assert!(flags.cf && flags.sf && flags.af && !flags.of);
+
+        let (result, flags) = sub(OperandSize::Word, 0x8000, 0x0001, false);
+        assert_eq!(result, 0x7FFF);
+        assert!(flags.of && !flags.cf);
+
+        let (result, flags) = sub(OperandSize::Qword, 5, 4, true);
+        assert_eq!(result, 0);
+        assert!(flags.zf && !flags.cf);
+
+        let (_, flags) = sub(OperandSize::Dword, 5, 5, true);
+        assert!(flags.cf);
+    }
+
+    #[test]
+    fn logic_clears_carry_and_overflow() {
+        let (result, flags) = logic(OperandSize::Word, 0x1_8003);
+        assert_eq!(result, 0x8003);
+        assert!(flags.sf && flags.pf && !flags.cf && !flags.of && !flags.zf);
+        assert!(!parity(0x01) && parity(0x0100));
+    }
+
+    #[test]
+    fn shifts() {
+        assert_eq!(shl(OperandSize::Byte, 0x81, 0), None);
+        assert_eq!(shr(OperandSize::Dword, 0x81, 32), None);
+
+        let (result, flags) = shl(OperandSize::Byte, 0x81, 1).unwrap();
+        assert_eq!(result, 0x02);
+        assert!(flags.cf && flags.of);
+        let (result, flags) = shl(OperandSize::Byte, 0x81, 9).unwrap();
+        assert_eq!(result, 0);
+        assert!(!flags.cf && flags.zf);
+        let (result, flags) = shl(OperandSize::Byte, 0x01, 8).unwrap();
+        assert_eq!(result, 0);
+        assert!(flags.cf);
+
+        let (result, flags) = shr(OperandSize::Word, 0x8001, 1).unwrap();
+        assert_eq!(result, 0x4000);
+        assert!(flags.cf && flags.of);
+
+        let (result, flags) = sar(OperandSize::Qword, 0x80000000_00000001, 63).unwrap();
+        assert_eq!(result, u64::MAX);
+        assert!(!flags.cf && !flags.of && flags.sf);
+        let (result, flags) = sar(OperandSize::Byte, 0x80, 31).unwrap();
+        assert_eq!(result, 0xFF);
+        assert!(flags.cf);
+    }
+
+    #[test]
+    fn rotates_set_cf_and_of() {
+        assert_eq!(rol(OperandSize::Byte, 0x81, 1), Some((0x03, true, true)));
+        // a count that is a multiple of the width still updates the flags
+        assert_eq!(rol(OperandSize::Byte, 0x81, 8), Some((0x81, true, false)));
+        assert_eq!(ror(OperandSize::Word, 0x0001, 1), Some((0x8000, true, true)));
+        assert_eq!(ror(OperandSize::Qword, 0x1, 64), None);
+        // 9-bit rotate of 0x80 with CF set
+        assert_eq!(rcl(OperandSize::Byte, 0x80, 1, true), Some((0x01, true, true)));
+        assert_eq!(rcl(OperandSize::Byte, 0x80, 9, true), None);
+        assert_eq!(rcr(OperandSize::Byte, 0x01, 1, false), Some((0x00, true, false)));
+        assert_eq!(rcr(OperandSize::Dword, 0x1, 2, true), Some((0xC000_0000, false, false)));
+    }
+
+    #[test]
+    fn double_shifts() {
+        let (result, flags) = shld(OperandSize::Dword, 0x8000_0001, 0xF000_0000, 4).unwrap();
+        assert_eq!(result, 0x0000_001F);
+        assert!(!flags.cf && !flags.of);
+        let (result, flags) = shld(OperandSize::Word, 0x1234, 0xABCD, 20).unwrap();
+        assert_eq!(result, 0xBCD1);
+        assert!(flags.sf && !flags.cf);
+        let (result, flags) = shrd(OperandSize::Qword, 0x3, 0x1, 1).unwrap();
+        assert_eq!(result, 0x8000_0000_0000_0001);
+        assert!(flags.cf && flags.of);
+        let (result, _) = shrd(OperandSize::Word, 0x1234, 0xABCD, 20).unwrap();
+        assert_eq!(result, 0x4ABC);
+        assert_eq!(shrd(OperandSize::Dword, 1, 1, 32), None);
+    }
+
+    #[test]
+    fn apply_writes_registers() {
+        let mut registers = Registers::new();
+        registers.set_flag(Flag::CF, true);
+        let (_, flags) = add(OperandSize::Byte, 0xFF, 0x01, false);
+        ArithFlags { cf: false, ..flags }.apply_except_cf(&mut registers);
+        assert!(registers.get_flag(Flag::CF) && registers.get_flag(Flag::ZF));
+        flags.apply(&mut registers);
+        assert!(registers.get_flag(Flag::CF) && !registers.get_flag(Flag::SF));
+
+        registers.set_iopl(3);
+        assert_eq!(registers.get_iopl(), 3);
+        assert!(registers.get_flag(Flag::ZF));
+    }
+}
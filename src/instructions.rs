@@ -1,2 +1,1064 @@
-// implement instructions here
+// x86-64 instruction decoder
 // reference: qemu/target/i386/tcg/decode-new.c.inc
+
+use crate::memory::MemoryFault;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+mod operand;
+mod alu;
+mod data;
+mod system;
+mod stack;
+mod control;
+mod shift;
+mod bit;
+mod muldiv;
+mod string;
+mod sse;
+mod sse_float;
+mod sse_int;
+mod avx;
+mod avx_float;
+mod avx_int;
+mod mxcsr;
+mod avx512;
+mod avx512_float;
+mod avx512_int;
+mod opmask;
+mod float80;
+mod x87;
+mod segment;
+
+// architectural limit, longer encodings raise #GP
+pub const MAX_INSTRUCTION_LENGTH: usize = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandSize {
+    Byte, Word, Dword, Qword
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpcodeMap {
+    Primary, Map0F, Map0F38, Map0F3A
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentPrefix {
+    ES, CS, SS, DS, FS, GS
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepPrefix {
+    // F3
+    Rep,
+    // F2
+    Repne
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MandatoryPrefix {
+    None, P66, PF3, PF2
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    InvalidOpcode,
+    TooLong,
+    // the byte source ran out before the instruction was complete
+    #[cfg(test)]
+    Truncated,
+    // an instruction byte cannot be fetched
+    Fault(MemoryFault),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Prefixes {
+    pub lock: bool,
+    pub rep: Option<RepPrefix>,
+    pub segment: Option<SegmentPrefix>,
+    pub operand_size: bool,
+    pub address_size: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rex {
+    pub w: bool,
+    pub r: bool,
+    pub x: bool,
+    pub b: bool,
+}
+
+// the VEX payload beyond what REX already carries, its R/X/B/W bits land in `rex`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vex {
+    // VEX.L, 256-bit vectors
+    pub l: bool,
+    // the extra source register, stored inverted in the prefix
+    pub vvvv: u8,
+    // VEX.pp stands in for a 66/F3/F2 prefix
+    pub pp: MandatoryPrefix,
+}
+
+// the EVEX payload, R/X/B/W again land in `rex`; X doubles as bit 4 of a register r/m
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evex {
+    // L'L: 128, 256 or 512-bit vectors, or the rounding mode of a register form with b set
+    pub ll: u8,
+    // the extra source register with V' as bit 4
+    pub vvvv: u8,
+    pub pp: MandatoryPrefix,
+    // R', bit 4 of ModRM.reg
+    pub r_high: bool,
+    // zeroing instead of merging masking
+    pub z: bool,
+    // embedded broadcast for memory forms, embedded rounding or SAE for register forms
+    pub b: bool,
+    // the writemask register, k0 meaning no masking
+    pub aaa: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModRM {
+    pub mode: u8,
+    pub reg: u8,
+    pub rm: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sib {
+    pub scale: u8,
+    pub index: u8,
+    pub base: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Immediate {
+    pub value: u64,
+    pub size: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub prefixes: Prefixes,
+    pub rex: Option<Rex>,
+    pub vex: Option<Vex>,
+    pub evex: Option<Evex>,
+    pub map: OpcodeMap,
+    pub opcode: u8,
+    pub modrm: Option<ModRM>,
+    pub sib: Option<Sib>,
+    pub displacement: i64,
+    pub displacement_size: usize,
+    pub immediate: Option<Immediate>,
+    // only ENTER carries a second immediate
+    pub immediate2: Option<Immediate>,
+    pub length: usize,
+}
+
+// immediate operand kinds, named after the Intel opcode map abbreviations
+#[derive(Clone, Copy)]
+enum Imm {
+    None,
+    Ib,
+    Iw,
+    // word or dword depending on operand size
+    Iz,
+    // word, dword or qword depending on operand size
+    Iv,
+    Jb,
+    Jz,
+    // ENTER
+    IwIb,
+    // memory offset sized by the address size
+    Moffs,
+    // F6/F7 group 3: only TEST (/0, /1) has an immediate
+    TestIb,
+    TestIz,
+}
+
+#[derive(Clone, Copy)]
+struct OpcodeEntry {
+    valid: bool,
+    modrm: bool,
+    imm: Imm,
+}
+
+const fn entry(modrm: bool, imm: Imm) -> OpcodeEntry {
+    OpcodeEntry { valid: true, modrm, imm }
+}
+
+// table shorthands
+const X: OpcodeEntry = OpcodeEntry { valid: false, modrm: false, imm: Imm::None };
+const N: OpcodeEntry = entry(false, Imm::None);
+const M: OpcodeEntry = entry(true, Imm::None);
+const MB: OpcodeEntry = entry(true, Imm::Ib);
+const MZ: OpcodeEntry = entry(true, Imm::Iz);
+const IB: OpcodeEntry = entry(false, Imm::Ib);
+const IW: OpcodeEntry = entry(false, Imm::Iw);
+const IZ: OpcodeEntry = entry(false, Imm::Iz);
+const IV: OpcodeEntry = entry(false, Imm::Iv);
+const JB: OpcodeEntry = entry(false, Imm::Jb);
+const JZ: OpcodeEntry = entry(false, Imm::Jz);
+const WB: OpcodeEntry = entry(false, Imm::IwIb);
+const MO: OpcodeEntry = entry(false, Imm::Moffs);
+const TB: OpcodeEntry = entry(true, Imm::TestIb);
+const TZ: OpcodeEntry = entry(true, Imm::TestIz);
+
+// one-byte opcode map in 64-bit mode, prefixes and escapes are handled before the lookup
+#[rustfmt::skip]
+static PRIMARY_MAP: [OpcodeEntry; 256] = [
+    //  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+        M,  M,  M,  M,  IB, IZ, X,  X,  M,  M,  M,  M,  IB, IZ, X,  X,  // 0
+        M,  M,  M,  M,  IB, IZ, X,  X,  M,  M,  M,  M,  IB, IZ, X,  X,  // 1
+        M,  M,  M,  M,  IB, IZ, X,  X,  M,  M,  M,  M,  IB, IZ, X,  X,  // 2
+        M,  M,  M,  M,  IB, IZ, X,  X,  M,  M,  M,  M,  IB, IZ, X,  X,  // 3
+        X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  X,  // 4
+        N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  // 5
+        X,  X,  X,  M,  X,  X,  X,  X,  IZ, MZ, IB, MB, N,  N,  N,  N,  // 6
+        JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, JB, // 7
+        MB, MZ, X,  MB, M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 8
+        N,  N,  N,  N,  N,  N,  N,  N,  N,  N,  X,  N,  N,  N,  N,  N,  // 9
+        MO, MO, MO, MO, N,  N,  N,  N,  IB, IZ, N,  N,  N,  N,  N,  N,  // A
+        IB, IB, IB, IB, IB, IB, IB, IB, IV, IV, IV, IV, IV, IV, IV, IV, // B
+        MB, MB, IW, N,  X,  X,  MB, MZ, WB, N,  IW, N,  N,  IB, X,  N,  // C
+        M,  M,  M,  M,  X,  X,  X,  N,  M,  M,  M,  M,  M,  M,  M,  M,  // D
+        JB, JB, JB, JB, IB, IB, IB, IB, JZ, JZ, X,  JB, N,  N,  N,  N,  // E
+        X,  N,  X,  X,  N,  N,  TB, TZ, N,  N,  N,  N,  N,  N,  M,  M,  // F
+];
+
+// two-byte opcode map (0F xx)
+#[rustfmt::skip]
+static MAP_0F: [OpcodeEntry; 256] = [
+    //  0   1   2   3   4   5   6   7   8   9   A   B   C   D   E   F
+        M,  M,  M,  M,  X,  N,  N,  N,  N,  N,  X,  N,  X,  M,  N,  X,  // 0
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 1
+        M,  M,  M,  M,  X,  X,  X,  X,  M,  M,  M,  M,  M,  M,  M,  M,  // 2
+        N,  N,  N,  N,  N,  N,  X,  N,  X,  X,  X,  X,  X,  X,  X,  X,  // 3
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 4
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 5
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 6
+        MB, MB, MB, MB, M,  M,  M,  N,  M,  M,  X,  X,  M,  M,  M,  M,  // 7
+        JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, JZ, // 8
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // 9
+        N,  N,  N,  M,  MB, M,  X,  X,  N,  N,  N,  M,  MB, M,  M,  M,  // A
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  MB, M,  M,  M,  M,  M,  // B
+        M,  M,  MB, M,  MB, MB, MB, M,  N,  N,  N,  N,  N,  N,  N,  N,  // C
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // D
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // E
+        M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  M,  // F
+];
+
+// three-byte maps: every 0F 38 opcode takes a ModRM, every 0F 3A opcode also an imm8
+static MAP_0F38: [OpcodeEntry; 256] = [M; 256];
+static MAP_0F3A: [OpcodeEntry; 256] = [MB; 256];
+
+impl OperandSize {
+    pub fn from_bytes(bytes: usize) -> OperandSize {
+        match bytes {
+            1 => OperandSize::Byte,
+            2 => OperandSize::Word,
+            4 => OperandSize::Dword,
+            _ => OperandSize::Qword,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        match self {
+            OperandSize::Byte => 1,
+            OperandSize::Word => 2,
+            OperandSize::Dword => 4,
+            OperandSize::Qword => 8,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self.bytes() as u32 * 8
+    }
+
+    pub fn mask(self) -> u64 {
+        match self {
+            OperandSize::Qword => u64::MAX,
+            _ => (1u64 << self.bits()) - 1,
+        }
+    }
+
+    pub fn sign_bit(self) -> u64 {
+        1u64 << (self.bits() - 1)
+    }
+
+    // sign-extend the low `self` bits of value to 64 bits
+    pub fn sign_extend(self, value: u64) -> u64 {
+        let shift = 64 - self.bits();
+        (((value << shift) as i64) >> shift) as u64
+    }
+}
+
+impl Immediate {
+    pub fn sign_extended(&self) -> u64 {
+        let shift = 64 - self.size * 8;
+        (((self.value << shift) as i64) >> shift) as u64
+    }
+}
+
+impl Instruction {
+    #[cfg(test)]
+    pub fn decode_bytes(bytes: &[u8]) -> Result<Instruction, DecodeError> {
+        Self::decode_with(|offset| bytes.get(offset).copied().ok_or(DecodeError::Truncated))
+    }
+
+    pub fn decode_with<F: FnMut(usize) -> Result<u8, DecodeError>>(fetch: F) -> Result<Instruction, DecodeError> {
+        let mut reader = ByteReader { fetch, position: 0 };
+        let mut prefixes = Prefixes::default();
+        let mut rex = None;
+
+        // legacy prefixes and REX, a REX prefix only counts when it directly precedes the opcode
+        let mut byte = reader.next()?;
+        loop {
+            match byte {
+                0xF0 => prefixes.lock = true,
+                0xF2 => prefixes.rep = Some(RepPrefix::Repne),
+                0xF3 => prefixes.rep = Some(RepPrefix::Rep),
+                0x26 => prefixes.segment = Some(SegmentPrefix::ES),
+                0x2E => prefixes.segment = Some(SegmentPrefix::CS),
+                0x36 => prefixes.segment = Some(SegmentPrefix::SS),
+                0x3E => prefixes.segment = Some(SegmentPrefix::DS),
+                0x64 => prefixes.segment = Some(SegmentPrefix::FS),
+                0x65 => prefixes.segment = Some(SegmentPrefix::GS),
+                0x66 => prefixes.operand_size = true,
+                0x67 => prefixes.address_size = true,
+                0x40..=0x4F => {
+                    rex = Some(Rex {
+                        w: byte & 0x08 != 0,
+                        r: byte & 0x04 != 0,
+                        x: byte & 0x02 != 0,
+                        b: byte & 0x01 != 0,
+                    });
+                    byte = reader.next()?;
+                    continue;
+                }
+                _ => break,
+            }
+            rex = None;
+            byte = reader.next()?;
+        }
+
+        // C4/C5 are always VEX and 62 always EVEX in 64-bit mode, LES/LDS/BOUND do not exist there
+        let mut vex = None;
+        let mut evex = None;
+        let (map, opcode) = if byte == 0xC4 || byte == 0xC5 || byte == 0x62 {
+            if prefixes.lock || prefixes.operand_size || prefixes.rep.is_some() || rex.is_some() {
+                return Err(DecodeError::InvalidOpcode);
+            }
+            let (prefix_rex, map) = if byte == 0x62 {
+                let (prefix_rex, payload, map) = reader.evex()?;
+                evex = Some(payload);
+                (prefix_rex, map)
+            } else {
+                let (prefix_rex, payload, map) = reader.vex(byte)?;
+                vex = Some(payload);
+                (prefix_rex, map)
+            };
+            rex = Some(prefix_rex);
+            (map, reader.next()?)
+        } else if byte == 0x0F {
+            match reader.next()? {
+                0x38 => (OpcodeMap::Map0F38, reader.next()?),
+                0x3A => (OpcodeMap::Map0F3A, reader.next()?),
+                second => (OpcodeMap::Map0F, second),
+            }
+        } else {
+            (OpcodeMap::Primary, byte)
+        };
+        let entry = match map {
+            OpcodeMap::Primary => PRIMARY_MAP[opcode as usize],
+            OpcodeMap::Map0F => MAP_0F[opcode as usize],
+            OpcodeMap::Map0F38 => MAP_0F38[opcode as usize],
+            OpcodeMap::Map0F3A => MAP_0F3A[opcode as usize],
+        };
+        if !entry.valid {
+            return Err(DecodeError::InvalidOpcode);
+        }
+
+        let mut instruction = Instruction {
+            prefixes,
+            rex,
+            vex,
+            evex,
+            map,
+            opcode,
+            modrm: None,
+            sib: None,
+            displacement: 0,
+            displacement_size: 0,
+            immediate: None,
+            immediate2: None,
+            length: 0,
+        };
+
+        if entry.modrm {
+            let byte = reader.next()?;
+            let modrm = ModRM {
+                mode: byte >> 6,
+                reg: (byte >> 3) & 7,
+                rm: byte & 7,
+            };
+            instruction.modrm = Some(modrm);
+            if modrm.mode != 3 {
+                let mut base = modrm.rm;
+                if modrm.rm == 4 {
+                    let byte = reader.next()?;
+                    let sib = Sib {
+                        scale: byte >> 6,
+                        index: (byte >> 3) & 7,
+                        base: byte & 7,
+                    };
+                    base = sib.base;
+                    instruction.sib = Some(sib);
+                }
+                instruction.displacement_size = match modrm.mode {
+                    0 if base == 5 => 4,
+                    0 => 0,
+                    1 => 1,
+                    _ => 4,
+                };
+                if instruction.displacement_size > 0 {
+                    let value = reader.read_sized(instruction.displacement_size)?;
+                    instruction.displacement = OperandSize::from_bytes(instruction.displacement_size).sign_extend(value) as i64;
+                }
+            }
+        }
+
+        let operand_size = instruction.operand_size();
+        let immediate_size = match entry.imm {
+            Imm::None => 0,
+            Imm::Ib | Imm::Jb => 1,
+            Imm::Iw => 2,
+            Imm::Iz => if operand_size == OperandSize::Word { 2 } else { 4 },
+            Imm::Iv => operand_size.bytes(),
+            Imm::Jz => 4,
+            Imm::IwIb => 2,
+            Imm::Moffs => instruction.address_size().bytes(),
+            Imm::TestIb | Imm::TestIz if instruction.modrm.map_or(0, |m| m.reg) > 1 => 0,
+            Imm::TestIb => 1,
+            Imm::TestIz => if operand_size == OperandSize::Word { 2 } else { 4 },
+        };
+        if immediate_size > 0 {
+            instruction.immediate = Some(Immediate {
+                value: reader.read_sized(immediate_size)?,
+                size: immediate_size,
+            });
+        }
+        if let Imm::IwIb = entry.imm {
+            instruction.immediate2 = Some(Immediate {
+                value: reader.read_sized(1)?,
+                size: 1,
+            });
+        }
+
+        instruction.length = reader.position;
+        Ok(instruction)
+    }
+
+    // effective operand size of a non-byte instruction
+    pub fn operand_size(&self) -> OperandSize {
+        if self.rex.is_some_and(|rex| rex.w) {
+            OperandSize::Qword
+        } else if self.prefixes.operand_size {
+            OperandSize::Word
+        } else {
+            OperandSize::Dword
+        }
+    }
+
+    pub fn address_size(&self) -> OperandSize {
+        if self.prefixes.address_size {
+            OperandSize::Dword
+        } else {
+            OperandSize::Qword
+        }
+    }
+
+    // SSE-style opcode selection, F2/F3 take precedence over 66; VEX encodes it in pp
+    pub fn mandatory_prefix(&self) -> MandatoryPrefix {
+        if let Some(vex) = self.vex {
+            return vex.pp;
+        }
+        if let Some(evex) = self.evex {
+            return evex.pp;
+        }
+        match self.prefixes.rep {
+            Some(RepPrefix::Rep) => MandatoryPrefix::PF3,
+            Some(RepPrefix::Repne) => MandatoryPrefix::PF2,
+            None if self.prefixes.operand_size => MandatoryPrefix::P66,
+            None => MandatoryPrefix::None,
+        }
+    }
+
+    // ModRM.reg extended by REX.R, and by EVEX.R' for the 32 vector registers
+    pub fn reg(&self) -> u8 {
+        let reg = self.modrm.map_or(0, |modrm| modrm.reg);
+        let high = if self.evex.is_some_and(|evex| evex.r_high) { 16 } else { 0 };
+        reg | high | if self.rex.is_some_and(|rex| rex.r) { 8 } else { 0 }
+    }
+
+    // ModRM.rm extended by REX.B, only meaningful for register operands where EVEX.X adds bit 4
+    pub fn rm(&self) -> u8 {
+        let rm = self.modrm.map_or(0, |modrm| modrm.rm);
+        let high = if self.evex.is_some() && !self.has_memory_operand() && self.rex.is_some_and(|rex| rex.x) { 16 } else { 0 };
+        rm | high | if self.rex.is_some_and(|rex| rex.b) { 8 } else { 0 }
+    }
+
+    // register encoded in the low three opcode bits (PUSH/POP/MOV/XCHG/BSWAP r)
+    pub fn opcode_reg(&self) -> u8 {
+        (self.opcode & 7) | if self.rex.is_some_and(|rex| rex.b) { 8 } else { 0 }
+    }
+
+    // the ModRM.reg field used as an opcode extension (/digit)
+    pub fn group_index(&self) -> u8 {
+        self.modrm.map_or(0, |modrm| modrm.reg)
+    }
+
+    // VEX.vvvv or EVEX.V'vvvv, 0 without either prefix
+    pub fn vvvv(&self) -> u8 {
+        match (self.vex, self.evex) {
+            (Some(vex), _) => vex.vvvv,
+            (_, Some(evex)) => evex.vvvv,
+            _ => 0,
+        }
+    }
+
+    pub fn has_memory_operand(&self) -> bool {
+        self.modrm.is_some_and(|modrm| modrm.mode != 3)
+    }
+
+    pub fn is_rip_relative(&self) -> bool {
+        self.modrm.is_some_and(|modrm| modrm.mode == 0 && modrm.rm == 5)
+    }
+
+    pub fn immediate_value(&self) -> u64 {
+        self.immediate.map_or(0, |imm| imm.value)
+    }
+
+    pub fn immediate_sign_extended(&self) -> u64 {
+        self.immediate.map_or(0, |imm| imm.sign_extended())
+    }
+}
+
+// route a decoded instruction to its implementation
+pub fn execute(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.vex.is_some() {
+        return execute_vex(cpu, instruction);
+    }
+    if instruction.evex.is_some() {
+        return execute_evex(cpu, instruction);
+    }
+    let group = instruction.group_index();
+    match (instruction.map, instruction.opcode) {
+        (OpcodeMap::Primary, 0x00..=0x3F) if instruction.opcode & 7 < 6 => alu::binary(cpu, instruction),
+        (OpcodeMap::Primary, 0x50..=0x57) => stack::push_register(cpu, instruction),
+        (OpcodeMap::Primary, 0x58..=0x5F) => stack::pop_register(cpu, instruction),
+        (OpcodeMap::Primary, 0x63) => data::mov_extend(cpu, instruction),
+        (OpcodeMap::Primary, 0x68) | (OpcodeMap::Primary, 0x6A) => stack::push_immediate(cpu, instruction),
+        (OpcodeMap::Primary, 0x69) | (OpcodeMap::Primary, 0x6B) => muldiv::imul(cpu, instruction),
+        (OpcodeMap::Primary, 0x70..=0x7F) => control::jcc(cpu, instruction),
+        (OpcodeMap::Primary, 0x80) | (OpcodeMap::Primary, 0x81) | (OpcodeMap::Primary, 0x83) => alu::group1(cpu, instruction),
+        (OpcodeMap::Primary, 0x84) | (OpcodeMap::Primary, 0x85) => alu::test(cpu, instruction),
+        (OpcodeMap::Primary, 0x86) | (OpcodeMap::Primary, 0x87) => data::xchg(cpu, instruction),
+        (OpcodeMap::Primary, 0x88..=0x8B) => data::mov(cpu, instruction),
+        (OpcodeMap::Primary, 0x8C) | (OpcodeMap::Primary, 0x8E) => segment::mov_segment(cpu, instruction),
+        (OpcodeMap::Primary, 0x8D) => data::lea(cpu, instruction),
+        (OpcodeMap::Primary, 0x8F) => stack::pop_rm(cpu, instruction),
+        (OpcodeMap::Primary, 0x90) if instruction.opcode_reg() == 0 => system::nop(cpu, instruction),
+        (OpcodeMap::Primary, 0x90..=0x97) => data::xchg_accumulator(cpu, instruction),
+        (OpcodeMap::Primary, 0x98) => data::sign_extend_accumulator(cpu, instruction),
+        (OpcodeMap::Primary, 0x99) => data::sign_extend_into_rdx(cpu, instruction),
+        (OpcodeMap::Primary, 0x9B) => x87::wait(cpu, instruction),
+        (OpcodeMap::Primary, 0x9C) => stack::pushf(cpu, instruction),
+        (OpcodeMap::Primary, 0x9D) => stack::popf(cpu, instruction),
+        (OpcodeMap::Primary, 0xA0..=0xA3) => data::mov_offset(cpu, instruction),
+        (OpcodeMap::Primary, 0xA4..=0xA7) | (OpcodeMap::Primary, 0xAA..=0xAF) => string::string(cpu, instruction),
+        (OpcodeMap::Primary, 0xA8) | (OpcodeMap::Primary, 0xA9) => alu::test(cpu, instruction),
+        (OpcodeMap::Primary, 0xB0..=0xBF) => data::mov_register_immediate(cpu, instruction),
+        (OpcodeMap::Primary, 0xC0) | (OpcodeMap::Primary, 0xC1) => shift::group2(cpu, instruction),
+        (OpcodeMap::Primary, 0xC2) | (OpcodeMap::Primary, 0xC3) => control::ret(cpu, instruction),
+        (OpcodeMap::Primary, 0xC6) | (OpcodeMap::Primary, 0xC7) => data::mov_immediate(cpu, instruction),
+        (OpcodeMap::Primary, 0xC8) => stack::enter(cpu, instruction),
+        (OpcodeMap::Primary, 0xC9) => stack::leave(cpu, instruction),
+        (OpcodeMap::Primary, 0xD0..=0xD3) => shift::group2(cpu, instruction),
+        (OpcodeMap::Primary, 0xD8..=0xDF) => x87::escape(cpu, instruction),
+        (OpcodeMap::Primary, 0xE0..=0xE2) => control::loop_(cpu, instruction),
+        (OpcodeMap::Primary, 0xE3) => control::jrcxz(cpu, instruction),
+        (OpcodeMap::Primary, 0xE8) => control::call_relative(cpu, instruction),
+        (OpcodeMap::Primary, 0xE9) | (OpcodeMap::Primary, 0xEB) => control::jmp_relative(cpu, instruction),
+        (OpcodeMap::Primary, 0xF6) | (OpcodeMap::Primary, 0xF7) => match group {
+            0 | 1 => alu::test(cpu, instruction),
+            2 => alu::not(cpu, instruction),
+            3 => alu::neg(cpu, instruction),
+            4 | 5 => muldiv::multiply(cpu, instruction),
+            _ => muldiv::divide(cpu, instruction),
+        },
+        (OpcodeMap::Primary, 0xF5) | (OpcodeMap::Primary, 0xF8) | (OpcodeMap::Primary, 0xF9) | (OpcodeMap::Primary, 0xFC) | (OpcodeMap::Primary, 0xFD) => system::flag_control(cpu, instruction),
+        (OpcodeMap::Primary, 0xFE) => alu::inc_dec(cpu, instruction),
+        (OpcodeMap::Primary, 0xFF) => match group {
+            0 | 1 => alu::inc_dec(cpu, instruction),
+            2 => control::call_indirect(cpu, instruction),
+            4 => control::jmp_indirect(cpu, instruction),
+            6 => stack::push_rm(cpu, instruction),
+            // far CALL/JMP through memory are not supported
+            _ => Err(Exception::InvalidOpcode),
+        },
+        (OpcodeMap::Primary, 0xCC) => system::int3(cpu, instruction),
+        (OpcodeMap::Primary, 0xF4) => system::hlt(cpu, instruction),
+        (OpcodeMap::Map0F, 0x00) => segment::group6(cpu, instruction),
+        (OpcodeMap::Map0F, 0x01) => segment::group7(cpu, instruction),
+        (OpcodeMap::Map0F, 0x05) => system::syscall(cpu, instruction),
+        (OpcodeMap::Map0F, 0x20) | (OpcodeMap::Map0F, 0x22) => system::mov_control_register(cpu, instruction),
+        (OpcodeMap::Map0F, 0x30) | (OpcodeMap::Map0F, 0x32) => system::msr(cpu, instruction),
+        (OpcodeMap::Map0F, 0x0B) | (OpcodeMap::Map0F, 0xB9) | (OpcodeMap::Map0F, 0xFF) => system::ud(cpu, instruction),
+        (OpcodeMap::Map0F, 0x18..=0x1F) => system::nop(cpu, instruction),
+        (OpcodeMap::Map0F, 0x10) | (OpcodeMap::Map0F, 0x11) | (OpcodeMap::Map0F, 0x28) | (OpcodeMap::Map0F, 0x29) | (OpcodeMap::Map0F, 0x2B) => sse::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x12) | (OpcodeMap::Map0F, 0x13) | (OpcodeMap::Map0F, 0x16) | (OpcodeMap::Map0F, 0x17) => sse::mov_half(cpu, instruction),
+        (OpcodeMap::Map0F, 0x14) | (OpcodeMap::Map0F, 0x15) => sse::unpack_float(cpu, instruction),
+        (OpcodeMap::Map0F, 0x2A) | (OpcodeMap::Map0F, 0x2C) | (OpcodeMap::Map0F, 0x2D) => sse_float::convert(cpu, instruction),
+        (OpcodeMap::Map0F, 0x2E) | (OpcodeMap::Map0F, 0x2F) => sse_float::comis(cpu, instruction),
+        (OpcodeMap::Map0F, 0x40..=0x4F) => control::cmovcc(cpu, instruction),
+        (OpcodeMap::Map0F, 0x50) | (OpcodeMap::Map0F, 0xD7) => sse::move_mask(cpu, instruction),
+        (OpcodeMap::Map0F, 0x54..=0x57) => sse_float::logic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x5A) | (OpcodeMap::Map0F, 0x5B) | (OpcodeMap::Map0F, 0xE6) => sse_float::convert(cpu, instruction),
+        (OpcodeMap::Map0F, 0x51..=0x5F) => sse_float::arithmetic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x6E) | (OpcodeMap::Map0F, 0x7E) | (OpcodeMap::Map0F, 0xD6) => sse::mov_integer(cpu, instruction),
+        (OpcodeMap::Map0F, 0x6F) | (OpcodeMap::Map0F, 0x7F) | (OpcodeMap::Map0F, 0xE7) => sse::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x60..=0x6D) | (OpcodeMap::Map0F, 0x74..=0x76) => sse_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F, 0x70) => sse::pshuf(cpu, instruction),
+        (OpcodeMap::Map0F, 0x71..=0x73) => sse_int::shift_immediate(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC2) => sse_float::compare(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC4) | (OpcodeMap::Map0F, 0xC5) => sse::insert_extract_word(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC6) => sse::shuffle_float(cpu, instruction),
+        (OpcodeMap::Map0F, 0xD0..=0xFE) => sse_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F, 0x80..=0x8F) => control::jcc(cpu, instruction),
+        (OpcodeMap::Map0F, 0x90..=0x9F) => control::setcc(cpu, instruction),
+        (OpcodeMap::Map0F, 0xA3) | (OpcodeMap::Map0F, 0xAB) | (OpcodeMap::Map0F, 0xB3) | (OpcodeMap::Map0F, 0xBB) => bit::bit_test(cpu, instruction),
+        (OpcodeMap::Map0F, 0xA4) | (OpcodeMap::Map0F, 0xA5) | (OpcodeMap::Map0F, 0xAC) | (OpcodeMap::Map0F, 0xAD) => shift::double_shift(cpu, instruction),
+        (OpcodeMap::Map0F, 0xAE) if instruction.mandatory_prefix() == MandatoryPrefix::PF3 && !instruction.has_memory_operand() => segment::fsgsbase(cpu, instruction),
+        (OpcodeMap::Map0F, 0xAE) => match group {
+            0 => x87::fxsave(cpu, instruction),
+            1 => x87::fxrstor(cpu, instruction),
+            2 => mxcsr::ldmxcsr(cpu, instruction),
+            3 => mxcsr::stmxcsr(cpu, instruction),
+            _ => Err(Exception::InvalidOpcode),
+        },
+        (OpcodeMap::Map0F, 0xAF) => muldiv::imul(cpu, instruction),
+        (OpcodeMap::Map0F, 0xBA) => bit::bit_test(cpu, instruction),
+        (OpcodeMap::Map0F, 0xB6) | (OpcodeMap::Map0F, 0xB7) | (OpcodeMap::Map0F, 0xBE) | (OpcodeMap::Map0F, 0xBF) => data::mov_extend(cpu, instruction),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// VEX encodings share opcode numbers with the legacy forms but are a separate instruction space
+fn execute_vex(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    match (instruction.map, instruction.opcode) {
+        (OpcodeMap::Map0F, 0x10) | (OpcodeMap::Map0F, 0x11) | (OpcodeMap::Map0F, 0x28) | (OpcodeMap::Map0F, 0x29) | (OpcodeMap::Map0F, 0x2B) => avx::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x14) | (OpcodeMap::Map0F, 0x15) => avx::unpack_float(cpu, instruction),
+        (OpcodeMap::Map0F, 0x41..=0x47) => opmask::logic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x54..=0x57) => avx_float::logic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x51..=0x5F) => avx_float::arithmetic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x60..=0x6D) | (OpcodeMap::Map0F, 0x74..=0x76) => avx_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F, 0x6F) | (OpcodeMap::Map0F, 0x7F) | (OpcodeMap::Map0F, 0xE7) => avx::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x70) => avx::pshuf(cpu, instruction),
+        (OpcodeMap::Map0F, 0x71..=0x73) => avx_int::shift_immediate(cpu, instruction),
+        (OpcodeMap::Map0F, 0x77) => avx::zero_upper(cpu, instruction),
+        (OpcodeMap::Map0F, 0x90..=0x93) => opmask::kmov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x98) => opmask::kortest(cpu, instruction),
+        (OpcodeMap::Map0F, 0xAE) if instruction.group_index() == 2 => mxcsr::ldmxcsr(cpu, instruction),
+        (OpcodeMap::Map0F, 0xAE) if instruction.group_index() == 3 => mxcsr::stmxcsr(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC2) => avx_float::compare(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC6) => avx::shuffle_float(cpu, instruction),
+        (OpcodeMap::Map0F, 0xD0..=0xFE) => avx_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x00) => avx::pshufb(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x16) | (OpcodeMap::Map0F38, 0x36) => avx::permute_dwords(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x18..=0x1A) | (OpcodeMap::Map0F38, 0x58..=0x5A) | (OpcodeMap::Map0F38, 0x78) | (OpcodeMap::Map0F38, 0x79) => avx::broadcast(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x45..=0x47) => avx_int::shift_variable(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x90..=0x93) => avx::gather(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x00) | (OpcodeMap::Map0F3A, 0x01) => avx::permute_qwords(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x06) | (OpcodeMap::Map0F3A, 0x46) => avx::permute_halves(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x18) | (OpcodeMap::Map0F3A, 0x38) => avx::insert_half(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x19) | (OpcodeMap::Map0F3A, 0x39) => avx::extract_half(cpu, instruction),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+fn execute_evex(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    match (instruction.map, instruction.opcode) {
+        (OpcodeMap::Map0F, 0x10) | (OpcodeMap::Map0F, 0x11) | (OpcodeMap::Map0F, 0x28) | (OpcodeMap::Map0F, 0x29) => avx512::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x6F) | (OpcodeMap::Map0F, 0x7F) => avx512::mov(cpu, instruction),
+        (OpcodeMap::Map0F, 0x54..=0x57) => avx512_float::logic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x51..=0x5F) => avx512_float::arithmetic(cpu, instruction),
+        (OpcodeMap::Map0F, 0x64..=0x66) | (OpcodeMap::Map0F, 0x74..=0x76) => avx512_int::compare(cpu, instruction),
+        (OpcodeMap::Map0F, 0xC2) => avx512_float::compare(cpu, instruction),
+        (OpcodeMap::Map0F, 0xD4) | (OpcodeMap::Map0F, 0xDB) | (OpcodeMap::Map0F, 0xDF) | (OpcodeMap::Map0F, 0xEB) | (OpcodeMap::Map0F, 0xEF) => avx512_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F, 0xF8..=0xFE) => avx512_int::packed(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x18) | (OpcodeMap::Map0F38, 0x19) | (OpcodeMap::Map0F38, 0x58) | (OpcodeMap::Map0F38, 0x59) | (OpcodeMap::Map0F38, 0x78..=0x7C) => avx512::broadcast(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x29) | (OpcodeMap::Map0F38, 0x37) => avx512_int::compare(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x40) => avx512_int::multiply_low(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x64) | (OpcodeMap::Map0F38, 0x66) => avx512_int::blend(cpu, instruction),
+        (OpcodeMap::Map0F38, 0x65) => avx512_float::blend(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x1E) | (OpcodeMap::Map0F3A, 0x1F) | (OpcodeMap::Map0F3A, 0x3E) | (OpcodeMap::Map0F3A, 0x3F) => avx512_int::compare(cpu, instruction),
+        (OpcodeMap::Map0F3A, 0x25) => avx512_int::ternlog(cpu, instruction),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// the low two bits of the last VEX/EVEX payload byte
+fn prefix_from_pp(byte: u8) -> MandatoryPrefix {
+    match byte & 3 {
+        0 => MandatoryPrefix::None,
+        1 => MandatoryPrefix::P66,
+        2 => MandatoryPrefix::PF3,
+        _ => MandatoryPrefix::PF2,
+    }
+}
+
+struct ByteReader<F> {
+    fetch: F,
+    position: usize,
+}
+
+impl<F: FnMut(usize) -> Result<u8, DecodeError>> ByteReader<F> {
+    fn next(&mut self) -> Result<u8, DecodeError> {
+        if self.position >= MAX_INSTRUCTION_LENGTH {
+            return Err(DecodeError::TooLong);
+        }
+        let byte = (self.fetch)(self.position)?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    // the rest of a two-byte (C5) or three-byte (C4) VEX prefix, R/X/B and vvvv are stored inverted
+    fn vex(&mut self, first: u8) -> Result<(Rex, Vex, OpcodeMap), DecodeError> {
+        let byte = self.next()?;
+        let (rex, map, last) = if first == 0xC5 {
+            (Rex { r: byte & 0x80 == 0, ..Rex::default() }, OpcodeMap::Map0F, byte)
+        } else {
+            let map = match byte & 0x1F {
+                1 => OpcodeMap::Map0F,
+                2 => OpcodeMap::Map0F38,
+                3 => OpcodeMap::Map0F3A,
+                _ => return Err(DecodeError::InvalidOpcode),
+            };
+            let last = self.next()?;
+            let rex = Rex {
+                w: last & 0x80 != 0,
+                r: byte & 0x80 == 0,
+                x: byte & 0x40 == 0,
+                b: byte & 0x20 == 0,
+            };
+            (rex, map, last)
+        };
+        let vex = Vex {
+            l: last & 0x04 != 0,
+            vvvv: !last >> 3 & 0xF,
+            pp: prefix_from_pp(last),
+        };
+        Ok((rex, vex, map))
+    }
+
+    // the three payload bytes of a 62 EVEX prefix: R X B R' 0 mmm, W vvvv 1 pp, z L'L b V' aaa
+    fn evex(&mut self) -> Result<(Rex, Evex, OpcodeMap), DecodeError> {
+        let (p0, p1, p2) = (self.next()?, self.next()?, self.next()?);
+        let map = match p0 & 0x0F {
+            1 => OpcodeMap::Map0F,
+            2 => OpcodeMap::Map0F38,
+            3 => OpcodeMap::Map0F3A,
+            _ => return Err(DecodeError::InvalidOpcode),
+        };
+        if p1 & 0x04 == 0 {
+            return Err(DecodeError::InvalidOpcode);
+        }
+        let rex = Rex {
+            w: p1 & 0x80 != 0,
+            r: p0 & 0x80 == 0,
+            x: p0 & 0x40 == 0,
+            b: p0 & 0x20 == 0,
+        };
+        let evex = Evex {
+            ll: p2 >> 5 & 3,
+            vvvv: (!p1 >> 3 & 0xF) | if p2 & 0x08 == 0 { 16 } else { 0 },
+            pp: prefix_from_pp(p1),
+            r_high: p0 & 0x10 == 0,
+            z: p2 & 0x80 != 0,
+            b: p2 & 0x10 != 0,
+            aaa: p2 & 7,
+        };
+        Ok((rex, evex, map))
+    }
+
+    // little-endian immediate or displacement
+    fn read_sized(&mut self, size: usize) -> Result<u64, DecodeError> {
+        let mut value = 0u64;
+        for i in 0..size {
+            value |= (self.next()? as u64) << (i * 8);
+        }
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Instruction {
+        let instruction = Instruction::decode_bytes(bytes).unwrap();
+        assert_eq!(instruction.length, bytes.len(), "length of {:02X?}", bytes);
+        instruction
+    }
+
+    #[test]
+    fn default_and_override_segments() {
+        use crate::registers::SegmentName;
+        // mov eax, [rbx] / mov eax, [rbp+8] / mov eax, [rsp+rbx] / mov eax, [r13+8] / mov eax, [rbx+rbp] / mov eax, [rip]
+        for (bytes, segment) in [
+            (&[0x8B, 0x03][..], SegmentName::DS),
+            (&[0x8B, 0x45, 0x08][..], SegmentName::SS),
+            (&[0x8B, 0x04, 0x1C][..], SegmentName::SS),
+            (&[0x41, 0x8B, 0x45, 0x08][..], SegmentName::DS),
+            (&[0x8B, 0x04, 0x2B][..], SegmentName::DS),
+            (&[0x8B, 0x05, 0x00, 0x00, 0x00, 0x00][..], SegmentName::DS),
+        ] {
+            assert_eq!(operand::segment(&decode(bytes)), segment, "{:02X?}", bytes);
+        }
+        // an override wins over the stack default: mov eax, gs:[rbp+8]
+        assert_eq!(operand::segment(&decode(&[0x65, 0x8B, 0x45, 0x08])), SegmentName::GS);
+    }
+
+    #[test]
+    fn primary_map_alu_forms() {
+        // add eax, ecx
+        let i = decode(&[0x01, 0xC8]);
+        assert_eq!((i.map, i.opcode), (OpcodeMap::Primary, 0x01));
+        assert_eq!(i.modrm, Some(ModRM { mode: 3, reg: 1, rm: 0 }));
+        assert_eq!(i.operand_size(), OperandSize::Dword);
+
+        // add rax, 0x12345678
+        let i = decode(&[0x48, 0x05, 0x78, 0x56, 0x34, 0x12]);
+        assert_eq!(i.operand_size(), OperandSize::Qword);
+        assert_eq!(i.immediate, Some(Immediate { value: 0x12345678, size: 4 }));
+
+        // add ax, 0x1234 uses a word immediate
+        let i = decode(&[0x66, 0x05, 0x34, 0x12]);
+        assert_eq!(i.operand_size(), OperandSize::Word);
+        assert_eq!(i.immediate_value(), 0x1234);
+
+        // sub qword [rbx], -1
+        let i = decode(&[0x48, 0x83, 0x2B, 0xFF]);
+        assert_eq!(i.group_index(), 5);
+        assert_eq!(i.immediate_sign_extended(), u64::MAX);
+    }
+
+    #[test]
+    fn primary_map_immediates() {
+        // mov r10, imm64
+        let i = decode(&[0x49, 0xBA, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(i.opcode_reg(), 10);
+        assert_eq!(i.immediate_value(), 0x0807060504030201);
+
+        // mov al, [moffs64]
+        let i = decode(&[0xA0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(i.immediate.unwrap().size, 8);
+        let i = decode(&[0x67, 0xA0, 1, 2, 3, 4]);
+        assert_eq!(i.immediate.unwrap().size, 4);
+
+        // enter 0x10, 1
+        let i = decode(&[0xC8, 0x10, 0x00, 0x01]);
+        assert_eq!(i.immediate_value(), 0x10);
+        assert_eq!(i.immediate2.unwrap().value, 1);
+
+        // test byte [rax], 0x7F has an immediate, not byte [rax] does not
+        assert_eq!(decode(&[0xF6, 0x00, 0x7F]).immediate_value(), 0x7F);
+        assert_eq!(decode(&[0xF6, 0x10]).immediate, None);
+        assert_eq!(decode(&[0x66, 0xF7, 0xC0, 0x34, 0x12]).immediate_value(), 0x1234);
+
+        // jmp rel8 / call rel32
+        assert_eq!(decode(&[0xEB, 0xFE]).immediate_sign_extended(), (-2i64) as u64);
+        assert_eq!(decode(&[0xE8, 0x00, 0x00, 0x00, 0x80]).immediate_sign_extended(), 0xFFFFFFFF_80000000);
+    }
+
+    #[test]
+    fn primary_map_prefixes_and_rex() {
+        // lock add fs:[rax], eax
+        let i = decode(&[0xF0, 0x64, 0x01, 0x00]);
+        assert!(i.prefixes.lock);
+        assert_eq!(i.prefixes.segment, Some(SegmentPrefix::FS));
+
+        // rep movsb
+        let i = decode(&[0xF3, 0xA4]);
+        assert_eq!(i.prefixes.rep, Some(RepPrefix::Rep));
+
+        // REX.RB extends reg and rm
+        let i = decode(&[0x4D, 0x01, 0xC8]);
+        assert_eq!((i.reg(), i.rm()), (9, 8));
+
+        // a legacy prefix after REX cancels it
+        let i = decode(&[0x48, 0x66, 0x01, 0xC8]);
+        assert_eq!(i.rex, None);
+        assert_eq!(i.operand_size(), OperandSize::Word);
+
+        // REX.W overrides 66
+        assert_eq!(decode(&[0x66, 0x48, 0x01, 0xC8]).operand_size(), OperandSize::Qword);
+    }
+
+    #[test]
+    fn primary_map_addressing() {
+        // mov eax, [rip + 0x10]
+        let i = decode(&[0x8B, 0x05, 0x10, 0x00, 0x00, 0x00]);
+        assert!(i.is_rip_relative());
+        assert_eq!(i.displacement, 0x10);
+
+        // mov eax, [rbx + rcx*4 - 8]
+        let i = decode(&[0x8B, 0x44, 0x8B, 0xF8]);
+        assert_eq!(i.sib, Some(Sib { scale: 2, index: 1, base: 3 }));
+        assert_eq!(i.displacement, -8);
+        assert_eq!(i.displacement_size, 1);
+
+        // mov eax, [rcx*8 + disp32], SIB with no base
+        let i = decode(&[0x8B, 0x04, 0xCD, 0x00, 0x10, 0x00, 0x00]);
+        assert_eq!(i.sib.unwrap().base, 5);
+        assert_eq!(i.displacement, 0x1000);
+
+        // mov eax, [rbp + disp32]
+        let i = decode(&[0x8B, 0x85, 0x00, 0x00, 0x00, 0x80]);
+        assert_eq!(i.displacement, -0x80000000);
+        assert!(i.has_memory_operand());
+    }
+
+    #[test]
+    fn primary_map_invalid_and_limits() {
+        // push es, aaa, les are gone in 64-bit mode
+        for opcode in [0x06, 0x37, 0xD4, 0x9A, 0xEA] {
+            assert_eq!(Instruction::decode_bytes(&[opcode, 0, 0, 0, 0, 0, 0]), Err(DecodeError::InvalidOpcode));
+        }
+        // fifteen prefixes followed by a nop is too long
+        let mut bytes = vec![0x66; 15];
+        bytes.push(0x90);
+        assert_eq!(Instruction::decode_bytes(&bytes), Err(DecodeError::TooLong));
+        assert_eq!(Instruction::decode_bytes(&[0x48, 0x05, 0x00]), Err(DecodeError::Truncated));
+    }
+
+    #[test]
+    fn map_0f() {
+        // syscall
+        let i = decode(&[0x0F, 0x05]);
+        assert_eq!((i.map, i.opcode), (OpcodeMap::Map0F, 0x05));
+        assert_eq!(i.modrm, None);
+
+        // jne rel32
+        let i = decode(&[0x0F, 0x85, 0xFC, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(i.immediate_sign_extended(), (-4i64) as u64);
+
+        // movzx eax, byte [rsi]
+        let i = decode(&[0x0F, 0xB6, 0x06]);
+        assert!(i.has_memory_operand());
+
+        // bt rax, 63
+        let i = decode(&[0x48, 0x0F, 0xBA, 0xE0, 0x3F]);
+        assert_eq!(i.group_index(), 4);
+        assert_eq!(i.immediate_value(), 63);
+
+        // movdqa xmm0, [rax] selects its form with the 66 prefix
+        let i = decode(&[0x66, 0x0F, 0x6F, 0x00]);
+        assert_eq!(i.mandatory_prefix(), MandatoryPrefix::P66);
+        // movdqu with both 66 and F3 is still F3
+        let i = decode(&[0x66, 0xF3, 0x0F, 0x6F, 0x00]);
+        assert_eq!(i.mandatory_prefix(), MandatoryPrefix::PF3);
+
+        // pshufd xmm1, xmm2, 0x1B
+        let i = decode(&[0x66, 0x0F, 0x70, 0xCA, 0x1B]);
+        assert_eq!(i.immediate_value(), 0x1B);
+
+        // bswap r12
+        assert_eq!(decode(&[0x49, 0x0F, 0xCC]).opcode_reg(), 12);
+
+        assert_eq!(Instruction::decode_bytes(&[0x0F, 0x04]), Err(DecodeError::InvalidOpcode));
+    }
+
+    #[test]
+    fn map_0f38() {
+        // pshufb xmm0, xmm1
+        let i = decode(&[0x66, 0x0F, 0x38, 0x00, 0xC1]);
+        assert_eq!((i.map, i.opcode), (OpcodeMap::Map0F38, 0x00));
+        assert_eq!(i.modrm, Some(ModRM { mode: 3, reg: 0, rm: 1 }));
+        assert_eq!(i.immediate, None);
+
+        // crc32 eax, byte [rdx + 4]
+        let i = decode(&[0xF2, 0x0F, 0x38, 0xF0, 0x42, 0x04]);
+        assert_eq!(i.mandatory_prefix(), MandatoryPrefix::PF2);
+        assert_eq!(i.displacement, 4);
+    }
+
+    #[test]
+    fn map_0f3a() {
+        // palignr xmm0, xmm1, 8
+        let i = decode(&[0x66, 0x0F, 0x3A, 0x0F, 0xC1, 0x08]);
+        assert_eq!((i.map, i.opcode), (OpcodeMap::Map0F3A, 0x0F));
+        assert_eq!(i.immediate_value(), 8);
+
+        // pextrq rax, xmm9, 1 with the immediate after a SIB and disp8 form
+        let i = decode(&[0x66, 0x4C, 0x0F, 0x3A, 0x16, 0x4C, 0x24, 0x08, 0x01]);
+        assert_eq!(i.reg(), 9);
+        assert_eq!(i.displacement, 8);
+        assert_eq!(i.immediate_value(), 1);
+    }
+
+    #[test]
+    fn vex_prefixes() {
+        // vmulps ymm0, ymm1, ymm2, two-byte form
+        let i = decode(&[0xC5, 0xF4, 0x59, 0xC2]);
+        assert_eq!((i.map, i.opcode), (OpcodeMap::Map0F, 0x59));
+        assert_eq!(i.vex, Some(Vex { l: true, vvvv: 1, pp: MandatoryPrefix::None }));
+        assert_eq!((i.reg(), i.rm()), (0, 2));
+
+        // vaddps ymm9, ymm1, ymm12, three-byte form with inverted R and B
+        let i = decode(&[0xC4, 0x41, 0x74, 0x58, 0xCC]);
+        assert_eq!((i.reg(), i.rm(), i.vvvv()), (9, 12, 1));
+
+        // vpgatherdd ymm0, [rax + ymm1*4], ymm2 in map 0F38 with pp = 66
+        let i = decode(&[0xC4, 0xE2, 0x6D, 0x90, 0x04, 0x88]);
+        assert_eq!((i.map, i.mandatory_prefix(), i.vvvv()), (OpcodeMap::Map0F38, MandatoryPrefix::P66, 2));
+        assert_eq!(i.sib, Some(Sib { scale: 2, index: 1, base: 0 }));
+
+        // vpermq ymm4, ymm2, 0x1B: W1, map 0F3A and an imm8
+        let i = decode(&[0xC4, 0xE3, 0xFD, 0x00, 0xE2, 0x1B]);
+        assert!(i.rex.is_some_and(|rex| rex.w));
+        assert_eq!(i.immediate_value(), 0x1B);
+
+        // 66, F2/F3 or REX before VEX, and the reserved maps, are #UD
+        for bytes in [&[0x66, 0xC5, 0xF8, 0x77][..], &[0xF3, 0xC5, 0xF8, 0x77], &[0x48, 0xC5, 0xF8, 0x77], &[0xC4, 0xE0, 0x7D, 0x00, 0xC0]] {
+            assert_eq!(Instruction::decode_bytes(bytes), Err(DecodeError::InvalidOpcode), "{:02X?}", bytes);
+        }
+    }
+
+    #[test]
+    fn evex_prefixes() {
+        // vmovdqa64 zmm31, zmm1: R and R' reach register 31
+        let i = decode(&[0x62, 0x61, 0xFD, 0x48, 0x6F, 0xF9]);
+        assert_eq!((i.map, i.mandatory_prefix(), i.reg(), i.rm()), (OpcodeMap::Map0F, MandatoryPrefix::P66, 31, 1));
+        assert!(i.rex.is_some_and(|rex| rex.w));
+        assert_eq!(i.evex.map(|evex| evex.ll), Some(2));
+
+        // vaddps zmm0, zmm1, zmm2, {rd-sae}: b on a register form, L'L holds the rounding mode
+        let i = decode(&[0x62, 0xF1, 0x74, 0x38, 0x58, 0xC2]);
+        assert_eq!(i.evex, Some(Evex { ll: 1, vvvv: 1, pp: MandatoryPrefix::None, r_high: false, z: false, b: true, aaa: 0 }));
+
+        // vaddps zmm20{k3}{z}, zmm17, [rax + 1]: V' extends vvvv, the memory form leaves X out of r/m
+        let i = decode(&[0x62, 0xE1, 0x74, 0xC3, 0x58, 0x60, 0x01]);
+        assert_eq!((i.reg(), i.vvvv(), i.rm()), (20, 17, 0));
+        assert!(i.evex.is_some_and(|evex| evex.z && evex.aaa == 3));
+        assert_eq!((i.displacement, i.displacement_size), (1, 1));
+
+        // P1 bit 2 clear, map 0, and prefixes before 62 are #UD
+        for bytes in [&[0x62, 0xF1, 0x70, 0x48, 0x58, 0xC2][..], &[0x62, 0xF0, 0x74, 0x48, 0x58, 0xC2], &[0x66, 0x62, 0xF1, 0x74, 0x48, 0x58, 0xC2]] {
+            assert_eq!(Instruction::decode_bytes(bytes), Err(DecodeError::InvalidOpcode), "{:02X?}", bytes);
+        }
+    }
+}
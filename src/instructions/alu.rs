@@ -0,0 +1,295 @@
+// integer ALU group: ADD/OR/ADC/SBB/AND/SUB/XOR/CMP, TEST, INC/DEC, NEG/NOT
+
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::flags;
+use crate::flags::ArithFlags;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+
+// in opcode order, also the /digit of the 80/81/83 group
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AluOp {
+    Add, Or, Adc, Sbb, And, Sub, Xor, Cmp
+}
+
+impl AluOp {
+    fn from_index(index: u8) -> AluOp {
+        match index & 7 {
+            0 => AluOp::Add,
+            1 => AluOp::Or,
+            2 => AluOp::Adc,
+            3 => AluOp::Sbb,
+            4 => AluOp::And,
+            5 => AluOp::Sub,
+            6 => AluOp::Xor,
+            _ => AluOp::Cmp,
+        }
+    }
+}
+
+fn compute(cpu: &Cpu, op: AluOp, size: OperandSize, a: u64, b: u64) -> (u64, ArithFlags) {
+    let cf = cpu.registers.get_flag(Flag::CF);
+    match op {
+        AluOp::Add => flags::add(size, a, b, false),
+        AluOp::Or => flags::logic(size, a | b),
+        AluOp::Adc => flags::add(size, a, b, cf),
+        AluOp::Sbb => flags::sub(size, a, b, cf),
+        AluOp::And => flags::logic(size, a & b),
+        AluOp::Sub | AluOp::Cmp => flags::sub(size, a, b, false),
+        AluOp::Xor => flags::logic(size, a ^ b),
+    }
+}
+
+// LOCK is only legal on read-modify-write forms with a memory destination
+pub fn check_lock(instruction: &Instruction, lockable: bool) -> Result<(), Exception> {
+    if instruction.prefixes.lock && !(lockable && instruction.has_memory_operand()) {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok(())
+}
+
+fn apply(cpu: &mut Cpu, instruction: &Instruction, op: AluOp, size: OperandSize, destination: Operand, source: u64) -> Result<(), Exception> {
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let (result, flags) = compute(cpu, op, size, value, source);
+    if op != AluOp::Cmp {
+        operand::write_operand(cpu, instruction, destination, size, result)?;
+    }
+    flags.apply(&mut cpu.registers);
+    Ok(())
+}
+
+// 00..3D: Eb,Gb / Ev,Gv / Gb,Eb / Gv,Ev / AL,Ib / rAX,Iz
+pub fn binary(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = AluOp::from_index(instruction.opcode >> 3);
+    let size = operand::byte_or_full(instruction);
+    match instruction.opcode & 7 {
+        0 | 1 => {
+            check_lock(instruction, op != AluOp::Cmp)?;
+            let destination = operand::rm_operand(cpu, instruction);
+            let source = operand::read_gpr(cpu, instruction, instruction.reg(), size);
+            apply(cpu, instruction, op, size, destination, source)
+        }
+        2 | 3 => {
+            check_lock(instruction, false)?;
+            let source = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?;
+            apply(cpu, instruction, op, size, Operand::Register(instruction.reg()), source)
+        }
+        _ => {
+            check_lock(instruction, false)?;
+            let source = instruction.immediate_sign_extended() & size.mask();
+            apply(cpu, instruction, op, size, Operand::Register(0), source)
+        }
+    }
+}
+
+// 80 Eb,Ib / 81 Ev,Iz / 83 Ev,Ib
+pub fn group1(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = AluOp::from_index(instruction.group_index());
+    check_lock(instruction, op != AluOp::Cmp)?;
+    let size = if instruction.opcode == 0x80 { OperandSize::Byte } else { instruction.operand_size() };
+    let destination = operand::rm_operand(cpu, instruction);
+    let source = instruction.immediate_sign_extended() & size.mask();
+    apply(cpu, instruction, op, size, destination, source)
+}
+
+// 84 Eb,Gb / 85 Ev,Gv / A8 AL,Ib / A9 rAX,Iz / F6,F7 /0 /1
+pub fn test(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = operand::byte_or_full(instruction);
+    let (a, b) = match instruction.opcode {
+        0x84 | 0x85 => (
+            operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?,
+            operand::read_gpr(cpu, instruction, instruction.reg(), size),
+        ),
+        0xA8 | 0xA9 => (
+            operand::read_gpr(cpu, instruction, 0, size),
+            instruction.immediate_sign_extended(),
+        ),
+        _ => (
+            operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?,
+            instruction.immediate_sign_extended(),
+        ),
+    };
+    let (_, flags) = flags::logic(size, a & b);
+    flags.apply(&mut cpu.registers);
+    Ok(())
+}
+
+// F6/F7 /2
+pub fn not(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, true)?;
+    let size = operand::byte_or_full(instruction);
+    let destination = operand::rm_operand(cpu, instruction);
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    operand::write_operand(cpu, instruction, destination, size, !value)
+}
+
+// F6/F7 /3
+pub fn neg(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, true)?;
+    let size = operand::byte_or_full(instruction);
+    let destination = operand::rm_operand(cpu, instruction);
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let (result, flags) = flags::sub(size, 0, value, false);
+    operand::write_operand(cpu, instruction, destination, size, result)?;
+    flags.apply(&mut cpu.registers);
+    Ok(())
+}
+
+// FE/FF /0 INC, /1 DEC, CF is preserved
+pub fn inc_dec(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, true)?;
+    let size = operand::byte_or_full(instruction);
+    let destination = operand::rm_operand(cpu, instruction);
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let (result, flags) = match instruction.group_index() {
+        0 => flags::add(size, value, 1, false),
+        1 => flags::sub(size, value, 1, false),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    operand::write_operand(cpu, instruction, destination, size, result)?;
+    flags.apply_except_cf(&mut cpu.registers);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::Flag;
+
+    use crate::cpu::Exception;
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    const DATA: u64 = 0x2000;
+
+    #[test]
+    fn register_forms_at_every_width() {
+        // add al, cl / add ax, cx / add eax, ecx / add rax, rcx
+        let mut cpu = cpu_with_code(&[0x00, 0xC8, 0x66, 0x01, 0xC8, 0x01, 0xC8, 0x48, 0x01, 0xC8]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xFFFFFFFF_FFFFFFFF);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0x00000000_00000001);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFFFFFFFF_FFFFFF00);
+        assert!(cpu.registers.get_flag(Flag::CF) && cpu.registers.get_flag(Flag::ZF));
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFFFFFFFF_FFFFFF01);
+        assert!(!cpu.registers.get_flag(Flag::CF));
+        cpu.step().unwrap();
+        // 32-bit destination zero-extends
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x00000000_FFFFFF02);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x00000000_FFFFFF03);
+    }
+
+    #[test]
+    fn memory_and_immediate_forms() {
+        // sub qword [rbx], rcx / xor edx, [rbx] / and byte [rbx], 0x0F / or word [rbx+2], 0x1234 / cmp dword [rbx], -1
+        let mut cpu = cpu_with_code(&[
+            0x48, 0x29, 0x0B,
+            0x33, 0x13,
+            0x80, 0x23, 0x0F,
+            0x66, 0x81, 0x4B, 0x02, 0x34, 0x12,
+            0x83, 0x3B, 0xFF,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RBX, DATA);
+        cpu.registers.set_gpr_value(GPRName::RCX, 1);
+        cpu.registers.set_gpr_value(GPRName::RDX, 0xFFFFFFFF_00000000);
+        cpu.memory.write::<u64>(DATA as usize, 0x8000_0000_0000_0000);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read::<u64>(DATA as usize), 0x7FFF_FFFF_FFFF_FFFF);
+        assert!(cpu.registers.get_flag(Flag::OF) && !cpu.registers.get_flag(Flag::CF));
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 0xFFFF_FFFF);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read::<u8>(DATA as usize), 0x0F);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read::<u16>(DATA as usize + 2), 0xFFFF);
+        cpu.step().unwrap();
+        assert!(!cpu.registers.get_flag(Flag::ZF) && cpu.registers.get_flag(Flag::CF));
+        assert_eq!(cpu.memory.read::<u32>(DATA as usize), 0xFFFF_FF0F);
+    }
+
+    #[test]
+    fn accumulator_short_forms_and_carry_chain() {
+        // add eax, 1 / adc edx, 0 / sbb al, 0 / cmp rax, -1
+        let mut cpu = cpu_with_code(&[0x05, 0x01, 0x00, 0x00, 0x00, 0x83, 0xD2, 0x00, 0x1C, 0x00, 0x48, 0x3D, 0xFF, 0xFF, 0xFF, 0xFF]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xFFFF_FFFF);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 1);
+        cpu.registers.set_flag(Flag::CF, true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFF);
+        cpu.step().unwrap();
+        assert!(!cpu.registers.get_flag(Flag::ZF));
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFF);
+    }
+
+    #[test]
+    fn high_byte_registers_without_rex() {
+        // add ah, bh / add spl, dil
+        let mut cpu = cpu_with_code(&[0x00, 0xFC, 0x40, 0x00, 0xFC]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x0100);
+        cpu.registers.set_gpr_value(GPRName::RBX, 0x0200);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x0300);
+        cpu.registers.set_gpr_value(GPRName::RSP, 0x10);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x22);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), 0x32);
+    }
+
+    #[test]
+    fn unary_and_test() {
+        // inc byte [rbx] / dec ecx / neg rdx / not word [rbx] / test al, 0x80 / test rcx, rcx
+        let mut cpu = cpu_with_code(&[
+            0xFE, 0x03,
+            0xFF, 0xC9,
+            0x48, 0xF7, 0xDA,
+            0x66, 0xF7, 0x13,
+            0xA8, 0x80,
+            0x48, 0x85, 0xC9,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RBX, DATA);
+        cpu.memory.write::<u16>(DATA as usize, 0x00FF);
+        cpu.registers.set_flag(Flag::CF, true);
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read::<u16>(DATA as usize), 0x0000);
+        assert!(cpu.registers.get_flag(Flag::ZF) && cpu.registers.get_flag(Flag::CF));
+        cpu.registers.set_gpr_value(GPRName::RCX, 1);
+        cpu.step().unwrap();
+        assert!(cpu.registers.get_flag(Flag::ZF));
+        cpu.registers.set_gpr_value(GPRName::RDX, 5);
+        cpu.step().unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), (-5i64) as u64);
+        assert!(cpu.registers.get_flag(Flag::CF));
+        cpu.step().unwrap();
+        assert_eq!(cpu.memory.read::<u16>(DATA as usize), 0xFFFF);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x80);
+        cpu.step().unwrap();
+        assert!(!cpu.registers.get_flag(Flag::ZF) && cpu.registers.get_flag(Flag::SF));
+        cpu.step().unwrap();
+        assert!(cpu.registers.get_flag(Flag::ZF) && !cpu.registers.get_flag(Flag::CF));
+    }
+
+    #[test]
+    fn lock_requires_memory_destination() {
+        // lock add eax, ecx
+        let mut cpu = cpu_with_code(&[0xF0, 0x01, 0xC8]);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+        // lock add [rbx], ecx
+        let mut cpu = cpu_with_code(&[0xF0, 0x01, 0x0B]);
+        cpu.registers.set_gpr_value(GPRName::RBX, DATA);
+        cpu.memory.write::<u32>(DATA as usize, 0);
+        assert_eq!(cpu.run(1), StopReason::StepLimit);
+    }
+}
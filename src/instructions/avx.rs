@@ -0,0 +1,500 @@
+// AVX/AVX2 register access and data movement: VMOV*, shuffles, permutes, broadcasts, gathers and VZEROUPPER/VZEROALL
+// unlike legacy SSE, every VEX encoding writes the whole destination: bits above the vector length are zeroed
+// up to the top of the ZMM register, so a VEX.128 write clears 511:128 where sse::write_xmm keeps them
+
+use crate::registers::VecRegName;
+use crate::registers::WriteMode;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+use crate::instructions::sse;
+
+// a YMM value as its low and high 128-bit halves
+pub type Ymm = [u128; 2];
+
+pub fn read_ymm(cpu: &Cpu, index: u8) -> Ymm {
+    let lanes = cpu.registers.lanes::<u128>(VecRegName::YMM, index as usize);
+    [lanes[0], lanes[1]]
+}
+
+// write the low `halves` halves of value and zero everything above them
+pub fn write_zero_upper(cpu: &mut Cpu, index: u8, value: Ymm, halves: usize) {
+    let mut lanes = vec![0u128; 2];
+    lanes[..halves].copy_from_slice(&value[..halves]);
+    cpu.registers.write_sections(VecRegName::YMM, index as usize, lanes, WriteMode::ZeroUpper);
+}
+
+// 128-bit halves covered by the vector length, VEX.L selects 256 bits
+pub fn halves(instruction: &Instruction) -> usize {
+    if instruction.vex.is_some_and(|vex| vex.l) { 2 } else { 1 }
+}
+
+pub fn write_vex(cpu: &mut Cpu, instruction: &Instruction, index: u8, value: Ymm) {
+    write_zero_upper(cpu, index, value, halves(instruction));
+}
+
+// instructions without a second source require VEX.vvvv = 1111
+pub fn check_no_vvvv(instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.vvvv() != 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok(())
+}
+
+// only the aligned moves check alignment under VEX, against the full operand width
+fn check_alignment(address: u64, halves: usize, aligned: bool) -> Result<(), Exception> {
+    if aligned && !address.is_multiple_of(16 * halves as u64) {
+        return Err(Exception::GeneralProtection(0));
+    }
+    Ok(())
+}
+
+fn read_memory(cpu: &Cpu, address: u64, halves: usize, aligned: bool) -> Result<Ymm, Exception> {
+    check_alignment(address, halves, aligned)?;
+    let low = cpu.read_memory::<u128>(address)?;
+    let high = if halves == 2 { cpu.read_memory::<u128>(address.wrapping_add(16))? } else { 0 };
+    Ok([low, high])
+}
+
+fn write_memory(cpu: &mut Cpu, address: u64, halves: usize, value: Ymm, aligned: bool) -> Result<(), Exception> {
+    check_alignment(address, halves, aligned)?;
+    cpu.write_memory::<u128>(address, value[0])?;
+    if halves == 2 {
+        cpu.write_memory::<u128>(address.wrapping_add(16), value[1])?;
+    }
+    Ok(())
+}
+
+// the vector register or memory operand in ModRM.rm, as wide as the vector length
+pub fn read_rm(cpu: &Cpu, instruction: &Instruction, aligned: bool) -> Result<Ymm, Exception> {
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Register(index) => Ok(read_ymm(cpu, index)),
+        Operand::Memory(address) => read_memory(cpu, address, halves(instruction), aligned),
+    }
+}
+
+// f on each pair of halves the vector length covers, None from f is #UD
+pub fn per_half(instruction: &Instruction, a: Ymm, b: Ymm, f: impl Fn(u128, u128) -> Option<u128>) -> Result<Ymm, Exception> {
+    let mut result = [0; 2];
+    for i in 0..halves(instruction) {
+        result[i] = f(a[i], b[i]).ok_or(Exception::InvalidOpcode)?;
+    }
+    Ok(result)
+}
+
+// the common three-operand shape: reg = f(vvvv, r/m), half by half
+pub fn binary(cpu: &mut Cpu, instruction: &Instruction, f: impl Fn(u128, u128) -> Option<u128>) -> Result<(), Exception> {
+    let b = read_rm(cpu, instruction, false)?;
+    let a = read_ymm(cpu, instruction.vvvv());
+    let result = per_half(instruction, a, b, f)?;
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// lanes numbered across all halves of a YMM or ZMM value
+pub fn element(value: &[u128], size: OperandSize, index: usize) -> u64 {
+    let count = sse::lane_count(size);
+    sse::lane(value[index / count], size, index % count)
+}
+
+pub fn set_element(value: &mut [u128], size: OperandSize, index: usize, element: u64) {
+    let count = sse::lane_count(size);
+    value[index / count] = sse::set_lane(value[index / count], size, index % count, element);
+}
+
+// VEX 0F 10/11 VMOVUPS/VMOVUPD/VMOVSS/VMOVSD, 28/29 VMOVAPS/VMOVAPD, 66/F3 0F 6F/7F VMOVDQA/VMOVDQU,
+// 0F 2B VMOVNTPS/VMOVNTPD and 66 0F E7 VMOVNTDQ
+pub fn mov(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (scalar, aligned) = match (instruction.opcode, instruction.mandatory_prefix()) {
+        (0x10 | 0x11, MandatoryPrefix::None | MandatoryPrefix::P66) => (None, false),
+        (0x10 | 0x11, MandatoryPrefix::PF3) => (Some(4), false),
+        (0x10 | 0x11, MandatoryPrefix::PF2) => (Some(8), false),
+        (0x28 | 0x29 | 0x2B, MandatoryPrefix::None | MandatoryPrefix::P66) => (None, true),
+        (0x6F | 0x7F | 0xE7, MandatoryPrefix::P66) => (None, true),
+        (0x6F | 0x7F, MandatoryPrefix::PF3) => (None, false),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    if matches!(instruction.opcode, 0x2B | 0xE7) && !instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    if let Some(bytes) = scalar {
+        return mov_scalar(cpu, instruction, bytes);
+    }
+    check_no_vvvv(instruction)?;
+
+    if matches!(instruction.opcode, 0x10 | 0x28 | 0x6F) {
+        let value = read_rm(cpu, instruction, aligned)?;
+        write_vex(cpu, instruction, instruction.reg(), value);
+        Ok(())
+    } else {
+        let value = read_ymm(cpu, instruction.reg());
+        match operand::rm_operand(cpu, instruction) {
+            Operand::Register(index) => {
+                write_vex(cpu, instruction, index, value);
+                Ok(())
+            }
+            Operand::Memory(address) => write_memory(cpu, address, halves(instruction), value, aligned),
+        }
+    }
+}
+
+// VMOVSS/VMOVSD: a load zeroes everything above the element, the register forms take bits 127:element from vvvv
+fn mov_scalar(cpu: &mut Cpu, instruction: &Instruction, bytes: usize) -> Result<(), Exception> {
+    match (instruction.opcode, operand::rm_operand(cpu, instruction)) {
+        (0x10, Operand::Memory(address)) => {
+            check_no_vvvv(instruction)?;
+            let value = sse::read_memory(cpu, address, bytes, false)?;
+            write_zero_upper(cpu, instruction.reg(), [value, 0], 1);
+        }
+        (_, Operand::Memory(address)) => {
+            check_no_vvvv(instruction)?;
+            sse::write_memory(cpu, address, bytes, sse::read_xmm(cpu, instruction.reg()), false)?;
+        }
+        (0x10, Operand::Register(index)) => {
+            let value = sse::merge(sse::read_xmm(cpu, instruction.vvvv()), sse::read_xmm(cpu, index), bytes);
+            write_zero_upper(cpu, instruction.reg(), [value, 0], 1);
+        }
+        (_, Operand::Register(index)) => {
+            let value = sse::merge(sse::read_xmm(cpu, instruction.vvvv()), sse::read_xmm(cpu, instruction.reg()), bytes);
+            write_zero_upper(cpu, index, [value, 0], 1);
+        }
+    }
+    Ok(())
+}
+
+// VEX 0F 14/15 VUNPCKLPS/VUNPCKHPS, 66 for the PD forms
+pub fn unpack_float(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match instruction.mandatory_prefix() {
+        MandatoryPrefix::None => OperandSize::Dword,
+        MandatoryPrefix::P66 => OperandSize::Qword,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let high = instruction.opcode == 0x15;
+    binary(cpu, instruction, |a, b| Some(sse::interleave(size, a, b, high)))
+}
+
+// VEX 0F C6 VSHUFPS / 66 0F C6 VSHUFPD, the 256-bit PD form takes a fresh pair of imm8 bits for the high half
+pub fn shuffle_float(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match instruction.mandatory_prefix() {
+        MandatoryPrefix::None => OperandSize::Dword,
+        MandatoryPrefix::P66 => OperandSize::Qword,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let imm = instruction.immediate_value() as u8;
+    let b = read_rm(cpu, instruction, false)?;
+    let a = read_ymm(cpu, instruction.vvvv());
+    let high_imm = if size == OperandSize::Qword { imm >> 2 } else { imm };
+    let result = [sse::shuffle_lanes(size, a[0], b[0], imm), sse::shuffle_lanes(size, a[1], b[1], high_imm)];
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VEX 66 0F 70 VPSHUFD / F3 0F 70 VPSHUFHW / F2 0F 70 VPSHUFLW, the same imm8 for each half
+pub fn pshuf(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_no_vvvv(instruction)?;
+    let prefix = instruction.mandatory_prefix();
+    let imm = instruction.immediate_value() as u8;
+    let source = read_rm(cpu, instruction, false)?;
+    let result = per_half(instruction, source, source, |value, _| sse::shuffle_words(prefix, value, imm))?;
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VPSHUFB: each byte picks a byte of a by the low four bits of b, or becomes zero when b's top bit is set
+pub fn shuffle_bytes(a: u128, b: u128) -> u128 {
+    let size = OperandSize::Byte;
+    sse::map_lanes(size, 0, b, |_, select| {
+        if select & 0x80 != 0 { 0 } else { sse::lane(a, size, select as usize & 15) }
+    })
+}
+
+// VEX 66 0F38 00 VPSHUFB
+pub fn pshufb(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    binary(cpu, instruction, |a, b| Some(shuffle_bytes(a, b)))
+}
+
+// the 256-bit only forms: L=1, 66 and the required VEX.W
+fn check_wide(instruction: &Instruction, w: bool) -> Result<(), Exception> {
+    if halves(instruction) != 2 || instruction.mandatory_prefix() != MandatoryPrefix::P66 || instruction.rex.is_some_and(|rex| rex.w) != w {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok(())
+}
+
+// VEX.256 66 0F3A 06 VPERM2F128 / 46 VPERM2I128, each half picked from the four of vvvv and r/m by a nibble of imm8
+pub fn permute_halves(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_wide(instruction, false)?;
+    let imm = instruction.immediate_value() as u8;
+    let b = read_rm(cpu, instruction, false)?;
+    let a = read_ymm(cpu, instruction.vvvv());
+    let sources = [a[0], a[1], b[0], b[1]];
+    let pick = |control: u8| if control & 8 != 0 { 0 } else { sources[control as usize & 3] };
+    write_vex(cpu, instruction, instruction.reg(), [pick(imm), pick(imm >> 4)]);
+    Ok(())
+}
+
+// VEX.256 66 0F3A 18 VINSERTF128 / 38 VINSERTI128 ymm, ymm, xmm/m128, imm8
+pub fn insert_half(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_wide(instruction, false)?;
+    let value = sse::read_rm(cpu, instruction, 16, false)?;
+    let mut result = read_ymm(cpu, instruction.vvvv());
+    result[instruction.immediate_value() as usize & 1] = value;
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VEX.256 66 0F3A 19 VEXTRACTF128 / 39 VEXTRACTI128 xmm/m128, ymm, imm8
+pub fn extract_half(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_wide(instruction, false)?;
+    check_no_vvvv(instruction)?;
+    let value = read_ymm(cpu, instruction.reg())[instruction.immediate_value() as usize & 1];
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Register(index) => write_zero_upper(cpu, index, [value, 0], 1),
+        Operand::Memory(address) => sse::write_memory(cpu, address, 16, value, false)?,
+    }
+    Ok(())
+}
+
+// VEX.256 66 0F3A 00 VPERMQ / 01 VPERMPD, each qword picked from the whole source by two imm8 bits
+pub fn permute_qwords(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_wide(instruction, true)?;
+    check_no_vvvv(instruction)?;
+    let imm = instruction.immediate_value() as usize;
+    let source = read_rm(cpu, instruction, false)?;
+    let size = OperandSize::Qword;
+    let mut result = [0; 2];
+    for i in 0..4 {
+        set_element(&mut result, size, i, element(&source, size, (imm >> (2 * i)) & 3));
+    }
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VEX.256 66 0F38 16 VPERMPS / 36 VPERMD, the dword indices come from vvvv and the data from r/m
+pub fn permute_dwords(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_wide(instruction, false)?;
+    let source = read_rm(cpu, instruction, false)?;
+    let indices = read_ymm(cpu, instruction.vvvv());
+    let size = OperandSize::Dword;
+    let mut result = [0; 2];
+    for i in 0..8 {
+        set_element(&mut result, size, i, element(&source, size, element(&indices, size, i) as usize & 7));
+    }
+    write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// a value repeated in every lane of a half
+fn splat(size: OperandSize, value: u64) -> u128 {
+    (0..sse::lane_count(size)).fold(0, |result, i| sse::set_lane(result, size, i, value))
+}
+
+// VEX 66 0F38 18 VBROADCASTSS, 19 VBROADCASTSD, 1A VBROADCASTF128, 58 VPBROADCASTD, 59 VPBROADCASTQ,
+// 5A VBROADCASTI128, 78 VPBROADCASTB and 79 VPBROADCASTW; AVX2 allows an XMM source except for the 128-bit ones
+pub fn broadcast(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 || instruction.rex.is_some_and(|rex| rex.w) {
+        return Err(Exception::InvalidOpcode);
+    }
+    check_no_vvvv(instruction)?;
+    let bytes = match instruction.opcode {
+        0x78 => 1,
+        0x79 => 2,
+        0x18 | 0x58 => 4,
+        0x19 | 0x59 => 8,
+        _ => 16,
+    };
+    let wide_only = matches!(instruction.opcode, 0x19 | 0x1A | 0x5A);
+    if (wide_only && halves(instruction) != 2) || (bytes == 16 && !instruction.has_memory_operand()) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let value = sse::read_rm(cpu, instruction, bytes, false)?;
+    let half = if bytes == 16 { value } else { splat(OperandSize::from_bytes(bytes), value as u64) };
+    write_vex(cpu, instruction, instruction.reg(), [half, half]);
+    Ok(())
+}
+
+// VEX 0F 77: VZEROUPPER (L=0) clears bits 511:128 of every register, VZEROALL (L=1) the whole registers
+pub fn zero_upper(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::None {
+        return Err(Exception::InvalidOpcode);
+    }
+    check_no_vvvv(instruction)?;
+    let keep = halves(instruction) == 1;
+    for index in 0..16 {
+        let low = if keep { sse::read_xmm(cpu, index) } else { 0 };
+        write_zero_upper(cpu, index, [low, 0], 1);
+    }
+    Ok(())
+}
+
+// VEX 66 0F38 90 VPGATHERDD/DQ, 91 VPGATHERQD/QQ, 92 VGATHERDPS/DPD, 93 VGATHERQPS/QPD
+// the address of each element takes its index from a vector register (VSIB), only elements whose mask top bit is
+// set are loaded and each load clears its mask element, so a faulting gather can be restarted where it stopped
+pub fn gather(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (modrm, sib) = match (instruction.modrm, instruction.sib) {
+        (Some(modrm), Some(sib)) if modrm.mode != 3 && instruction.mandatory_prefix() == MandatoryPrefix::P66 => (modrm, sib),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let rex = instruction.rex.unwrap_or_default();
+    let index_register = sib.index | (rex.x as u8) << 3;
+    let (destination, mask) = (instruction.reg(), instruction.vvvv());
+    if destination == mask || destination == index_register || mask == index_register {
+        return Err(Exception::InvalidOpcode);
+    }
+
+    let size = if rex.w { OperandSize::Qword } else { OperandSize::Dword };
+    let index_size = if instruction.opcode & 1 == 0 { OperandSize::Dword } else { OperandSize::Qword };
+    // the wider of index and element decides how many fit in the vector
+    let count = 16 * halves(instruction) / size.bytes().max(index_size.bytes());
+    let base = if sib.base == 5 && modrm.mode == 0 {
+        0
+    } else {
+        operand::read_gpr64(cpu, sib.base | (rex.b as u8) << 3)
+    };
+    let indices = read_ymm(cpu, index_register);
+    let mut data = read_ymm(cpu, destination);
+    let mut masks = read_ymm(cpu, mask);
+
+    let mut fault = None;
+    for i in 0..count {
+        if element(&masks, size, i) & size.sign_bit() == 0 {
+            continue;
+        }
+        let index = index_size.sign_extend(element(&indices, index_size, i));
+        let offset = base.wrapping_add(index << sib.scale).wrapping_add(instruction.displacement as u64);
+        let address = operand::linear_address(cpu, instruction, offset & instruction.address_size().mask());
+        match operand::read_memory_sized(cpu, address, size) {
+            Ok(value) => {
+                set_element(&mut data, size, i, value);
+                set_element(&mut masks, size, i, 0);
+            }
+            Err(exception) => {
+                fault = Some(exception);
+                break;
+            }
+        }
+    }
+    if fault.is_none() {
+        masks = [0; 2];
+    }
+    // elements past the count are zeroed, as is everything above the vector length
+    for i in count..2 * sse::lane_count(size) {
+        set_element(&mut data, size, i, 0);
+        set_element(&mut masks, size, i, 0);
+    }
+    write_vex(cpu, instruction, destination, data);
+    write_vex(cpu, instruction, mask, masks);
+    fault.map_or(Ok(()), Err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    fn dirty(cpu: &mut Cpu, index: u8) {
+        cpu.registers.set_by_sections(VecRegName::ZMM, index as usize, vec![u64::MAX; 8]);
+    }
+
+    fn upper_is_zero(cpu: &Cpu, index: u8, from: usize) -> bool {
+        let lanes = cpu.registers.get_by_sections::<u64>(VecRegName::ZMM, index as usize).unwrap();
+        lanes[from / 64..].iter().all(|lane| *lane == 0)
+    }
+
+    #[test]
+    fn vex_writes_zero_the_upper_bits_and_legacy_writes_keep_them() {
+        // addps xmm1, xmm2 / vaddps xmm3, xmm3, xmm2 / vpaddd ymm4, ymm4, ymm2 / vzeroupper
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0x58, 0xCA,
+            0xC5, 0xE0, 0x58, 0xDA,
+            0xC5, 0xDD, 0xFE, 0xE2,
+            0xC5, 0xF8, 0x77,
+        ]);
+        for index in [1, 3, 4, 5] {
+            dirty(&mut cpu, index);
+        }
+        sse::write_xmm(&mut cpu, 1, 0);
+        sse::write_xmm(&mut cpu, 3, 0);
+        write_zero_upper(&mut cpu, 2, [1, 1], 2);
+
+        cpu.run(1);
+        assert_eq!(sse::read_xmm(&cpu, 1), 1);
+        assert_eq!(read_ymm(&cpu, 1)[1], u128::MAX);
+        cpu.run(1);
+        assert_eq!(sse::read_xmm(&cpu, 3), 1);
+        assert!(upper_is_zero(&cpu, 3, 128));
+        cpu.run(1);
+        assert_eq!(read_ymm(&cpu, 4), [u128::MAX - (u32::MAX as u128), u128::MAX - (u32::MAX as u128)]);
+        assert!(upper_is_zero(&cpu, 4, 256));
+        cpu.run(1);
+        assert_eq!(read_ymm(&cpu, 4)[1], 0);
+        assert_eq!(read_ymm(&cpu, 5), [u128::MAX, 0]);
+        assert!(upper_is_zero(&cpu, 5, 128));
+    }
+
+    #[test]
+    fn broadcasts_and_permutes() {
+        // vbroadcastss ymm0, [0x2000] / vperm2f128 ymm1, ymm0, ymm2, 0x21 / vextractf128 xmm3, ymm1, 1 / vpermq ymm4, ymm2, 0x1B
+        let mut cpu = cpu_with_code(&[
+            0xC4, 0xE2, 0x7D, 0x18, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xC4, 0xE3, 0x7D, 0x06, 0xCA, 0x21,
+            0xC4, 0xE3, 0x7D, 0x19, 0xCB, 0x01,
+            0xC4, 0xE3, 0xFD, 0x00, 0xE2, 0x1B,
+        ]);
+        cpu.memory.write::<u32>(0x2000, 0x3F80_0000);
+        write_zero_upper(&mut cpu, 2, [2 << 64 | 1, 4 << 64 | 3], 2);
+        dirty(&mut cpu, 3);
+        cpu.run(4);
+        let splat = 0x3F80_0000_3F80_0000_3F80_0000_3F80_0000;
+        assert_eq!(read_ymm(&cpu, 0), [splat, splat]);
+        // low half from a's high half, high half from b's low half
+        assert_eq!(read_ymm(&cpu, 1), [splat, 2 << 64 | 1]);
+        assert_eq!(read_ymm(&cpu, 3), [2 << 64 | 1, 0]);
+        assert!(upper_is_zero(&cpu, 3, 128));
+        assert_eq!(read_ymm(&cpu, 4), [3 << 64 | 4, 1 << 64 | 2]);
+    }
+
+    #[test]
+    fn gathers_follow_the_mask() {
+        // vpgatherdd ymm0, [rax + ymm1*4], ymm2 / vpgatherdd ymm0, [rax + ymm0*4], ymm2
+        let mut cpu = cpu_with_code(&[
+            0xC4, 0xE2, 0x6D, 0x90, 0x04, 0x88,
+            0xC4, 0xE2, 0x6D, 0x90, 0x04, 0x80,
+        ]);
+        for i in 0..8 {
+            cpu.memory.write::<u32>(0x2000 + 4 * i, 100 + i as u32);
+        }
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x2010);
+        let size = OperandSize::Dword;
+        // indices 3, 2, 1, 0, -1, -2, -3, -4 and every other element masked
+        let (mut indices, mut masks) = ([0; 2], [0; 2]);
+        for i in 0..8 {
+            set_element(&mut indices, size, i, (3 - i as i64) as u64);
+            set_element(&mut masks, size, i, if i % 2 == 0 { 0x8000_0000 } else { 0 });
+        }
+        write_zero_upper(&mut cpu, 1, indices, 2);
+        write_zero_upper(&mut cpu, 2, masks, 2);
+        write_zero_upper(&mut cpu, 0, [u128::MAX, u128::MAX], 2);
+        cpu.run(1);
+        let data = read_ymm(&cpu, 0);
+        for (i, expected) in [107, u32::MAX, 105, u32::MAX, 103, u32::MAX, 101, u32::MAX].iter().enumerate() {
+            assert_eq!(element(&data, size, i), *expected as u64, "element {}", i);
+        }
+        assert_eq!(read_ymm(&cpu, 2), [0, 0]);
+        // the destination doubling as the index register is #UD
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+    }
+}
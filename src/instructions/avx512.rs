@@ -0,0 +1,384 @@
+// AVX-512 register access and EVEX operand handling: vector length, writemasks, embedded broadcast, compressed
+// displacements and embedded rounding, plus the full-width moves and broadcasts
+// a masked write keeps (or with EVEX.z zeroes) the elements its opmask leaves out, and like VEX zeroes the
+// destination above the vector length
+
+use crate::registers::VecRegName;
+use crate::registers::WriteMode;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::OpcodeMap;
+use crate::instructions::avx;
+use crate::instructions::operand;
+use crate::instructions::sse;
+use crate::instructions::sse_float::RoundingMode;
+use crate::instructions::mxcsr::Environment;
+
+// a ZMM value as four 128-bit quarters
+pub type Zmm = [u128; 4];
+
+pub fn read_zmm(cpu: &Cpu, index: u8) -> Zmm {
+    let lanes = cpu.registers.lanes::<u128>(VecRegName::ZMM, index as usize);
+    std::array::from_fn(|i| lanes[i])
+}
+
+pub fn write_zmm(cpu: &mut Cpu, index: u8, value: Zmm) {
+    cpu.registers.write_sections(VecRegName::ZMM, index as usize, value.to_vec(), WriteMode::Full);
+}
+
+fn w(instruction: &Instruction) -> bool {
+    instruction.rex.is_some_and(|rex| rex.w)
+}
+
+// Dword for W0, Qword for W1, the element size of most D/Q and PS/PD pairs
+pub fn element_size(instruction: &Instruction) -> OperandSize {
+    if w(instruction) { OperandSize::Qword } else { OperandSize::Dword }
+}
+
+// EVEX.b on a register-register form selects embedded rounding (or SAE) and forces 512-bit vectors
+pub fn embedded_rounding(instruction: &Instruction) -> bool {
+    instruction.evex.is_some_and(|evex| evex.b) && !instruction.has_memory_operand() && rounding_control(instruction)
+}
+
+// the float arithmetic and compares, the only register forms here with embedded rounding or SAE
+fn rounding_control(instruction: &Instruction) -> bool {
+    instruction.map == OpcodeMap::Map0F && matches!(instruction.opcode, 0x51 | 0x58 | 0x59 | 0x5C..=0x5F | 0xC2)
+}
+
+// MXCSR, or the embedded rounding mode with every exception suppressed
+pub fn environment(cpu: &Cpu, instruction: &Instruction) -> Environment {
+    match instruction.evex {
+        Some(evex) if embedded_rounding(instruction) => {
+            Environment::suppressing(RoundingMode::from_bits(evex.ll), cpu.registers.get_mxcsr())
+        }
+        _ => Environment::from_cpu(cpu),
+    }
+}
+
+// bytes of the vector length, L'L = 3 is reserved, and so is EVEX.b on register forms without embedded rounding
+pub fn vector_bytes(instruction: &Instruction) -> Result<usize, Exception> {
+    if embedded_rounding(instruction) {
+        return Ok(64);
+    }
+    if instruction.evex.is_some_and(|evex| evex.b) && !instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    match instruction.evex.map_or(0, |evex| evex.ll) {
+        0 => Ok(16),
+        1 => Ok(32),
+        2 => Ok(64),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// memory forms of instructions without embedded broadcast must leave EVEX.b clear, as must register forms
+// without embedded rounding
+pub fn check_no_broadcast(instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.evex.is_some_and(|evex| evex.b) && (instruction.has_memory_operand() || !rounding_control(instruction)) {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok(())
+}
+
+// the writemask from EVEX.aaa, k0 selects every element
+pub fn writemask(cpu: &Cpu, instruction: &Instruction) -> u64 {
+    match instruction.evex.map_or(0, |evex| evex.aaa) {
+        0 => u64::MAX,
+        k => cpu.registers.get_opmask(k as usize),
+    }
+}
+
+// EVEX compresses disp8: it counts in units of N, the size of the memory access
+pub fn memory_address(cpu: &Cpu, instruction: &Instruction, n: usize) -> u64 {
+    let offset = operand::effective_address(cpu, instruction);
+    let offset = if instruction.displacement_size == 1 {
+        offset.wrapping_add((instruction.displacement as u64).wrapping_mul(n as u64 - 1)) & instruction.address_size().mask()
+    } else {
+        offset
+    };
+    operand::linear_address(cpu, instruction, offset)
+}
+
+fn read_memory(cpu: &Cpu, address: u64, bytes: usize) -> Result<Zmm, Exception> {
+    let mut value = [0; 4];
+    for (i, quarter) in value.iter_mut().take(bytes / 16).enumerate() {
+        *quarter = cpu.read_memory::<u128>(address.wrapping_add(16 * i as u64))?;
+    }
+    Ok(value)
+}
+
+fn splat(size: OperandSize, bytes: usize, element: u64) -> Zmm {
+    let mut value = [0; 4];
+    for i in 0..bytes / size.bytes() {
+        avx::set_element(&mut value, size, i, element);
+    }
+    value
+}
+
+// the r/m source of a full-vector instruction: a register, `bytes` of memory, or with EVEX.b one broadcast element
+pub fn read_source(cpu: &Cpu, instruction: &Instruction, size: OperandSize, bytes: usize) -> Result<Zmm, Exception> {
+    if !instruction.has_memory_operand() {
+        return Ok(read_zmm(cpu, instruction.rm()));
+    }
+    if instruction.evex.is_some_and(|evex| evex.b) {
+        let address = memory_address(cpu, instruction, size.bytes());
+        let element = operand::read_memory_sized(cpu, address, size)?;
+        return Ok(splat(size, bytes, element));
+    }
+    read_memory(cpu, memory_address(cpu, instruction, bytes), bytes)
+}
+
+// the elements of the first `bytes` the writemask selects come from result, the others from fallback (or zero
+// with EVEX.z); everything above is zero
+pub fn merge_masked(cpu: &Cpu, instruction: &Instruction, size: OperandSize, bytes: usize, result: Zmm, fallback: Zmm) -> Zmm {
+    let mask = writemask(cpu, instruction);
+    let zeroing = instruction.evex.is_some_and(|evex| evex.z);
+    let mut value = [0; 4];
+    for i in 0..bytes / size.bytes() {
+        let element = if mask >> i & 1 == 1 {
+            avx::element(&result, size, i)
+        } else if zeroing {
+            0
+        } else {
+            avx::element(&fallback, size, i)
+        };
+        avx::set_element(&mut value, size, i, element);
+    }
+    value
+}
+
+pub fn write_masked(cpu: &mut Cpu, instruction: &Instruction, index: u8, size: OperandSize, bytes: usize, result: Zmm) {
+    let value = merge_masked(cpu, instruction, size, bytes, result, read_zmm(cpu, index));
+    write_zmm(cpu, index, value);
+}
+
+// a masked store writes only the selected elements, so unselected ones cannot fault
+fn store_masked(cpu: &mut Cpu, instruction: &Instruction, address: u64, size: OperandSize, bytes: usize, value: Zmm) -> Result<(), Exception> {
+    if instruction.evex.is_some_and(|evex| evex.z) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let mask = writemask(cpu, instruction);
+    for i in 0..bytes / size.bytes() {
+        if mask >> i & 1 == 1 {
+            let element_address = address.wrapping_add((i * size.bytes()) as u64);
+            operand::write_memory_sized(cpu, element_address, size, avx::element(&value, size, i))?;
+        }
+    }
+    Ok(())
+}
+
+// f on each 128-bit quarter of the vector length
+pub fn per_quarter(bytes: usize, a: Zmm, b: Zmm, f: impl Fn(u128, u128) -> Option<u128>) -> Result<Zmm, Exception> {
+    let mut result = [0; 4];
+    for i in 0..bytes / 16 {
+        result[i] = f(a[i], b[i]).ok_or(Exception::InvalidOpcode)?;
+    }
+    Ok(result)
+}
+
+// the common shape: reg = f(vvvv, r/m) per quarter, masked by `size` elements
+pub fn binary(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize, f: impl Fn(u128, u128) -> Option<u128>) -> Result<(), Exception> {
+    let bytes = vector_bytes(instruction)?;
+    let b = read_source(cpu, instruction, size, bytes)?;
+    let a = read_zmm(cpu, instruction.vvvv());
+    let result = per_quarter(bytes, a, b, f)?;
+    write_masked(cpu, instruction, instruction.reg(), size, bytes, result);
+    Ok(())
+}
+
+// the scalar forms: element 0 under mask bit 0, bits 127:element from vvvv and zero above
+pub fn write_scalar(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize, result: u64) {
+    let upper = sse::read_xmm(cpu, instruction.vvvv());
+    let low = merge_masked(cpu, instruction, size, size.bytes(), [result as u128, 0, 0, 0], [sse::read_xmm(cpu, instruction.reg()), 0, 0, 0]);
+    write_zmm(cpu, instruction.reg(), [sse::set_lane(upper, size, 0, low[0] as u64), 0, 0, 0]);
+}
+
+// element 0 of an XMM register or an element in memory, disp8 scaled by the element size
+pub fn read_scalar(cpu: &Cpu, instruction: &Instruction, size: OperandSize) -> Result<u64, Exception> {
+    check_no_broadcast(instruction)?;
+    if instruction.has_memory_operand() {
+        operand::read_memory_sized(cpu, memory_address(cpu, instruction, size.bytes()), size)
+    } else {
+        Ok(sse::lane(sse::read_xmm(cpu, instruction.rm()), size, 0))
+    }
+}
+
+// EVEX 0F 10/11 VMOVUPS/VMOVUPD/VMOVSS/VMOVSD, 28/29 VMOVAPS/VMOVAPD, 66 0F 6F/7F VMOVDQA32/64,
+// F3 0F 6F/7F VMOVDQU32/64 and F2 0F 6F/7F VMOVDQU8/16
+pub fn mov(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (size, aligned) = match (instruction.opcode, instruction.mandatory_prefix()) {
+        (0x10 | 0x11, MandatoryPrefix::PF3) => return mov_scalar(cpu, instruction, OperandSize::Dword),
+        (0x10 | 0x11, MandatoryPrefix::PF2) => return mov_scalar(cpu, instruction, OperandSize::Qword),
+        (0x10 | 0x11, MandatoryPrefix::None) if !w(instruction) => (OperandSize::Dword, false),
+        (0x10 | 0x11, MandatoryPrefix::P66) if w(instruction) => (OperandSize::Qword, false),
+        (0x28 | 0x29, MandatoryPrefix::None) if !w(instruction) => (OperandSize::Dword, true),
+        (0x28 | 0x29, MandatoryPrefix::P66) if w(instruction) => (OperandSize::Qword, true),
+        (0x6F | 0x7F, MandatoryPrefix::P66) => (element_size(instruction), true),
+        (0x6F | 0x7F, MandatoryPrefix::PF3) => (element_size(instruction), false),
+        (0x6F | 0x7F, MandatoryPrefix::PF2) => (if w(instruction) { OperandSize::Word } else { OperandSize::Byte }, false),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    avx::check_no_vvvv(instruction)?;
+    check_no_broadcast(instruction)?;
+    let bytes = vector_bytes(instruction)?;
+    let address = memory_address(cpu, instruction, bytes);
+    if instruction.has_memory_operand() && aligned && !address.is_multiple_of(bytes as u64) {
+        return Err(Exception::GeneralProtection(0));
+    }
+
+    if matches!(instruction.opcode, 0x10 | 0x28 | 0x6F) {
+        let value = read_source(cpu, instruction, size, bytes)?;
+        write_masked(cpu, instruction, instruction.reg(), size, bytes, value);
+        Ok(())
+    } else {
+        let value = read_zmm(cpu, instruction.reg());
+        if instruction.has_memory_operand() {
+            store_masked(cpu, instruction, address, size, bytes, value)
+        } else {
+            write_masked(cpu, instruction, instruction.rm(), size, bytes, value);
+            Ok(())
+        }
+    }
+}
+
+// VMOVSS/VMOVSD: a load zeroes everything above the element, the register forms take bits 127:element from vvvv
+fn mov_scalar(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize) -> Result<(), Exception> {
+    check_no_broadcast(instruction)?;
+    let memory = instruction.has_memory_operand();
+    if memory {
+        avx::check_no_vvvv(instruction)?;
+    }
+    let address = memory_address(cpu, instruction, size.bytes());
+    match (instruction.opcode, memory) {
+        (0x10, true) => {
+            let value = operand::read_memory_sized(cpu, address, size)?;
+            let old = [sse::read_xmm(cpu, instruction.reg()), 0, 0, 0];
+            let low = merge_masked(cpu, instruction, size, size.bytes(), [value as u128, 0, 0, 0], old);
+            write_zmm(cpu, instruction.reg(), low);
+        }
+        (_, true) => {
+            if writemask(cpu, instruction) & 1 == 1 {
+                let value = sse::lane(sse::read_xmm(cpu, instruction.reg()), size, 0);
+                operand::write_memory_sized(cpu, address, size, value)?;
+            }
+        }
+        (0x10, false) => {
+            let value = sse::lane(sse::read_xmm(cpu, instruction.rm()), size, 0);
+            write_scalar(cpu, instruction, size, value);
+        }
+        (_, false) => {
+            // 11 /r with a register swaps the roles: rm is the destination
+            let value = sse::lane(sse::read_xmm(cpu, instruction.reg()), size, 0);
+            let upper = sse::read_xmm(cpu, instruction.vvvv());
+            let old = [sse::read_xmm(cpu, instruction.rm()), 0, 0, 0];
+            let low = merge_masked(cpu, instruction, size, size.bytes(), [value as u128, 0, 0, 0], old);
+            write_zmm(cpu, instruction.rm(), [sse::set_lane(upper, size, 0, low[0] as u64), 0, 0, 0]);
+        }
+    }
+    Ok(())
+}
+
+// EVEX 66 0F38 18 VBROADCASTSS, 19 VBROADCASTSD, 58 VPBROADCASTD, 59 VPBROADCASTQ, 78 VPBROADCASTB, 79 VPBROADCASTW
+// from element 0 of an XMM register or memory, and 7A/7B/7C VPBROADCASTB/W/D/Q from a general purpose register
+pub fn broadcast(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    avx::check_no_vvvv(instruction)?;
+    check_no_broadcast(instruction)?;
+    let bytes = vector_bytes(instruction)?;
+    let size = match (instruction.opcode, w(instruction)) {
+        (0x18 | 0x58, false) => OperandSize::Dword,
+        (0x19 | 0x59, true) if instruction.opcode == 0x59 || bytes > 16 => OperandSize::Qword,
+        (0x78 | 0x7A, false) => OperandSize::Byte,
+        (0x79 | 0x7B, false) => OperandSize::Word,
+        (0x7C, _) => element_size(instruction),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let element = match instruction.opcode {
+        0x7A..=0x7C if instruction.has_memory_operand() => return Err(Exception::InvalidOpcode),
+        0x7A..=0x7C => operand::read_gpr64(cpu, instruction.rm() & 15) & size.mask(),
+        _ => read_scalar(cpu, instruction, size)?,
+    };
+    write_masked(cpu, instruction, instruction.reg(), size, bytes, splat(size, bytes, element));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn masked_moves_merge_or_zero() {
+        // vmovdqu32 zmm1{k1}, [rax] / vmovdqu32 zmm2{k1}{z}, [rax] / vmovdqa64 zmm31, zmm1 / vmovdqu32 [rax+0x40]{k1}, zmm31
+        let mut cpu = cpu_with_code(&[
+            0x62, 0xF1, 0x7E, 0x49, 0x6F, 0x08,
+            0x62, 0xF1, 0x7E, 0xC9, 0x6F, 0x10,
+            0x62, 0x61, 0xFD, 0x48, 0x6F, 0xF9,
+            0x62, 0x61, 0x7E, 0x49, 0x7F, 0x78, 0x01,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x2000);
+        for i in 0..16 {
+            cpu.memory.write::<u32>(0x2000 + 4 * i, 0x100 + i as u32);
+        }
+        cpu.memory.write::<u64>(0x2040, u64::MAX);
+        cpu.registers.set_opmask(1, 0b1010_0000_0000_0011);
+        write_zmm(&mut cpu, 1, [u128::MAX; 4]);
+        write_zmm(&mut cpu, 2, [u128::MAX; 4]);
+        cpu.run(4);
+
+        let size = OperandSize::Dword;
+        let merged = read_zmm(&cpu, 1);
+        let zeroed = read_zmm(&cpu, 2);
+        for i in 0..16 {
+            let selected = [0, 1, 13, 15].contains(&i);
+            assert_eq!(avx::element(&merged, size, i), if selected { 0x100 + i as u64 } else { 0xFFFF_FFFF }, "merged {}", i);
+            assert_eq!(avx::element(&zeroed, size, i), if selected { 0x100 + i as u64 } else { 0 }, "zeroed {}", i);
+        }
+        assert_eq!(read_zmm(&cpu, 31), merged);
+        // disp8 of 1 scales by the 64-byte vector, only the selected dwords are stored
+        assert_eq!(cpu.memory.read::<u64>(0x2040), 0x101_0000_0100);
+        assert_eq!(cpu.memory.read::<u32>(0x2040 + 4 * 13), 0x10D);
+        assert_eq!(cpu.memory.read::<u32>(0x2040 + 4 * 12), 0);
+    }
+
+    #[test]
+    fn broadcasts_from_memory_register_and_gpr() {
+        // vpbroadcastd ymm3{k2}{z}, [rax+4] (disp8 scaled by 4) / vpbroadcastq zmm20, rcx / vbroadcastss xmm5, xmm20
+        let mut cpu = cpu_with_code(&[
+            0x62, 0xF2, 0x7D, 0xAA, 0x58, 0x58, 0x01,
+            0x62, 0xE2, 0xFD, 0x48, 0x7C, 0xE1,
+            0x62, 0xB2, 0x7D, 0x08, 0x18, 0xEC,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x2000);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0x1122_3344_5566_7788);
+        cpu.memory.write::<u32>(0x2004, 0xABCD);
+        cpu.registers.set_opmask(2, 0b0110);
+        write_zmm(&mut cpu, 3, [u128::MAX; 4]);
+        write_zmm(&mut cpu, 5, [u128::MAX; 4]);
+        cpu.run(3);
+        assert_eq!(read_zmm(&cpu, 3), [0xABCD_0000_ABCD << 32, 0, 0, 0]);
+        let q = 0x1122_3344_5566_7788u128;
+        assert_eq!(read_zmm(&cpu, 20), [q << 64 | q; 4]);
+        assert_eq!(read_zmm(&cpu, 5), [0x5566_7788_5566_7788_5566_7788_5566_7788, 0, 0, 0]);
+    }
+
+    #[test]
+    fn register_forms_without_embedded_rounding_reject_evex_b() {
+        // vpaddd zmm1, zmm2, zmm3 and vmovdqu32 zmm1, zmm2, both with EVEX.b set
+        for code in [[0x62, 0xF1, 0x6D, 0x58, 0xFE, 0xCB], [0x62, 0xF1, 0x7E, 0x58, 0x6F, 0xCA]] {
+            let mut cpu = cpu_with_code(&code);
+            assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+        }
+    }
+}
@@ -0,0 +1,166 @@
+// AVX-512 floating point: masked arithmetic with embedded broadcast and rounding, logic, and compares into opmasks
+// elements the writemask leaves out are not computed, so they raise no MXCSR exceptions
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::avx;
+use crate::instructions::avx512;
+use crate::instructions::avx512::Zmm;
+use crate::instructions::sse;
+use crate::instructions::sse_float;
+use crate::instructions::sse_float::FloatOp;
+
+// element size and scalar-ness from pp, with EVEX.W required to match the element size
+fn float_format(instruction: &Instruction) -> Result<(OperandSize, bool), Exception> {
+    let (size, scalar) = sse_float::float_format(instruction.mandatory_prefix());
+    if avx512::element_size(instruction) != size {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok((size, scalar))
+}
+
+// f on the elements of vvvv and r/m the writemask selects, zero elsewhere
+fn selected(cpu: &Cpu, instruction: &Instruction, size: OperandSize, bytes: usize, f: impl Fn(u64, u64) -> u64) -> Result<Zmm, Exception> {
+    let b = avx512::read_source(cpu, instruction, size, bytes)?;
+    let a = avx512::read_zmm(cpu, instruction.vvvv());
+    let mask = avx512::writemask(cpu, instruction);
+    let mut result = [0; 4];
+    for i in (0..bytes / size.bytes()).filter(|i| mask >> i & 1 == 1) {
+        avx::set_element(&mut result, size, i, f(avx::element(&a, size, i), avx::element(&b, size, i)));
+    }
+    Ok(result)
+}
+
+// EVEX 0F 51 VSQRT, 58 VADD, 59 VMUL, 5C VSUB, 5D VMIN, 5E VDIV, 5F VMAX in their PS/PD/SS/SD forms
+pub fn arithmetic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = FloatOp::from_opcode(instruction.opcode).ok_or(Exception::InvalidOpcode)?;
+    let (size, scalar) = float_format(instruction)?;
+    let environment = avx512::environment(cpu, instruction);
+    if scalar {
+        let b = avx512::read_scalar(cpu, instruction, size)?;
+        let a = sse::lane(sse::read_xmm(cpu, instruction.vvvv()), size, 0);
+        let selected = avx512::writemask(cpu, instruction) & 1 == 1;
+        let result = if selected { sse_float::apply(op, size, a, b, &environment) } else { 0 };
+        environment.commit(cpu)?;
+        avx512::write_scalar(cpu, instruction, size, result);
+        return Ok(());
+    }
+    if op == FloatOp::Sqrt {
+        avx::check_no_vvvv(instruction)?;
+    }
+    let bytes = avx512::vector_bytes(instruction)?;
+    let result = selected(cpu, instruction, size, bytes, |x, y| sse_float::apply(op, size, x, y, &environment))?;
+    environment.commit(cpu)?;
+    avx512::write_masked(cpu, instruction, instruction.reg(), size, bytes, result);
+    Ok(())
+}
+
+// EVEX 0F 54 VANDPS, 55 VANDNPS, 56 VORPS, 57 VXORPS, 66 for the PD forms (AVX512DQ)
+pub fn logic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (size, scalar) = float_format(instruction)?;
+    if scalar {
+        return Err(Exception::InvalidOpcode);
+    }
+    let opcode = instruction.opcode;
+    avx512::binary(cpu, instruction, size, |a, b| Some(sse_float::bitwise(opcode, a, b)))
+}
+
+// EVEX 0F C2 VCMPPS/VCMPPD/VCMPSS/VCMPSD k{k}, vvvv, r/m, imm8: one result bit per element in the opmask
+// named by ModRM.reg, bits the writemask clears and bits past the last element are zero
+pub fn compare(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (size, scalar) = float_format(instruction)?;
+    if instruction.evex.is_some_and(|evex| evex.z) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let imm = instruction.immediate_value() as u8 & 0x1F;
+    let environment = avx512::environment(cpu, instruction);
+    let writemask = avx512::writemask(cpu, instruction);
+    let compare = |x, y| sse_float::compare_lane(size, imm, x, y, &environment);
+    let (result, count) = if scalar {
+        let b = avx512::read_scalar(cpu, instruction, size)?;
+        let a = sse::lane(sse::read_xmm(cpu, instruction.vvvv()), size, 0);
+        let result = if writemask & 1 == 1 { compare(a, b) } else { 0 };
+        ([result as u128, 0, 0, 0], 1)
+    } else {
+        let bytes = avx512::vector_bytes(instruction)?;
+        (selected(cpu, instruction, size, bytes, compare)?, bytes / size.bytes())
+    };
+    let mask = (0..count).fold(0u64, |mask, i| mask | ((avx::element(&result, size, i) != 0) as u64) << i);
+    environment.commit(cpu)?;
+    cpu.registers.set_opmask(instruction.group_index() as usize, mask & writemask);
+    Ok(())
+}
+
+// EVEX 66 0F38 65 VBLENDMPS/VBLENDMPD: the writemask picks r/m elements over vvvv ones
+pub fn blend(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let size = avx512::element_size(instruction);
+    let bytes = avx512::vector_bytes(instruction)?;
+    let b = avx512::read_source(cpu, instruction, size, bytes)?;
+    let a = avx512::read_zmm(cpu, instruction.vvvv());
+    let result = avx512::merge_masked(cpu, instruction, size, bytes, b, a);
+    avx512::write_zmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    use crate::instructions::sse_float::tests::ps;
+
+    #[test]
+    fn embedded_rounding_and_broadcast() {
+        // vaddps zmm0, zmm1, zmm2, {rd-sae} / vaddps zmm3, zmm1, zmm2 / vmulps zmm4{k1}, zmm1, dword [0x2000]{1to16}
+        // vdivsd xmm5, xmm6, xmm7, {rz-sae}
+        let mut cpu = cpu_with_code(&[
+            0x62, 0xF1, 0x74, 0x38, 0x58, 0xC2,
+            0x62, 0xF1, 0x74, 0x48, 0x58, 0xDA,
+            0x62, 0xF1, 0x74, 0x59, 0x59, 0x24, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x62, 0xF1, 0xCF, 0x78, 0x5E, 0xEF,
+        ]);
+        // 1 + 2^-30 is inexact in single precision, -1 - 2^-30 rounds the other way when rounding down
+        let tiny = 2f32.powi(-30);
+        let one = 1f32;
+        avx512::write_zmm(&mut cpu, 1, [ps([one, -one, 3.0, 4.0]), ps([5.0; 4]), ps([6.0; 4]), ps([7.0; 4])]);
+        avx512::write_zmm(&mut cpu, 2, [ps([tiny, -tiny, -3.0, 0.0]), 0, 0, 0]);
+        cpu.memory.write::<u32>(0x2000, 2f32.to_bits());
+        cpu.registers.set_opmask(1, 0b11);
+        avx512::write_zmm(&mut cpu, 4, [u128::MAX; 4]);
+        sse::write_xmm(&mut cpu, 6, (1f64.to_bits() as u128) | 0x55 << 64);
+        sse::write_xmm(&mut cpu, 7, 10f64.to_bits() as u128);
+        cpu.run(4);
+
+        let rounded_down = avx512::read_zmm(&cpu, 0);
+        assert_eq!(rounded_down[0], ps([one, (-one).next_down(), -0.0, 4.0]));
+        assert_eq!(avx512::read_zmm(&cpu, 3)[0], ps([one, -one, 0.0, 4.0]));
+        assert_eq!(avx512::read_zmm(&cpu, 4), [ps([2.0, -2.0, 0.0, 0.0]) | u128::MAX << 64, u128::MAX, u128::MAX, u128::MAX]);
+        // the nearest double to 0.1 lies above it, truncation takes the one below
+        assert_eq!(sse::read_xmm(&cpu, 5), 0.1f64.next_down().to_bits() as u128 | 0x55 << 64);
+    }
+
+    #[test]
+    fn compares_into_opmasks_and_blends() {
+        // vcmpps k2{k1}, zmm1, zmm2, 1 (LT) / vblendmps zmm3{k2}, zmm1, zmm2
+        let mut cpu = cpu_with_code(&[
+            0x62, 0xF1, 0x74, 0x49, 0xC2, 0xD2, 0x01,
+            0x62, 0xF2, 0x75, 0x4A, 0x65, 0xDA,
+        ]);
+        avx512::write_zmm(&mut cpu, 1, [ps([1.0, 5.0, 1.0, f32::NAN]), ps([1.0; 4]), ps([1.0; 4]), ps([9.0; 4])]);
+        avx512::write_zmm(&mut cpu, 2, [ps([2.0, 2.0, 2.0, 2.0]), ps([2.0; 4]), ps([2.0; 4]), ps([2.0; 4])]);
+        cpu.registers.set_opmask(1, 0x0FFB);
+        cpu.run(2);
+        assert_eq!(cpu.registers.get_opmask(2), 0x0FF1);
+        let blended = avx512::read_zmm(&cpu, 3);
+        assert_eq!(blended[0], ps([2.0, 5.0, 1.0, f32::NAN]));
+        assert_eq!(blended[3], ps([9.0; 4]));
+    }
+}
@@ -0,0 +1,213 @@
+// AVX-512 packed integer instructions: masked add/sub, logic, VPMULL, compares into opmasks, VPTERNLOG and blends
+// the D/Q forms take their element size from EVEX.W and allow embedded broadcast, the B/W forms (AVX512BW) do not
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::OpcodeMap;
+use crate::instructions::avx;
+use crate::instructions::avx512;
+use crate::instructions::sse;
+use crate::instructions::sse_int;
+
+fn check_p66(instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    Ok(())
+}
+
+// element size of a B/W/D/Q opcode, with EVEX.W fixed for the D and Q forms
+fn opcode_size(instruction: &Instruction) -> Result<OperandSize, Exception> {
+    let w = instruction.rex.is_some_and(|rex| rex.w);
+    let size = match instruction.opcode {
+        0xFC | 0xF8 | 0x74 | 0x64 => OperandSize::Byte,
+        0xFD | 0xF9 | 0x75 | 0x65 => OperandSize::Word,
+        0xFE | 0xFA | 0x76 | 0x66 if !w => OperandSize::Dword,
+        0xD4 | 0xFB if w => OperandSize::Qword,
+        // VPAND/VPANDN/VPOR/VPXOR D and Q
+        0xDB | 0xDF | 0xEB | 0xEF => avx512::element_size(instruction),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    if size.bytes() < 4 {
+        avx512::check_no_broadcast(instruction)?;
+    }
+    Ok(size)
+}
+
+// EVEX 66 0F FC..FE/D4 VPADD*, F8..FB VPSUB*, DB VPANDD/Q, DF VPANDND/Q, EB VPORD/Q and EF VPXORD/Q
+pub fn packed(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_p66(instruction)?;
+    let size = opcode_size(instruction)?;
+    let opcode = instruction.opcode;
+    avx512::binary(cpu, instruction, size, |a, b| sse_int::compute(opcode, a, b))
+}
+
+// EVEX 66 0F38 40 VPMULLD (W0) / VPMULLQ (W1), the low half of each product
+pub fn multiply_low(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_p66(instruction)?;
+    let size = avx512::element_size(instruction);
+    avx512::binary(cpu, instruction, size, |a, b| Some(sse::map_lanes(size, a, b, u64::wrapping_mul)))
+}
+
+// VPCMP predicates: EQ, LT, LE, FALSE, NE, NLT, NLE, TRUE
+fn predicate(predicate: u8, x: i128, y: i128) -> bool {
+    match predicate & 7 {
+        0 => x == y,
+        1 => x < y,
+        2 => x <= y,
+        3 => false,
+        4 => x != y,
+        5 => x >= y,
+        6 => x > y,
+        _ => true,
+    }
+}
+
+// EVEX 66 0F 74..76 VPCMPEQB/W/D and 64..66 VPCMPGTB/W/D, 66 0F38 29 VPCMPEQQ and 37 VPCMPGTQ,
+// 66 0F3A 1F/1E VPCMPD/Q and VPCMPUD/UQ, 3F/3E VPCMPB/W and VPCMPUB/UW: the result bits go to the opmask
+// in ModRM.reg, cleared where the writemask is clear
+pub fn compare(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_p66(instruction)?;
+    if instruction.evex.is_some_and(|evex| evex.z) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let w = instruction.rex.is_some_and(|rex| rex.w);
+    let (size, predicate_bits, signed) = match (instruction.map, instruction.opcode) {
+        (OpcodeMap::Map0F, 0x74..=0x76) => (opcode_size(instruction)?, 0, true),
+        (OpcodeMap::Map0F, 0x64..=0x66) => (opcode_size(instruction)?, 6, true),
+        (OpcodeMap::Map0F38, 0x29) if w => (OperandSize::Qword, 0, true),
+        (OpcodeMap::Map0F38, 0x37) if w => (OperandSize::Qword, 6, true),
+        (OpcodeMap::Map0F3A, 0x1E | 0x1F) => {
+            (avx512::element_size(instruction), instruction.immediate_value() as u8, instruction.opcode == 0x1F)
+        }
+        (OpcodeMap::Map0F3A, 0x3E | 0x3F) => {
+            avx512::check_no_broadcast(instruction)?;
+            let size = if w { OperandSize::Word } else { OperandSize::Byte };
+            (size, instruction.immediate_value() as u8, instruction.opcode == 0x3F)
+        }
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let bytes = avx512::vector_bytes(instruction)?;
+    let b = avx512::read_source(cpu, instruction, size, bytes)?;
+    let a = avx512::read_zmm(cpu, instruction.vvvv());
+    let value = |v: u64| if signed { size.sign_extend(v) as i64 as i128 } else { v as i128 };
+    let mask = (0..bytes / size.bytes()).fold(0u64, |mask, i| {
+        let result = predicate(predicate_bits, value(avx::element(&a, size, i)), value(avx::element(&b, size, i)));
+        mask | (result as u64) << i
+    });
+    cpu.registers.set_opmask(instruction.group_index() as usize, mask & avx512::writemask(cpu, instruction));
+    Ok(())
+}
+
+// every result bit is imm8 indexed by the matching bits of a, b and c, as in a three-input truth table
+pub fn ternary_logic(imm: u8, a: u128, b: u128, c: u128) -> u128 {
+    (0..8).filter(|i| imm >> i & 1 == 1).fold(0, |result, i| {
+        let pick = |bit: u8, value: u128| if i & bit != 0 { value } else { !value };
+        result | (pick(4, a) & pick(2, b) & pick(1, c))
+    })
+}
+
+// EVEX 66 0F3A 25 VPTERNLOGD/Q: the destination is the first input, vvvv the second and r/m the third
+pub fn ternlog(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_p66(instruction)?;
+    let size = avx512::element_size(instruction);
+    let bytes = avx512::vector_bytes(instruction)?;
+    let imm = instruction.immediate_value() as u8;
+    let c = avx512::read_source(cpu, instruction, size, bytes)?;
+    let b = avx512::read_zmm(cpu, instruction.vvvv());
+    let a = avx512::read_zmm(cpu, instruction.reg());
+    let mut result = [0; 4];
+    for i in 0..bytes / 16 {
+        result[i] = ternary_logic(imm, a[i], b[i], c[i]);
+    }
+    avx512::write_masked(cpu, instruction, instruction.reg(), size, bytes, result);
+    Ok(())
+}
+
+// EVEX 66 0F38 64 VPBLENDMD/Q and 66 VPBLENDMB/W: the writemask picks r/m elements over vvvv ones
+pub fn blend(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_p66(instruction)?;
+    let w = instruction.rex.is_some_and(|rex| rex.w);
+    let size = match (instruction.opcode, w) {
+        (0x66, false) => OperandSize::Byte,
+        (0x66, true) => OperandSize::Word,
+        _ => avx512::element_size(instruction),
+    };
+    if size.bytes() < 4 {
+        avx512::check_no_broadcast(instruction)?;
+    }
+    let bytes = avx512::vector_bytes(instruction)?;
+    let b = avx512::read_source(cpu, instruction, size, bytes)?;
+    let a = avx512::read_zmm(cpu, instruction.vvvv());
+    let result = avx512::merge_masked(cpu, instruction, size, bytes, b, a);
+    avx512::write_zmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn ternary_truth_tables() {
+        let (a, b, c) = (0b1111_0000, 0b1100_1100, 0b1010_1010);
+        // the imm8 of a function is the function applied to the three canonical inputs
+        assert_eq!(ternary_logic(0x96, a, b, c), a ^ b ^ c);
+        assert_eq!(ternary_logic(0xE8, a, b, c) & 0xFF, 0b1110_1000);
+        assert_eq!(ternary_logic(0xCA, a, b, c), (a & b) | (!a & c));
+        assert_eq!(ternary_logic(0x00, a, b, c), 0);
+        assert_eq!(ternary_logic(0xFF, a, b, c), u128::MAX);
+    }
+
+    #[test]
+    fn masked_integer_forms() {
+        // vpaddd zmm0{k1}{z}, zmm1, dword [rax]{1to16} / vpcmpud k2, zmm0, zmm1, 6 (NLE) / vpsubq ymm17{k2}, ymm1, ymm2
+        // vpmullq xmm3, xmm1, xmm2 / vpternlogd zmm1, zmm2, zmm3, 0x96
+        let mut cpu = cpu_with_code(&[
+            0x62, 0xF1, 0x75, 0xD9, 0xFE, 0x00,
+            0x62, 0xF3, 0x7D, 0x48, 0x1E, 0xD1, 0x06,
+            0x62, 0xE1, 0xF5, 0x2A, 0xFB, 0xCA,
+            0x62, 0xF2, 0xF5, 0x08, 0x40, 0xDA,
+            0x62, 0xF3, 0x6D, 0x48, 0x25, 0xCB, 0x96,
+        ]);
+        let size = OperandSize::Dword;
+        let mut one_to_sixteen = [0; 4];
+        for i in 0..16 {
+            avx::set_element(&mut one_to_sixteen, size, i, i as u64 + 1);
+        }
+        avx512::write_zmm(&mut cpu, 1, one_to_sixteen);
+        avx512::write_zmm(&mut cpu, 2, [5 << 64 | 7, 1, 0, 0]);
+        avx512::write_zmm(&mut cpu, 17, [u128::MAX; 4]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x2000);
+        cpu.memory.write::<u32>(0x2000, 0xFFFF_FFFF);
+        cpu.registers.set_opmask(1, 0x00FF);
+        cpu.run(2);
+
+        // zmm0 = zmm1 - 1 in the low eight elements, zero above
+        let sum = avx512::read_zmm(&cpu, 0);
+        for i in 0..16 {
+            assert_eq!(avx::element(&sum, size, i), if i < 8 { i as u64 } else { 0 }, "element {}", i);
+        }
+        // unsigned zmm0 > zmm1 nowhere: (i) > (i + 1) never, 0 > (i + 1) never
+        assert_eq!(cpu.registers.get_opmask(2), 0);
+        cpu.registers.set_opmask(2, 0b0101);
+        cpu.run(3);
+        let difference = avx512::read_zmm(&cpu, 17);
+        // qwords 0 and 2 take the difference, 1 and 3 keep their old value
+        assert_eq!(difference[0], (u64::MAX as u128) << 64 | ((2 << 32 | 1) - 7));
+        assert_eq!(difference[1], (u64::MAX as u128) << 64 | (6 << 32 | 4));
+        assert_eq!(difference[2..], [0, 0]);
+        assert_eq!(avx512::read_zmm(&cpu, 3), [(20 << 32 | 15) << 64 | (14 << 32 | 7), 0, 0, 0]);
+        let xor = avx512::read_zmm(&cpu, 1);
+        let expected = [0, 1, 2, 3].map(|i| one_to_sixteen[i] ^ [5 << 64 | 7, 1, 0, 0][i] ^ avx512::read_zmm(&cpu, 3)[i]);
+        assert_eq!(xor, expected);
+    }
+}
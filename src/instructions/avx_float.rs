@@ -0,0 +1,102 @@
+// AVX floating point: three-operand arithmetic, logic and compares on XMM/YMM
+// the packed forms work on every lane the vector length covers, the scalar forms compute lane 0 and copy
+// the rest of the low 128 bits from vvvv; all of them zero the destination above the result
+// arithmetic and compares run under MXCSR like their SSE forms and write nothing when they raise #XM
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::avx;
+use crate::instructions::sse;
+use crate::instructions::sse_float;
+use crate::instructions::sse_float::FloatOp;
+use crate::instructions::mxcsr::Environment;
+
+// reg = vvvv with lane 0 replaced by f(vvvv, element of r/m)
+fn scalar(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize, environment: &Environment, f: impl Fn(u64, u64) -> u64) -> Result<(), Exception> {
+    let b = sse::read_rm(cpu, instruction, size.bytes(), false)?;
+    let a = sse::read_xmm(cpu, instruction.vvvv());
+    let result = sse_float::float_lanes(size, true, a, b, f);
+    environment.commit(cpu)?;
+    avx::write_zero_upper(cpu, instruction.reg(), [result, 0], 1);
+    Ok(())
+}
+
+// reg = f(vvvv, r/m) on every lane the vector length covers
+fn packed(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize, environment: &Environment, f: impl Fn(u64, u64) -> u64) -> Result<(), Exception> {
+    let b = avx::read_rm(cpu, instruction, false)?;
+    let a = avx::read_ymm(cpu, instruction.vvvv());
+    let result = avx::per_half(instruction, a, b, |a, b| Some(sse_float::float_lanes(size, false, a, b, &f)))?;
+    environment.commit(cpu)?;
+    avx::write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VEX 0F 51 VSQRT, 58 VADD, 59 VMUL, 5C VSUB, 5D VMIN, 5E VDIV, 5F VMAX in their PS/PD/SS/SD forms
+pub fn arithmetic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = FloatOp::from_opcode(instruction.opcode).ok_or(Exception::InvalidOpcode)?;
+    let (size, is_scalar) = sse_float::float_format(instruction.mandatory_prefix());
+    let environment = Environment::from_cpu(cpu);
+    let f = |x, y| sse_float::apply(op, size, x, y, &environment);
+    if is_scalar {
+        return scalar(cpu, instruction, size, &environment, f);
+    }
+    // VSQRTPS/PD have a single source
+    if op == FloatOp::Sqrt {
+        avx::check_no_vvvv(instruction)?;
+    }
+    packed(cpu, instruction, size, &environment, f)
+}
+
+// VEX 0F 54 VANDPS, 55 VANDNPS, 56 VORPS, 57 VXORPS, 66 for the PD forms
+pub fn logic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if !matches!(instruction.mandatory_prefix(), MandatoryPrefix::None | MandatoryPrefix::P66) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let opcode = instruction.opcode;
+    avx::binary(cpu, instruction, |a, b| Some(sse_float::bitwise(opcode, a, b)))
+}
+
+// VEX 0F C2 VCMPPS/VCMPPD/VCMPSS/VCMPSD with the full 32 predicates
+pub fn compare(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (size, is_scalar) = sse_float::float_format(instruction.mandatory_prefix());
+    let imm = instruction.immediate_value() as u8 & 0x1F;
+    let environment = Environment::from_cpu(cpu);
+    let f = |x, y| sse_float::compare_lane(size, imm, x, y, &environment);
+    if is_scalar {
+        return scalar(cpu, instruction, size, &environment, f);
+    }
+    packed(cpu, instruction, size, &environment, f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    use crate::instructions::sse_float::tests::ps;
+
+    #[test]
+    fn packed_and_scalar_forms() {
+        // vmulps ymm0, ymm1, [0x2000] / vsubss xmm2, xmm1, xmm3 / vcmpps ymm4, ymm1, ymm3, 0x0E (GT_OS)
+        let mut cpu = cpu_with_code(&[
+            0xC5, 0xF4, 0x59, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xC5, 0xF2, 0x5C, 0xD3,
+            0xC5, 0xF4, 0xC2, 0xE3, 0x0E,
+        ]);
+        avx::write_zero_upper(&mut cpu, 1, [ps([1.0, 2.0, 3.0, 4.0]), ps([5.0, 6.0, 7.0, 8.0])], 2);
+        avx::write_zero_upper(&mut cpu, 3, [ps([0.5, 2.0, 4.0, f32::NAN]), ps([0.0; 4])], 2);
+        // the memory operand of a VEX arithmetic instruction needs no alignment, 0x2000 happens to be aligned
+        cpu.memory.write::<u128>(0x2000, ps([2.0; 4]));
+        cpu.memory.write::<u128>(0x2010, ps([-1.0; 4]));
+        avx::write_zero_upper(&mut cpu, 2, [u128::MAX, u128::MAX], 2);
+        cpu.run(3);
+        assert_eq!(avx::read_ymm(&cpu, 0), [ps([2.0, 4.0, 6.0, 8.0]), ps([-5.0, -6.0, -7.0, -8.0])]);
+        assert_eq!(avx::read_ymm(&cpu, 2), [ps([0.5, 2.0, 3.0, 4.0]), 0]);
+        assert_eq!(avx::read_ymm(&cpu, 4), [u32::MAX as u128, u128::MAX]);
+    }
+}
@@ -0,0 +1,99 @@
+// AVX/AVX2 packed integer instructions: the SSE2 set in its three-operand VEX form, 256 bits wide with VEX.L,
+// plus the AVX2 per-element variable shifts
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::avx;
+use crate::instructions::sse;
+use crate::instructions::sse_int;
+use crate::instructions::sse_int::ShiftKind;
+
+// VPSRL*, VPSRA* and VPSLL* by a count in an XMM register or m128
+fn shifts_by_count(opcode: u8) -> bool {
+    matches!(opcode, 0xD1..=0xD3 | 0xE1 | 0xE2 | 0xF1..=0xF3)
+}
+
+// VEX 66 0F 60..6D, 74..76 and D1..FE, the same operations as the SSE2 forms applied to each 128-bit half
+pub fn packed(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let opcode = instruction.opcode;
+    if !shifts_by_count(opcode) {
+        return avx::binary(cpu, instruction, |a, b| sse_int::compute(opcode, a, b));
+    }
+    // the count operand stays 128 bits wide and applies to both halves
+    let count = sse::read_rm(cpu, instruction, 16, false)?;
+    let a = avx::read_ymm(cpu, instruction.vvvv());
+    let result = avx::per_half(instruction, a, [count, count], |a, b| sse_int::compute(opcode, a, b))?;
+    avx::write_vex(cpu, instruction, instruction.reg(), result);
+    Ok(())
+}
+
+// VEX 66 0F 71/72/73 /digit ib: vvvv = r/m shifted, the byte shifts work within each half
+pub fn shift_immediate(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 || instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    let (opcode, group, count) = (instruction.opcode, instruction.group_index(), instruction.immediate_value());
+    let value = avx::read_ymm(cpu, instruction.rm());
+    let result = avx::per_half(instruction, value, value, |value, _| sse_int::shift_by_immediate(opcode, group, value, count))?;
+    avx::write_vex(cpu, instruction, instruction.vvvv(), result);
+    Ok(())
+}
+
+// VEX 66 0F38 45 VPSRLVD/Q, 46 VPSRAVD, 47 VPSLLVD/Q: every element shifted by the matching element of r/m
+pub fn shift_variable(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let w = instruction.rex.is_some_and(|rex| rex.w);
+    let kind = match (instruction.opcode, w) {
+        (0x45, _) => ShiftKind::Right,
+        (0x46, false) => ShiftKind::Arithmetic,
+        (0x47, _) => ShiftKind::Left,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let size = if w { OperandSize::Qword } else { OperandSize::Dword };
+    avx::binary(cpu, instruction, |a, b| {
+        Some(sse::map_lanes(size, a, b, |value, count| sse_int::shift(size, kind, value as u128, count) as u64))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    fn dwords(values: [u32; 4]) -> u128 {
+        values.iter().enumerate().fold(0, |result, (i, value)| result | (*value as u128) << (32 * i))
+    }
+
+    #[test]
+    fn operates_on_both_halves() {
+        // vpaddd ymm0, ymm1, ymm2 / vpslld ymm3, ymm1, xmm4 / vpsrld ymm5, ymm1, 4 / vpsravd ymm6, ymm7, ymm2 / vpxor xmm1, xmm1, xmm1
+        let mut cpu = cpu_with_code(&[
+            0xC5, 0xF5, 0xFE, 0xC2,
+            0xC5, 0xF5, 0xF2, 0xDC,
+            0xC5, 0xD5, 0x72, 0xD1, 0x04,
+            0xC4, 0xE2, 0x45, 0x46, 0xF2,
+            0xC5, 0xF1, 0xEF, 0xC9,
+        ]);
+        avx::write_zero_upper(&mut cpu, 1, [dwords([1, 2, 3, 4]), dwords([0x10, 0x20, 0x30, 0xFFFF_FFFF])], 2);
+        avx::write_zero_upper(&mut cpu, 2, [dwords([1, 1, 1, 1]), dwords([0, 4, 31, 40])], 2);
+        // only the low 64 bits count
+        avx::write_zero_upper(&mut cpu, 4, [1 << 64 | 2, u128::MAX], 2);
+        avx::write_zero_upper(&mut cpu, 7, [dwords([8, 8, 8, 8]), dwords([0x8000_0000; 4])], 2);
+        cpu.run(5);
+        assert_eq!(avx::read_ymm(&cpu, 0), [dwords([2, 3, 4, 5]), dwords([0x10, 0x24, 0x4F, 0x27])]);
+        assert_eq!(avx::read_ymm(&cpu, 3), [dwords([4, 8, 12, 16]), dwords([0x40, 0x80, 0xC0, 0xFFFF_FFFC])]);
+        assert_eq!(avx::read_ymm(&cpu, 5), [0, dwords([1, 2, 3, 0x0FFF_FFFF])]);
+        assert_eq!(avx::read_ymm(&cpu, 6), [dwords([4, 4, 4, 4]), dwords([0x8000_0000, 0xF800_0000, u32::MAX, u32::MAX])]);
+        assert_eq!(avx::read_ymm(&cpu, 1), [0, 0]);
+    }
+}
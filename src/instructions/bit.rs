@@ -0,0 +1,108 @@
+// bit tests: BT, BTS, BTR and BTC with register or immediate bit offsets
+
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+use crate::instructions::alu::check_lock;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BitOp {
+    Test, Set, Reset, Complement
+}
+
+// 0F A3/AB/B3/BB Ev,Gv and 0F BA /4../7 Ev,Ib
+// a register offset is signed and may address memory outside the operand, an immediate one wraps
+// only CF is written, the undefined OF/SF/AF/PF keep their values as on Bochs
+pub fn bit_test(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = match (instruction.opcode, instruction.group_index()) {
+        (0xA3, _) | (0xBA, 4) => BitOp::Test,
+        (0xAB, _) | (0xBA, 5) => BitOp::Set,
+        (0xB3, _) | (0xBA, 6) => BitOp::Reset,
+        (0xBB, _) | (0xBA, 7) => BitOp::Complement,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    check_lock(instruction, op != BitOp::Test)?;
+    let size = instruction.operand_size();
+    let bits = size.bits() as u64;
+    let destination = operand::rm_operand(cpu, instruction);
+    let (destination, bit) = if instruction.opcode == 0xBA {
+        (destination, instruction.immediate_value() & (bits - 1))
+    } else {
+        let offset = operand::read_gpr(cpu, instruction, instruction.reg(), size);
+        let destination = match destination {
+            Operand::Memory(address) => {
+                let units = (size.sign_extend(offset) as i64) >> size.bits().trailing_zeros();
+                Operand::Memory(address.wrapping_add(units.wrapping_mul(size.bytes() as i64) as u64))
+            }
+            register => register,
+        };
+        (destination, offset & (bits - 1))
+    };
+
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let mask = 1u64 << bit;
+    let result = match op {
+        BitOp::Test => value,
+        BitOp::Set => value | mask,
+        BitOp::Reset => value & !mask,
+        BitOp::Complement => value ^ mask,
+    };
+    if op != BitOp::Test {
+        operand::write_operand(cpu, instruction, destination, size, result)?;
+    }
+    cpu.registers.set_flag(Flag::CF, value & mask != 0);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::Flag;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::Exception;
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn register_and_immediate_offsets() {
+        // bt eax, 35 (wraps to 3) / bts rbx, rcx / btr ebx, 0
+        let mut cpu = cpu_with_code(&[0x0F, 0xBA, 0xE0, 0x23, 0x48, 0x0F, 0xAB, 0xCB, 0x0F, 0xBA, 0xF3, 0x00]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x8);
+        cpu.registers.set_gpr_value(GPRName::RBX, 1);
+        cpu.registers.set_gpr_value(GPRName::RCX, 63 + 64);
+        cpu.run(1);
+        assert!(cpu.registers.get_flag(Flag::CF));
+        cpu.run(1);
+        assert!(!cpu.registers.get_flag(Flag::CF));
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0x8000_0000_0000_0001);
+        cpu.run(1);
+        assert!(cpu.registers.get_flag(Flag::CF));
+        // 32-bit BTR zero-extends like any 32-bit write
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0);
+    }
+
+    #[test]
+    fn memory_bit_strings() {
+        // btc dword [0x2010], ecx / lock bts word [0x2010], dx / bt [0x2010], 1 with lock faults
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0xBB, 0x0C, 0x25, 0x10, 0x20, 0x00, 0x00,
+            0xF0, 0x66, 0x0F, 0xAB, 0x14, 0x25, 0x10, 0x20, 0x00, 0x00,
+            0xF0, 0x0F, 0xBA, 0x24, 0x25, 0x10, 0x20, 0x00, 0x00, 0x01,
+        ]);
+        cpu.memory.write::<u64>(0x2008, 0);
+        cpu.memory.write::<u64>(0x2010, 0);
+        cpu.memory.write::<u64>(0x2018, 0);
+        // bit 33 is bit 1 of the dword after the operand, -1 the top bit of the word before it
+        cpu.registers.set_gpr_value(GPRName::RCX, 33);
+        cpu.registers.set_gpr_value(GPRName::RDX, 0xFFFF);
+        cpu.run(2);
+        assert_eq!(cpu.memory.read::<u32>(0x2014), 0x2);
+        assert_eq!(cpu.memory.read::<u16>(0x200E), 0x8000);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+    }
+}
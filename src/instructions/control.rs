@@ -0,0 +1,213 @@
+// control transfer: JMP, CALL, RET, Jcc, LOOP/JrCXZ and the flag-conditional SETcc/CMOVcc
+// near branches are always 64-bit in 64-bit mode, a 66 prefix is ignored as on Intel hardware
+
+use crate::registers::GPRName;
+use crate::registers::IPName;
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::flags;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::stack;
+
+fn relative_target(cpu: &Cpu, instruction: &Instruction) -> u64 {
+    cpu.registers.get_ip_value(IPName::RIP).wrapping_add(instruction.immediate_sign_extended())
+}
+
+// push the return address and branch, RSP is restored if the target faults
+fn call(cpu: &mut Cpu, target: u64) -> Result<(), Exception> {
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    let rip = cpu.registers.get_ip_value(IPName::RIP);
+    stack::push(cpu, OperandSize::Qword, rip)?;
+    cpu.jump(target).inspect_err(|_| {
+        cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+    })
+}
+
+// E9 Jz / EB Jb
+pub fn jmp_relative(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    cpu.jump(relative_target(cpu, instruction))
+}
+
+// E8 Jz
+pub fn call_relative(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    call(cpu, relative_target(cpu, instruction))
+}
+
+// FF /4
+pub fn jmp_indirect(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let target = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), OperandSize::Qword)?;
+    cpu.jump(target)
+}
+
+// FF /2
+pub fn call_indirect(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let target = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), OperandSize::Qword)?;
+    call(cpu, target)
+}
+
+// C3 / C2 Iw, the immediate is released from the stack after the return address
+pub fn ret(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    let target = stack::pop(cpu, OperandSize::Qword)?;
+    if let Err(exception) = cpu.jump(target) {
+        cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+        return Err(exception);
+    }
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP).wrapping_add(instruction.immediate_value());
+    cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+    Ok(())
+}
+
+// 70+cc Jb / 0F 80+cc Jz
+pub fn jcc(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if flags::condition(&cpu.registers, instruction.opcode & 0xF) {
+        cpu.jump(relative_target(cpu, instruction))
+    } else {
+        Ok(())
+    }
+}
+
+// 0F 90+cc, the ModRM.reg field is ignored
+pub fn setcc(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let value = flags::condition(&cpu.registers, instruction.opcode & 0xF) as u64;
+    let rm = operand::rm_operand(cpu, instruction);
+    operand::write_operand(cpu, instruction, rm, OperandSize::Byte, value)
+}
+
+// 0F 40+cc, the source is read and a 32-bit destination zero-extended even when the condition fails
+pub fn cmovcc(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = instruction.operand_size();
+    let source = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?;
+    let value = if flags::condition(&cpu.registers, instruction.opcode & 0xF) {
+        source
+    } else {
+        operand::read_gpr(cpu, instruction, instruction.reg(), size)
+    };
+    operand::write_gpr(cpu, instruction, instruction.reg(), size, value);
+    Ok(())
+}
+
+// E0 LOOPNE / E1 LOOPE / E2 LOOP, the counter is RCX or ECX by address size and flags are untouched
+pub fn loop_(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = instruction.address_size();
+    let count = operand::read_gpr(cpu, instruction, 1, size).wrapping_sub(1) & size.mask();
+    let zf = cpu.registers.get_flag(Flag::ZF);
+    let taken = count != 0 && match instruction.opcode {
+        0xE0 => !zf,
+        0xE1 => zf,
+        _ => true,
+    };
+    if taken {
+        cpu.jump(relative_target(cpu, instruction))?;
+    }
+    operand::write_gpr(cpu, instruction, 1, size, count);
+    Ok(())
+}
+
+// E3 JRCXZ / JECXZ
+pub fn jrcxz(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if operand::read_gpr(cpu, instruction, 1, instruction.address_size()) == 0 {
+        cpu.jump(relative_target(cpu, instruction))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::IPName;
+    use crate::registers::Flag;
+    use crate::registers::CRName;
+    use crate::registers::CR0_PG;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::Exception;
+    use crate::cpu::tests::cpu_with_code;
+    use crate::cpu::tests::CODE;
+
+    const STACK: u64 = 0x8000;
+
+    #[test]
+    fn call_ret_and_jumps() {
+        // 00 call 0x0A / 05 hlt / 06 int3 x4 / 0A mov eax, 0x1014 / 0F jmp rax / 11 int3 x3 / 14 ret 8
+        let mut cpu = cpu_with_code(&[
+            0xE8, 0x05, 0x00, 0x00, 0x00,
+            0xF4,
+            0xCC, 0xCC, 0xCC, 0xCC,
+            0xB8, 0x14, 0x10, 0x00, 0x00,
+            0xFF, 0xE0,
+            0xCC, 0xCC, 0xCC,
+            0xC2, 0x08, 0x00,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RSP, STACK);
+        assert_eq!(cpu.run(100), StopReason::Halt);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 6);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 8), CODE as u64 + 5);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK + 8);
+    }
+
+    #[test]
+    fn conditions_follow_rflags() {
+        // cmp eax, ebx / jl +2 / int3 / int3 / setg cl / cmovl edx, ebx / cmovg r8d, ebx
+        let mut cpu = cpu_with_code(&[
+            0x39, 0xD8,
+            0x7C, 0x02,
+            0xCC, 0xCC,
+            0x0F, 0x9F, 0xC1,
+            0x0F, 0x4C, 0xD3,
+            0x44, 0x0F, 0x4F, 0xC3,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, (-5i64) as u64);
+        cpu.registers.set_gpr_value(GPRName::RBX, 3);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0xFF);
+        cpu.registers.set_gpr_value(GPRName::R8, 0xFFFFFFFF_00000007);
+        assert_eq!(cpu.run(5), StopReason::StepLimit);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 3);
+        // a false CMOV with a 32-bit destination still zero-extends
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::R8), 7);
+    }
+
+    #[test]
+    fn loop_variants() {
+        // loop -2 (to itself) with RCX = 3, then loope/loopne/jrcxz
+        let mut cpu = cpu_with_code(&[0xE2, 0xFE, 0xE1, 0x00, 0xE3, 0x02, 0xCC, 0xCC, 0xE0, 0xFE, 0xF4]);
+        cpu.registers.set_gpr_value(GPRName::RCX, 3);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 2);
+        // loope with RCX wrapping to all ones and ZF clear falls through
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), u64::MAX);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 8);
+        // loopne falls through with ZF set even though the count is not exhausted
+        cpu.registers.set_gpr_value(GPRName::RCX, 2);
+        cpu.registers.set_flag(Flag::ZF, true);
+        assert_eq!(cpu.run(10), StopReason::Halt);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 1);
+    }
+
+    #[test]
+    fn non_canonical_target_faults_on_the_branch() {
+        // jmp rax under paging
+        let mut cpu = cpu_with_code(&[0xFF, 0xE0]);
+        cpu.memory.write::<u64>(0x10000, 0x11000 | 7);
+        // identity-mapped 1 GiB page
+        cpu.memory.write::<u64>(0x11000, 0x87);
+        cpu.registers.set_cr_value(CRName::CR3, 0x10000);
+        cpu.registers.set_cr_value(CRName::CR0, CR0_PG);
+        cpu.update_paging();
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x0000_8000_0000_0000);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0)));
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64);
+    }
+}
@@ -0,0 +1,170 @@
+// data movement: MOV, MOVZX/MOVSX/MOVSXD, LEA, XCHG and the accumulator sign extensions
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+
+// 88 Eb,Gb / 89 Ev,Gv / 8A Gb,Eb / 8B Gv,Ev
+pub fn mov(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = operand::byte_or_full(instruction);
+    let rm = operand::rm_operand(cpu, instruction);
+    if instruction.opcode & 2 == 0 {
+        let value = operand::read_gpr(cpu, instruction, instruction.reg(), size);
+        operand::write_operand(cpu, instruction, rm, size, value)
+    } else {
+        let value = operand::read_operand(cpu, instruction, rm, size)?;
+        operand::write_gpr(cpu, instruction, instruction.reg(), size, value);
+        Ok(())
+    }
+}
+
+// C6 /0 Eb,Ib / C7 /0 Ev,Iz
+pub fn mov_immediate(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.group_index() != 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let size = operand::byte_or_full(instruction);
+    let rm = operand::rm_operand(cpu, instruction);
+    operand::write_operand(cpu, instruction, rm, size, instruction.immediate_sign_extended())
+}
+
+// B0+r Ib / B8+r Iv
+pub fn mov_register_immediate(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = if instruction.opcode < 0xB8 { OperandSize::Byte } else { instruction.operand_size() };
+    operand::write_gpr(cpu, instruction, instruction.opcode_reg(), size, instruction.immediate_value());
+    Ok(())
+}
+
+// A0 AL,Ob / A1 rAX,Ov / A2 Ob,AL / A3 Ov,rAX
+pub fn mov_offset(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = operand::byte_or_full(instruction);
+    let address = Operand::Memory(operand::linear_address(cpu, instruction, instruction.immediate_value()));
+    if instruction.opcode < 0xA2 {
+        let value = operand::read_operand(cpu, instruction, address, size)?;
+        operand::write_gpr(cpu, instruction, 0, size, value);
+        Ok(())
+    } else {
+        let value = operand::read_gpr(cpu, instruction, 0, size);
+        operand::write_operand(cpu, instruction, address, size, value)
+    }
+}
+
+// 8D
+pub fn lea(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if !instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    let address = operand::effective_address(cpu, instruction);
+    operand::write_gpr(cpu, instruction, instruction.reg(), instruction.operand_size(), address);
+    Ok(())
+}
+
+// 0F B6/B7 MOVZX, 0F BE/BF MOVSX, 63 MOVSXD
+pub fn mov_extend(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (source_size, signed) = match instruction.opcode {
+        0xB6 => (OperandSize::Byte, false),
+        0xB7 => (OperandSize::Word, false),
+        0xBE => (OperandSize::Byte, true),
+        0xBF => (OperandSize::Word, true),
+        _ => (OperandSize::Dword, true),
+    };
+    let value = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), source_size)?;
+    let value = if signed { source_size.sign_extend(value) } else { value };
+    operand::write_gpr(cpu, instruction, instruction.reg(), instruction.operand_size(), value);
+    Ok(())
+}
+
+// 86 Eb,Gb / 87 Ev,Gv, locked implicitly
+pub fn xchg(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = operand::byte_or_full(instruction);
+    let rm = operand::rm_operand(cpu, instruction);
+    let a = operand::read_operand(cpu, instruction, rm, size)?;
+    let b = operand::read_gpr(cpu, instruction, instruction.reg(), size);
+    operand::write_operand(cpu, instruction, rm, size, b)?;
+    operand::write_gpr(cpu, instruction, instruction.reg(), size, a);
+    Ok(())
+}
+
+// 90+r, rAX with a register
+pub fn xchg_accumulator(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = instruction.operand_size();
+    let a = operand::read_gpr(cpu, instruction, 0, size);
+    let b = operand::read_gpr(cpu, instruction, instruction.opcode_reg(), size);
+    operand::write_gpr(cpu, instruction, 0, size, b);
+    operand::write_gpr(cpu, instruction, instruction.opcode_reg(), size, a);
+    Ok(())
+}
+
+// 98 CBW/CWDE/CDQE
+pub fn sign_extend_accumulator(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = instruction.operand_size();
+    let half = match size {
+        OperandSize::Word => OperandSize::Byte,
+        OperandSize::Dword => OperandSize::Word,
+        _ => OperandSize::Dword,
+    };
+    let value = half.sign_extend(operand::read_gpr(cpu, instruction, 0, half));
+    operand::write_gpr(cpu, instruction, 0, size, value);
+    Ok(())
+}
+
+// 99 CWD/CDQ/CQO
+pub fn sign_extend_into_rdx(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = instruction.operand_size();
+    let negative = operand::read_gpr(cpu, instruction, 0, size) & size.sign_bit() != 0;
+    operand::write_gpr(cpu, instruction, 2, size, if negative { u64::MAX } else { 0 });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+
+    use crate::cpu::tests::cpu_with_code;
+    use crate::cpu::tests::CODE;
+
+    #[test]
+    fn mov_lea_and_extensions() {
+        // mov r9, imm64 / mov [r9+8], r9d / movsx rax, byte [r9+8] / movzx ecx, word [r9+8] / lea rdx, [rip+0x10] / mov al, [moffs]
+        let mut cpu = cpu_with_code(&[
+            0x49, 0xB9, 0xF0, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x45, 0x89, 0x49, 0x08,
+            0x49, 0x0F, 0xBE, 0x41, 0x08,
+            0x41, 0x0F, 0xB7, 0x49, 0x08,
+            0x48, 0x8D, 0x15, 0x10, 0x00, 0x00, 0x00,
+            0xA0, 0xF8, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        ]);
+        cpu.run(6);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::R9), 0x20F0);
+        assert_eq!(cpu.memory.read::<u32>(0x20F8), 0x20F0);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0x20F0);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), CODE as u64 + 31 + 0x10);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFFFFFFFF_FFFFFFF0);
+    }
+
+    #[test]
+    fn xchg_and_accumulator_extensions() {
+        // xchg rax, r8 / cdqe / cqo
+        let mut cpu = cpu_with_code(&[0x49, 0x90, 0x48, 0x98, 0x48, 0x99]);
+        cpu.registers.set_gpr_value(GPRName::R8, 0x80000000);
+        cpu.registers.set_gpr_value(GPRName::RAX, 7);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::R8), 7);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFFFFFFFF_80000000);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), u64::MAX);
+    }
+
+    #[test]
+    fn fs_override_uses_fs_base() {
+        // mov rax, fs:[0x28]
+        let mut cpu = cpu_with_code(&[0x64, 0x48, 0x8B, 0x04, 0x25, 0x28, 0x00, 0x00, 0x00]);
+        cpu.memory.write::<u64>(0x5028, 0xC0FFEE);
+        cpu.registers.set_fs_base(0x5000);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xC0FFEE);
+    }
+}
@@ -0,0 +1,539 @@
+// x87 80-bit extended precision in software: a sign, a 15-bit biased exponent and a 64-bit significand with an
+// explicit integer bit
+// results are computed exactly (or with a sticky bit) and rounded once, to the format and rounding mode asked
+// for; exceptions come back as x87 status word bits, with C1 set when an inexact result was rounded up
+
+use std::cmp::Ordering;
+
+use primitive_types::U256 as u256;
+
+use crate::instructions::sse_float::RoundingMode;
+
+pub const INVALID: u16 = 1 << 0;
+pub const DENORMAL: u16 = 1 << 1;
+pub const DIVIDE_BY_ZERO: u16 = 1 << 2;
+pub const OVERFLOW: u16 = 1 << 3;
+pub const UNDERFLOW: u16 = 1 << 4;
+pub const PRECISION: u16 = 1 << 5;
+// C1 of the status word
+pub const ROUNDED_UP: u16 = 1 << 9;
+
+const BIAS: i32 = 16383;
+const MAX_EXPONENT: u16 = 0x7FFF;
+const INTEGER_BIT: u64 = 1 << 63;
+const QUIET_BIT: u64 = 1 << 62;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct F80 {
+    pub sign: bool,
+    pub exponent: u16,
+    pub significand: u64,
+}
+
+// a binary format results are rounded to: the significand precision counting the integer bit, and the exponent bias
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Format {
+    pub precision: u32,
+    pub bias: i32,
+}
+
+pub const SINGLE: Format = Format { precision: 24, bias: 127 };
+pub const DOUBLE: Format = Format { precision: 53, bias: 1023 };
+pub const EXTENDED: Format = Format { precision: 64, bias: BIAS };
+
+impl Format {
+    // the x87 precision control field: the extended exponent range with a 24, 53 or 64-bit significand
+    pub fn precision_control(bits: u16) -> Format {
+        match bits & 3 {
+            0 => Format { precision: 24, bias: BIAS },
+            2 => Format { precision: 53, bias: BIAS },
+            _ => EXTENDED,
+        }
+    }
+
+    fn max_exponent(self) -> i32 {
+        2 * self.bias + 1
+    }
+
+    fn exponent_bits(self) -> u32 {
+        (self.bias + 1).trailing_zeros() + 1
+    }
+
+    fn fraction_bits(self) -> u32 {
+        self.precision - 1
+    }
+}
+
+// a finite nonzero value as significand * 2^(exponent - BIAS - 63), the integer bit set
+#[derive(Debug, Clone, Copy)]
+struct Unpacked {
+    exponent: i32,
+    significand: u64,
+}
+
+enum Class {
+    Zero,
+    Finite(Unpacked),
+    Infinity,
+    NaN,
+    // unnormals, pseudo-NaNs and pseudo-infinities, invalid as operands
+    Unsupported,
+}
+
+impl F80 {
+    // the QNaN floating-point indefinite
+    pub const INDEFINITE: F80 = F80 { sign: true, exponent: MAX_EXPONENT, significand: INTEGER_BIT | QUIET_BIT };
+    pub const ONE: F80 = F80 { sign: false, exponent: BIAS as u16, significand: INTEGER_BIT };
+
+    pub fn zero(sign: bool) -> F80 {
+        F80 { sign, exponent: 0, significand: 0 }
+    }
+
+    pub fn infinity(sign: bool) -> F80 {
+        F80 { sign, exponent: MAX_EXPONENT, significand: INTEGER_BIT }
+    }
+
+    pub fn from_raw(raw: u128) -> F80 {
+        F80 { sign: raw >> 79 & 1 == 1, exponent: (raw >> 64) as u16 & MAX_EXPONENT, significand: raw as u64 }
+    }
+
+    pub fn raw(self) -> u128 {
+        (self.sign as u128) << 79 | (self.exponent as u128) << 64 | self.significand as u128
+    }
+
+    fn class(self) -> Class {
+        match (self.exponent, self.significand) {
+            (0, 0) => Class::Zero,
+            // denormals, and pseudo-denormals with the integer bit set, share the exponent of 1
+            (0, significand) => {
+                let shift = significand.leading_zeros();
+                Class::Finite(Unpacked { exponent: 1 - shift as i32, significand: significand << shift })
+            }
+            (_, significand) if significand & INTEGER_BIT == 0 => Class::Unsupported,
+            (MAX_EXPONENT, INTEGER_BIT) => Class::Infinity,
+            (MAX_EXPONENT, _) => Class::NaN,
+            (exponent, significand) => Class::Finite(Unpacked { exponent: exponent as i32, significand }),
+        }
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.exponent == 0 && self.significand == 0
+    }
+
+    pub fn is_nan(self) -> bool {
+        matches!(self.class(), Class::NaN)
+    }
+
+    pub fn is_signalling(self) -> bool {
+        self.is_nan() && self.significand & QUIET_BIT == 0
+    }
+
+    pub fn is_denormal(self) -> bool {
+        self.exponent == 0 && self.significand != 0
+    }
+
+    // a finite value in the normal range, the x87 "valid" tag
+    pub fn is_normal(self) -> bool {
+        self.exponent != 0 && self.exponent != MAX_EXPONENT && self.significand & INTEGER_BIT != 0
+    }
+
+    pub fn negate(self) -> F80 {
+        F80 { sign: !self.sign, ..self }
+    }
+
+    pub fn abs(self) -> F80 {
+        F80 { sign: false, ..self }
+    }
+
+    pub fn from_integer(value: i64) -> F80 {
+        if value == 0 {
+            return F80::zero(false);
+        }
+        let magnitude = value.unsigned_abs();
+        let shift = magnitude.leading_zeros();
+        F80 { sign: value < 0, exponent: (BIAS + 63) as u16 - shift as u16, significand: magnitude << shift }
+    }
+}
+
+fn shift_right_sticky(value: u128, shift: u32) -> u128 {
+    if shift >= 128 {
+        (value != 0) as u128
+    } else {
+        value >> shift | ((value & ((1 << shift) - 1)) != 0) as u128
+    }
+}
+
+// a rounded result in `format`: the biased exponent (zero for denormals) and the significand with its integer bit
+// at bit 63
+struct Rounded {
+    sign: bool,
+    exponent: u16,
+    significand: u64,
+    flags: u16,
+}
+
+impl Rounded {
+    fn extended(self) -> (F80, u16) {
+        (F80 { sign: self.sign, exponent: self.exponent, significand: self.significand }, self.flags)
+    }
+}
+
+// round sign * significand * 2^(exponent - BIAS - 127) into `format`; tininess is detected after rounding, so a
+// result that rounds up to the smallest normal does not underflow
+fn round(sign: bool, exponent: i32, significand: u128, format: Format, mode: RoundingMode) -> Rounded {
+    let shift = significand.leading_zeros();
+    let significand = significand << shift;
+    let mut exponent = exponent - shift as i32 - BIAS + format.bias;
+    let subnormal = exponent < 1;
+    let significand = if subnormal { shift_right_sticky(significand, (1 - exponent) as u32) } else { significand };
+    exponent = exponent.max(1);
+
+    let dropped = 128 - format.precision;
+    let lost = significand & ((1 << dropped) - 1);
+    let half = 1 << (dropped - 1);
+    let mut kept = significand >> dropped;
+    let up = match mode {
+        RoundingMode::Nearest => lost > half || (lost == half && kept & 1 == 1),
+        RoundingMode::Down => lost != 0 && sign,
+        RoundingMode::Up => lost != 0 && !sign,
+        RoundingMode::TowardZero => false,
+    };
+    kept += up as u128;
+    if kept >> format.precision != 0 {
+        kept >>= 1;
+        exponent += 1;
+    }
+
+    if exponent >= format.max_exponent() {
+        // infinity, or the largest finite value when the rounding mode points back toward zero
+        let to_infinity = match mode {
+            RoundingMode::Nearest => true,
+            RoundingMode::Down => sign,
+            RoundingMode::Up => !sign,
+            RoundingMode::TowardZero => false,
+        };
+        let flags = OVERFLOW | PRECISION | if to_infinity { ROUNDED_UP } else { 0 };
+        return if to_infinity {
+            Rounded { sign, exponent: format.max_exponent() as u16, significand: INTEGER_BIT, flags }
+        } else {
+            Rounded { sign, exponent: format.max_exponent() as u16 - 1, significand: u64::MAX << (64 - format.precision), flags }
+        };
+    }
+
+    let significand = (kept as u64) << (64 - format.precision);
+    let tiny = significand & INTEGER_BIT == 0;
+    let mut flags = 0;
+    if lost != 0 {
+        flags |= PRECISION | if up { ROUNDED_UP } else { 0 } | if tiny { UNDERFLOW } else { 0 };
+    }
+    let exponent = if significand & INTEGER_BIT == 0 { 0 } else { exponent as u16 };
+    Rounded { sign, exponent, significand, flags }
+}
+
+// a value already in the extended format, rounded to the precision control setting
+fn round_finite(sign: bool, value: Unpacked, format: Format, mode: RoundingMode) -> (F80, u16) {
+    round(sign, value.exponent, (value.significand as u128) << 64, format, mode).extended()
+}
+
+// the NaN result of an operation with a NaN or unsupported operand: the quieted NaN with the larger significand,
+// invalid for signalling NaNs; the indefinite for unsupported encodings
+fn propagate(operands: &[F80]) -> Option<(F80, u16)> {
+    if operands.iter().any(|operand| matches!(operand.class(), Class::Unsupported)) {
+        return Some((F80::INDEFINITE, INVALID));
+    }
+    let flags = if operands.iter().any(|operand| operand.is_signalling()) { INVALID } else { 0 };
+    let nan = operands.iter().copied().filter(|operand| operand.is_nan())
+        .reduce(|a, b| if b.significand | QUIET_BIT > a.significand | QUIET_BIT { b } else { a })?;
+    Some((F80 { significand: nan.significand | QUIET_BIT, ..nan }, flags))
+}
+
+fn denormal_flags(operands: &[F80]) -> u16 {
+    if operands.iter().any(|operand| operand.is_denormal()) { DENORMAL } else { 0 }
+}
+
+// a + b, or a - b
+pub fn add(a: F80, b: F80, subtract: bool, format: Format, mode: RoundingMode) -> (F80, u16) {
+    if let Some(result) = propagate(&[a, b]) {
+        return result;
+    }
+    let flags = denormal_flags(&[a, b]);
+    let b = if subtract { b.negate() } else { b };
+    let (result, rounding) = match (a.class(), b.class()) {
+        (Class::Infinity, Class::Infinity) if a.sign != b.sign => return (F80::INDEFINITE, INVALID),
+        (Class::Infinity, _) => (a, 0),
+        (_, Class::Infinity) => (b, 0),
+        // an exact zero sum is +0 unless both zeros are negative, or -0 when rounding down
+        (Class::Zero, Class::Zero) => (F80::zero(if a.sign == b.sign { a.sign } else { mode == RoundingMode::Down }), 0),
+        (Class::Zero, Class::Finite(y)) => round_finite(b.sign, y, format, mode),
+        (Class::Finite(x), Class::Zero) => round_finite(a.sign, x, format, mode),
+        (Class::Finite(x), Class::Finite(y)) => {
+            // the larger magnitude first, the other aligned to it with 63 guard bits and a sticky bit
+            let ((x, x_sign), (y, y_sign)) = if (x.exponent, x.significand) >= (y.exponent, y.significand) {
+                ((x, a.sign), (y, b.sign))
+            } else {
+                ((y, b.sign), (x, a.sign))
+            };
+            let large = (x.significand as u128) << 63;
+            let small = shift_right_sticky((y.significand as u128) << 63, (x.exponent - y.exponent) as u32);
+            if x_sign == y_sign {
+                round(x_sign, x.exponent + 1, large + small, format, mode).extended()
+            } else if large == small {
+                (F80::zero(mode == RoundingMode::Down), 0)
+            } else {
+                round(x_sign, x.exponent + 1, large - small, format, mode).extended()
+            }
+        }
+        _ => return (F80::INDEFINITE, INVALID),
+    };
+    (result, flags | rounding)
+}
+
+pub fn mul(a: F80, b: F80, format: Format, mode: RoundingMode) -> (F80, u16) {
+    if let Some(result) = propagate(&[a, b]) {
+        return result;
+    }
+    let flags = denormal_flags(&[a, b]);
+    let sign = a.sign != b.sign;
+    let (result, rounding) = match (a.class(), b.class()) {
+        (Class::Infinity, Class::Zero) | (Class::Zero, Class::Infinity) => return (F80::INDEFINITE, INVALID),
+        (Class::Infinity, _) | (_, Class::Infinity) => (F80::infinity(sign), 0),
+        (Class::Zero, _) | (_, Class::Zero) => (F80::zero(sign), 0),
+        (Class::Finite(x), Class::Finite(y)) => {
+            let product = x.significand as u128 * y.significand as u128;
+            round(sign, x.exponent + y.exponent - BIAS + 1, product, format, mode).extended()
+        }
+        _ => return (F80::INDEFINITE, INVALID),
+    };
+    (result, flags | rounding)
+}
+
+pub fn div(a: F80, b: F80, format: Format, mode: RoundingMode) -> (F80, u16) {
+    if let Some(result) = propagate(&[a, b]) {
+        return result;
+    }
+    let flags = denormal_flags(&[a, b]);
+    let sign = a.sign != b.sign;
+    let (result, rounding) = match (a.class(), b.class()) {
+        (Class::Infinity, Class::Infinity) | (Class::Zero, Class::Zero) => return (F80::INDEFINITE, INVALID),
+        (Class::Infinity, _) => (F80::infinity(sign), 0),
+        (_, Class::Infinity) | (Class::Zero, _) => (F80::zero(sign), 0),
+        (Class::Finite(_), Class::Zero) => (F80::infinity(sign), DIVIDE_BY_ZERO),
+        (Class::Finite(x), Class::Finite(y)) => {
+            // 64 quotient bits and 62 more from a second step of long division, the rest as a sticky bit
+            let divisor = y.significand as u128;
+            let numerator = (x.significand as u128) << 64;
+            let (high, remainder) = (numerator / divisor, numerator % divisor);
+            let (low, remainder) = ((remainder << 64) / divisor, (remainder << 64) % divisor);
+            let quotient = high << 62 | low >> 2 | (low & 3 != 0 || remainder != 0) as u128;
+            round(sign, x.exponent - y.exponent + BIAS + 1, quotient, format, mode).extended()
+        }
+        _ => return (F80::INDEFINITE, INVALID),
+    };
+    (result, flags | rounding)
+}
+
+pub fn sqrt(a: F80, format: Format, mode: RoundingMode) -> (F80, u16) {
+    if let Some(result) = propagate(&[a]) {
+        return result;
+    }
+    let flags = denormal_flags(&[a]);
+    match a.class() {
+        // the square root of -0 is -0
+        Class::Zero => (a, 0),
+        _ if a.sign => (F80::INDEFINITE, INVALID),
+        Class::Infinity => (a, 0),
+        Class::Finite(x) => {
+            // an even power of two, and enough significand bits below the point for a 96-bit root
+            let power = x.exponent - BIAS - 63;
+            let shift = 128 + power.rem_euclid(2);
+            let square = u256::from(x.significand) << shift as usize;
+            let root = square.integer_sqrt();
+            let sticky = (root * root != square) as u128;
+            let (result, rounding) = round(false, (power - shift) / 2 + BIAS + 127, root.low_u128() | sticky, format, mode).extended();
+            (result, flags | rounding)
+        }
+        _ => (F80::INDEFINITE, INVALID),
+    }
+}
+
+// None when unordered; invalid for signalling NaNs and unsupported encodings, and with `signal_quiet` for quiet NaNs
+pub fn compare(a: F80, b: F80, signal_quiet: bool) -> (Option<Ordering>, u16) {
+    if let Some((_, flags)) = propagate(&[a, b]) {
+        return (None, if signal_quiet { INVALID } else { flags });
+    }
+    let magnitude = |value: F80| match value.class() {
+        Class::Zero => (i32::MIN, 0),
+        Class::Finite(unpacked) => (unpacked.exponent, unpacked.significand),
+        _ => (i32::MAX, 0),
+    };
+    // zeros compare equal whatever their signs
+    let negative = |value: F80| value.sign && !value.is_zero();
+    let ordering = match (negative(a), negative(b)) {
+        (false, true) => Ordering::Greater,
+        (true, false) => Ordering::Less,
+        (false, false) => magnitude(a).cmp(&magnitude(b)),
+        (true, true) => magnitude(b).cmp(&magnitude(a)),
+    };
+    (Some(ordering), denormal_flags(&[a, b]))
+}
+
+// a signed integer of `bits` bits, rounded as `mode` says; invalid and the integer indefinite (only the sign bit
+// set) for NaNs, infinities and values out of range
+pub fn to_integer(value: F80, bits: u32, mode: RoundingMode) -> (u64, u16) {
+    let indefinite = (1u64 << (bits - 1), INVALID);
+    let unpacked = match value.class() {
+        Class::Zero => return (0, 0),
+        Class::Finite(unpacked) => unpacked,
+        _ => return indefinite,
+    };
+    // the integer part is significand >> shift; at 65 and beyond what remains is below one half
+    let shift = BIAS + 63 - unpacked.exponent;
+    if shift < 0 {
+        return indefinite;
+    }
+    let significand = unpacked.significand as u128;
+    let (integer, fraction, half) = match shift {
+        0 => (significand, 0, 1),
+        1..=64 => (significand >> shift, significand & ((1 << shift) - 1), 1 << (shift - 1)),
+        _ => (0, 1, 2),
+    };
+    let inexact = fraction != 0;
+    let up = match mode {
+        RoundingMode::Nearest => fraction > half || (fraction == half && integer & 1 == 1),
+        RoundingMode::Down => inexact && value.sign,
+        RoundingMode::Up => inexact && !value.sign,
+        RoundingMode::TowardZero => false,
+    };
+    let magnitude = integer + up as u128;
+    let limit = 1u128 << (bits - 1);
+    if magnitude > limit || (magnitude == limit && !value.sign) {
+        return indefinite;
+    }
+    let result = if value.sign { (magnitude as u64).wrapping_neg() } else { magnitude as u64 };
+    let mask = if bits == 64 { u64::MAX } else { (1 << bits) - 1 };
+    let flags = if inexact { PRECISION | if up { ROUNDED_UP } else { 0 } } else { 0 };
+    (result & mask, flags)
+}
+
+// a single or double value widened exactly; denormals flag DE and signalling NaNs are quieted with IE
+pub fn from_binary(bits: u64, format: Format) -> (F80, u16) {
+    let fraction_bits = format.fraction_bits();
+    let max_exponent = format.max_exponent() as u64;
+    let sign = bits >> (fraction_bits + format.exponent_bits()) & 1 == 1;
+    let exponent = bits >> fraction_bits & max_exponent;
+    let fraction = bits & ((1 << fraction_bits) - 1);
+    let significand = fraction << (63 - fraction_bits);
+    match exponent {
+        0 if fraction == 0 => (F80::zero(sign), 0),
+        0 => {
+            let shift = significand.leading_zeros();
+            let exponent = (1 - format.bias + BIAS) as u16 - shift as u16;
+            (F80 { sign, exponent, significand: significand << shift }, DENORMAL)
+        }
+        _ if exponent == max_exponent && fraction == 0 => (F80::infinity(sign), 0),
+        _ if exponent == max_exponent => {
+            let nan = F80 { sign, exponent: MAX_EXPONENT, significand: INTEGER_BIT | significand };
+            let flags = if nan.is_signalling() { INVALID } else { 0 };
+            (F80 { significand: nan.significand | QUIET_BIT, ..nan }, flags)
+        }
+        _ => (F80 { sign, exponent: (exponent as i32 - format.bias + BIAS) as u16, significand: INTEGER_BIT | significand }, 0),
+    }
+}
+
+// rounded into a single or double, NaNs keep the top of their payload and are quieted
+pub fn to_binary(value: F80, format: Format, mode: RoundingMode) -> (u64, u16) {
+    let fraction_bits = format.fraction_bits();
+    let max_exponent = format.max_exponent() as u64;
+    let pack = |sign: bool, exponent: u64, significand: u64| {
+        (sign as u64) << (fraction_bits + format.exponent_bits()) | exponent << fraction_bits | (significand << 1) >> (64 - fraction_bits)
+    };
+    match value.class() {
+        Class::Zero => (pack(value.sign, 0, 0), 0),
+        Class::Infinity => (pack(value.sign, max_exponent, 0), 0),
+        Class::NaN => {
+            let flags = if value.is_signalling() { INVALID } else { 0 };
+            (pack(value.sign, max_exponent, value.significand | QUIET_BIT), flags)
+        }
+        Class::Unsupported => (pack(true, max_exponent, QUIET_BIT), INVALID),
+        Class::Finite(unpacked) => {
+            let rounded = round(value.sign, unpacked.exponent, (unpacked.significand as u128) << 64, format, mode);
+            let flags = rounded.flags | denormal_flags(&[value]);
+            (pack(rounded.sign, rounded.exponent as u64, rounded.significand), flags)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn double(value: f64) -> F80 {
+        from_binary(value.to_bits(), DOUBLE).0
+    }
+
+    fn to_f64(value: F80) -> f64 {
+        f64::from_bits(to_binary(value, DOUBLE, RoundingMode::Nearest).0)
+    }
+
+    #[test]
+    fn correctly_rounded_results() {
+        let nearest = RoundingMode::Nearest;
+        let three = F80::from_integer(3);
+        // 1/3 = 0.AAAA... rounds up in its last place, and down when truncating
+        assert_eq!(div(F80::ONE, three, EXTENDED, nearest), (F80::from_raw(0x3FFD_AAAA_AAAA_AAAA_AAAB), PRECISION | ROUNDED_UP));
+        assert_eq!(div(F80::ONE, three, EXTENDED, RoundingMode::TowardZero).0, F80::from_raw(0x3FFD_AAAA_AAAA_AAAA_AAAA));
+        assert_eq!(sqrt(F80::from_integer(2), EXTENDED, nearest).0, F80::from_raw(0x3FFF_B504_F333_F9DE_6484));
+        assert_eq!(sqrt(F80::from_integer(16), EXTENDED, nearest), (F80::from_integer(4), 0));
+        // 1 + 2^-63 needs all 64 bits, precision control at 53 bits loses the low one
+        let tiny = F80 { sign: false, exponent: (BIAS - 63) as u16, significand: INTEGER_BIT };
+        assert_eq!(add(F80::ONE, tiny, false, EXTENDED, nearest), (F80::from_raw(0x3FFF_8000_0000_0000_0001), 0));
+        assert_eq!(add(F80::ONE, tiny, false, Format::precision_control(2), nearest), (F80::ONE, PRECISION));
+        assert_eq!(to_f64(mul(double(1.5), double(-2.25), EXTENDED, nearest).0), -3.375);
+        assert_eq!(to_f64(add(double(0.1), double(0.2), true, EXTENDED, nearest).0), 0.1 - 0.2);
+    }
+
+    #[test]
+    fn special_values() {
+        let nearest = RoundingMode::Nearest;
+        let (infinity, flags) = div(F80::ONE, F80::zero(true), EXTENDED, nearest);
+        assert_eq!((infinity, flags), (F80::infinity(true), DIVIDE_BY_ZERO));
+        assert_eq!(add(infinity, infinity.negate(), false, EXTENDED, nearest), (F80::INDEFINITE, INVALID));
+        assert_eq!(sqrt(F80::ONE.negate(), EXTENDED, nearest), (F80::INDEFINITE, INVALID));
+        // the quieted NaN with the larger significand wins, signalling ones flag IE
+        let snan = F80 { sign: false, exponent: MAX_EXPONENT, significand: INTEGER_BIT | 5 };
+        let qnan = F80 { sign: true, exponent: MAX_EXPONENT, significand: INTEGER_BIT | QUIET_BIT | 1 };
+        assert_eq!(mul(snan, qnan, EXTENDED, nearest), (F80 { significand: INTEGER_BIT | QUIET_BIT | 5, ..snan }, INVALID));
+        assert_eq!(compare(qnan, F80::ONE, false), (None, 0));
+        assert_eq!(compare(qnan, F80::ONE, true), (None, INVALID));
+        assert_eq!(compare(F80::zero(true), F80::zero(false), false), (Some(Ordering::Equal), 0));
+        // overflow in the double format: infinity, or the largest double rounding toward zero
+        let huge = mul(double(f64::MAX), F80::from_integer(2), EXTENDED, nearest).0;
+        assert_eq!(to_binary(huge, DOUBLE, nearest), (f64::INFINITY.to_bits(), OVERFLOW | PRECISION | ROUNDED_UP));
+        assert_eq!(to_binary(huge, DOUBLE, RoundingMode::TowardZero).0, f64::MAX.to_bits());
+        // a double denormal widens exactly to a normal extended value and flags DE, and narrows back unchanged
+        let (denormal, flags) = from_binary(3, DOUBLE);
+        assert_eq!(flags, DENORMAL);
+        assert!(denormal.is_normal());
+        assert_eq!(to_binary(denormal, DOUBLE, nearest), (3, 0));
+    }
+
+    #[test]
+    fn integer_conversions() {
+        let two_and_a_half = double(2.5);
+        assert_eq!(to_integer(two_and_a_half, 32, RoundingMode::Nearest), (2, PRECISION));
+        assert_eq!(to_integer(two_and_a_half, 32, RoundingMode::Up), (3, PRECISION | ROUNDED_UP));
+        assert_eq!(to_integer(two_and_a_half.negate(), 16, RoundingMode::Down), (0xFFFD, PRECISION | ROUNDED_UP));
+        assert_eq!(to_integer(double(40000.0), 16, RoundingMode::Nearest), (0x8000, INVALID));
+        assert_eq!(to_integer(F80::from_integer(i64::MIN), 64, RoundingMode::Nearest), (1 << 63, 0));
+        assert_eq!(to_integer(double(0.25), 64, RoundingMode::Nearest), (0, PRECISION));
+        assert_eq!(to_integer(F80::INDEFINITE, 32, RoundingMode::Nearest), (0x8000_0000, INVALID));
+    }
+
+    #[test]
+    fn tininess_after_rounding() {
+        // just below the smallest normal single: rounding up reaches it without underflow, truncating stays denormal
+        let below = double(f32::MIN_POSITIVE as f64 * (1.0 - 2f64.powi(-30)));
+        assert_eq!(to_binary(below, SINGLE, RoundingMode::Nearest), (0x0080_0000, PRECISION | ROUNDED_UP));
+        assert_eq!(to_binary(below, SINGLE, RoundingMode::TowardZero), (0x007F_FFFF, PRECISION | UNDERFLOW));
+    }
+}
@@ -0,0 +1,217 @@
+// multiply and divide: MUL, IMUL in its one, two and three operand forms, DIV and IDIV
+// the one operand forms use the accumulator pair AH:AL, DX:AX, EDX:EAX or RDX:RAX
+
+use crate::registers::GPRName;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::flags;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::alu::check_lock;
+
+// (low, high) halves of the double-width accumulator
+fn accumulator(size: OperandSize) -> (GPRName, GPRName) {
+    match size {
+        OperandSize::Byte => (GPRName::AL, GPRName::AH),
+        OperandSize::Word => (GPRName::AX, GPRName::DX),
+        OperandSize::Dword => (GPRName::EAX, GPRName::EDX),
+        OperandSize::Qword => (GPRName::RAX, GPRName::RDX),
+    }
+}
+
+// CF and OF report a product that does not fit the low half, SF/ZF/PF come from the low half and AF
+// is cleared as on Bochs
+fn product_flags(cpu: &mut Cpu, size: OperandSize, low: u64, overflow: bool) {
+    let flags = flags::ArithFlags { cf: overflow, of: overflow, ..flags::result_flags(size, low) };
+    flags.apply(&mut cpu.registers);
+}
+
+fn signed_product(size: OperandSize, a: u64, b: u64) -> (u64, u64, bool) {
+    let product = size.sign_extend(a) as i64 as i128 * size.sign_extend(b) as i64 as i128;
+    let low = product as u64 & size.mask();
+    let high = (product >> size.bits()) as u64 & size.mask();
+    (low, high, product != size.sign_extend(low) as i64 as i128)
+}
+
+fn source(cpu: &Cpu, instruction: &Instruction, size: OperandSize) -> Result<u64, Exception> {
+    operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)
+}
+
+// F6/F7 /4 MUL and /5 IMUL
+pub fn multiply(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = operand::byte_or_full(instruction);
+    let (low_name, high_name) = accumulator(size);
+    let a = cpu.registers.get_gpr_value(low_name);
+    let b = source(cpu, instruction, size)?;
+    let (low, high, overflow) = if instruction.group_index() == 4 {
+        let product = (a & size.mask()) as u128 * (b & size.mask()) as u128;
+        let high = (product >> size.bits()) as u64;
+        (product as u64 & size.mask(), high, high != 0)
+    } else {
+        signed_product(size, a, b)
+    };
+    cpu.registers.set_gpr_value(low_name, low);
+    cpu.registers.set_gpr_value(high_name, high);
+    product_flags(cpu, size, low, overflow);
+    Ok(())
+}
+
+// 0F AF IMUL Gv,Ev / 69 IMUL Gv,Ev,Iz / 6B IMUL Gv,Ev,Ib, the upper half of the product is discarded
+pub fn imul(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = instruction.operand_size();
+    let b = source(cpu, instruction, size)?;
+    let a = match instruction.opcode {
+        0xAF => operand::read_gpr(cpu, instruction, instruction.reg(), size),
+        _ => instruction.immediate_sign_extended(),
+    };
+    let (low, _, overflow) = signed_product(size, a, b);
+    operand::write_gpr(cpu, instruction, instruction.reg(), size, low);
+    product_flags(cpu, size, low, overflow);
+    Ok(())
+}
+
+// F6/F7 /6 DIV and /7 IDIV, a zero divisor or a quotient too wide for the low half raise #DE
+// before anything is written; the flags are left unchanged as on Bochs
+pub fn divide(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = operand::byte_or_full(instruction);
+    let (low_name, high_name) = accumulator(size);
+    let dividend = ((cpu.registers.get_gpr_value(high_name) as u128) << size.bits())
+        | cpu.registers.get_gpr_value(low_name) as u128;
+    let divisor = source(cpu, instruction, size)? & size.mask();
+    if divisor == 0 {
+        return Err(Exception::DivideError);
+    }
+
+    let (quotient, remainder) = if instruction.group_index() == 6 {
+        let quotient = dividend / divisor as u128;
+        if quotient > size.mask() as u128 {
+            return Err(Exception::DivideError);
+        }
+        (quotient as u64, (dividend % divisor as u128) as u64)
+    } else {
+        let shift = 128 - 2 * size.bits();
+        let dividend = ((dividend << shift) as i128) >> shift;
+        let divisor = size.sign_extend(divisor) as i64 as i128;
+        // i128::MIN / -1 is the only host overflow, it is also out of range for the guest
+        let quotient = dividend.checked_div(divisor).ok_or(Exception::DivideError)?;
+        let limit = size.sign_bit() as i128;
+        if quotient < -limit || quotient >= limit {
+            return Err(Exception::DivideError);
+        }
+        (quotient as u64, (dividend % divisor) as u64)
+    };
+    cpu.registers.set_gpr_value(low_name, quotient);
+    cpu.registers.set_gpr_value(high_name, remainder);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::IPName;
+    use crate::registers::Flag;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::Exception;
+    use crate::cpu::tests::cpu_with_code;
+    use crate::cpu::tests::CODE;
+
+    #[test]
+    fn multiply_forms() {
+        // mul bl / imul rcx / imul edx, esi / imul r8w, si, -3 / imul eax, dword [0x2000], 0x10
+        let mut cpu = cpu_with_code(&[
+            0xF6, 0xE3,
+            0x48, 0xF7, 0xE9,
+            0x0F, 0xAF, 0xD6,
+            0x66, 0x44, 0x6B, 0xC6, 0xFD,
+            0x69, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x1234_0080);
+        cpu.registers.set_gpr_value(GPRName::RBX, 0x04);
+        cpu.run(1);
+        // AX = 0x80 * 4, the rest of RAX is kept
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x1234_0200);
+        assert!(cpu.registers.get_flag(Flag::CF) && cpu.registers.get_flag(Flag::OF));
+
+        cpu.registers.set_gpr_value(GPRName::RAX, (-2i64) as u64);
+        cpu.registers.set_gpr_value(GPRName::RCX, 3);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), (-6i64) as u64);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), u64::MAX);
+        assert!(!cpu.registers.get_flag(Flag::CF) && !cpu.registers.get_flag(Flag::OF));
+
+        cpu.registers.set_gpr_value(GPRName::RDX, 0xFFFF_FFFF_0001_0000);
+        cpu.registers.set_gpr_value(GPRName::RSI, 0x1_0000);
+        cpu.run(1);
+        // the truncated product is zero-extended and overflows
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 0);
+        assert!(cpu.registers.get_flag(Flag::CF) && cpu.registers.get_flag(Flag::ZF));
+
+        cpu.registers.set_gpr_value(GPRName::R8, 0xAAAA_AAAA_AAAA_AAAA);
+        cpu.registers.set_gpr_value(GPRName::RSI, 7);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::R8), 0xAAAA_AAAA_AAAA_FFEB);
+        assert!(!cpu.registers.get_flag(Flag::OF) && cpu.registers.get_flag(Flag::SF));
+
+        cpu.memory.write::<u32>(0x2000, 0x0800_0000);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x8000_0000);
+        assert!(cpu.registers.get_flag(Flag::OF));
+    }
+
+    #[test]
+    fn divide_forms() {
+        // div cl / idiv rbx / div word [0x2000]
+        let mut cpu = cpu_with_code(&[0xF6, 0xF1, 0x48, 0xF7, 0xFB, 0x66, 0xF7, 0x34, 0x25, 0x00, 0x20, 0x00, 0x00]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 1000);
+        cpu.registers.set_gpr_value(GPRName::RCX, 7);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::AL), 142);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::AH), 6);
+
+        cpu.registers.set_gpr_value(GPRName::RAX, (-7i64) as u64);
+        cpu.registers.set_gpr_value(GPRName::RDX, u64::MAX);
+        cpu.registers.set_gpr_value(GPRName::RBX, 2);
+        cpu.run(1);
+        // truncates toward zero, the remainder takes the sign of the dividend
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), (-3i64) as u64);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), u64::MAX);
+
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x5678);
+        cpu.registers.set_gpr_value(GPRName::RDX, 0x1234);
+        cpu.memory.write::<u16>(0x2000, 0x4000);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::AX), 0x48D1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::DX), 0x1678);
+    }
+
+    #[test]
+    fn divide_errors_fault_without_side_effects() {
+        // div ecx / idiv rcx / idiv bl
+        for (code, rax, rdx, rcx) in [
+            (&[0xF7, 0xF1][..], 1, 0, 0),
+            (&[0xF7, 0xF1][..], 0, 1, 1),
+            (&[0x48, 0xF7, 0xF9][..], 0, 1, 1),
+            (&[0x48, 0xF7, 0xF9][..], 0, 0x8000_0000_0000_0000, u64::MAX),
+            (&[0xF6, 0xFB][..], 0xFF80, 0, 0),
+        ] {
+            let mut cpu = cpu_with_code(code);
+            cpu.registers.set_gpr_value(GPRName::RAX, rax);
+            cpu.registers.set_gpr_value(GPRName::RDX, rdx);
+            cpu.registers.set_gpr_value(GPRName::RCX, rcx);
+            // bl = -1: -128 / -1 does not fit a signed byte
+            cpu.registers.set_gpr_value(GPRName::RBX, 0xFF);
+            assert_eq!(cpu.run(1), StopReason::Fault(Exception::DivideError));
+            assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64);
+            assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), rax);
+            assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), rdx);
+        }
+    }
+}
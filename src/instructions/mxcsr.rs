@@ -0,0 +1,168 @@
+// MXCSR: exception flags in bits 0-5, DAZ in bit 6, the matching exception masks in bits 7-12, rounding control
+// in bits 13-14 and FTZ in bit 15, bits 31:16 are reserved
+// an instruction computes under an Environment taken from MXCSR and commits the exceptions its lanes raised at
+// the end: the flags are sticky, and any unmasked one raises #XM without writing a result
+
+use std::cell::Cell;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+use crate::instructions::sse_float::RoundingMode;
+
+pub const INVALID: u32 = 1 << 0;
+pub const DENORMAL: u32 = 1 << 1;
+pub const DIVIDE_BY_ZERO: u32 = 1 << 2;
+pub const OVERFLOW: u32 = 1 << 3;
+pub const UNDERFLOW: u32 = 1 << 4;
+pub const PRECISION: u32 = 1 << 5;
+
+const FLAGS: u32 = 0x3F;
+const DAZ: u32 = 1 << 6;
+const MASK_SHIFT: u32 = 7;
+const ROUNDING_SHIFT: u32 = 13;
+const FTZ: u32 = 1 << 15;
+// the bits LDMXCSR may set, also what FXSAVE reports as MXCSR_MASK
+pub const WRITABLE: u32 = 0xFFFF;
+// detected from the operands, before a result exists
+const PRE_COMPUTATION: u32 = INVALID | DENORMAL | DIVIDE_BY_ZERO;
+
+pub struct Environment {
+    pub mode: RoundingMode,
+    // denormal sources read as zero
+    pub daz: bool,
+    // tiny results become zero when underflow is masked
+    pub ftz: bool,
+    masks: u32,
+    // EVEX embedded rounding implies SAE: every exception is masked and no flag is recorded
+    suppress: bool,
+    raised: Cell<u32>,
+}
+
+impl Environment {
+    pub fn new(mxcsr: u32) -> Environment {
+        Environment {
+            mode: RoundingMode::from_bits((mxcsr >> ROUNDING_SHIFT) as u8),
+            daz: mxcsr & DAZ != 0,
+            ftz: mxcsr & FTZ != 0,
+            masks: mxcsr >> MASK_SHIFT & FLAGS,
+            suppress: false,
+            raised: Cell::new(0),
+        }
+    }
+
+    pub fn from_cpu(cpu: &Cpu) -> Environment {
+        Environment::new(cpu.registers.get_mxcsr())
+    }
+
+    pub fn suppressing(mode: RoundingMode, mxcsr: u32) -> Environment {
+        Environment { mode, suppress: true, ..Environment::new(mxcsr) }
+    }
+
+    pub fn masked(&self, flag: u32) -> bool {
+        self.suppress || self.masks & flag == flag
+    }
+
+    pub fn raise(&self, flags: u32) {
+        self.raised.set(self.raised.get() | flags);
+    }
+
+    // record the raised flags in MXCSR, #XM when one of them is unmasked; an unmasked invalid, denormal or
+    // divide-by-zero stops the instruction before the flags of its results are known
+    pub fn commit(&self, cpu: &mut Cpu) -> Result<(), Exception> {
+        if self.suppress {
+            return Ok(());
+        }
+        let mut raised = self.raised.get();
+        let unmasked = raised & !self.masks;
+        if unmasked & PRE_COMPUTATION != 0 {
+            raised &= PRE_COMPUTATION;
+        }
+        cpu.registers.set_mxcsr(cpu.registers.get_mxcsr() | raised);
+        if unmasked != 0 {
+            return Err(Exception::SIMDFloatingPoint);
+        }
+        Ok(())
+    }
+}
+
+// LDMXCSR/STMXCSR take a 32-bit memory operand and no mandatory prefix, the VEX forms need L0 and no vvvv
+fn memory_operand(cpu: &Cpu, instruction: &Instruction) -> Result<u64, Exception> {
+    let vex_invalid = instruction.vex.is_some_and(|vex| vex.l) || instruction.vvvv() != 0;
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Memory(address) if instruction.mandatory_prefix() == MandatoryPrefix::None && !vex_invalid => Ok(address),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// 0F AE /2 LDMXCSR m32 and VEX.L0 0F AE /2 VLDMXCSR, #GP(0) on reserved bits
+pub fn ldmxcsr(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let address = memory_operand(cpu, instruction)?;
+    let value = cpu.read_memory::<u32>(address)?;
+    if value & !WRITABLE != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    cpu.registers.set_mxcsr(value);
+    Ok(())
+}
+
+// 0F AE /3 STMXCSR m32 and VEX.L0 0F AE /3 VSTMXCSR
+pub fn stmxcsr(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let address = memory_operand(cpu, instruction)?;
+    let value = cpu.registers.get_mxcsr();
+    cpu.write_memory::<u32>(address, value)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn load_and_store() {
+        // stmxcsr [0x2000] / ldmxcsr [0x2004] / vstmxcsr [0x2008] / ldmxcsr [0x200C]
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0xAE, 0x1C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x0F, 0xAE, 0x14, 0x25, 0x04, 0x20, 0x00, 0x00,
+            0xC5, 0xF8, 0xAE, 0x1C, 0x25, 0x08, 0x20, 0x00, 0x00,
+            0x0F, 0xAE, 0x14, 0x25, 0x0C, 0x20, 0x00, 0x00,
+        ]);
+        cpu.memory.write::<u32>(0x2004, 0x5FC1);
+        cpu.memory.write::<u32>(0x200C, 0x1_0000);
+        cpu.run(3);
+        assert_eq!(cpu.memory.read::<u32>(0x2000), 0x1F80);
+        assert_eq!(cpu.memory.read::<u32>(0x2008), 0x5FC1);
+        let environment = Environment::from_cpu(&cpu);
+        assert_eq!(environment.mode, RoundingMode::Up);
+        assert!(environment.daz && !environment.ftz);
+        // a reserved bit faults and leaves MXCSR alone
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0)));
+        assert_eq!(cpu.registers.get_mxcsr(), 0x5FC1);
+    }
+
+    #[test]
+    fn commit_sets_sticky_flags_and_raises_unmasked() {
+        let mut cpu = cpu_with_code(&[]);
+        let environment = Environment::from_cpu(&cpu);
+        environment.raise(PRECISION);
+        assert_eq!(environment.commit(&mut cpu), Ok(()));
+        // with divide-by-zero unmasked, only the pre-computation flags of a faulting instruction are recorded
+        cpu.registers.set_mxcsr(0x1F80 & !(DIVIDE_BY_ZERO << MASK_SHIFT));
+        let environment = Environment::from_cpu(&cpu);
+        environment.raise(DIVIDE_BY_ZERO | OVERFLOW);
+        assert_eq!(environment.commit(&mut cpu), Err(Exception::SIMDFloatingPoint));
+        assert_eq!(cpu.registers.get_mxcsr() & FLAGS, DIVIDE_BY_ZERO);
+        // SAE records nothing
+        let environment = Environment::suppressing(RoundingMode::Down, cpu.registers.get_mxcsr());
+        environment.raise(DIVIDE_BY_ZERO);
+        assert!(environment.masked(DIVIDE_BY_ZERO));
+        assert_eq!(environment.commit(&mut cpu), Ok(()));
+    }
+}
@@ -0,0 +1,192 @@
+// register and memory operand access shared by the instruction families
+
+use crate::registers::GPRName;
+use crate::registers::IPName;
+use crate::registers::SegmentName;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::SegmentPrefix;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    // GPR encoding index 0..15
+    Register(u8),
+    // linear address
+    Memory(u64),
+}
+
+// GPRs in encoding order (RAX, RCX, RDX, RBX, RSP, RBP, RSI, RDI, R8..R15)
+const GPR64: [GPRName; 16] = [
+    GPRName::RAX, GPRName::RCX, GPRName::RDX, GPRName::RBX, GPRName::RSP, GPRName::RBP, GPRName::RSI, GPRName::RDI,
+    GPRName::R8, GPRName::R9, GPRName::R10, GPRName::R11, GPRName::R12, GPRName::R13, GPRName::R14, GPRName::R15,
+];
+const GPR32: [GPRName; 16] = [
+    GPRName::EAX, GPRName::ECX, GPRName::EDX, GPRName::EBX, GPRName::ESP, GPRName::EBP, GPRName::ESI, GPRName::EDI,
+    GPRName::R8D, GPRName::R9D, GPRName::R10D, GPRName::R11D, GPRName::R12D, GPRName::R13D, GPRName::R14D, GPRName::R15D,
+];
+const GPR16: [GPRName; 16] = [
+    GPRName::AX, GPRName::CX, GPRName::DX, GPRName::BX, GPRName::SP, GPRName::BP, GPRName::SI, GPRName::DI,
+    GPRName::R8W, GPRName::R9W, GPRName::R10W, GPRName::R11W, GPRName::R12W, GPRName::R13W, GPRName::R14W, GPRName::R15W,
+];
+const GPR8: [GPRName; 16] = [
+    GPRName::AL, GPRName::CL, GPRName::DL, GPRName::BL, GPRName::SPL, GPRName::BPL, GPRName::SIL, GPRName::DIL,
+    GPRName::R8B, GPRName::R9B, GPRName::R10B, GPRName::R11B, GPRName::R12B, GPRName::R13B, GPRName::R14B, GPRName::R15B,
+];
+// without a REX prefix byte registers 4..7 are the legacy high bytes
+const GPR8_LEGACY: [GPRName; 8] = [
+    GPRName::AL, GPRName::CL, GPRName::DL, GPRName::BL, GPRName::AH, GPRName::CH, GPRName::DH, GPRName::BH,
+];
+
+pub fn gpr_name(instruction: &Instruction, index: u8, size: OperandSize) -> GPRName {
+    let index = index as usize & 15;
+    match size {
+        OperandSize::Byte if instruction.rex.is_none() && index < 8 => GPR8_LEGACY[index],
+        OperandSize::Byte => GPR8[index],
+        OperandSize::Word => GPR16[index],
+        OperandSize::Dword => GPR32[index],
+        OperandSize::Qword => GPR64[index],
+    }
+}
+
+pub fn read_gpr(cpu: &Cpu, instruction: &Instruction, index: u8, size: OperandSize) -> u64 {
+    cpu.registers.get_gpr_value(gpr_name(instruction, index, size))
+}
+
+// 32-bit writes zero-extend, 8 and 16-bit writes merge
+pub fn write_gpr(cpu: &mut Cpu, instruction: &Instruction, index: u8, size: OperandSize, value: u64) {
+    cpu.registers.set_gpr_value(gpr_name(instruction, index, size), value);
+}
+
+// full 64-bit register by encoding index, for address and stack computations
+pub fn read_gpr64(cpu: &Cpu, index: u8) -> u64 {
+    cpu.registers.get_gpr_value(GPR64[index as usize & 15])
+}
+
+pub fn write_gpr64(cpu: &mut Cpu, index: u8, value: u64) {
+    cpu.registers.set_gpr_value(GPR64[index as usize & 15], value);
+}
+
+// the segment a memory access goes through: the override prefix, else SS for an address based on RSP or RBP and DS
+// for everything else
+pub fn segment(instruction: &Instruction) -> SegmentName {
+    if let Some(prefix) = instruction.prefixes.segment {
+        return match prefix {
+            SegmentPrefix::ES => SegmentName::ES,
+            SegmentPrefix::CS => SegmentName::CS,
+            SegmentPrefix::SS => SegmentName::SS,
+            SegmentPrefix::DS => SegmentName::DS,
+            SegmentPrefix::FS => SegmentName::FS,
+            SegmentPrefix::GS => SegmentName::GS,
+        };
+    }
+    let modrm = match instruction.modrm {
+        Some(modrm) if modrm.mode != 3 => modrm,
+        _ => return SegmentName::DS,
+    };
+    let base = match instruction.sib {
+        Some(sib) if sib.base == 5 && modrm.mode == 0 => return SegmentName::DS,
+        Some(sib) => sib.base,
+        None if instruction.is_rip_relative() => return SegmentName::DS,
+        None => modrm.rm,
+    };
+    // R12 and R13 are not stack registers
+    match base | (instruction.rex.is_some_and(|rex| rex.b) as u8) << 3 {
+        4 | 5 => SegmentName::SS,
+        _ => SegmentName::DS,
+    }
+}
+
+// 64-bit mode treats the bases of CS, DS, ES and SS as zero, only FS and GS keep theirs
+pub fn segment_base(cpu: &Cpu, segment: SegmentName) -> u64 {
+    match segment {
+        SegmentName::FS | SegmentName::GS => cpu.registers.get_segment(segment).cache.base,
+        _ => 0,
+    }
+}
+
+// linear address of a segment offset, e.g. a moffs or an effective address
+pub fn linear_address(cpu: &Cpu, instruction: &Instruction, offset: u64) -> u64 {
+    segment_base(cpu, segment(instruction)).wrapping_add(offset)
+}
+
+// ModRM/SIB address, RIP already points at the next instruction
+pub fn effective_address(cpu: &Cpu, instruction: &Instruction) -> u64 {
+    let modrm = match instruction.modrm {
+        Some(modrm) => modrm,
+        None => return 0,
+    };
+    let rex = instruction.rex.unwrap_or_default();
+    let displacement = instruction.displacement as u64;
+    let address = if instruction.is_rip_relative() {
+        cpu.registers.get_ip_value(IPName::RIP).wrapping_add(displacement)
+    } else if let Some(sib) = instruction.sib {
+        let base = if sib.base == 5 && modrm.mode == 0 {
+            0
+        } else {
+            read_gpr64(cpu, sib.base | (rex.b as u8) << 3)
+        };
+        let index = sib.index | (rex.x as u8) << 3;
+        // index 4 (RSP) means no index, R12 is a valid index
+        let scaled = if index == 4 { 0 } else { read_gpr64(cpu, index) << sib.scale };
+        base.wrapping_add(scaled).wrapping_add(displacement)
+    } else {
+        read_gpr64(cpu, instruction.rm()).wrapping_add(displacement)
+    };
+    address & instruction.address_size().mask()
+}
+
+pub fn rm_operand(cpu: &Cpu, instruction: &Instruction) -> Operand {
+    if instruction.has_memory_operand() {
+        Operand::Memory(linear_address(cpu, instruction, effective_address(cpu, instruction)))
+    } else {
+        Operand::Register(instruction.rm())
+    }
+}
+
+pub fn read_memory_sized(cpu: &Cpu, address: u64, size: OperandSize) -> Result<u64, Exception> {
+    Ok(match size {
+        OperandSize::Byte => cpu.read_memory::<u8>(address)? as u64,
+        OperandSize::Word => cpu.read_memory::<u16>(address)? as u64,
+        OperandSize::Dword => cpu.read_memory::<u32>(address)? as u64,
+        OperandSize::Qword => cpu.read_memory::<u64>(address)?,
+    })
+}
+
+pub fn write_memory_sized(cpu: &mut Cpu, address: u64, size: OperandSize, value: u64) -> Result<(), Exception> {
+    match size {
+        OperandSize::Byte => cpu.write_memory::<u8>(address, value as u8),
+        OperandSize::Word => cpu.write_memory::<u16>(address, value as u16),
+        OperandSize::Dword => cpu.write_memory::<u32>(address, value as u32),
+        OperandSize::Qword => cpu.write_memory::<u64>(address, value),
+    }
+}
+
+pub fn read_operand(cpu: &Cpu, instruction: &Instruction, operand: Operand, size: OperandSize) -> Result<u64, Exception> {
+    match operand {
+        Operand::Register(index) => Ok(read_gpr(cpu, instruction, index, size)),
+        Operand::Memory(address) => read_memory_sized(cpu, address, size),
+    }
+}
+
+pub fn write_operand(cpu: &mut Cpu, instruction: &Instruction, operand: Operand, size: OperandSize, value: u64) -> Result<(), Exception> {
+    match operand {
+        Operand::Register(index) => {
+            write_gpr(cpu, instruction, index, size, value);
+            Ok(())
+        }
+        Operand::Memory(address) => write_memory_sized(cpu, address, size, value),
+    }
+}
+
+// operand size of an instruction whose low opcode bit selects byte or full size
+pub fn byte_or_full(instruction: &Instruction) -> OperandSize {
+    if instruction.opcode & 1 == 0 {
+        OperandSize::Byte
+    } else {
+        instruction.operand_size()
+    }
+}
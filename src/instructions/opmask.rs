@@ -0,0 +1,145 @@
+// AVX-512 opmask instructions, all VEX-encoded: KMOV, the K logic operations and KORTEST
+// the B/W/D/Q width comes from pp and VEX.W; results are zero-extended to the full 64-bit register
+
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+
+// W (none), Q (none, W1), B (66) and D (66, W1) forms of 41..4x, 90, 91 and 98
+fn width(instruction: &Instruction) -> Result<OperandSize, Exception> {
+    let w = instruction.rex.is_some_and(|rex| rex.w);
+    match (instruction.mandatory_prefix(), w) {
+        (MandatoryPrefix::None, false) => Ok(OperandSize::Word),
+        (MandatoryPrefix::None, true) => Ok(OperandSize::Qword),
+        (MandatoryPrefix::P66, false) => Ok(OperandSize::Byte),
+        (MandatoryPrefix::P66, true) => Ok(OperandSize::Dword),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+fn vector_length(instruction: &Instruction) -> bool {
+    instruction.vex.is_some_and(|vex| vex.l)
+}
+
+fn read_k(cpu: &Cpu, index: u8) -> u64 {
+    cpu.registers.get_opmask(index as usize & 7)
+}
+
+fn write_k(cpu: &mut Cpu, index: u8, size: OperandSize, value: u64) {
+    cpu.registers.set_opmask(index as usize & 7, value & size.mask());
+}
+
+// VEX.L1 0F 41 KAND, 42 KANDN, 45 KOR, 46 KXNOR, 47 KXOR k, k, k and VEX.L0 0F 44 KNOT k, k
+pub fn logic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = width(instruction)?;
+    if instruction.has_memory_operand() || vector_length(instruction) != (instruction.opcode != 0x44) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let (a, b) = (read_k(cpu, instruction.vvvv()), read_k(cpu, instruction.rm()));
+    let result = match instruction.opcode {
+        0x41 => a & b,
+        0x42 => !a & b,
+        0x44 if instruction.vvvv() == 0 => !b,
+        0x45 => a | b,
+        0x46 => !(a ^ b),
+        0x47 => a ^ b,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    write_k(cpu, instruction.group_index(), size, result);
+    Ok(())
+}
+
+// VEX.L0 0F 90 KMOV k, k/m; 91 KMOV m, k; 92 KMOV k, r32/r64; 93 KMOV r32/r64, k
+pub fn kmov(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if vector_length(instruction) || instruction.vvvv() != 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    // the general purpose forms spell the width differently: none is W, 66 is B, F2 is D or (W1) Q
+    let gpr_width = || match (instruction.mandatory_prefix(), instruction.rex.is_some_and(|rex| rex.w)) {
+        (MandatoryPrefix::None, false) => Ok(OperandSize::Word),
+        (MandatoryPrefix::P66, false) => Ok(OperandSize::Byte),
+        (MandatoryPrefix::PF2, false) => Ok(OperandSize::Dword),
+        (MandatoryPrefix::PF2, true) => Ok(OperandSize::Qword),
+        _ => Err(Exception::InvalidOpcode),
+    };
+    match (instruction.opcode, operand::rm_operand(cpu, instruction)) {
+        (0x90, Operand::Register(index)) => {
+            let value = read_k(cpu, index);
+            write_k(cpu, instruction.group_index(), width(instruction)?, value);
+        }
+        (0x90, Operand::Memory(address)) => {
+            let size = width(instruction)?;
+            let value = operand::read_memory_sized(cpu, address, size)?;
+            write_k(cpu, instruction.group_index(), size, value);
+        }
+        (0x91, Operand::Memory(address)) => {
+            let size = width(instruction)?;
+            operand::write_memory_sized(cpu, address, size, read_k(cpu, instruction.group_index()) & size.mask())?;
+        }
+        (0x92, Operand::Register(index)) => {
+            let value = operand::read_gpr64(cpu, index);
+            write_k(cpu, instruction.group_index(), gpr_width()?, value);
+        }
+        (0x93, Operand::Register(index)) => {
+            let value = read_k(cpu, index) & gpr_width()?.mask();
+            operand::write_gpr64(cpu, instruction.reg(), value);
+        }
+        _ => return Err(Exception::InvalidOpcode),
+    }
+    Ok(())
+}
+
+// VEX.L0 0F 98 KORTEST k, k: ZF when the OR is all zeros, CF when it is all ones, the other flags cleared
+pub fn kortest(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = width(instruction)?;
+    if instruction.has_memory_operand() || vector_length(instruction) || instruction.vvvv() != 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let value = (read_k(cpu, instruction.group_index()) | read_k(cpu, instruction.rm())) & size.mask();
+    cpu.registers.set_flag(Flag::ZF, value == 0);
+    cpu.registers.set_flag(Flag::CF, value == size.mask());
+    for flag in [Flag::OF, Flag::SF, Flag::AF, Flag::PF] {
+        cpu.registers.set_flag(flag, false);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn moves_logic_and_kortest() {
+        // kmovw k1, eax / kmovb k2, [0x2000] / kandnw k3, k1, k2 / knotb k4, k3 / kortestw k3, k4 / kmovq rcx, k4
+        let mut cpu = cpu_with_code(&[
+            0xC5, 0xF8, 0x92, 0xC8,
+            0xC5, 0xF9, 0x90, 0x14, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xC5, 0xF4, 0x42, 0xDA,
+            0xC5, 0xF9, 0x44, 0xE3,
+            0xC5, 0xF8, 0x98, 0xDC,
+            0xC4, 0xE1, 0xFB, 0x93, 0xCC,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xDEAD_00F0);
+        cpu.memory.write::<u16>(0x2000, 0x1FF);
+        cpu.run(5);
+        assert_eq!(cpu.registers.get_opmask(1), 0x00F0);
+        assert_eq!(cpu.registers.get_opmask(2), 0xFF);
+        assert_eq!(cpu.registers.get_opmask(3), 0x0F);
+        assert_eq!(cpu.registers.get_opmask(4), 0xF0);
+        // 0x0F | 0xF0 in a word is neither zero nor all ones
+        assert!(!cpu.registers.get_flag(Flag::ZF) && !cpu.registers.get_flag(Flag::CF));
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0xF0);
+    }
+}
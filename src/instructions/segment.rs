@@ -0,0 +1,432 @@
+// segment state 64-bit code can reach: selectors loaded through MOV Sreg, LLDT and LTR with their descriptors
+// cached, the FS/GS bases through RDFSBASE/WRFSBASE, SWAPGS and the descriptor table registers through
+// LGDT/LIDT/SGDT/SIDT
+
+use crate::registers::CRName;
+use crate::registers::CR4_FSGSBASE;
+use crate::registers::DescriptorTable;
+use crate::registers::Segment;
+use crate::registers::SegmentCache;
+use crate::registers::SegmentName;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+
+// SegmentCache attribute bits
+const ACCESSED: u16 = 1 << 0;
+// writable for data, readable for code
+const READ_WRITE: u16 = 1 << 1;
+const CONFORMING: u16 = 1 << 2;
+const CODE: u16 = 1 << 3;
+const NON_SYSTEM: u16 = 1 << 4;
+const PRESENT: u16 = 1 << 7;
+const GRANULARITY: u16 = 1 << 15;
+const SYSTEM_TYPE: u16 = 0xF;
+const LDT: u16 = 0x2;
+const AVAILABLE_TSS: u16 = 0x9;
+const BUSY: u16 = 1 << 1;
+
+// the Sreg encoding of the ModRM reg field
+const SEGMENTS: [SegmentName; 6] = [SegmentName::ES, SegmentName::CS, SegmentName::SS, SegmentName::DS, SegmentName::FS, SegmentName::GS];
+
+fn is_null(selector: u16) -> bool {
+    selector & !3 == 0
+}
+
+// selector faults carry the selector without its RPL as the error code
+fn selector_fault(selector: u16) -> Exception {
+    Exception::GeneralProtection((selector & !3) as u32)
+}
+
+// linear address of the descriptor a selector picks in the GDT or the LDT, #GP(selector) past the table limit
+fn descriptor_address(cpu: &Cpu, selector: u16, size: u64) -> Result<u64, Exception> {
+    let (base, limit) = if selector & 4 == 0 {
+        let gdtr = cpu.registers.get_gdtr();
+        (gdtr.base, gdtr.limit as u64)
+    } else {
+        let ldtr = cpu.registers.get_ldtr();
+        if ldtr.cache.attributes & PRESENT == 0 {
+            return Err(selector_fault(selector));
+        }
+        (ldtr.cache.base, ldtr.cache.limit as u64)
+    };
+    let offset = (selector & !7) as u64;
+    if offset + size - 1 > limit {
+        return Err(selector_fault(selector));
+    }
+    Ok(base.wrapping_add(offset))
+}
+
+// the hidden part of a segment register from the low 8 bytes of a descriptor
+fn decode_descriptor(descriptor: u64) -> SegmentCache {
+    let attributes = (descriptor >> 40 & 0xFF) as u16 | ((descriptor >> 52 & 0xF) as u16) << 12;
+    let limit = (descriptor & 0xFFFF) as u32 | ((descriptor >> 48 & 0xF) as u32) << 16;
+    SegmentCache {
+        base: descriptor >> 16 & 0xFF_FFFF | (descriptor >> 56) << 24,
+        limit: if attributes & GRANULARITY != 0 { limit << 12 | 0xFFF } else { limit },
+        attributes,
+    }
+}
+
+// MOV Sreg: CS cannot be loaded, SS takes a writable data segment at CPL and the rest data or readable code
+// segments the CPL and RPL may access
+pub fn load_segment(cpu: &mut Cpu, name: SegmentName, selector: u16) -> Result<(), Exception> {
+    let cpl = cpu.cpl();
+    let rpl = (selector & 3) as u8;
+    let mut segment = cpu.registers.get_segment(name);
+    segment.selector = selector;
+    if is_null(selector) {
+        // 64-bit mode accepts null data selectors, and a null SS outside CPL 3; they leave the segment unusable
+        if name == SegmentName::SS && (cpl == 3 || rpl != cpl) {
+            return Err(Exception::GeneralProtection(0));
+        }
+        segment.cache.limit = 0;
+        segment.cache.attributes = 0;
+        cpu.registers.set_segment(name, segment);
+        return Ok(());
+    }
+    let address = descriptor_address(cpu, selector, 8)?;
+    let cache = decode_descriptor(cpu.read_system::<u64>(address)?);
+    let attributes = cache.attributes;
+    let dpl = (attributes >> 5 & 3) as u8;
+    let code = attributes & CODE != 0;
+    let valid = attributes & NON_SYSTEM != 0 && if name == SegmentName::SS {
+        !code && attributes & READ_WRITE != 0 && rpl == cpl && dpl == cpl
+    } else {
+        (!code || attributes & READ_WRITE != 0) && (code && attributes & CONFORMING != 0 || dpl >= cpl.max(rpl))
+    };
+    if !valid {
+        return Err(selector_fault(selector));
+    }
+    if attributes & PRESENT == 0 {
+        let error_code = (selector & !3) as u32;
+        return Err(if name == SegmentName::SS { Exception::StackFault(error_code) } else { Exception::SegmentNotPresent(error_code) });
+    }
+    // the processor marks a descriptor accessed the first time a segment register loads it
+    if attributes & ACCESSED == 0 {
+        cpu.write_system::<u8>(address.wrapping_add(5), (attributes | ACCESSED) as u8)?;
+    }
+    segment.cache = SegmentCache { attributes: attributes | ACCESSED, ..cache };
+    cpu.registers.set_segment(name, segment);
+    Ok(())
+}
+
+// the 16-byte GDT descriptor an LLDT or LTR selector picks, checked for its system type and presence
+fn load_system_descriptor(cpu: &Cpu, selector: u16, expected: u16) -> Result<(u64, SegmentCache), Exception> {
+    if selector & 4 != 0 {
+        return Err(selector_fault(selector));
+    }
+    let address = descriptor_address(cpu, selector, 16)?;
+    let mut cache = decode_descriptor(cpu.read_system::<u64>(address)?);
+    if cache.attributes & NON_SYSTEM != 0 || cache.attributes & SYSTEM_TYPE != expected {
+        return Err(selector_fault(selector));
+    }
+    if cache.attributes & PRESENT == 0 {
+        return Err(Exception::SegmentNotPresent((selector & !3) as u32));
+    }
+    cache.base |= (cpu.read_system::<u32>(address.wrapping_add(8))? as u64) << 32;
+    Ok((address, cache))
+}
+
+// SLDT, STR and MOV r/m,Sreg: a register takes the zero-extended selector at the operand size, memory 16 bits
+fn store_selector(cpu: &mut Cpu, instruction: &Instruction, selector: u16) -> Result<(), Exception> {
+    let destination = operand::rm_operand(cpu, instruction);
+    let size = match destination {
+        Operand::Register(_) => instruction.operand_size(),
+        Operand::Memory(_) => OperandSize::Word,
+    };
+    operand::write_operand(cpu, instruction, destination, size, selector as u64)
+}
+
+// 8C Ev,Sw / 8E Sw,Ew
+pub fn mov_segment(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let name = *SEGMENTS.get(instruction.group_index() as usize).ok_or(Exception::InvalidOpcode)?;
+    if instruction.opcode == 0x8C {
+        return store_selector(cpu, instruction, cpu.registers.get_segment(name).selector);
+    }
+    if name == SegmentName::CS {
+        return Err(Exception::InvalidOpcode);
+    }
+    let source = operand::rm_operand(cpu, instruction);
+    let selector = operand::read_operand(cpu, instruction, source, OperandSize::Word)? as u16;
+    load_segment(cpu, name, selector)
+}
+
+// 0F 00 /0 SLDT, /1 STR, /2 LLDT, /3 LTR; loading is CPL 0 only and LTR marks the TSS descriptor busy
+pub fn group6(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let group = instruction.group_index();
+    match group {
+        0 => return store_selector(cpu, instruction, cpu.registers.get_ldtr().selector),
+        1 => return store_selector(cpu, instruction, cpu.registers.get_tr().selector),
+        2 | 3 => {}
+        _ => return Err(Exception::InvalidOpcode),
+    }
+    if cpu.cpl() != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    let source = operand::rm_operand(cpu, instruction);
+    let selector = operand::read_operand(cpu, instruction, source, OperandSize::Word)? as u16;
+    if group == 2 {
+        // a null selector leaves the LDT unusable
+        let cache = if is_null(selector) { SegmentCache::default() } else { load_system_descriptor(cpu, selector, LDT)?.1 };
+        cpu.registers.set_ldtr(Segment { selector, cache });
+        return Ok(());
+    }
+    if is_null(selector) {
+        return Err(Exception::GeneralProtection(0));
+    }
+    let (address, mut cache) = load_system_descriptor(cpu, selector, AVAILABLE_TSS)?;
+    cache.attributes |= BUSY;
+    cpu.write_system::<u8>(address.wrapping_add(5), cache.attributes as u8)?;
+    cpu.registers.set_tr(Segment { selector, cache });
+    Ok(())
+}
+
+// F3 0F AE /0 RDFSBASE, /1 RDGSBASE, /2 WRFSBASE, /3 WRGSBASE, register forms only and gated by CR4.FSGSBASE
+pub fn fsgsbase(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let group = instruction.group_index();
+    if group > 3 || cpu.registers.get_cr_value(CRName::CR4) & CR4_FSGSBASE == 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let register = match operand::rm_operand(cpu, instruction) {
+        Operand::Register(register) => register,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let size = if instruction.rex.is_some_and(|rex| rex.w) { OperandSize::Qword } else { OperandSize::Dword };
+    let segment = if group & 1 == 0 { SegmentName::FS } else { SegmentName::GS };
+    let mut state = cpu.registers.get_segment(segment);
+    if group < 2 {
+        operand::write_gpr(cpu, instruction, register, size, state.cache.base);
+    } else {
+        let base = operand::read_gpr(cpu, instruction, register, size);
+        cpu.check_canonical(base)?;
+        state.cache.base = base;
+        cpu.registers.set_segment(segment, state);
+    }
+    Ok(())
+}
+
+// 0F 01 F8 SWAPGS, exchanges GS.base with KernelGSBase at CPL 0
+pub fn swapgs(cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    if cpu.cpl() != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    let gs_base = cpu.registers.get_gs_base();
+    cpu.registers.set_gs_base(cpu.registers.get_kernel_gs_base());
+    cpu.registers.set_kernel_gs_base(gs_base);
+    Ok(())
+}
+
+// the m16&64 operand of the descriptor table instructions: the limit followed by the base
+fn table_operand(cpu: &Cpu, instruction: &Instruction) -> Result<u64, Exception> {
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Memory(address) => Ok(address),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+fn read_table(cpu: &Cpu, instruction: &Instruction) -> Result<DescriptorTable, Exception> {
+    if cpu.cpl() != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    let address = table_operand(cpu, instruction)?;
+    let limit = cpu.read_memory::<u16>(address)?;
+    let base = cpu.read_memory::<u64>(address.wrapping_add(2))?;
+    cpu.check_canonical(base)?;
+    Ok(DescriptorTable { base, limit })
+}
+
+fn write_table(cpu: &mut Cpu, instruction: &Instruction, table: DescriptorTable) -> Result<(), Exception> {
+    let address = table_operand(cpu, instruction)?;
+    cpu.write_memory::<u16>(address, table.limit)?;
+    cpu.write_memory::<u64>(address.wrapping_add(2), table.base)?;
+    Ok(())
+}
+
+// 0F 01 /0 SGDT m, /1 SIDT m, /2 LGDT m, /3 LIDT m and the register form 0F 01 F8 SWAPGS
+pub fn group7(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    match instruction.group_index() {
+        0 => write_table(cpu, instruction, cpu.registers.get_gdtr()),
+        1 => write_table(cpu, instruction, cpu.registers.get_idtr()),
+        2 => {
+            let table = read_table(cpu, instruction)?;
+            cpu.registers.set_gdtr(table);
+            Ok(())
+        }
+        3 => {
+            let table = read_table(cpu, instruction)?;
+            cpu.registers.set_idtr(table);
+            Ok(())
+        }
+        7 if !instruction.has_memory_operand() && instruction.modrm.is_some_and(|modrm| modrm.rm == 0) => swapgs(cpu, instruction),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+    use crate::registers::CR0_PG;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    const GDT: u64 = 0x4000;
+    const LDT_BASE: u64 = 0x5000;
+
+    // null, flat code and data, a DPL 3 data segment based at 0x12345678, a not-present data segment, an LDT at
+    // 0x5000 and a 64-bit TSS above 4 GiB; the LDT holds one data segment based at 0xABCD
+    fn cpu_with_gdt(code: &[u8]) -> Cpu {
+        let mut cpu = cpu_with_code(code);
+        let descriptors = [
+            0,
+            0x00AF_9B00_0000_FFFF,
+            0x00CF_9300_0000_FFFF,
+            0x12CF_F234_5678_FFFF,
+            0x00CF_1300_0000_FFFF,
+            0x0000_8200_5000_0017, 0,
+            0x0000_8900_6000_0067, 1,
+        ];
+        for (index, descriptor) in descriptors.iter().enumerate() {
+            cpu.memory.write::<u64>(GDT as usize + index * 8, *descriptor);
+        }
+        cpu.memory.write::<u64>(LDT_BASE as usize, 0x00CF_9300_ABCD_FFFF);
+        cpu.registers.set_gdtr(DescriptorTable { base: GDT, limit: descriptors.len() as u16 * 8 - 1 });
+        cpu
+    }
+
+    #[test]
+    fn mov_segment_loads_descriptor_caches() {
+        // mov eax, 0x18 / mov fs, eax / mov ebx, fs / mov cs, eax
+        let mut cpu = cpu_with_gdt(&[0xB8, 0x18, 0x00, 0x00, 0x00, 0x8E, 0xE0, 0x8C, 0xE3, 0x8E, 0xC8]);
+        cpu.registers.set_gpr_value(GPRName::RBX, u64::MAX);
+        cpu.run(3);
+        let fs = cpu.registers.get_segment(SegmentName::FS);
+        assert_eq!(fs, Segment { selector: 0x18, cache: SegmentCache { base: 0x1234_5678, limit: 0xFFFF_FFFF, attributes: 0xC0F3 } });
+        assert_eq!(cpu.memory.read::<u8>(GDT as usize + 0x18 + 5), 0xF3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0x18);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+
+        assert_eq!(load_segment(&mut cpu, SegmentName::DS, 0x20), Err(Exception::SegmentNotPresent(0x20)));
+        assert_eq!(load_segment(&mut cpu, SegmentName::DS, 0x4B), Err(Exception::GeneralProtection(0x48)));
+        assert_eq!(load_segment(&mut cpu, SegmentName::SS, 0x18), Err(Exception::GeneralProtection(0x18)));
+        assert_eq!(load_segment(&mut cpu, SegmentName::DS, 0x05), Err(Exception::GeneralProtection(0x04)));
+        // null selectors leave the segment unusable but keep the FS/GS base
+        assert_eq!(load_segment(&mut cpu, SegmentName::FS, 0), Ok(()));
+        assert_eq!(cpu.registers.get_segment(SegmentName::FS).cache, SegmentCache { base: 0x1234_5678, limit: 0, attributes: 0 });
+        assert_eq!(load_segment(&mut cpu, SegmentName::SS, 0), Ok(()));
+    }
+
+    #[test]
+    fn load_and_store_ldtr_and_tr() {
+        // mov eax, 0x28 / lldt ax / mov eax, 0x38 / ltr ax / sldt ebx / str [0x2100] / ltr ax
+        let mut cpu = cpu_with_gdt(&[
+            0xB8, 0x28, 0x00, 0x00, 0x00,
+            0x0F, 0x00, 0xD0,
+            0xB8, 0x38, 0x00, 0x00, 0x00,
+            0x0F, 0x00, 0xD8,
+            0x0F, 0x00, 0xC3,
+            0x0F, 0x00, 0x0C, 0x25, 0x00, 0x21, 0x00, 0x00,
+            0x0F, 0x00, 0xD8,
+        ]);
+        cpu.run(6);
+        let ldtr = cpu.registers.get_ldtr();
+        assert_eq!((ldtr.selector, ldtr.cache.base, ldtr.cache.limit), (0x28, LDT_BASE, 0x17));
+        let tr = cpu.registers.get_tr();
+        assert_eq!((tr.selector, tr.cache.base, tr.cache.attributes & SYSTEM_TYPE), (0x38, 0x1_0000_6000, 0xB));
+        assert_eq!(cpu.memory.read::<u8>(GDT as usize + 0x38 + 5), 0x8B);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0x28);
+        assert_eq!(cpu.memory.read::<u16>(0x2100), 0x38);
+        // the TSS is busy now
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0x38)));
+
+        // selectors with TI set go through the LDT
+        assert_eq!(load_segment(&mut cpu, SegmentName::GS, 0x04), Ok(()));
+        assert_eq!(cpu.registers.get_gs_base(), 0xABCD);
+        assert_eq!(load_segment(&mut cpu, SegmentName::GS, 0x0C), Err(Exception::GeneralProtection(0x0C)));
+    }
+
+    #[test]
+    fn cpl_3_loads_read_and_mark_supervisor_descriptors() {
+        let mut cpu = cpu_with_gdt(&[]);
+        cpu.memory.write::<u64>(0x10000, 0x11000 | 7);
+        // identity-mapped 1 GiB page, supervisor only
+        cpu.memory.write::<u64>(0x11000, 0x83);
+        cpu.registers.set_cr_value(CRName::CR3, 0x10000);
+        cpu.registers.set_cr_value(CRName::CR0, CR0_PG);
+        cpu.update_paging();
+        let mut cs = cpu.registers.get_segment(SegmentName::CS);
+        cs.selector |= 3;
+        cpu.registers.set_segment(SegmentName::CS, cs);
+        assert_eq!(load_segment(&mut cpu, SegmentName::DS, 0x1B), Ok(()));
+        assert_eq!(cpu.registers.get_segment(SegmentName::DS).cache.base, 0x1234_5678);
+        assert_eq!(cpu.memory.read::<u8>(GDT as usize + 0x18 + 5), 0xF3);
+    }
+
+    #[test]
+    fn read_and_write_fs_gs_base() {
+        // wrfsbase rax / rdgsbase ebx / mov rcx, fs:[0x10] / wrgsbase rdx
+        let mut cpu = cpu_with_code(&[
+            0xF3, 0x48, 0x0F, 0xAE, 0xD0,
+            0xF3, 0x0F, 0xAE, 0xCB,
+            0x64, 0x48, 0x8B, 0x0C, 0x25, 0x10, 0x00, 0x00, 0x00,
+            0xF3, 0x48, 0x0F, 0xAE, 0xDA,
+        ]);
+        // faults until CR4.FSGSBASE is set
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+        let cr4 = cpu.registers.get_cr_value(CRName::CR4);
+        cpu.registers.set_cr_value(CRName::CR4, cr4 | CR4_FSGSBASE);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x3000);
+        cpu.registers.set_gpr_value(GPRName::RBX, u64::MAX);
+        cpu.registers.set_gs_base(0x1_2345_6789);
+        cpu.memory.write::<u64>(0x3010, 0xFEED);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_fs_base(), 0x3000);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0x2345_6789);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0xFEED);
+        // the selector and the rest of the cache are untouched
+        let fs = cpu.registers.get_segment(SegmentName::FS);
+        assert_eq!((fs.selector, fs.cache.attributes), (0, 0));
+    }
+
+    #[test]
+    fn swapgs_needs_cpl_0() {
+        // swapgs / swapgs
+        let mut cpu = cpu_with_code(&[0x0F, 0x01, 0xF8, 0x0F, 0x01, 0xF8]);
+        cpu.registers.set_gs_base(0x1000);
+        cpu.registers.set_kernel_gs_base(0x2000);
+        cpu.run(1);
+        assert_eq!((cpu.registers.get_gs_base(), cpu.registers.get_kernel_gs_base()), (0x2000, 0x1000));
+        let mut cs = cpu.registers.get_segment(SegmentName::CS);
+        cs.selector |= 3;
+        cpu.registers.set_segment(SegmentName::CS, cs);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0)));
+        assert_eq!(cpu.registers.get_gs_base(), 0x2000);
+    }
+
+    #[test]
+    fn load_and_store_descriptor_tables() {
+        // lgdt [0x2000] / sgdt [0x2010] / lidt [0x2000] / sidt [0x2020]
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0x01, 0x14, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x0F, 0x01, 0x04, 0x25, 0x10, 0x20, 0x00, 0x00,
+            0x0F, 0x01, 0x1C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x0F, 0x01, 0x0C, 0x25, 0x20, 0x20, 0x00, 0x00,
+        ]);
+        cpu.memory.write::<u16>(0x2000, 0x7F);
+        cpu.memory.write::<u64>(0x2002, 0x8000);
+        cpu.run(4);
+        let table = DescriptorTable { base: 0x8000, limit: 0x7F };
+        assert_eq!((cpu.registers.get_gdtr(), cpu.registers.get_idtr()), (table, table));
+        assert_eq!(cpu.memory.read::<u16>(0x2010), 0x7F);
+        assert_eq!(cpu.memory.read::<u64>(0x2022), 0x8000);
+        assert_eq!(cpu.registers.get_ldtr(), Segment::default());
+    }
+}
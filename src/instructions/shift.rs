@@ -0,0 +1,148 @@
+// shifts and rotates: the C0/C1/D0..D3 group and SHLD/SHRD
+
+use crate::registers::GPRName;
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::flags;
+use crate::flags::ArithFlags;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+use crate::instructions::alu::check_lock;
+
+enum Outcome {
+    Shift(u64, ArithFlags),
+    Rotate(u64, bool, bool),
+}
+
+// a masked count of zero changes no flags, but a 32-bit register destination is still zero-extended
+fn finish(cpu: &mut Cpu, instruction: &Instruction, size: OperandSize, destination: Operand, value: u64, outcome: Option<Outcome>) -> Result<(), Exception> {
+    match outcome {
+        Some(Outcome::Shift(result, flags)) => {
+            operand::write_operand(cpu, instruction, destination, size, result)?;
+            flags.apply(&mut cpu.registers);
+        }
+        Some(Outcome::Rotate(result, cf, of)) => {
+            operand::write_operand(cpu, instruction, destination, size, result)?;
+            cpu.registers.set_flag(Flag::CF, cf);
+            cpu.registers.set_flag(Flag::OF, of);
+        }
+        None if matches!(destination, Operand::Register(_)) => {
+            operand::write_operand(cpu, instruction, destination, size, value)?;
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+// C0/C1 by imm8, D0/D1 by one, D2/D3 by CL; /0 ROL /1 ROR /2 RCL /3 RCR /4 SHL /5 SHR /6 SAL /7 SAR
+pub fn group2(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = operand::byte_or_full(instruction);
+    let count = match instruction.opcode {
+        0xC0 | 0xC1 => instruction.immediate_value(),
+        0xD0 | 0xD1 => 1,
+        _ => cpu.registers.get_gpr_value(GPRName::CL),
+    };
+    let destination = operand::rm_operand(cpu, instruction);
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let carry = cpu.registers.get_flag(Flag::CF);
+    let rotate = |(result, cf, of)| Outcome::Rotate(result, cf, of);
+    let shift = |(result, flags)| Outcome::Shift(result, flags);
+    let outcome = match instruction.group_index() {
+        0 => flags::rol(size, value, count).map(rotate),
+        1 => flags::ror(size, value, count).map(rotate),
+        2 => flags::rcl(size, value, count, carry).map(rotate),
+        3 => flags::rcr(size, value, count, carry).map(rotate),
+        4 | 6 => flags::shl(size, value, count).map(shift),
+        5 => flags::shr(size, value, count).map(shift),
+        _ => flags::sar(size, value, count).map(shift),
+    };
+    finish(cpu, instruction, size, destination, value, outcome)
+}
+
+// 0F A4/A5 SHLD Ev,Gv,Ib/CL and 0F AC/AD SHRD Ev,Gv,Ib/CL
+pub fn double_shift(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let size = instruction.operand_size();
+    let count = match instruction.opcode {
+        0xA4 | 0xAC => instruction.immediate_value(),
+        _ => cpu.registers.get_gpr_value(GPRName::CL),
+    };
+    let destination = operand::rm_operand(cpu, instruction);
+    let value = operand::read_operand(cpu, instruction, destination, size)?;
+    let source = operand::read_gpr(cpu, instruction, instruction.reg(), size);
+    let result = if instruction.opcode < 0xA8 {
+        flags::shld(size, value, source, count)
+    } else {
+        flags::shrd(size, value, source, count)
+    };
+    finish(cpu, instruction, size, destination, value, result.map(|(result, flags)| Outcome::Shift(result, flags)))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::Flag;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn shifts_and_rotates_by_all_count_sources() {
+        // shl eax, 4 / sar bl, 1 / ror rdx, cl / rcl word [0x2000], 1
+        let mut cpu = cpu_with_code(&[
+            0xC1, 0xE0, 0x04,
+            0xD0, 0xFB,
+            0x48, 0xD3, 0xCA,
+            0x66, 0xD1, 0x14, 0x25, 0x00, 0x20, 0x00, 0x00,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xFFFFFFFF_F0000001);
+        cpu.registers.set_gpr_value(GPRName::RBX, 0x81);
+        cpu.registers.set_gpr_value(GPRName::RCX, 4);
+        cpu.registers.set_gpr_value(GPRName::RDX, 0x1F);
+        cpu.memory.write::<u16>(0x2000, 0x8000);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x10);
+        assert!(cpu.registers.get_flag(Flag::CF));
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), 0xC0);
+        assert!(cpu.registers.get_flag(Flag::CF) && cpu.registers.get_flag(Flag::SF));
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 0xF000_0000_0000_0001);
+        assert!(cpu.registers.get_flag(Flag::CF));
+        cpu.run(1);
+        // CF (set by ROR) rotates in at the bottom, the top bit goes to CF
+        assert_eq!(cpu.memory.read::<u16>(0x2000), 0x0001);
+        assert!(cpu.registers.get_flag(Flag::CF) && cpu.registers.get_flag(Flag::OF));
+    }
+
+    #[test]
+    fn zero_count_keeps_flags_but_zero_extends() {
+        // shl eax, cl with CL = 32 (masked to 0)
+        let mut cpu = cpu_with_code(&[0xD3, 0xE0]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xFFFFFFFF_00000001);
+        cpu.registers.set_gpr_value(GPRName::RCX, 32);
+        cpu.registers.set_flag(Flag::ZF, true);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 1);
+        assert!(cpu.registers.get_flag(Flag::ZF));
+    }
+
+    #[test]
+    fn shld_and_shrd() {
+        // shld eax, ebx, 8 / shrd rdx, rbx, cl
+        let mut cpu = cpu_with_code(&[0x0F, 0xA4, 0xD8, 0x08, 0x48, 0x0F, 0xAD, 0xDA]);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x11223344);
+        cpu.registers.set_gpr_value(GPRName::RBX, 0xAABBCCDD);
+        cpu.registers.set_gpr_value(GPRName::RCX, 16);
+        cpu.registers.set_gpr_value(GPRName::RDX, 0x1234_5678_9ABC_DEF0);
+        cpu.run(2);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x223344AA);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 0xCCDD_1234_5678_9ABC);
+    }
+}
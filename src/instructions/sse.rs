@@ -0,0 +1,383 @@
+// SSE/SSE2 register access and data movement: MOV*, MOVD/MOVQ, unpacks, shuffles and sign masks
+// legacy SSE encodings write only bits 127:0 of a register, anything above is left alone
+
+use crate::registers::VecRegName;
+use crate::registers::WriteMode;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+
+pub fn read_xmm(cpu: &Cpu, index: u8) -> u128 {
+    cpu.registers.lanes::<u128>(VecRegName::XMM, index as usize)[0]
+}
+
+pub fn write_xmm(cpu: &mut Cpu, index: u8, value: u128) {
+    cpu.registers.write_sections(VecRegName::XMM, index as usize, vec![value], WriteMode::Merge);
+}
+
+// the low `bytes` bytes of a value
+pub fn truncate(value: u128, bytes: usize) -> u128 {
+    if bytes >= 16 { value } else { value & ((1u128 << (bytes * 8)) - 1) }
+}
+
+// 16-byte operands of the aligned forms (and of legacy SSE arithmetic) must be 16-byte aligned
+fn check_alignment(address: u64, bytes: usize, aligned: bool) -> Result<(), Exception> {
+    if aligned && bytes == 16 && !address.is_multiple_of(16) {
+        return Err(Exception::GeneralProtection(0));
+    }
+    Ok(())
+}
+
+pub fn read_memory(cpu: &Cpu, address: u64, bytes: usize, aligned: bool) -> Result<u128, Exception> {
+    check_alignment(address, bytes, aligned)?;
+    Ok(match bytes {
+        2 => cpu.read_memory::<u16>(address)? as u128,
+        4 => cpu.read_memory::<u32>(address)? as u128,
+        8 => cpu.read_memory::<u64>(address)? as u128,
+        _ => cpu.read_memory::<u128>(address)?,
+    })
+}
+
+pub fn write_memory(cpu: &mut Cpu, address: u64, bytes: usize, value: u128, aligned: bool) -> Result<(), Exception> {
+    check_alignment(address, bytes, aligned)?;
+    match bytes {
+        2 => cpu.write_memory::<u16>(address, value as u16),
+        4 => cpu.write_memory::<u32>(address, value as u32),
+        8 => cpu.write_memory::<u64>(address, value as u64),
+        _ => cpu.write_memory::<u128>(address, value),
+    }
+}
+
+// the XMM or memory operand in ModRM.rm, `bytes` wide and zero-extended
+pub fn read_rm(cpu: &Cpu, instruction: &Instruction, bytes: usize, aligned: bool) -> Result<u128, Exception> {
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Register(index) => Ok(truncate(read_xmm(cpu, index), bytes)),
+        Operand::Memory(address) => read_memory(cpu, address, bytes, aligned),
+    }
+}
+
+// a register destination takes the low `bytes` bytes, the rest of its low 128 bits kept
+fn write_rm(cpu: &mut Cpu, instruction: &Instruction, bytes: usize, value: u128, aligned: bool) -> Result<(), Exception> {
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Register(index) => {
+            let old = read_xmm(cpu, index);
+            write_xmm(cpu, index, merge(old, value, bytes));
+            Ok(())
+        }
+        Operand::Memory(address) => write_memory(cpu, address, bytes, value, aligned),
+    }
+}
+
+// replace the low `bytes` bytes of old
+pub fn merge(old: u128, value: u128, bytes: usize) -> u128 {
+    (old & !truncate(u128::MAX, bytes)) | truncate(value, bytes)
+}
+
+// MOVD/MOVQ, CVTSI2SS and friends: 64-bit with REX.W, 32-bit otherwise, 66 being a mandatory prefix
+pub fn gpr_size(instruction: &Instruction) -> OperandSize {
+    if instruction.rex.is_some_and(|rex| rex.w) { OperandSize::Qword } else { OperandSize::Dword }
+}
+
+pub fn lane_count(size: OperandSize) -> usize {
+    16 / size.bytes()
+}
+
+pub fn lane(value: u128, size: OperandSize, index: usize) -> u64 {
+    (value >> (index * size.bits() as usize)) as u64 & size.mask()
+}
+
+pub fn set_lane(value: u128, size: OperandSize, index: usize, lane: u64) -> u128 {
+    let shift = index * size.bits() as usize;
+    (value & !((size.mask() as u128) << shift)) | ((lane & size.mask()) as u128) << shift
+}
+
+// apply f to each pair of lanes
+pub fn map_lanes(size: OperandSize, a: u128, b: u128, f: impl Fn(u64, u64) -> u64) -> u128 {
+    (0..lane_count(size)).fold(0, |result, i| set_lane(result, size, i, f(lane(a, size, i), lane(b, size, i))))
+}
+
+// UNPCKL*/PUNPCKL* interleave the low halves of a and b, the H forms the high halves
+pub fn interleave(size: OperandSize, a: u128, b: u128, high: bool) -> u128 {
+    let half = lane_count(size) / 2;
+    let base = if high { half } else { 0 };
+    (0..half).fold(0, |result, i| {
+        let result = set_lane(result, size, 2 * i, lane(a, size, base + i));
+        set_lane(result, size, 2 * i + 1, lane(b, size, base + i))
+    })
+}
+
+// 0F 10/11 MOVUPS/MOVUPD/MOVSS/MOVSD, 0F 28/29 MOVAPS/MOVAPD, 66/F3 0F 6F/7F MOVDQA/MOVDQU,
+// 0F 2B MOVNTPS/MOVNTPD and 66 0F E7 MOVNTDQ
+pub fn mov(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let prefix = instruction.mandatory_prefix();
+    let (bytes, aligned) = match (instruction.opcode, prefix) {
+        (0x10 | 0x11, MandatoryPrefix::None | MandatoryPrefix::P66) => (16, false),
+        (0x10 | 0x11, MandatoryPrefix::PF3) => (4, false),
+        (0x10 | 0x11, MandatoryPrefix::PF2) => (8, false),
+        (0x28 | 0x29 | 0x2B, MandatoryPrefix::None | MandatoryPrefix::P66) => (16, true),
+        (0x6F | 0x7F | 0xE7, MandatoryPrefix::P66) => (16, true),
+        (0x6F | 0x7F, MandatoryPrefix::PF3) => (16, false),
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    if matches!(instruction.opcode, 0x2B | 0xE7) && !instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+
+    if matches!(instruction.opcode, 0x10 | 0x28 | 0x6F) {
+        let value = read_rm(cpu, instruction, bytes, aligned)?;
+        // a scalar load from memory zeroes the rest of the low 128 bits, a register move merges
+        let value = if instruction.has_memory_operand() {
+            value
+        } else {
+            merge(read_xmm(cpu, instruction.reg()), value, bytes)
+        };
+        write_xmm(cpu, instruction.reg(), value);
+        Ok(())
+    } else {
+        let value = read_xmm(cpu, instruction.reg());
+        write_rm(cpu, instruction, bytes, value, aligned)
+    }
+}
+
+// 0F 12/13 MOVLPS/MOVLPD/MOVHLPS and 0F 16/17 MOVHPS/MOVHPD/MOVLHPS, 64-bit halves
+pub fn mov_half(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let prefix = instruction.mandatory_prefix();
+    let memory = instruction.has_memory_operand();
+    if !matches!(prefix, MandatoryPrefix::None | MandatoryPrefix::P66) || (!memory && (prefix == MandatoryPrefix::P66 || instruction.opcode & 1 == 1)) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let high = instruction.opcode >= 0x16;
+    let size = OperandSize::Qword;
+    let register = read_xmm(cpu, instruction.reg());
+    if instruction.opcode & 1 == 1 {
+        return write_rm(cpu, instruction, 8, lane(register, size, high as usize) as u128, false);
+    }
+    let source = match operand::rm_operand(cpu, instruction) {
+        // MOVHLPS takes the high half of the source, MOVLHPS the low one
+        Operand::Register(index) => lane(read_xmm(cpu, index), size, !high as usize),
+        Operand::Memory(address) => read_memory(cpu, address, 8, false)? as u64,
+    };
+    write_xmm(cpu, instruction.reg(), set_lane(register, size, high as usize, source));
+    Ok(())
+}
+
+// 66 0F 6E MOVD/MOVQ xmm, r/m; 66 0F 7E MOVD/MOVQ r/m, xmm; F3 0F 7E MOVQ xmm, xmm/m64; 66 0F D6 MOVQ xmm/m64, xmm
+pub fn mov_integer(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = gpr_size(instruction);
+    match (instruction.opcode, instruction.mandatory_prefix()) {
+        (0x6E, MandatoryPrefix::P66) => {
+            let value = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?;
+            write_xmm(cpu, instruction.reg(), value as u128);
+        }
+        (0x7E, MandatoryPrefix::P66) => {
+            let value = read_xmm(cpu, instruction.reg()) as u64 & size.mask();
+            let destination = operand::rm_operand(cpu, instruction);
+            operand::write_operand(cpu, instruction, destination, size, value)?;
+        }
+        (0x7E, MandatoryPrefix::PF3) => {
+            let value = read_rm(cpu, instruction, 8, false)?;
+            write_xmm(cpu, instruction.reg(), value);
+        }
+        (0xD6, MandatoryPrefix::P66) => {
+            let value = truncate(read_xmm(cpu, instruction.reg()), 8);
+            match operand::rm_operand(cpu, instruction) {
+                // the register form zeroes bits 127:64
+                Operand::Register(index) => write_xmm(cpu, index, value),
+                Operand::Memory(address) => write_memory(cpu, address, 8, value, false)?,
+            }
+        }
+        _ => return Err(Exception::InvalidOpcode),
+    }
+    Ok(())
+}
+
+// 0F 14/15 UNPCKLPS/UNPCKHPS, 66 for the PD forms
+pub fn unpack_float(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match instruction.mandatory_prefix() {
+        MandatoryPrefix::None => OperandSize::Dword,
+        MandatoryPrefix::P66 => OperandSize::Qword,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let b = read_rm(cpu, instruction, 16, true)?;
+    let a = read_xmm(cpu, instruction.reg());
+    write_xmm(cpu, instruction.reg(), interleave(size, a, b, instruction.opcode == 0x15));
+    Ok(())
+}
+
+// SHUFPS: the low two lanes are picked from a, the high two from b, two imm8 bits each; SHUFPD one bit per lane
+pub fn shuffle_lanes(size: OperandSize, a: u128, b: u128, imm: u8) -> u128 {
+    let count = lane_count(size);
+    let bits = (count / 2).trailing_zeros() as usize + 1;
+    (0..count).fold(0, |result, i| {
+        let source = if i < count / 2 { a } else { b };
+        let select = (imm as usize >> (i * bits)) & (count - 1);
+        set_lane(result, size, i, lane(source, size, select))
+    })
+}
+
+// 0F C6 SHUFPS / 66 0F C6 SHUFPD
+pub fn shuffle_float(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match instruction.mandatory_prefix() {
+        MandatoryPrefix::None => OperandSize::Dword,
+        MandatoryPrefix::P66 => OperandSize::Qword,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let b = read_rm(cpu, instruction, 16, true)?;
+    let a = read_xmm(cpu, instruction.reg());
+    write_xmm(cpu, instruction.reg(), shuffle_lanes(size, a, b, instruction.immediate_value() as u8));
+    Ok(())
+}
+
+// PSHUFD picks every dword by two imm8 bits, PSHUFHW/PSHUFLW do the same for the words of one half
+pub fn shuffle_words(prefix: MandatoryPrefix, value: u128, imm: u8) -> Option<u128> {
+    let pick = |size: OperandSize, base: usize, value: u128| {
+        (0..4).fold(value, |result, i| {
+            set_lane(result, size, base + i, lane(value, size, base + ((imm as usize >> (2 * i)) & 3)))
+        })
+    };
+    match prefix {
+        MandatoryPrefix::P66 => Some(pick(OperandSize::Dword, 0, value)),
+        MandatoryPrefix::PF3 => Some(pick(OperandSize::Word, 4, value)),
+        MandatoryPrefix::PF2 => Some(pick(OperandSize::Word, 0, value)),
+        MandatoryPrefix::None => None,
+    }
+}
+
+// 66 0F 70 PSHUFD / F3 0F 70 PSHUFHW / F2 0F 70 PSHUFLW
+pub fn pshuf(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let source = read_rm(cpu, instruction, 16, true)?;
+    let result = shuffle_words(instruction.mandatory_prefix(), source, instruction.immediate_value() as u8)
+        .ok_or(Exception::InvalidOpcode)?;
+    write_xmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+// the top bit of every lane, lane 0 in bit 0
+pub fn sign_mask(size: OperandSize, value: u128) -> u64 {
+    (0..lane_count(size)).fold(0, |mask, i| mask | (lane(value, size, i) >> (size.bits() - 1)) << i)
+}
+
+// 0F 50 MOVMSKPS / 66 0F 50 MOVMSKPD / 66 0F D7 PMOVMSKB, register operands only
+pub fn move_mask(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match (instruction.opcode, instruction.mandatory_prefix()) {
+        (0x50, MandatoryPrefix::None) => OperandSize::Dword,
+        (0x50, MandatoryPrefix::P66) => OperandSize::Qword,
+        (0xD7, MandatoryPrefix::P66) => OperandSize::Byte,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    if instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    let mask = sign_mask(size, read_xmm(cpu, instruction.rm()));
+    operand::write_gpr64(cpu, instruction.reg(), mask);
+    Ok(())
+}
+
+// 66 0F C4 PINSRW xmm, r32/m16, imm8 / 66 0F C5 PEXTRW r32, xmm, imm8
+pub fn insert_extract_word(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let index = instruction.immediate_value() as usize & 7;
+    if instruction.opcode == 0xC4 {
+        let value = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), OperandSize::Word)?;
+        let register = read_xmm(cpu, instruction.reg());
+        write_xmm(cpu, instruction.reg(), set_lane(register, OperandSize::Word, index, value));
+    } else {
+        if instruction.has_memory_operand() {
+            return Err(Exception::InvalidOpcode);
+        }
+        let value = lane(read_xmm(cpu, instruction.rm()), OperandSize::Word, index);
+        operand::write_gpr64(cpu, instruction.reg(), value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    fn set_xmm(cpu: &mut Cpu, index: u8, value: u128) {
+        write_xmm(cpu, index, value);
+    }
+
+    #[test]
+    fn moves_keep_the_bits_above_128() {
+        // movups xmm1, [0x2000] / movss xmm2, [0x2010] / movss xmm3, xmm1 / movaps [0x2021], xmm1
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0x10, 0x0C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xF3, 0x0F, 0x10, 0x14, 0x25, 0x10, 0x20, 0x00, 0x00,
+            0xF3, 0x0F, 0x10, 0xD9,
+            0x0F, 0x29, 0x0C, 0x25, 0x21, 0x20, 0x00, 0x00,
+        ]);
+        cpu.registers.set_by_sections::<u64>(VecRegName::ZMM, 1, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        cpu.memory.write::<u128>(0x2000, 0x0F0E0D0C_0B0A0908_07060504_03020100);
+        cpu.memory.write::<u32>(0x2010, 0xAABBCCDD);
+        set_xmm(&mut cpu, 2, u128::MAX);
+        set_xmm(&mut cpu, 3, u128::MAX);
+        cpu.run(3);
+        assert_eq!(read_xmm(&cpu, 1), 0x0F0E0D0C_0B0A0908_07060504_03020100);
+        assert_eq!(cpu.registers.get_by_sections::<u64>(VecRegName::ZMM, 1).unwrap()[2..], [3, 4, 5, 6, 7, 8]);
+        // a load from memory zeroes the rest of the register, a register move merges
+        assert_eq!(read_xmm(&cpu, 2), 0xAABBCCDD);
+        assert_eq!(read_xmm(&cpu, 3), 0xFFFFFFFF_FFFFFFFF_FFFFFFFF_03020100);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0)));
+    }
+
+    #[test]
+    fn integer_moves_and_halves() {
+        // movq xmm0, rax / movd ecx, xmm0 / movhps xmm0, [0x2000] / movhlps xmm1, xmm0 / movq [0x2008], xmm1
+        let mut cpu = cpu_with_code(&[
+            0x66, 0x48, 0x0F, 0x6E, 0xC0,
+            0x66, 0x0F, 0x7E, 0xC1,
+            0x0F, 0x16, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x0F, 0x12, 0xC8,
+            0x66, 0x0F, 0xD6, 0x0C, 0x25, 0x08, 0x20, 0x00, 0x00,
+        ]);
+        set_xmm(&mut cpu, 0, u128::MAX);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x11223344_55667788);
+        cpu.registers.set_gpr_value(GPRName::RCX, u64::MAX);
+        cpu.memory.write::<u64>(0x2000, 0xCAFE);
+        cpu.run(2);
+        assert_eq!(read_xmm(&cpu, 0), 0x11223344_55667788);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0x55667788);
+        cpu.run(3);
+        assert_eq!(read_xmm(&cpu, 0), 0xCAFE << 64 | 0x11223344_55667788);
+        assert_eq!(read_xmm(&cpu, 1), 0xCAFE);
+        assert_eq!(cpu.memory.read::<u64>(0x2008), 0xCAFE);
+    }
+
+    #[test]
+    fn shuffles_and_masks() {
+        // pshufd xmm1, xmm0, 0x1B / shufps xmm0, xmm1, 0x4E / unpcklps xmm2, xmm1 / movmskps eax, xmm1 / pextrw edx, xmm1, 7
+        let mut cpu = cpu_with_code(&[
+            0x66, 0x0F, 0x70, 0xC8, 0x1B,
+            0x0F, 0xC6, 0xC1, 0x4E,
+            0x0F, 0x14, 0xD1,
+            0x0F, 0x50, 0xC1,
+            0x66, 0x0F, 0xC5, 0xD1, 0x07,
+        ]);
+        set_xmm(&mut cpu, 0, 0x80000003_00000002_80000001_00000000);
+        set_xmm(&mut cpu, 2, 0x0000000D_0000000C_0000000B_0000000A);
+        cpu.run(1);
+        assert_eq!(read_xmm(&cpu, 1), 0x00000000_80000001_00000002_80000003);
+        cpu.run(1);
+        assert_eq!(read_xmm(&cpu, 0), 0x00000002_80000003_80000003_00000002);
+        cpu.run(1);
+        assert_eq!(read_xmm(&cpu, 2), 0x00000002_0000000B_80000003_0000000A);
+        cpu.run(2);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0b0101);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), 0);
+    }
+}
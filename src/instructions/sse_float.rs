@@ -0,0 +1,762 @@
+// SSE/SSE2 floating point: arithmetic, logic, compares, COMIS/UCOMIS and conversions
+// NaN results follow the SSE rules: the first NaN source, quieted, wins; an invalid operation with
+// ordinary inputs returns the negative default NaN
+// results are rounded as MXCSR asks and the IEEE exceptions are collected in an mxcsr::Environment, so a
+// result is only written once the instruction has committed them
+
+use std::cmp::Ordering;
+
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::sse;
+use crate::instructions::mxcsr;
+use crate::instructions::mxcsr::Environment;
+
+// the two lane formats, as raw bits in a u64
+pub trait Float: Copy + PartialOrd
+    + std::ops::Add<Output = Self> + std::ops::Sub<Output = Self>
+    + std::ops::Mul<Output = Self> + std::ops::Div<Output = Self>
+    + std::ops::Neg<Output = Self>
+{
+    const QUIET: u64;
+    const DEFAULT_NAN: u64;
+    const SIGN: u64;
+
+    fn from_raw(raw: u64) -> Self;
+    fn raw(self) -> u64;
+    fn sqrt(self) -> Self;
+    fn is_nan(self) -> bool;
+    fn is_infinite(self) -> bool;
+    fn is_subnormal(self) -> bool;
+    fn mul_add(self, a: Self, b: Self) -> Self;
+    fn next_up(self) -> Self;
+    fn next_down(self) -> Self;
+    fn to_f64(self) -> f64;
+    fn from_f64(value: f64) -> Self;
+}
+
+impl Float for f32 {
+    const QUIET: u64 = 1 << 22;
+    const DEFAULT_NAN: u64 = 0xFFC0_0000;
+    const SIGN: u64 = 1 << 31;
+
+    fn from_raw(raw: u64) -> Self { f32::from_bits(raw as u32) }
+    fn raw(self) -> u64 { self.to_bits() as u64 }
+    fn sqrt(self) -> Self { f32::sqrt(self) }
+    fn is_nan(self) -> bool { f32::is_nan(self) }
+    fn is_infinite(self) -> bool { f32::is_infinite(self) }
+    fn is_subnormal(self) -> bool { f32::is_subnormal(self) }
+    fn mul_add(self, a: Self, b: Self) -> Self { f32::mul_add(self, a, b) }
+    fn next_up(self) -> Self { f32::next_up(self) }
+    fn next_down(self) -> Self { f32::next_down(self) }
+    fn to_f64(self) -> f64 { self as f64 }
+    fn from_f64(value: f64) -> Self { value as f32 }
+}
+
+impl Float for f64 {
+    const QUIET: u64 = 1 << 51;
+    const DEFAULT_NAN: u64 = 0xFFF8_0000_0000_0000;
+    const SIGN: u64 = 1 << 63;
+
+    fn from_raw(raw: u64) -> Self { f64::from_bits(raw) }
+    fn raw(self) -> u64 { self.to_bits() }
+    fn sqrt(self) -> Self { f64::sqrt(self) }
+    fn is_nan(self) -> bool { f64::is_nan(self) }
+    fn is_infinite(self) -> bool { f64::is_infinite(self) }
+    fn is_subnormal(self) -> bool { f64::is_subnormal(self) }
+    fn mul_add(self, a: Self, b: Self) -> Self { f64::mul_add(self, a, b) }
+    fn next_up(self) -> Self { f64::next_up(self) }
+    fn next_down(self) -> Self { f64::next_down(self) }
+    fn to_f64(self) -> f64 { self }
+    fn from_f64(value: f64) -> Self { value }
+}
+
+// MXCSR.RC and EVEX embedded rounding encode these as 0..3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    Nearest, Down, Up, TowardZero
+}
+
+impl RoundingMode {
+    pub fn from_bits(bits: u8) -> RoundingMode {
+        match bits & 3 {
+            0 => RoundingMode::Nearest,
+            1 => RoundingMode::Down,
+            2 => RoundingMode::Up,
+            _ => RoundingMode::TowardZero,
+        }
+    }
+}
+
+// in 0F 5x opcode order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatOp {
+    Sqrt, Add, Mul, Sub, Min, Div, Max
+}
+
+impl FloatOp {
+    pub fn from_opcode(opcode: u8) -> Option<FloatOp> {
+        Some(match opcode {
+            0x51 => FloatOp::Sqrt,
+            0x58 => FloatOp::Add,
+            0x59 => FloatOp::Mul,
+            0x5C => FloatOp::Sub,
+            0x5D => FloatOp::Min,
+            0x5E => FloatOp::Div,
+            0x5F => FloatOp::Max,
+            _ => return None,
+        })
+    }
+}
+
+// element size and whether only lane 0 is computed, from the mandatory prefix: PS, PD, SS, SD
+pub fn float_format(prefix: MandatoryPrefix) -> (OperandSize, bool) {
+    match prefix {
+        MandatoryPrefix::None => (OperandSize::Dword, false),
+        MandatoryPrefix::P66 => (OperandSize::Qword, false),
+        MandatoryPrefix::PF3 => (OperandSize::Dword, true),
+        MandatoryPrefix::PF2 => (OperandSize::Qword, true),
+    }
+}
+
+fn quiet<F: Float>(raw: u64) -> u64 {
+    raw | F::QUIET
+}
+
+fn is_signalling<F: Float>(value: F) -> bool {
+    value.is_nan() && value.raw() & F::QUIET == 0
+}
+
+fn magnitude<F: Float>(value: F) -> F {
+    F::from_raw(value.raw() & !F::SIGN)
+}
+
+// a source as the instruction sees it, DAZ reads denormals as zeros of the same sign
+fn flush<F: Float>(environment: &Environment, raw: u64) -> F {
+    let value = F::from_raw(raw);
+    if environment.daz && value.is_subnormal() { F::from_raw(raw & F::SIGN) } else { value }
+}
+
+// invalid for a signalling NaN source, or for any NaN when quiet ones signal too; whether there was a NaN
+fn check_nans<F: Float>(environment: &Environment, sources: &[F], signal_quiet: bool) -> bool {
+    if sources.iter().any(|source| is_signalling(*source) || (signal_quiet && source.is_nan())) {
+        environment.raise(mxcsr::INVALID);
+    }
+    sources.iter().any(|source| source.is_nan())
+}
+
+fn check_denormals<F: Float>(environment: &Environment, sources: &[F]) {
+    if sources.iter().any(|source| source.is_subnormal()) {
+        environment.raise(mxcsr::DENORMAL);
+    }
+}
+
+// the sign of the exact result minus the round-to-nearest one, found without rounding error (TwoSum, FMA
+// residues); tiny products and quotients are scaled by 2^64 first, and radicands below one by 2^128 with the root
+// by 2^64, so the residue cannot underflow, and an overflow to infinity counts as rounding away from the finite
+// result
+fn rounding_error<F: Float>(op: FloatOp, x: F, y: F, result: F) -> Ordering {
+    let zero = F::from_raw(0);
+    if y.is_infinite() || (op != FloatOp::Sqrt && x.is_infinite()) || (op == FloatOp::Div && y == zero) {
+        return Ordering::Equal;
+    }
+    let tiny = result.is_subnormal() || result == zero;
+    let scale = F::from_f64(2f64.powi(64));
+    let error = match op {
+        FloatOp::Add | FloatOp::Sub if !result.is_infinite() => {
+            let y = if op == FloatOp::Sub { -y } else { y };
+            let y_part = result - x;
+            let x_part = result - y_part;
+            (x - x_part) + (y - y_part)
+        }
+        // the smaller factor of a product this small is below 2^-63
+        FloatOp::Mul if tiny => {
+            let (small, large) = if magnitude(x) < magnitude(y) { (x, y) } else { (y, x) };
+            (small * scale).mul_add(large, -(result * scale))
+        }
+        FloatOp::Mul if !result.is_infinite() => x.mul_add(y, -result),
+        // the remainder x - result * y carries the error with the sign of y
+        FloatOp::Div if !result.is_infinite() => {
+            let (x, result) = if tiny && magnitude(x) < F::from_f64(1.0) { (x * scale, result * scale) } else { (x, result) };
+            let remainder = (-result).mul_add(y, x);
+            if y < zero { -remainder } else { remainder }
+        }
+        FloatOp::Sqrt if magnitude(y) < F::from_f64(1.0) => (-(result * scale)).mul_add(result * scale, y * scale * scale),
+        FloatOp::Sqrt => (-result).mul_add(result, y),
+        _ => -result,
+    };
+    error.partial_cmp(&zero).unwrap_or(Ordering::Equal)
+}
+
+// take a round-to-nearest result to where the rounding mode puts it and raise overflow, underflow and precision
+// for it; tininess is detected after rounding, and FTZ flushes tiny results when underflow is masked
+fn finish<F: Float>(environment: &Environment, nearest: F, error: Ordering) -> F {
+    let zero = F::from_raw(0);
+    let result = match (environment.mode, error) {
+        (RoundingMode::Down, Ordering::Less) => nearest.next_down(),
+        (RoundingMode::Up, Ordering::Greater) => nearest.next_up(),
+        (RoundingMode::TowardZero, Ordering::Less) if nearest > zero => nearest.next_down(),
+        (RoundingMode::TowardZero, Ordering::Greater) if nearest < zero => nearest.next_up(),
+        _ => nearest,
+    };
+    let inexact = error != Ordering::Equal;
+    let tiny = result.is_subnormal() || (result == zero && inexact);
+    let underflow_masked = environment.masked(mxcsr::UNDERFLOW);
+    if inexact && (nearest.is_infinite() || result.is_infinite()) {
+        environment.raise(mxcsr::OVERFLOW | mxcsr::PRECISION);
+    } else if tiny && environment.ftz && underflow_masked {
+        environment.raise(mxcsr::UNDERFLOW | mxcsr::PRECISION);
+        return F::from_raw(result.raw() & F::SIGN);
+    } else if tiny && (inexact || !underflow_masked) {
+        environment.raise(mxcsr::UNDERFLOW | if inexact { mxcsr::PRECISION } else { 0 });
+    } else if inexact {
+        environment.raise(mxcsr::PRECISION);
+    }
+    result
+}
+
+fn compute<F: Float>(op: FloatOp, a: u64, b: u64, environment: &Environment) -> u64 {
+    let (x, y) = (flush::<F>(environment, a), flush::<F>(environment, b));
+    // SQRT only has the second source
+    let both = [x, y];
+    let sources = if op == FloatOp::Sqrt { &both[1..] } else { &both[..] };
+    // MIN/MAX signal on quiet NaNs too, and return the second source on NaNs and on equal values, zeros of
+    // either sign included
+    if let FloatOp::Min | FloatOp::Max = op {
+        if !check_nans(environment, sources, true) {
+            check_denormals(environment, sources);
+        }
+        let first = if op == FloatOp::Min { x < y } else { x > y };
+        return if first { x.raw() } else { y.raw() };
+    }
+    if check_nans(environment, sources, false) {
+        let nan = sources.iter().find(|source| source.is_nan()).map_or(0, |source| source.raw());
+        return quiet::<F>(nan);
+    }
+    let result = match op {
+        FloatOp::Add => x + y,
+        FloatOp::Sub => x - y,
+        FloatOp::Mul => x * y,
+        FloatOp::Div => x / y,
+        _ => y.sqrt(),
+    };
+    if result.is_nan() {
+        environment.raise(mxcsr::INVALID);
+        return F::DEFAULT_NAN;
+    }
+    check_denormals(environment, sources);
+    let zero = F::from_raw(0);
+    if op == FloatOp::Div && y == zero && !x.is_infinite() {
+        environment.raise(mxcsr::DIVIDE_BY_ZERO);
+        return result.raw();
+    }
+    let error = rounding_error(op, x, y, result);
+    // an exact zero sum is -0 when rounding down, unless both operands were +0
+    if environment.mode == RoundingMode::Down && matches!(op, FloatOp::Add | FloatOp::Sub) && result == zero && error == Ordering::Equal {
+        let y = if op == FloatOp::Sub { -y } else { y };
+        return if x.raw() == 0 && y.raw() == 0 { result.raw() } else { F::SIGN };
+    }
+    finish(environment, result, error).raw()
+}
+
+pub fn apply(op: FloatOp, size: OperandSize, a: u64, b: u64, environment: &Environment) -> u64 {
+    match size {
+        OperandSize::Dword => compute::<f32>(op, a, b, environment),
+        _ => compute::<f64>(op, a, b, environment),
+    }
+}
+
+// every lane for the packed forms, lane 0 for the scalar ones with the rest of a passed through
+pub fn float_lanes(size: OperandSize, scalar: bool, a: u128, b: u128, f: impl Fn(u64, u64) -> u64) -> u128 {
+    if scalar {
+        sse::set_lane(a, size, 0, f(sse::lane(a, size, 0), sse::lane(b, size, 0)))
+    } else {
+        sse::map_lanes(size, a, b, f)
+    }
+}
+
+// the second source: all 16 bytes (aligned in memory) for packed forms, one element for scalar ones
+fn source(cpu: &Cpu, instruction: &Instruction, size: OperandSize, scalar: bool) -> Result<u128, Exception> {
+    if scalar {
+        sse::read_rm(cpu, instruction, size.bytes(), false)
+    } else {
+        sse::read_rm(cpu, instruction, 16, true)
+    }
+}
+
+// 0F 51 SQRT, 58 ADD, 59 MUL, 5C SUB, 5D MIN, 5E DIV, 5F MAX in their PS/PD/SS/SD forms
+pub fn arithmetic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let op = FloatOp::from_opcode(instruction.opcode).ok_or(Exception::InvalidOpcode)?;
+    let (size, scalar) = float_format(instruction.mandatory_prefix());
+    let b = source(cpu, instruction, size, scalar)?;
+    let a = sse::read_xmm(cpu, instruction.reg());
+    let environment = Environment::from_cpu(cpu);
+    let result = float_lanes(size, scalar, a, b, |x, y| apply(op, size, x, y, &environment));
+    environment.commit(cpu)?;
+    sse::write_xmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+pub fn bitwise(opcode: u8, a: u128, b: u128) -> u128 {
+    match opcode & 3 {
+        0 => a & b,
+        1 => !a & b,
+        2 => a | b,
+        _ => a ^ b,
+    }
+}
+
+// 0F 54 ANDPS, 55 ANDNPS, 56 ORPS, 57 XORPS, 66 for the PD forms
+pub fn logic(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if !matches!(instruction.mandatory_prefix(), MandatoryPrefix::None | MandatoryPrefix::P66) {
+        return Err(Exception::InvalidOpcode);
+    }
+    let b = sse::read_rm(cpu, instruction, 16, true)?;
+    let a = sse::read_xmm(cpu, instruction.reg());
+    sse::write_xmm(cpu, instruction.reg(), bitwise(instruction.opcode, a, b));
+    Ok(())
+}
+
+// the pre-computation checks of a comparison, then how a compares to b, None when unordered
+fn ordering<F: Float>(environment: &Environment, a: u64, b: u64, signal_quiet: bool) -> Option<Ordering> {
+    let sources = [flush::<F>(environment, a), flush::<F>(environment, b)];
+    if !check_nans(environment, &sources, signal_quiet) {
+        check_denormals(environment, &sources);
+    }
+    sources[0].partial_cmp(&sources[1])
+}
+
+// CMPPS predicates 0..7: EQ, LT, LE, UNORD, NEQ, NLT, NLE, ORD; VCMPPS adds 8..15: EQ_UQ, NGE, NGT, FALSE,
+// NEQ_OQ, GE, GT, TRUE, and 16..31 repeat them with the other signalling behaviour
+fn predicate(predicate: u8, ordering: Option<Ordering>) -> bool {
+    match predicate & 0xF {
+        0 => ordering == Some(Ordering::Equal),
+        1 => ordering == Some(Ordering::Less),
+        2 => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        3 => ordering.is_none(),
+        4 => ordering != Some(Ordering::Equal),
+        5 => ordering != Some(Ordering::Less),
+        6 => !matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        7 => ordering.is_some(),
+        8 => matches!(ordering, None | Some(Ordering::Equal)),
+        9 => matches!(ordering, None | Some(Ordering::Less)),
+        10 => ordering != Some(Ordering::Greater),
+        11 => false,
+        12 => matches!(ordering, Some(Ordering::Less | Ordering::Greater)),
+        13 => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        14 => ordering == Some(Ordering::Greater),
+        _ => true,
+    }
+}
+
+pub fn compare_lane(size: OperandSize, imm: u8, a: u64, b: u64, environment: &Environment) -> u64 {
+    // LT, LE, NLT, NLE and their 8..15 counterparts signal on quiet NaNs, bit 4 swaps quiet and signalling
+    let signal_quiet = matches!(imm & 3, 1 | 2) != (imm & 0x10 != 0);
+    let ordering = match size {
+        OperandSize::Dword => ordering::<f32>(environment, a, b, signal_quiet),
+        _ => ordering::<f64>(environment, a, b, signal_quiet),
+    };
+    if predicate(imm, ordering) { size.mask() } else { 0 }
+}
+
+// 0F C2 CMPPS/CMPPD/CMPSS/CMPSD, every lane becomes all ones or all zeros
+pub fn compare(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (size, scalar) = float_format(instruction.mandatory_prefix());
+    // the legacy encoding only has the first eight predicates
+    let imm = instruction.immediate_value() as u8 & 7;
+    let b = source(cpu, instruction, size, scalar)?;
+    let a = sse::read_xmm(cpu, instruction.reg());
+    let environment = Environment::from_cpu(cpu);
+    let result = float_lanes(size, scalar, a, b, |x, y| compare_lane(size, imm, x, y, &environment));
+    environment.commit(cpu)?;
+    sse::write_xmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+// ZF/PF/CF as an unsigned compare would set them, all three for unordered; OF, SF and AF cleared
+pub fn comparison_flags(cpu: &mut Cpu, ordering: Option<Ordering>) {
+    let (zf, pf, cf) = match ordering {
+        None => (true, true, true),
+        Some(Ordering::Less) => (false, false, true),
+        Some(Ordering::Equal) => (true, false, false),
+        Some(Ordering::Greater) => (false, false, false),
+    };
+    cpu.registers.set_flag(Flag::ZF, zf);
+    cpu.registers.set_flag(Flag::PF, pf);
+    cpu.registers.set_flag(Flag::CF, cf);
+    for flag in [Flag::OF, Flag::SF, Flag::AF] {
+        cpu.registers.set_flag(flag, false);
+    }
+}
+
+// 0F 2E UCOMISS / 0F 2F COMISS, 66 for the SD forms; COMIS signals invalid on quiet NaNs as well
+pub fn comis(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = match instruction.mandatory_prefix() {
+        MandatoryPrefix::None => OperandSize::Dword,
+        MandatoryPrefix::P66 => OperandSize::Qword,
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    let b = sse::read_rm(cpu, instruction, size.bytes(), false)? as u64;
+    let a = sse::lane(sse::read_xmm(cpu, instruction.reg()), size, 0);
+    let environment = Environment::from_cpu(cpu);
+    let signal_quiet = instruction.opcode == 0x2F;
+    let ordering = match size {
+        OperandSize::Dword => ordering::<f32>(&environment, a, b, signal_quiet),
+        _ => ordering::<f64>(&environment, a, b, signal_quiet),
+    };
+    environment.commit(cpu)?;
+    comparison_flags(cpu, ordering);
+    Ok(())
+}
+
+// float to signed integer, rounded as MXCSR says unless truncating; invalid and the integer indefinite (only the
+// sign bit set) for NaN and out of range values
+pub fn to_integer(environment: &Environment, value: u64, float: OperandSize, integer: OperandSize, truncate: bool) -> u64 {
+    let value = match float {
+        OperandSize::Dword => flush::<f32>(environment, value).to_f64(),
+        _ => flush::<f64>(environment, value),
+    };
+    let rounded = match environment.mode {
+        _ if truncate => value.trunc(),
+        RoundingMode::Nearest => value.round_ties_even(),
+        RoundingMode::Down => value.floor(),
+        RoundingMode::Up => value.ceil(),
+        RoundingMode::TowardZero => value.trunc(),
+    };
+    let limit = integer.sign_bit() as f64;
+    if rounded.is_nan() || rounded >= limit || rounded < -limit {
+        environment.raise(mxcsr::INVALID);
+        return integer.sign_bit();
+    }
+    if rounded != value {
+        environment.raise(mxcsr::PRECISION);
+    }
+    rounded as i64 as u64 & integer.mask()
+}
+
+pub fn from_integer(environment: &Environment, value: u64, integer: OperandSize, float: OperandSize) -> u64 {
+    let value = integer.sign_extend(value) as i64;
+    // every i64 is exact in an i128, and so is every integral float of this range
+    match float {
+        OperandSize::Dword => {
+            let nearest = value as f32;
+            finish(environment, nearest, (value as i128).cmp(&(nearest as i128))).raw()
+        }
+        _ => {
+            let nearest = value as f64;
+            finish(environment, nearest, (value as i128).cmp(&(nearest as i128))).raw()
+        }
+    }
+}
+
+// single to double and back, NaNs are quieted and keep the top of their payload
+pub fn widen(environment: &Environment, value: u64) -> u64 {
+    let single = flush::<f32>(environment, value);
+    if check_nans(environment, &[single], false) {
+        let sign = (value >> 31) & 1;
+        return (sign << 63) | 0x7FF0_0000_0000_0000 | f64::QUIET | (value & 0x7F_FFFF) << 29;
+    }
+    check_denormals(environment, &[single]);
+    (single as f64).raw()
+}
+
+pub fn narrow(environment: &Environment, value: u64) -> u64 {
+    let double = flush::<f64>(environment, value);
+    if check_nans(environment, &[double], false) {
+        let sign = value >> 63;
+        return (sign << 31) | 0x7F80_0000 | f32::QUIET | ((value >> 29) & 0x3F_FFFF);
+    }
+    check_denormals(environment, &[double]);
+    let nearest = double as f32;
+    // an infinite nearest from a finite double compares as the overflow it is
+    let error = double.partial_cmp(&(nearest as f64)).unwrap_or(Ordering::Equal);
+    finish(environment, nearest, error).raw()
+}
+
+// 0F 2A CVTSI2SS/SD, 0F 2C CVTTSS2SI/CVTTSD2SI, 0F 2D CVTSS2SI/CVTSD2SI (F3 and F2 only, the MMX forms are not supported),
+// 0F 5A CVTPS2PD/CVTPD2PS/CVTSS2SD/CVTSD2SS, 0F 5B CVTDQ2PS/CVTPS2DQ/CVTTPS2DQ and 0F E6 CVTTPD2DQ/CVTDQ2PD/CVTPD2DQ
+pub fn convert(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let prefix = instruction.mandatory_prefix();
+    let reg = instruction.reg();
+    let register = sse::read_xmm(cpu, reg);
+    let (dword, qword) = (OperandSize::Dword, OperandSize::Qword);
+    let environment = Environment::from_cpu(cpu);
+    let environment = &environment;
+    let result = match (instruction.opcode, prefix) {
+        (0x2A, MandatoryPrefix::PF3 | MandatoryPrefix::PF2) => {
+            let (float, _) = float_format(prefix);
+            let integer = sse::gpr_size(instruction);
+            let value = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), integer)?;
+            sse::set_lane(register, float, 0, from_integer(environment, value, integer, float))
+        }
+        (0x2C | 0x2D, MandatoryPrefix::PF3 | MandatoryPrefix::PF2) => {
+            let (float, _) = float_format(prefix);
+            let value = sse::read_rm(cpu, instruction, float.bytes(), false)? as u64;
+            let integer = to_integer(environment, value, float, sse::gpr_size(instruction), instruction.opcode == 0x2C);
+            environment.commit(cpu)?;
+            operand::write_gpr(cpu, instruction, reg, sse::gpr_size(instruction), integer);
+            return Ok(());
+        }
+        (0x5A, MandatoryPrefix::None) => {
+            let source = sse::read_rm(cpu, instruction, 8, false)?;
+            (0..2).fold(0, |result, i| sse::set_lane(result, qword, i, widen(environment, sse::lane(source, dword, i))))
+        }
+        (0x5A, MandatoryPrefix::P66) => {
+            let source = sse::read_rm(cpu, instruction, 16, true)?;
+            (0..2).fold(0, |result, i| sse::set_lane(result, dword, i, narrow(environment, sse::lane(source, qword, i))))
+        }
+        (0x5A, MandatoryPrefix::PF3) => {
+            let source = sse::read_rm(cpu, instruction, 4, false)? as u64;
+            sse::set_lane(register, qword, 0, widen(environment, source))
+        }
+        (0x5A, MandatoryPrefix::PF2) => {
+            let source = sse::read_rm(cpu, instruction, 8, false)? as u64;
+            sse::set_lane(register, dword, 0, narrow(environment, source))
+        }
+        (0x5B, MandatoryPrefix::None) => {
+            let source = sse::read_rm(cpu, instruction, 16, true)?;
+            sse::map_lanes(dword, source, 0, |x, _| from_integer(environment, x, dword, dword))
+        }
+        (0x5B, MandatoryPrefix::P66 | MandatoryPrefix::PF3) => {
+            let truncate = prefix == MandatoryPrefix::PF3;
+            let source = sse::read_rm(cpu, instruction, 16, true)?;
+            sse::map_lanes(dword, source, 0, |x, _| to_integer(environment, x, dword, dword, truncate))
+        }
+        (0xE6, MandatoryPrefix::P66 | MandatoryPrefix::PF2) => {
+            let truncate = prefix == MandatoryPrefix::P66;
+            let source = sse::read_rm(cpu, instruction, 16, true)?;
+            (0..2).fold(0, |result, i| {
+                sse::set_lane(result, dword, i, to_integer(environment, sse::lane(source, qword, i), qword, dword, truncate))
+            })
+        }
+        (0xE6, MandatoryPrefix::PF3) => {
+            let source = sse::read_rm(cpu, instruction, 8, false)?;
+            (0..2).fold(0, |result, i| sse::set_lane(result, qword, i, from_integer(environment, sse::lane(source, dword, i), dword, qword)))
+        }
+        _ => return Err(Exception::InvalidOpcode),
+    };
+    environment.commit(cpu)?;
+    sse::write_xmm(cpu, reg, result);
+    Ok(())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    use crate::registers::GPRName;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    // four singles packed into an XMM value, element 0 lowest
+    pub(crate) fn ps(values: [f32; 4]) -> u128 {
+        values.iter().enumerate().fold(0, |result, (i, value)| result | (value.to_bits() as u128) << (32 * i))
+    }
+
+    fn pd(values: [f64; 2]) -> u128 {
+        values[0].to_bits() as u128 | (values[1].to_bits() as u128) << 64
+    }
+
+    #[test]
+    fn packed_and_scalar_arithmetic() {
+        // addps xmm0, xmm1 / mulsd xmm2, [0x2000] / divpd xmm3, xmm4 / sqrtss xmm5, xmm5
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0x58, 0xC1,
+            0xF2, 0x0F, 0x59, 0x14, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x66, 0x0F, 0x5E, 0xDC,
+            0xF3, 0x0F, 0x51, 0xED,
+        ]);
+        sse::write_xmm(&mut cpu, 0, ps([1.0, 2.0, 3.0, 4.0]));
+        sse::write_xmm(&mut cpu, 1, ps([0.5, 0.25, -3.0, 1e30]));
+        sse::write_xmm(&mut cpu, 2, pd([3.0, 7.0]));
+        cpu.memory.write::<u64>(0x2000, 2.5f64.to_bits());
+        sse::write_xmm(&mut cpu, 3, pd([1.0, 0.0]));
+        sse::write_xmm(&mut cpu, 4, pd([0.0, 0.0]));
+        sse::write_xmm(&mut cpu, 5, ps([16.0, 9.0, 9.0, 9.0]));
+        cpu.run(4);
+        assert_eq!(sse::read_xmm(&cpu, 0), ps([1.5, 2.25, 0.0, 1e30]));
+        assert_eq!(sse::read_xmm(&cpu, 2), pd([7.5, 7.0]));
+        // 0/0 is invalid and gives the negative default NaN
+        assert_eq!(sse::read_xmm(&cpu, 3), f64::INFINITY.to_bits() as u128 | (f64::DEFAULT_NAN as u128) << 64);
+        assert_eq!(sse::read_xmm(&cpu, 5), ps([4.0, 9.0, 9.0, 9.0]));
+    }
+
+    #[test]
+    fn nan_min_max_and_logic() {
+        // minps xmm0, xmm1 / maxsd xmm2, xmm3 / addss xmm4, xmm1 / xorps xmm6, xmm6
+        let mut cpu = cpu_with_code(&[0x0F, 0x5D, 0xC1, 0xF2, 0x0F, 0x5F, 0xD3, 0xF3, 0x0F, 0x58, 0xE1, 0x0F, 0x57, 0xF6]);
+        let snan = f32::from_bits(0x7F80_0001);
+        sse::write_xmm(&mut cpu, 0, ps([1.0, f32::NAN, -0.0, 5.0]));
+        sse::write_xmm(&mut cpu, 1, ps([snan, 2.0, 0.0, 4.0]));
+        sse::write_xmm(&mut cpu, 2, pd([f64::NAN, 1.0]));
+        sse::write_xmm(&mut cpu, 3, pd([3.0, 2.0]));
+        sse::write_xmm(&mut cpu, 4, ps([1.0, 1.0, 1.0, 1.0]));
+        sse::write_xmm(&mut cpu, 6, u128::MAX);
+        cpu.run(4);
+        // the second source on NaNs and on equal zeros
+        assert_eq!(sse::read_xmm(&cpu, 0), ps([snan, 2.0, 0.0, 4.0]));
+        assert_eq!(sse::read_xmm(&cpu, 2), pd([3.0, 1.0]));
+        // an SNaN operand comes back quieted
+        assert_eq!(sse::lane(sse::read_xmm(&cpu, 4), OperandSize::Dword, 0), 0x7FC0_0001);
+        assert_eq!(sse::read_xmm(&cpu, 6), 0);
+    }
+
+    #[test]
+    fn compares_set_masks_and_flags() {
+        // cmpltps xmm0, xmm1 / cmpunordsd xmm2, xmm3 / ucomiss xmm4, xmm1 / comisd xmm4, xmm5
+        let mut cpu = cpu_with_code(&[
+            0x0F, 0xC2, 0xC1, 0x01,
+            0xF2, 0x0F, 0xC2, 0xD3, 0x03,
+            0x0F, 0x2E, 0xE1,
+            0x66, 0x0F, 0x2F, 0xE5,
+        ]);
+        sse::write_xmm(&mut cpu, 0, ps([1.0, 2.0, f32::NAN, 4.0]));
+        sse::write_xmm(&mut cpu, 1, ps([2.0, 2.0, 1.0, 3.0]));
+        sse::write_xmm(&mut cpu, 2, pd([1.0, 6.0]));
+        sse::write_xmm(&mut cpu, 3, pd([f64::NAN, 0.0]));
+        sse::write_xmm(&mut cpu, 4, pd([-1.0, 0.0]));
+        sse::write_xmm(&mut cpu, 5, pd([-1.0, 0.0]));
+        cpu.run(2);
+        assert_eq!(sse::read_xmm(&cpu, 0), 0x00000000_00000000_00000000_FFFFFFFF);
+        assert_eq!(sse::read_xmm(&cpu, 2), u64::MAX as u128 | (6f64.to_bits() as u128) << 64);
+        // xmm4 lane 0 is 0.0 as a float, below 2.0
+        cpu.run(1);
+        assert!(cpu.registers.get_flag(Flag::CF) && !cpu.registers.get_flag(Flag::ZF));
+        cpu.registers.set_flag(Flag::OF, true);
+        cpu.run(1);
+        assert!(cpu.registers.get_flag(Flag::ZF) && !cpu.registers.get_flag(Flag::CF));
+        assert!(!cpu.registers.get_flag(Flag::PF) && !cpu.registers.get_flag(Flag::OF));
+    }
+
+    #[test]
+    fn conversions() {
+        // cvtsi2sd xmm0, rax / cvttss2si ecx, xmm1 / cvtss2si rdx, xmm1 / cvtps2pd xmm2, xmm1 / cvtpd2ps xmm3, xmm2 / cvtps2dq xmm4, xmm1
+        let mut cpu = cpu_with_code(&[
+            0xF2, 0x48, 0x0F, 0x2A, 0xC0,
+            0xF3, 0x0F, 0x2C, 0xC9,
+            0xF3, 0x48, 0x0F, 0x2D, 0xD1,
+            0x0F, 0x5A, 0xD1,
+            0x66, 0x0F, 0x5A, 0xDA,
+            0x66, 0x0F, 0x5B, 0xE1,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RAX, (-3i64) as u64);
+        sse::write_xmm(&mut cpu, 0, pd([0.0, 8.0]));
+        sse::write_xmm(&mut cpu, 1, ps([-2.5, 1e10, 3.5, f32::NAN]));
+        sse::write_xmm(&mut cpu, 3, u128::MAX);
+        cpu.run(6);
+        assert_eq!(sse::read_xmm(&cpu, 0), pd([-3.0, 8.0]));
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), (-2i32) as u32 as u64);
+        // round to nearest even
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDX), (-2i64) as u64);
+        assert_eq!(sse::read_xmm(&cpu, 2), pd([-2.5, 1e10]));
+        assert_eq!(sse::read_xmm(&cpu, 3), ps([-2.5, 1e10, 0.0, 0.0]));
+        assert_eq!(sse::read_xmm(&cpu, 4), 0x80000000_00000004_80000000_FFFFFFFE);
+    }
+
+    #[test]
+    fn mxcsr_rounding_flags_and_exceptions() {
+        // addss xmm0, xmm1 / addss xmm2, xmm1 / mulss xmm3, xmm4 / addss xmm5, xmm6 (twice) / addss xmm7, xmm1
+        // cvtss2si eax, xmm6 / divss xmm5, xmm4
+        let mut cpu = cpu_with_code(&[
+            0xF3, 0x0F, 0x58, 0xC1,
+            0xF3, 0x0F, 0x58, 0xD1,
+            0xF3, 0x0F, 0x59, 0xDC,
+            0xF3, 0x0F, 0x58, 0xEE,
+            0xF3, 0x0F, 0x58, 0xEE,
+            0xF3, 0x0F, 0x58, 0xF9,
+            0xF3, 0x0F, 0x2D, 0xC6,
+            0xF3, 0x0F, 0x5E, 0xEC,
+        ]);
+        let lane = |cpu: &Cpu, index| sse::lane(sse::read_xmm(cpu, index), OperandSize::Dword, 0) as u32;
+        let flags = |cpu: &Cpu| cpu.registers.get_mxcsr() & 0x3F;
+        let masked = 0x1F80;
+        let denormal = f32::from_bits(1 << 9);
+        for index in [0, 1, 2] {
+            sse::write_xmm(&mut cpu, index, ps([f32::MAX; 4]));
+        }
+        sse::write_xmm(&mut cpu, 3, ps([2f32.powi(-70); 4]));
+        sse::write_xmm(&mut cpu, 4, ps([2f32.powi(-70); 4]));
+        sse::write_xmm(&mut cpu, 5, ps([1.0; 4]));
+        sse::write_xmm(&mut cpu, 6, ps([denormal; 4]));
+        sse::write_xmm(&mut cpu, 7, ps([f32::from_bits(0x7F80_0001); 4]));
+
+        // a masked overflow is infinity rounding to nearest and the largest finite value rounding toward zero
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 0), f32::INFINITY.to_bits());
+        assert_eq!(flags(&cpu), (mxcsr::OVERFLOW | mxcsr::PRECISION));
+        cpu.registers.set_mxcsr(masked | 3 << 13);
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 2), f32::MAX.to_bits());
+        assert_eq!(flags(&cpu), (mxcsr::OVERFLOW | mxcsr::PRECISION));
+
+        // 2^-140 is tiny, FTZ flushes it
+        cpu.registers.set_mxcsr(masked | 1 << 15);
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 3), 0);
+        assert_eq!(flags(&cpu), (mxcsr::UNDERFLOW | mxcsr::PRECISION));
+
+        // a denormal source flags DE, and is lost to rounding; DAZ reads it as zero and the sum is exact
+        cpu.registers.set_mxcsr(masked);
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 5), 1f32.to_bits());
+        assert_eq!(flags(&cpu), (mxcsr::DENORMAL | mxcsr::PRECISION));
+        cpu.registers.set_mxcsr(masked | 1 << 6);
+        cpu.run(1);
+        assert_eq!(flags(&cpu), 0);
+
+        // an SNaN comes back quieted with IE set
+        cpu.registers.set_mxcsr(masked);
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 7), 0x7FC0_0001);
+        assert_eq!(flags(&cpu), mxcsr::INVALID);
+
+        // CVTSS2SI follows RC
+        cpu.registers.set_mxcsr(masked | 2 << 13);
+        sse::write_xmm(&mut cpu, 6, ps([2.5; 4]));
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 3);
+        assert_eq!(flags(&cpu), mxcsr::PRECISION);
+
+        // unmasked divide-by-zero raises #XM and leaves the destination alone
+        cpu.registers.set_mxcsr(masked & !(mxcsr::DIVIDE_BY_ZERO << 7));
+        sse::write_xmm(&mut cpu, 4, 0);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::SIMDFloatingPoint));
+        assert_eq!(lane(&cpu, 5), 1f32.to_bits());
+        assert_eq!(flags(&cpu), mxcsr::DIVIDE_BY_ZERO);
+    }
+
+    #[test]
+    fn directed_rounding_of_products_and_quotients() {
+        let environment = |rc: u32| Environment::new(0x1F80 | rc << 13);
+        let third = |rc| f64::from_raw(apply(FloatOp::Div, OperandSize::Qword, 1f64.raw(), 3f64.raw(), &environment(rc)));
+        assert!(third(1) < third(2));
+        assert_eq!(third(1), third(3));
+        // products below the normal range keep their rounding direction
+        let tiny = f64::from_bits(3);
+        let half = |rc| apply(FloatOp::Mul, OperandSize::Qword, tiny.raw(), 0.5f64.raw(), &environment(rc));
+        assert_eq!((half(0), half(1), half(2), half(3)), (2, 1, 2, 1));
+    }
+
+    #[test]
+    fn directed_rounding_of_denormal_square_roots() {
+        // sqrtsd xmm0, xmm1 / sqrtsd xmm2, xmm1
+        let mut cpu = cpu_with_code(&[0xF2, 0x0F, 0x51, 0xC1, 0xF2, 0x0F, 0x51, 0xD1]);
+        let lane = |cpu: &Cpu, index| sse::lane(sse::read_xmm(cpu, index), OperandSize::Qword, 0);
+        // the root of 2^-1073 is irrational, its residue is far below the smallest denormal
+        sse::write_xmm(&mut cpu, 1, 2);
+        cpu.registers.set_mxcsr(0x1F80 | 2 << 13);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_mxcsr() & 0x3F, mxcsr::DENORMAL | mxcsr::PRECISION);
+        cpu.registers.set_mxcsr(0x1F80 | 1 << 13);
+        cpu.run(1);
+        assert_eq!(lane(&cpu, 0), lane(&cpu, 2) + 1);
+    }
+}
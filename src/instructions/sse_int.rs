@@ -0,0 +1,243 @@
+// SSE2 packed integer instructions on XMM: arithmetic, saturation, compares, packs, unpacks and shifts
+// only the 66-prefixed forms exist here, the unprefixed MMX encodings are not supported and raise #UD
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::sse;
+
+const BYTE: OperandSize = OperandSize::Byte;
+const WORD: OperandSize = OperandSize::Word;
+const DWORD: OperandSize = OperandSize::Dword;
+const QWORD: OperandSize = OperandSize::Qword;
+
+// lane size of the B/W/D/Q opcode rows, from the low two opcode bits
+fn size_of(index: u8) -> OperandSize {
+    match index & 3 {
+        0 => BYTE,
+        1 => WORD,
+        2 => DWORD,
+        _ => QWORD,
+    }
+}
+
+fn signed(size: OperandSize, value: u64) -> i64 {
+    size.sign_extend(value) as i64
+}
+
+fn saturate_signed(size: OperandSize, value: i64) -> u64 {
+    let max = (size.sign_bit() - 1) as i64;
+    value.clamp(-max - 1, max) as u64
+}
+
+fn saturate_unsigned(size: OperandSize, value: i64) -> u64 {
+    value.clamp(0, size.mask() as i64) as u64
+}
+
+// a's lanes narrowed into the low half, b's into the high half
+fn pack(from: OperandSize, to: OperandSize, a: u128, b: u128, saturate: impl Fn(i64) -> u64) -> u128 {
+    let count = sse::lane_count(from);
+    (0..2 * count).fold(0, |result, i| {
+        let source = if i < count { a } else { b };
+        sse::set_lane(result, to, i, saturate(signed(from, sse::lane(source, from, i % count))))
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftKind {
+    Left, Right, Arithmetic
+}
+
+// counts past the lane width clear it, or fill it with the sign for arithmetic shifts
+pub fn shift(size: OperandSize, kind: ShiftKind, value: u128, count: u64) -> u128 {
+    let bits = size.bits() as u64;
+    sse::map_lanes(size, value, 0, |lane, _| match kind {
+        ShiftKind::Left if count < bits => lane << count,
+        ShiftKind::Right if count < bits => lane >> count,
+        ShiftKind::Arithmetic => (signed(size, lane) >> count.min(bits - 1)) as u64,
+        _ => 0,
+    })
+}
+
+// PSLLDQ/PSRLDQ shift the whole register by bytes
+pub fn shift_bytes(value: u128, count: u64, left: bool) -> u128 {
+    match (count, left) {
+        (16.., _) => 0,
+        (_, true) => value << (count * 8),
+        (_, false) => value >> (count * 8),
+    }
+}
+
+// the result of a 66 0F xx packed integer opcode, None for opcodes that are not one
+pub fn compute(opcode: u8, a: u128, b: u128) -> Option<u128> {
+    let count = b as u64;
+    Some(match opcode {
+        0x60..=0x62 => sse::interleave(size_of(opcode), a, b, false),
+        0x68..=0x6A => sse::interleave(size_of(opcode), a, b, true),
+        0x6C | 0x6D => sse::interleave(QWORD, a, b, opcode == 0x6D),
+        0x63 => pack(WORD, BYTE, a, b, |x| saturate_signed(BYTE, x)),
+        0x67 => pack(WORD, BYTE, a, b, |x| saturate_unsigned(BYTE, x)),
+        0x6B => pack(DWORD, WORD, a, b, |x| saturate_signed(WORD, x)),
+        0x64..=0x66 => {
+            let size = size_of(opcode);
+            sse::map_lanes(size, a, b, |x, y| if signed(size, x) > signed(size, y) { size.mask() } else { 0 })
+        }
+        0x74..=0x76 => {
+            let size = size_of(opcode);
+            sse::map_lanes(size, a, b, |x, y| if x == y { size.mask() } else { 0 })
+        }
+        0xD1..=0xD3 => shift(size_of(opcode), ShiftKind::Right, a, count),
+        0xE1 | 0xE2 => shift(size_of(opcode), ShiftKind::Arithmetic, a, count),
+        0xF1..=0xF3 => shift(size_of(opcode), ShiftKind::Left, a, count),
+        0xD4 => sse::map_lanes(QWORD, a, b, u64::wrapping_add),
+        0xFB => sse::map_lanes(QWORD, a, b, u64::wrapping_sub),
+        0xFC..=0xFE => sse::map_lanes(size_of(opcode), a, b, u64::wrapping_add),
+        0xF8..=0xFA => sse::map_lanes(size_of(opcode), a, b, u64::wrapping_sub),
+        0xD5 => sse::map_lanes(WORD, a, b, |x, y| x.wrapping_mul(y)),
+        0xE5 => sse::map_lanes(WORD, a, b, |x, y| ((signed(WORD, x) * signed(WORD, y)) >> 16) as u64),
+        0xE4 => sse::map_lanes(WORD, a, b, |x, y| (x * y) >> 16),
+        0xF4 => sse::map_lanes(QWORD, a, b, |x, y| (x & 0xFFFF_FFFF) * (y & 0xFFFF_FFFF)),
+        0xF5 => sse::map_lanes(DWORD, a, b, |x, y| {
+            let product = |shift: u32| signed(WORD, x >> shift) * signed(WORD, y >> shift);
+            product(0).wrapping_add(product(16)) as u64
+        }),
+        0xF6 => sse::map_lanes(QWORD, a, b, |x, y| {
+            (0..8).map(|i| ((x >> (8 * i)) as u8).abs_diff((y >> (8 * i)) as u8) as u64).sum()
+        }),
+        0xD8 | 0xD9 => {
+            let size = if opcode == 0xD8 { BYTE } else { WORD };
+            sse::map_lanes(size, a, b, u64::saturating_sub)
+        }
+        0xDC | 0xDD => {
+            let size = if opcode == 0xDC { BYTE } else { WORD };
+            sse::map_lanes(size, a, b, |x, y| saturate_unsigned(size, (x + y) as i64))
+        }
+        0xE8 | 0xE9 | 0xEC | 0xED => {
+            let size = if opcode & 1 == 0 { BYTE } else { WORD };
+            let add = opcode >= 0xEC;
+            sse::map_lanes(size, a, b, |x, y| {
+                let (x, y) = (signed(size, x), signed(size, y));
+                saturate_signed(size, if add { x + y } else { x - y })
+            })
+        }
+        0xDA => sse::map_lanes(BYTE, a, b, u64::min),
+        0xDE => sse::map_lanes(BYTE, a, b, u64::max),
+        0xEA => sse::map_lanes(WORD, a, b, |x, y| if signed(WORD, x) < signed(WORD, y) { x } else { y }),
+        0xEE => sse::map_lanes(WORD, a, b, |x, y| if signed(WORD, x) > signed(WORD, y) { x } else { y }),
+        0xE0 => sse::map_lanes(BYTE, a, b, |x, y| (x + y + 1) >> 1),
+        0xE3 => sse::map_lanes(WORD, a, b, |x, y| (x + y + 1) >> 1),
+        0xDB => a & b,
+        0xDF => !a & b,
+        0xEB => a | b,
+        0xEF => a ^ b,
+        _ => return None,
+    })
+}
+
+// 66 0F 60..6D, 74..76 and D1..FE: PUNPCK*, PACK*, PCMPGT*/PCMPEQ*, PADD*/PSUB* and their saturating forms,
+// PMUL*, PMADDWD, PSADBW, PMIN*/PMAX*, PAVG*, the shifts by an XMM count and PAND/PANDN/POR/PXOR
+pub fn packed(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let b = sse::read_rm(cpu, instruction, 16, true)?;
+    let a = sse::read_xmm(cpu, instruction.reg());
+    let result = compute(instruction.opcode, a, b).ok_or(Exception::InvalidOpcode)?;
+    sse::write_xmm(cpu, instruction.reg(), result);
+    Ok(())
+}
+
+// the shift a 66 0F 71/72/73 /digit ib encodes, applied to value
+pub fn shift_by_immediate(opcode: u8, group: u8, value: u128, count: u64) -> Option<u128> {
+    Some(match (opcode, group) {
+        (0x71..=0x73, 2) => shift(size_of(opcode - 0x70), ShiftKind::Right, value, count),
+        (0x71 | 0x72, 4) => shift(size_of(opcode - 0x70), ShiftKind::Arithmetic, value, count),
+        (0x71..=0x73, 6) => shift(size_of(opcode - 0x70), ShiftKind::Left, value, count),
+        (0x73, 3) => shift_bytes(value, count, false),
+        (0x73, 7) => shift_bytes(value, count, true),
+        _ => return None,
+    })
+}
+
+// 66 0F 71 PSRLW/PSRAW/PSLLW, 72 PSRLD/PSRAD/PSLLD, 73 PSRLQ/PSRLDQ/PSLLQ/PSLLDQ by imm8 on an XMM register
+pub fn shift_immediate(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.mandatory_prefix() != MandatoryPrefix::P66 || instruction.has_memory_operand() {
+        return Err(Exception::InvalidOpcode);
+    }
+    let value = sse::read_xmm(cpu, instruction.rm());
+    let result = shift_by_immediate(instruction.opcode, instruction.group_index(), value, instruction.immediate_value())
+        .ok_or(Exception::InvalidOpcode)?;
+    sse::write_xmm(cpu, instruction.rm(), result);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    fn bytes(values: [u8; 16]) -> u128 {
+        u128::from_le_bytes(values)
+    }
+
+    fn words(values: [u16; 8]) -> u128 {
+        values.iter().enumerate().fold(0, |result, (i, value)| result | (*value as u128) << (16 * i))
+    }
+
+    #[test]
+    fn arithmetic_and_saturation() {
+        let a = bytes([250, 5, 0x80, 0x7F, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        let b = bytes([10, 10, 0xFF, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(compute(0xFC, a, b), Some(bytes([4, 15, 0x7F, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2])));
+        assert_eq!(compute(0xDC, a, b), Some(bytes([255, 15, 0xFF, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2])));
+        assert_eq!(compute(0xD8, a, b), Some(bytes([240, 0, 0, 0x7E, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0])));
+        // -128 + -1 and 127 + 1 clamp
+        assert_eq!(compute(0xEC, a, b).map(|r| r & 0xFFFF_FFFF), Some(0x7F_80_0F_04));
+        let a = words([0x8000, 3, 0xFFFF, 0x7FFF, 0, 0, 0, 0]);
+        let b = words([2, 0xFFFF, 0xFFFF, 2, 0, 0, 0, 0]);
+        assert_eq!(compute(0xD5, a, b), Some(words([0, 0xFFFD, 1, 0xFFFE, 0, 0, 0, 0])));
+        assert_eq!(compute(0xE5, a, b), Some(words([0xFFFF, 0xFFFF, 0, 0, 0, 0, 0, 0])));
+        assert_eq!(compute(0xE4, a, b), Some(words([1, 2, 0xFFFE, 0, 0, 0, 0, 0])));
+        assert_eq!(compute(0xF5, a, b).map(|r| r as u64), Some(0x0000FFFF_FFFEFFFD));
+        assert_eq!(compute(0x00, a, b), None);
+    }
+
+    #[test]
+    fn compares_packs_and_shifts() {
+        let a = words([1, 0xFFFF, 0x8000, 300, 0, 0, 0, 0]);
+        let b = words([1, 1, 0x7FFF, 300, 0, 0, 0, 0]);
+        assert_eq!(compute(0x75, a, b), Some(words([0xFFFF, 0, 0, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF, 0xFFFF])));
+        assert_eq!(compute(0x65, a, b), Some(0));
+        assert_eq!(compute(0x65, b, a), Some(words([0, 0xFFFF, 0xFFFF, 0, 0, 0, 0, 0])));
+        // signed saturation to bytes, then unsigned
+        assert_eq!(compute(0x63, a, 0).map(|r| r as u32), Some(0x7F_80_FF_01));
+        assert_eq!(compute(0x67, a, 0).map(|r| r as u32), Some(0xFF_00_00_01));
+        assert_eq!(shift(WORD, ShiftKind::Arithmetic, a, 20), words([0, 0xFFFF, 0xFFFF, 0, 0, 0, 0, 0]));
+        assert_eq!(shift(WORD, ShiftKind::Left, a, 16), 0);
+        assert_eq!(shift_by_immediate(0x73, 3, a, 2), Some(a >> 16));
+        assert_eq!(shift_by_immediate(0x72, 3, a, 2), None);
+    }
+
+    #[test]
+    fn executes_from_xmm_and_memory() {
+        // paddd xmm0, [0x2000] / pcmpeqb xmm1, xmm1 / psrlq xmm1, 60 / punpcklqdq xmm0, xmm1 / paddb xmm0, [0x2008]
+        let mut cpu = cpu_with_code(&[
+            0x66, 0x0F, 0xFE, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0x66, 0x0F, 0x74, 0xC9,
+            0x66, 0x0F, 0x73, 0xD1, 0x3C,
+            0x66, 0x0F, 0x6C, 0xC1,
+            0x66, 0x0F, 0xFC, 0x04, 0x25, 0x08, 0x20, 0x00, 0x00,
+        ]);
+        sse::write_xmm(&mut cpu, 0, 0x00000004_00000003_00000002_FFFFFFFF);
+        cpu.memory.write::<u128>(0x2000, 0x00000001_00000001_00000001_00000001);
+        cpu.run(4);
+        assert_eq!(sse::read_xmm(&cpu, 1), 0xF << 64 | 0xF);
+        assert_eq!(sse::read_xmm(&cpu, 0), 0xF << 64 | 0x00000003_00000000);
+        // misaligned memory operands fault
+        assert_eq!(cpu.run(1), crate::cpu::StopReason::Fault(Exception::GeneralProtection(0)));
+    }
+}
@@ -0,0 +1,236 @@
+// stack instructions: PUSH, POP, PUSHF, POPF, ENTER and LEAVE
+// in 64-bit mode stack operations are 64-bit unless a 66 prefix makes them 16-bit
+
+use crate::registers::GPRName;
+use crate::registers::FLAGSName;
+use crate::registers::Flag;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::operand;
+
+// RFLAGS bits POPF can change at CPL 0, IF and IOPL are further restricted by privilege
+const POPF_MASK: u64 = Flag::CF.mask() | Flag::PF.mask() | Flag::AF.mask() | Flag::ZF.mask() | Flag::SF.mask()
+    | Flag::TF.mask() | Flag::IF.mask() | Flag::DF.mask() | Flag::OF.mask() | IOPL_MASK | Flag::NT.mask()
+    | Flag::AC.mask() | Flag::ID.mask();
+const IOPL_MASK: u64 = 3 << 12;
+
+// REX.W does not matter, only 66 selects the 16-bit form
+pub fn stack_operand_size(instruction: &Instruction) -> OperandSize {
+    if instruction.prefixes.operand_size && !instruction.rex.is_some_and(|rex| rex.w) {
+        OperandSize::Word
+    } else {
+        OperandSize::Qword
+    }
+}
+
+// RSP only moves once the write succeeded, so a faulting push can be restarted
+pub fn push(cpu: &mut Cpu, size: OperandSize, value: u64) -> Result<(), Exception> {
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP).wrapping_sub(size.bytes() as u64);
+    operand::write_memory_sized(cpu, rsp, size, value)?;
+    cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+    Ok(())
+}
+
+pub fn pop(cpu: &mut Cpu, size: OperandSize) -> Result<u64, Exception> {
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    let value = operand::read_memory_sized(cpu, rsp, size)?;
+    cpu.registers.set_gpr_value(GPRName::RSP, rsp.wrapping_add(size.bytes() as u64));
+    Ok(value)
+}
+
+// 50+r
+pub fn push_register(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let value = operand::read_gpr(cpu, instruction, instruction.opcode_reg(), size);
+    push(cpu, size, value)
+}
+
+// 58+r
+pub fn pop_register(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let value = pop(cpu, size)?;
+    operand::write_gpr(cpu, instruction, instruction.opcode_reg(), size, value);
+    Ok(())
+}
+
+// 68 Iz / 6A Ib, sign-extended to the stack operand size
+pub fn push_immediate(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    push(cpu, stack_operand_size(instruction), instruction.immediate_sign_extended())
+}
+
+// FF /6
+pub fn push_rm(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let value = operand::read_operand(cpu, instruction, operand::rm_operand(cpu, instruction), size)?;
+    push(cpu, size, value)
+}
+
+// 8F /0, a memory destination is addressed with RSP already incremented
+pub fn pop_rm(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if instruction.group_index() != 0 {
+        return Err(Exception::InvalidOpcode);
+    }
+    let size = stack_operand_size(instruction);
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    let value = pop(cpu, size)?;
+    let destination = operand::rm_operand(cpu, instruction);
+    operand::write_operand(cpu, instruction, destination, size, value).inspect_err(|_| {
+        cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+    })
+}
+
+// 9C, the pushed image has RF and VM cleared
+pub fn pushf(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let rflags = cpu.registers.get_flags_value(FLAGSName::RFLAGS) & !(Flag::RF.mask() | Flag::VM.mask());
+    push(cpu, stack_operand_size(instruction), rflags)
+}
+
+// 9D
+pub fn popf(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let value = pop(cpu, size)?;
+    let mut mask = POPF_MASK & size.mask();
+    let cpl = cpu.cpl() as u64;
+    if cpl > 0 {
+        mask &= !IOPL_MASK;
+    }
+    if cpl > cpu.registers.get_iopl() as u64 {
+        mask &= !Flag::IF.mask();
+    }
+    let rflags = cpu.registers.get_flags_value(FLAGSName::RFLAGS);
+    // RF is always cleared, bit 1 always set
+    let rflags = ((rflags & !mask) | (value & mask) | 2) & !Flag::RF.mask();
+    cpu.registers.set_flags_value(FLAGSName::RFLAGS, rflags);
+    Ok(())
+}
+
+// C8 Iw,Ib: push RBP, copy `level - 1` outer frame pointers, then reserve the frame
+pub fn enter(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let frame_size = instruction.immediate_value();
+    let level = instruction.immediate2.map_or(0, |imm| imm.value) & 31;
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    let rbp = cpu.registers.get_gpr_value(GPRName::RBP);
+
+    let result = (|| {
+        push(cpu, size, rbp)?;
+        let frame = cpu.registers.get_gpr_value(GPRName::RSP);
+        if level > 0 {
+            let mut pointer = rbp;
+            for _ in 1..level {
+                pointer = pointer.wrapping_sub(size.bytes() as u64);
+                let value = operand::read_memory_sized(cpu, pointer, size)?;
+                push(cpu, size, value)?;
+            }
+            push(cpu, size, frame)?;
+        }
+        Ok(frame)
+    })();
+    match result {
+        Ok(frame) => {
+            operand::write_gpr64(cpu, 5, match size {
+                OperandSize::Word => (rbp & !0xFFFF) | (frame & 0xFFFF),
+                _ => frame,
+            });
+            let rsp = cpu.registers.get_gpr_value(GPRName::RSP).wrapping_sub(frame_size);
+            cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+            Ok(())
+        }
+        // nothing changes when a push faults
+        Err(exception) => {
+            cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+            Err(exception)
+        }
+    }
+}
+
+// C9: RSP = RBP, then pop RBP
+pub fn leave(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let size = stack_operand_size(instruction);
+    let rsp = cpu.registers.get_gpr_value(GPRName::RSP);
+    cpu.registers.set_gpr_value(GPRName::RSP, cpu.registers.get_gpr_value(GPRName::RBP));
+    match pop(cpu, size) {
+        Ok(value) => {
+            operand::write_gpr(cpu, instruction, 5, size, value);
+            Ok(())
+        }
+        Err(exception) => {
+            cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+            Err(exception)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::FLAGSName;
+
+    use crate::cpu::tests::cpu_with_code;
+
+    const STACK: u64 = 0x8000;
+
+    #[test]
+    fn push_and_pop_forms() {
+        // push rbx / push -2 / push word 0x1234 / pop r9w / pop qword [rsp] / push qword [rsp] / pop rax / pop rcx
+        let mut cpu = cpu_with_code(&[
+            0x53,
+            0x6A, 0xFE,
+            0x66, 0x68, 0x34, 0x12,
+            0x66, 0x41, 0x59,
+            0x8F, 0x44, 0x24, 0x00,
+            0xFF, 0x34, 0x24,
+            0x58,
+            0x59,
+        ]);
+        cpu.registers.set_gpr_value(GPRName::RSP, STACK);
+        cpu.registers.set_gpr_value(GPRName::RBX, 0x1122334455667788);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK - 18);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 16), u64::MAX - 1);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::R9), 0x1234);
+        // pop [rsp]: the -2 moves onto the slot of rbx, which is where RSP points afterwards
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK - 8);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 8), u64::MAX - 1);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), u64::MAX - 1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), u64::MAX - 1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK);
+    }
+
+    #[test]
+    fn pushf_popf_and_frames() {
+        // pushfq / popfq / enter 0x20, 0 / leave / enter 8, 2
+        let mut cpu = cpu_with_code(&[0x9C, 0x9D, 0xC8, 0x20, 0x00, 0x00, 0xC9, 0xC8, 0x08, 0x00, 0x02]);
+        cpu.registers.set_gpr_value(GPRName::RSP, STACK);
+        cpu.registers.set_gpr_value(GPRName::RBP, 0x9000);
+        cpu.memory.write::<u64>(0x8FF8, 0xF00D);
+        cpu.registers.set_flags_value(FLAGSName::RFLAGS, 0x10000 | 0x8D5);
+        cpu.run(1);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 8), 0x8D5);
+        cpu.memory.write::<u64>(STACK as usize - 8, 0x3FFFFF);
+        cpu.run(1);
+        // reserved bits, VM, VIF and VIP keep their values and RF is cleared
+        assert_eq!(cpu.registers.get_flags_value(FLAGSName::RFLAGS), 0x247FD7);
+
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBP), STACK - 8);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK - 8 - 0x20);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBP), 0x9000);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK);
+
+        // level 2 copies one outer frame pointer and pushes the new frame
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBP), STACK - 8);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 16), 0xF00D);
+        assert_eq!(cpu.memory.read::<u64>(STACK as usize - 24), STACK - 8);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), STACK - 32);
+    }
+}
@@ -0,0 +1,284 @@
+// string instructions: MOVS, CMPS, STOS, LODS and SCAS with their REP/REPE/REPNE forms
+// the source is seg:RSI (DS unless overridden), the destination always ES:RDI, and the address size
+// selects RSI/RDI/RCX or ESI/EDI/ECX
+
+use crate::registers::Flag;
+
+use crate::paging::PAGE_SIZE;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::flags;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::RepPrefix;
+use crate::instructions::operand;
+use crate::instructions::alu::check_lock;
+
+const RCX: u8 = 1;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+
+// rep movsb/stosb with at least this many bytes left go through the bulk byte accessors
+const BULK_THRESHOLD: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StringOp {
+    Movs, Cmps, Stos, Lods, Scas
+}
+
+impl StringOp {
+    fn uses_source(self) -> bool {
+        matches!(self, StringOp::Movs | StringOp::Cmps | StringOp::Lods)
+    }
+
+    fn uses_destination(self) -> bool {
+        !matches!(self, StringOp::Lods)
+    }
+
+    // only CMPS and SCAS look at REPE/REPNE, the others treat both as REP
+    fn compares(self) -> bool {
+        matches!(self, StringOp::Cmps | StringOp::Scas)
+    }
+}
+
+// one element, then RSI and/or RDI move by the element size in the direction DF selects
+fn element(cpu: &mut Cpu, instruction: &Instruction, op: StringOp, size: OperandSize) -> Result<(), Exception> {
+    let address_size = instruction.address_size();
+    let rsi = operand::read_gpr(cpu, instruction, RSI, address_size);
+    let rdi = operand::read_gpr(cpu, instruction, RDI, address_size);
+    let source = operand::linear_address(cpu, instruction, rsi);
+    // ES cannot be overridden and has a zero base in 64-bit mode
+    let destination = rdi;
+    match op {
+        StringOp::Movs => {
+            let value = operand::read_memory_sized(cpu, source, size)?;
+            operand::write_memory_sized(cpu, destination, size, value)?;
+        }
+        StringOp::Cmps => {
+            let a = operand::read_memory_sized(cpu, source, size)?;
+            let b = operand::read_memory_sized(cpu, destination, size)?;
+            flags::sub(size, a, b, false).1.apply(&mut cpu.registers);
+        }
+        StringOp::Stos => {
+            let value = operand::read_gpr(cpu, instruction, 0, size);
+            operand::write_memory_sized(cpu, destination, size, value)?;
+        }
+        StringOp::Lods => {
+            let value = operand::read_memory_sized(cpu, source, size)?;
+            operand::write_gpr(cpu, instruction, 0, size, value);
+        }
+        StringOp::Scas => {
+            let a = operand::read_gpr(cpu, instruction, 0, size);
+            let b = operand::read_memory_sized(cpu, destination, size)?;
+            flags::sub(size, a, b, false).1.apply(&mut cpu.registers);
+        }
+    }
+
+    let delta = if cpu.registers.get_flag(Flag::DF) {
+        (size.bytes() as u64).wrapping_neg()
+    } else {
+        size.bytes() as u64
+    };
+    if op.uses_source() {
+        operand::write_gpr(cpu, instruction, RSI, address_size, rsi.wrapping_add(delta));
+    }
+    if op.uses_destination() {
+        operand::write_gpr(cpu, instruction, RDI, address_size, rdi.wrapping_add(delta));
+    }
+    Ok(())
+}
+
+// forward rep movsb/stosb a page-sized chunk at a time; a chunk that would fault, wrap the address
+// size or overlap its source the way a byte-at-a-time copy could observe is left to the element loop,
+// which then stops on the exact faulting byte
+fn bulk(cpu: &mut Cpu, instruction: &Instruction, op: StringOp) {
+    let address_size = instruction.address_size();
+    let mask = address_size.mask();
+    let mut buffer = [0u8; PAGE_SIZE];
+    if op == StringOp::Stos {
+        buffer.fill(operand::read_gpr(cpu, instruction, 0, OperandSize::Byte) as u8);
+    }
+    loop {
+        let count = operand::read_gpr(cpu, instruction, RCX, address_size);
+        let rsi = operand::read_gpr(cpu, instruction, RSI, address_size);
+        let rdi = operand::read_gpr(cpu, instruction, RDI, address_size);
+        let mut chunk = count.min(PAGE_SIZE as u64).min((mask - rdi).saturating_add(1));
+        if op == StringOp::Movs {
+            chunk = chunk.min((mask - rsi).saturating_add(1));
+        }
+        if chunk < BULK_THRESHOLD {
+            return;
+        }
+        let length = chunk as usize;
+        if op == StringOp::Movs {
+            let source = operand::linear_address(cpu, instruction, rsi);
+            if rdi > source && rdi - source < chunk {
+                return;
+            }
+            if cpu.read_bytes(source, &mut buffer[..length]).is_err() {
+                return;
+            }
+        }
+        if cpu.write_bytes(rdi, &buffer[..length]).is_err() {
+            return;
+        }
+        if op == StringOp::Movs {
+            operand::write_gpr(cpu, instruction, RSI, address_size, rsi.wrapping_add(chunk));
+        }
+        operand::write_gpr(cpu, instruction, RDI, address_size, rdi.wrapping_add(chunk));
+        operand::write_gpr(cpu, instruction, RCX, address_size, count - chunk);
+    }
+}
+
+// A4/A5 MOVS, A6/A7 CMPS, AA/AB STOS, AC/AD LODS, AE/AF SCAS
+// a fault part way through a REP leaves RSI/RDI/RCX at the faulting element so the instruction restarts there
+pub fn string(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    check_lock(instruction, false)?;
+    let op = match instruction.opcode & !1 {
+        0xA4 => StringOp::Movs,
+        0xA6 => StringOp::Cmps,
+        0xAA => StringOp::Stos,
+        0xAC => StringOp::Lods,
+        _ => StringOp::Scas,
+    };
+    let size = operand::byte_or_full(instruction);
+    let rep = match instruction.prefixes.rep {
+        Some(rep) => rep,
+        None => return element(cpu, instruction, op, size),
+    };
+
+    let address_size = instruction.address_size();
+    if size == OperandSize::Byte && matches!(op, StringOp::Movs | StringOp::Stos) && !cpu.registers.get_flag(Flag::DF) {
+        bulk(cpu, instruction, op);
+    }
+    loop {
+        let count = operand::read_gpr(cpu, instruction, RCX, address_size);
+        if count == 0 {
+            return Ok(());
+        }
+        element(cpu, instruction, op, size)?;
+        operand::write_gpr(cpu, instruction, RCX, address_size, count - 1);
+        if op.compares() && cpu.registers.get_flag(Flag::ZF) != (rep == RepPrefix::Rep) {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::registers::GPRName;
+    use crate::registers::IPName;
+    use crate::registers::Flag;
+
+    use crate::memory::Perms;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+    use crate::cpu::tests::CODE;
+
+    #[test]
+    fn single_elements_follow_df() {
+        // movsd / lodsw / std / stosq / scasb
+        let mut cpu = cpu_with_code(&[0xA5, 0x66, 0xAD, 0xFD, 0x48, 0xAB, 0xAE]);
+        cpu.memory.write::<u32>(0x2000, 0xDDCCBBAA);
+        cpu.memory.write::<u16>(0x2004, 0x1234);
+        cpu.registers.set_gpr_value(GPRName::RSI, 0x2000);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x3000);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xFFFF_FFFF_FFFF_0000);
+        cpu.run(2);
+        assert_eq!(cpu.memory.read::<u32>(0x3000), 0xDDCCBBAA);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0xFFFF_FFFF_FFFF_1234);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSI), 0x2006);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x3004);
+        cpu.run(2);
+        assert_eq!(cpu.memory.read::<u64>(0x3004), 0xFFFF_FFFF_FFFF_1234);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x3004 - 8);
+        cpu.run(1);
+        // AL 0x34 - 0x00 at 0x2FFC
+        assert!(!cpu.registers.get_flag(Flag::ZF));
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x3004 - 9);
+    }
+
+    #[test]
+    fn repe_and_repne_stop_on_zf() {
+        // repe cmpsb / repne scasw
+        let mut cpu = cpu_with_code(&[0xF3, 0xA6, 0x66, 0xF2, 0xAF]);
+        cpu.memory.write_vec::<u8>(0x2000, b"abcdef".to_vec());
+        cpu.memory.write_vec::<u8>(0x3000, b"abcxef".to_vec());
+        cpu.registers.set_gpr_value(GPRName::RSI, 0x2000);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x3000);
+        cpu.registers.set_gpr_value(GPRName::RCX, 6);
+        cpu.run(1);
+        // stops after the mismatching fourth byte
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 2);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSI), 0x2004);
+        assert!(!cpu.registers.get_flag(Flag::ZF));
+
+        cpu.memory.write_vec::<u16>(0x4000, vec![1, 2, 3, 4]);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x4000);
+        cpu.registers.set_gpr_value(GPRName::RAX, 3);
+        cpu.registers.set_gpr_value(GPRName::RCX, 100);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x4006);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 97);
+        assert!(cpu.registers.get_flag(Flag::ZF));
+    }
+
+    #[test]
+    fn bulk_copy_and_fill() {
+        // rep movsb / rep stosb / rep movsb with the destination one byte into the source
+        let mut cpu = cpu_with_code(&[0xF3, 0xA4, 0xF3, 0xAA, 0xF3, 0xA4]);
+        let data: Vec<u8> = (0..10000u32).map(|i| (i * 7) as u8).collect();
+        cpu.memory.write_vec::<u8>(0x10010, data.clone());
+        cpu.registers.set_gpr_value(GPRName::RSI, 0x10010);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x20FFF);
+        cpu.registers.set_gpr_value(GPRName::RCX, data.len() as u64);
+        cpu.run(1);
+        assert_eq!(cpu.memory.read_vec::<u8>(0x20FFF, data.len()), data);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSI), 0x10010 + 10000);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x20FFF + 10000);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0);
+
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x5A);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x30000);
+        cpu.registers.set_gpr_value(GPRName::RCX, 5000);
+        cpu.run(1);
+        assert_eq!(cpu.memory.read_vec::<u8>(0x30000, 5001), [vec![0x5A; 5000], vec![0]].concat());
+
+        // the overlapping copy replicates the first byte, as a byte-at-a-time copy would
+        cpu.registers.set_gpr_value(GPRName::RSI, 0x30000);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x30001);
+        cpu.registers.set_gpr_value(GPRName::RCX, 5000);
+        cpu.run(1);
+        assert_eq!(cpu.memory.read_vec::<u8>(0x30000, 5001), vec![0x5A; 5001]);
+    }
+
+    #[test]
+    fn address_size_and_restart_after_a_fault() {
+        // rep stosb with 67: ECX counts, EDI wraps
+        let mut cpu = cpu_with_code(&[0x67, 0xF3, 0xAA, 0xF3, 0xAA]);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0x1_0000_0002);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0xFFFF_FFFF);
+        cpu.registers.set_gpr_value(GPRName::RAX, 0x77);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 1);
+        assert_eq!(cpu.memory.read::<u8>(0xFFFF_FFFF), 0x77);
+        assert_eq!(cpu.memory.read::<u8>(0), 0x77);
+
+        // the page at 0x3000 is unmapped, the fault leaves the count at the first unwritten byte
+        cpu.memory.set_lazy_allocation(false);
+        cpu.memory.map(0x2000, 0x1000, Perms::RW).unwrap();
+        cpu.registers.set_gpr_value(GPRName::RAX, 0xEE);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x2F00);
+        cpu.registers.set_gpr_value(GPRName::RCX, 0x200);
+        assert!(matches!(cpu.run(1), StopReason::Fault(_)));
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 3);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), 0x100);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RDI), 0x3000);
+        assert_eq!(cpu.memory.read::<u8>(0x2FFF), 0xEE);
+    }
+}
@@ -0,0 +1,144 @@
+use crate::registers::GPRName;
+use crate::registers::IPName;
+use crate::registers::FLAGSName;
+use crate::registers::Flag;
+use crate::registers::CRName;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::operand;
+
+const MSR_EFER: u32 = 0xC000_0080;
+
+// 90, 0F 1F /0 and the 0F 18..0F 1E hint space
+pub fn nop(_cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    Ok(())
+}
+
+// F4
+pub fn hlt(cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    cpu.halt();
+    Ok(())
+}
+
+// CC
+pub fn int3(_cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    Err(Exception::Breakpoint)
+}
+
+// F5 CMC, F8 CLC, F9 STC, FC CLD, FD STD
+pub fn flag_control(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    match instruction.opcode {
+        0xF5 => {
+            let cf = cpu.registers.get_flag(Flag::CF);
+            cpu.registers.set_flag(Flag::CF, !cf);
+        }
+        0xF8 | 0xF9 => cpu.registers.set_flag(Flag::CF, instruction.opcode == 0xF9),
+        _ => cpu.registers.set_flag(Flag::DF, instruction.opcode == 0xFD),
+    }
+    Ok(())
+}
+
+// 0F 0B, 0F B9 and 0F FF are defined to raise #UD
+pub fn ud(_cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    Err(Exception::InvalidOpcode)
+}
+
+// 0F 05, the return address goes to RCX and RFLAGS to R11 before the system call layer runs
+pub fn syscall(cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    let rip = cpu.registers.get_ip_value(IPName::RIP);
+    let rflags = cpu.registers.get_flags_value(FLAGSName::RFLAGS);
+    cpu.registers.set_gpr_value(GPRName::RCX, rip);
+    cpu.registers.set_gpr_value(GPRName::R11, rflags);
+    cpu.request_syscall();
+    Ok(())
+}
+
+fn control_register(index: u8) -> Result<CRName, Exception> {
+    match index {
+        0 => Ok(CRName::CR0),
+        2 => Ok(CRName::CR2),
+        3 => Ok(CRName::CR3),
+        4 => Ok(CRName::CR4),
+        8 => Ok(CRName::CR8),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// 0F 20 MOV Rq,Cd / 0F 22 MOV Cd,Rq, always 64-bit and register to register whatever ModRM.mod says; writes to
+// CR0, CR3 and CR4 rebuild the translation
+pub fn mov_control_register(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let name = control_register(instruction.reg())?;
+    if cpu.cpl() != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    if instruction.opcode == 0x20 {
+        operand::write_gpr64(cpu, instruction.rm(), cpu.registers.get_cr_value(name));
+        return Ok(());
+    }
+    let rebuild = matches!(name, CRName::CR0 | CRName::CR3 | CRName::CR4);
+    cpu.registers.set_cr_value(name, operand::read_gpr64(cpu, instruction.rm()));
+    if rebuild {
+        cpu.update_paging();
+    }
+    Ok(())
+}
+
+// 0F 30 WRMSR / 0F 32 RDMSR, EDX:EAX to or from the MSR ECX selects; EFER is the only one modelled
+pub fn msr(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    if cpu.cpl() != 0 || cpu.registers.get_gpr_value(GPRName::ECX) as u32 != MSR_EFER {
+        return Err(Exception::GeneralProtection(0));
+    }
+    if instruction.opcode == 0x32 {
+        let value = cpu.registers.get_efer();
+        cpu.registers.set_gpr_value(GPRName::RAX, value & 0xFFFF_FFFF);
+        cpu.registers.set_gpr_value(GPRName::RDX, value >> 32);
+        return Ok(());
+    }
+    let value = cpu.registers.get_gpr_value(GPRName::EDX) << 32 | cpu.registers.get_gpr_value(GPRName::EAX);
+    cpu.registers.set_efer(value);
+    cpu.update_paging();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::CR0_PG;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    #[test]
+    fn control_registers_and_efer_enable_paging() {
+        // mov eax, 0x10000 / mov cr3, rax / mov ecx, EFER / mov eax, NXE / xor edx, edx / wrmsr /
+        // mov eax, 0x80000000 / mov cr0, rax / mov rbx, cr0 / rdmsr / mov cr1, rax
+        let mut cpu = cpu_with_code(&[
+            0xB8, 0x00, 0x00, 0x01, 0x00,
+            0x0F, 0x22, 0xD8,
+            0xB9, 0x80, 0x00, 0x00, 0xC0,
+            0xB8, 0x00, 0x08, 0x00, 0x00,
+            0x31, 0xD2,
+            0x0F, 0x30,
+            0xB8, 0x00, 0x00, 0x00, 0x80,
+            0x0F, 0x22, 0xC0,
+            0x0F, 0x20, 0xC3,
+            0x0F, 0x32,
+            0x0F, 0x22, 0xC8,
+        ]);
+        // identity-mapped 1 GiB page
+        cpu.memory.write::<u64>(0x10000, 0x11000 | 7);
+        cpu.memory.write::<u64>(0x11000, 0x87);
+        cpu.run(8);
+        let paging = cpu.memory.paging().unwrap();
+        assert_eq!((paging.cr3, paging.no_execute), (0x10000, true));
+        // fetches go through the page tables from here on
+        cpu.run(2);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RBX), CR0_PG);
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX), 0x800);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::InvalidOpcode));
+    }
+}
@@ -0,0 +1,759 @@
+// x87 FPU: the D8-DF escapes over an eight register stack of 80-bit values, plus FWAIT and FXSAVE/FXRSTOR
+// the status word keeps the sticky exception flags, TOP in bits 11-13 and the condition codes C0-C3; an unmasked
+// exception only sets ES and is delivered as #MF by the next waiting instruction
+// arithmetic rounds to the precision and rounding control of the control word
+
+use std::cmp::Ordering;
+
+use crate::cpu::Cpu;
+use crate::cpu::Exception;
+
+use crate::instructions::Instruction;
+use crate::instructions::OperandSize;
+use crate::instructions::MandatoryPrefix;
+use crate::instructions::operand;
+use crate::instructions::operand::Operand;
+use crate::instructions::sse;
+use crate::instructions::sse_float;
+use crate::instructions::sse_float::RoundingMode;
+use crate::instructions::mxcsr;
+use crate::instructions::float80;
+use crate::instructions::float80::F80;
+use crate::instructions::float80::Format;
+use crate::instructions::float80::{INVALID, DENORMAL, DIVIDE_BY_ZERO};
+
+const EXCEPTIONS: u16 = 0x3F;
+const STACK_FAULT: u16 = 1 << 6;
+const ERROR_SUMMARY: u16 = 1 << 7;
+const C0: u16 = 1 << 8;
+const C1: u16 = 1 << 9;
+const C2: u16 = 1 << 10;
+const C3: u16 = 1 << 14;
+const BUSY: u16 = 1 << 15;
+const TOP_SHIFT: u16 = 11;
+
+const TAG_VALID: u16 = 0;
+const TAG_ZERO: u16 = 1;
+const TAG_SPECIAL: u16 = 2;
+const TAG_EMPTY: u16 = 3;
+
+fn top(cpu: &Cpu) -> usize {
+    (cpu.registers.get_fpu_status() >> TOP_SHIFT & 7) as usize
+}
+
+fn set_top(cpu: &mut Cpu, top: usize) {
+    let status = cpu.registers.get_fpu_status() & !(7 << TOP_SHIFT) | (top as u16) << TOP_SHIFT;
+    cpu.registers.set_fpu_status(status);
+}
+
+// the physical register behind ST(i)
+fn physical(cpu: &Cpu, i: usize) -> usize {
+    (top(cpu) + i) & 7
+}
+
+fn tag(cpu: &Cpu, physical: usize) -> u16 {
+    cpu.registers.get_fpu_tag() >> (2 * physical) & 3
+}
+
+fn set_tag(cpu: &mut Cpu, physical: usize, tag: u16) {
+    let tags = cpu.registers.get_fpu_tag() & !(3 << (2 * physical)) | tag << (2 * physical);
+    cpu.registers.set_fpu_tag(tags);
+}
+
+fn tag_of(value: F80) -> u16 {
+    if value.is_zero() {
+        TAG_ZERO
+    } else if value.is_normal() {
+        TAG_VALID
+    } else {
+        TAG_SPECIAL
+    }
+}
+
+fn set_st(cpu: &mut Cpu, i: usize, value: F80) {
+    let physical = physical(cpu, i);
+    cpu.registers.set_fpu_register(physical, value.raw());
+    set_tag(cpu, physical, tag_of(value));
+}
+
+// ST(i) as an operand: reading an empty register is a stack underflow, and the masked response computes with the
+// indefinite
+fn read_st(cpu: &Cpu, i: usize, flags: &mut u16) -> F80 {
+    let physical = physical(cpu, i);
+    if tag(cpu, physical) == TAG_EMPTY {
+        *flags |= INVALID | STACK_FAULT;
+        return F80::INDEFINITE;
+    }
+    F80::from_raw(cpu.registers.get_fpu_register(physical))
+}
+
+// a push onto a full register is a stack overflow, which sets C1 and loads the indefinite when masked
+fn push(cpu: &mut Cpu, value: F80, mut flags: u16) {
+    let top = (top(cpu) + 7) & 7;
+    let value = if tag(cpu, top) != TAG_EMPTY {
+        flags |= INVALID | STACK_FAULT | C1;
+        F80::INDEFINITE
+    } else {
+        value
+    };
+    if raise(cpu, flags) {
+        set_top(cpu, top);
+        set_st(cpu, 0, value);
+    }
+}
+
+fn pop(cpu: &mut Cpu) {
+    set_tag(cpu, physical(cpu, 0), TAG_EMPTY);
+    set_top(cpu, physical(cpu, 1));
+}
+
+// record an instruction's exception flags, stack fault and C1 in the status word, setting ES and B when one of the
+// exceptions is unmasked; false when an unmasked invalid, denormal or divide-by-zero leaves the destination alone
+fn raise(cpu: &mut Cpu, flags: u16) -> bool {
+    let unmasked = flags & EXCEPTIONS & !cpu.registers.get_fpu_control();
+    let mut status = cpu.registers.get_fpu_status() & !C1 | flags & (EXCEPTIONS | STACK_FAULT | C1);
+    if unmasked != 0 {
+        status |= ERROR_SUMMARY | BUSY;
+    }
+    cpu.registers.set_fpu_status(status);
+    unmasked & (INVALID | DENORMAL | DIVIDE_BY_ZERO) == 0
+}
+
+// waiting instructions first deliver an exception an earlier one left pending
+fn check_pending(cpu: &Cpu) -> Result<(), Exception> {
+    if cpu.registers.get_fpu_status() & ERROR_SUMMARY != 0 {
+        return Err(Exception::FloatingPointError);
+    }
+    Ok(())
+}
+
+fn precision(cpu: &Cpu) -> Format {
+    Format::precision_control(cpu.registers.get_fpu_control() >> 8)
+}
+
+fn rounding(cpu: &Cpu) -> RoundingMode {
+    RoundingMode::from_bits((cpu.registers.get_fpu_control() >> 10) as u8)
+}
+
+// the arithmetic of ModRM.reg 0, 1 and 4-7 in the D8/DC/DA/DE escapes: add, mul, sub, subr, div, divr
+fn compute(cpu: &Cpu, operation: u8, a: F80, b: F80) -> (F80, u16) {
+    let (format, mode) = (precision(cpu), rounding(cpu));
+    match operation {
+        0 => float80::add(a, b, false, format, mode),
+        1 => float80::mul(a, b, format, mode),
+        4 => float80::add(a, b, true, format, mode),
+        5 => float80::add(b, a, true, format, mode),
+        6 => float80::div(a, b, format, mode),
+        _ => float80::div(b, a, format, mode),
+    }
+}
+
+// ST(0) against `source`, into C3/C2/C0 (FCOM: 000 greater, 001 less, 100 equal, 111 unordered) or into ZF/PF/CF
+// for FCOMI; false when an unmasked exception stopped it
+fn compare(cpu: &mut Cpu, source: F80, mut flags: u16, signal_quiet: bool, eflags: bool) -> bool {
+    let destination = read_st(cpu, 0, &mut flags);
+    let (ordering, compare_flags) = float80::compare(destination, source, signal_quiet);
+    if !raise(cpu, flags | compare_flags) {
+        return false;
+    }
+    if eflags {
+        sse_float::comparison_flags(cpu, ordering);
+    } else {
+        let codes = match ordering {
+            Some(Ordering::Greater) => 0,
+            Some(Ordering::Less) => C0,
+            Some(Ordering::Equal) => C3,
+            None => C3 | C2 | C0,
+        };
+        cpu.registers.set_fpu_status(cpu.registers.get_fpu_status() & !(C3 | C2 | C0) | codes);
+    }
+    true
+}
+
+fn load_real(cpu: &mut Cpu, address: u64, format: Format) -> Result<(), Exception> {
+    let bits = match format.precision {
+        24 => cpu.read_memory::<u32>(address)? as u64,
+        _ => cpu.read_memory::<u64>(address)?,
+    };
+    let (value, flags) = float80::from_binary(bits, format);
+    push(cpu, value, flags);
+    Ok(())
+}
+
+fn load_extended(cpu: &mut Cpu, address: u64) -> Result<F80, Exception> {
+    let low = cpu.read_memory::<u64>(address)? as u128;
+    let high = cpu.read_memory::<u16>(address + 8)? as u128;
+    Ok(F80::from_raw(high << 64 | low))
+}
+
+fn load_integer(cpu: &mut Cpu, address: u64, size: OperandSize) -> Result<F80, Exception> {
+    let value = operand::read_memory_sized(cpu, address, size)?;
+    Ok(F80::from_integer(size.sign_extend(value) as i64))
+}
+
+fn store_real(cpu: &mut Cpu, address: u64, format: Format, and_pop: bool) -> Result<(), Exception> {
+    let mut flags = 0;
+    let value = read_st(cpu, 0, &mut flags);
+    let (bits, rounding_flags) = float80::to_binary(value, format, rounding(cpu));
+    if !raise(cpu, flags | rounding_flags) {
+        return Ok(());
+    }
+    match format.precision {
+        24 => cpu.write_memory::<u32>(address, bits as u32)?,
+        _ => cpu.write_memory::<u64>(address, bits)?,
+    }
+    if and_pop {
+        pop(cpu);
+    }
+    Ok(())
+}
+
+fn store_extended(cpu: &mut Cpu, address: u64) -> Result<(), Exception> {
+    let mut flags = 0;
+    let value = read_st(cpu, 0, &mut flags);
+    if !raise(cpu, flags) {
+        return Ok(());
+    }
+    cpu.write_memory::<u64>(address, value.significand)?;
+    cpu.write_memory::<u16>(address + 8, (value.raw() >> 64) as u16)?;
+    pop(cpu);
+    Ok(())
+}
+
+// FIST/FISTP round as the control word says, FISTTP truncates; out of range stores the integer indefinite
+fn store_integer(cpu: &mut Cpu, address: u64, size: OperandSize, truncate: bool, and_pop: bool) -> Result<(), Exception> {
+    let mut flags = 0;
+    let value = read_st(cpu, 0, &mut flags);
+    let mode = if truncate { RoundingMode::TowardZero } else { rounding(cpu) };
+    let (integer, conversion_flags) = float80::to_integer(value, size.bits(), mode);
+    if !raise(cpu, flags | conversion_flags) {
+        return Ok(());
+    }
+    operand::write_memory_sized(cpu, address, size, integer)?;
+    if and_pop {
+        pop(cpu);
+    }
+    Ok(())
+}
+
+// D8 m32real, DC m64real, DA m32int and DE m16int: ST(0) = ST(0) op source, or FCOM/FCOMP against it
+fn arithmetic_memory(cpu: &mut Cpu, opcode: u8, operation: u8, address: u64) -> Result<(), Exception> {
+    let (source, mut flags) = match opcode {
+        0xD8 => float80::from_binary(cpu.read_memory::<u32>(address)? as u64, float80::SINGLE),
+        0xDC => float80::from_binary(cpu.read_memory::<u64>(address)?, float80::DOUBLE),
+        0xDA => (load_integer(cpu, address, OperandSize::Dword)?, 0),
+        _ => (load_integer(cpu, address, OperandSize::Word)?, 0),
+    };
+    if operation == 2 || operation == 3 {
+        if compare(cpu, source, flags, true, false) && operation == 3 {
+            pop(cpu);
+        }
+        return Ok(());
+    }
+    let destination = read_st(cpu, 0, &mut flags);
+    let (result, result_flags) = compute(cpu, operation, destination, source);
+    if raise(cpu, flags | result_flags) {
+        set_st(cpu, 0, result);
+    }
+    Ok(())
+}
+
+// D8 ST(0) = ST(0) op ST(i); DC ST(i) = ST(i) op ST(0) and DE the same then pop, where the reversed forms swap
+// sub/subr and div/divr
+fn arithmetic_register(cpu: &mut Cpu, opcode: u8, operation: u8, i: usize) -> Result<(), Exception> {
+    let mut flags = 0;
+    match (opcode, operation) {
+        // FCOMPP is the only DE compare
+        (0xDE, 3) if i == 1 => {
+            let source = read_st(cpu, 1, &mut flags);
+            if compare(cpu, source, flags, true, false) {
+                pop(cpu);
+                pop(cpu);
+            }
+        }
+        (0xDE, 2 | 3) => return Err(Exception::InvalidOpcode),
+        (_, 2 | 3) => {
+            let source = read_st(cpu, i, &mut flags);
+            if compare(cpu, source, flags, true, false) && operation == 3 {
+                pop(cpu);
+            }
+        }
+        (0xD8, _) => {
+            let (destination, source) = (read_st(cpu, 0, &mut flags), read_st(cpu, i, &mut flags));
+            let (result, result_flags) = compute(cpu, operation, destination, source);
+            if raise(cpu, flags | result_flags) {
+                set_st(cpu, 0, result);
+            }
+        }
+        _ => {
+            let (destination, source) = (read_st(cpu, i, &mut flags), read_st(cpu, 0, &mut flags));
+            let operation = if operation >= 4 { operation ^ 1 } else { operation };
+            let (result, result_flags) = compute(cpu, operation, destination, source);
+            if raise(cpu, flags | result_flags) {
+                set_st(cpu, i, result);
+                if opcode == 0xDE {
+                    pop(cpu);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// D9 /5 FLDCW m16; ES follows whichever exception flags the new masks leave unmasked
+fn load_control(cpu: &mut Cpu, address: u64) -> Result<(), Exception> {
+    let control = cpu.read_memory::<u16>(address)?;
+    cpu.registers.set_fpu_control(control);
+    let status = cpu.registers.get_fpu_status();
+    let status = if status & EXCEPTIONS & !control != 0 {
+        status | ERROR_SUMMARY | BUSY
+    } else {
+        status & !(ERROR_SUMMARY | BUSY)
+    };
+    cpu.registers.set_fpu_status(status);
+    Ok(())
+}
+
+// D9 /0 FLD m32, /2 FST m32, /3 FSTP m32, /5 FLDCW; DB /0 FILD m32, /1 FISTTP m32, /2 FIST m32, /3 FISTP m32,
+// /5 FLD m80, /7 FSTP m80; DD /0 FLD m64, /1 FISTTP m64, /2 FST m64, /3 FSTP m64; DF /0 FILD m16,
+// /1 FISTTP m16, /2 FIST m16, /3 FISTP m16, /5 FILD m64, /7 FISTP m64
+fn memory_form(cpu: &mut Cpu, opcode: u8, reg: u8, address: u64) -> Result<(), Exception> {
+    let size = match opcode {
+        0xDB => OperandSize::Dword,
+        0xDD => OperandSize::Qword,
+        _ => OperandSize::Word,
+    };
+    match (opcode, reg) {
+        (0xD8 | 0xDA | 0xDC | 0xDE, _) => arithmetic_memory(cpu, opcode, reg, address),
+        (0xD9, 0) => load_real(cpu, address, float80::SINGLE),
+        (0xD9, 2 | 3) => store_real(cpu, address, float80::SINGLE, reg == 3),
+        (0xD9, 5) => load_control(cpu, address),
+        (0xDD, 0) => load_real(cpu, address, float80::DOUBLE),
+        (0xDD, 2 | 3) => store_real(cpu, address, float80::DOUBLE, reg == 3),
+        (0xDB | 0xDD | 0xDF, 1) => store_integer(cpu, address, size, true, true),
+        (0xDB | 0xDF, 0) => {
+            let value = load_integer(cpu, address, size)?;
+            push(cpu, value, 0);
+            Ok(())
+        }
+        (0xDB | 0xDF, 2 | 3) => store_integer(cpu, address, size, false, reg == 3),
+        (0xDB, 5) => {
+            let value = load_extended(cpu, address)?;
+            push(cpu, value, 0);
+            Ok(())
+        }
+        (0xDB, 7) => store_extended(cpu, address),
+        (0xDF, 5) => {
+            let value = load_integer(cpu, address, OperandSize::Qword)?;
+            push(cpu, value, 0);
+            Ok(())
+        }
+        (0xDF, 7) => store_integer(cpu, address, OperandSize::Qword, false, true),
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// DA C0/C8/D0/D8 FCMOVB/E/BE/U and DB the negations FCMOVNB/NE/NBE/NU, from the Jcc condition codes
+fn conditional_move(cpu: &mut Cpu, reg: u8, negate: bool, i: usize) {
+    let code = [0x2, 0x4, 0x6, 0xA][reg as usize] | negate as u8;
+    let condition = crate::flags::condition(&cpu.registers, code);
+    let mut flags = 0;
+    let (destination, source) = (read_st(cpu, 0, &mut flags), read_st(cpu, i, &mut flags));
+    if raise(cpu, flags) {
+        set_st(cpu, 0, if condition { source } else { destination });
+    }
+}
+
+fn register_form(cpu: &mut Cpu, opcode: u8, reg: u8, i: usize) -> Result<(), Exception> {
+    let mut flags = 0;
+    match (opcode, reg, i) {
+        (0xD8 | 0xDC | 0xDE, _, _) => return arithmetic_register(cpu, opcode, reg, i),
+        // D9 C0+i FLD ST(i)
+        (0xD9, 0, _) => {
+            let value = read_st(cpu, i, &mut flags);
+            push(cpu, value, flags);
+        }
+        // D9 C8+i FXCH ST(i)
+        (0xD9, 1, _) => {
+            let (a, b) = (read_st(cpu, 0, &mut flags), read_st(cpu, i, &mut flags));
+            if raise(cpu, flags) {
+                set_st(cpu, 0, b);
+                set_st(cpu, i, a);
+            }
+        }
+        // D9 D0 FNOP
+        (0xD9, 2, 0) => {}
+        // D9 E0 FCHS, E1 FABS
+        (0xD9, 4, 0 | 1) => {
+            let value = read_st(cpu, 0, &mut flags);
+            if raise(cpu, flags) {
+                set_st(cpu, 0, if i == 0 { value.negate() } else { value.abs() });
+            }
+        }
+        // D9 E4 FTST, any NaN is invalid
+        (0xD9, 4, 4) => {
+            compare(cpu, F80::zero(false), 0, true, false);
+        }
+        // D9 E8 FLD1, EE FLDZ
+        (0xD9, 5, 0) => push(cpu, F80::ONE, 0),
+        (0xD9, 5, 6) => push(cpu, F80::zero(false), 0),
+        // D9 FA FSQRT
+        (0xD9, 7, 2) => {
+            let value = read_st(cpu, 0, &mut flags);
+            let (result, result_flags) = float80::sqrt(value, precision(cpu), rounding(cpu));
+            if raise(cpu, flags | result_flags) {
+                set_st(cpu, 0, result);
+            }
+        }
+        (0xDA | 0xDB, 0..=3, _) => conditional_move(cpu, reg, opcode == 0xDB, i),
+        // DA E9 FUCOMPP
+        (0xDA, 5, 1) => {
+            let source = read_st(cpu, 1, &mut flags);
+            if compare(cpu, source, flags, false, false) {
+                pop(cpu);
+                pop(cpu);
+            }
+        }
+        // DB E8+i FUCOMI, F0+i FCOMI; DF E8+i FUCOMIP, F0+i FCOMIP
+        (0xDB | 0xDF, 5 | 6, _) => {
+            let source = read_st(cpu, i, &mut flags);
+            if compare(cpu, source, flags, reg == 6, true) && opcode == 0xDF {
+                pop(cpu);
+            }
+        }
+        // DD C0+i FFREE
+        (0xDD, 0, _) => set_tag(cpu, physical(cpu, i), TAG_EMPTY),
+        // DD D0+i FST ST(i), D8+i FSTP ST(i)
+        (0xDD, 2 | 3, _) => {
+            let value = read_st(cpu, 0, &mut flags);
+            if raise(cpu, flags) {
+                set_st(cpu, i, value);
+                if reg == 3 {
+                    pop(cpu);
+                }
+            }
+        }
+        // DD E0+i FUCOM, E8+i FUCOMP
+        (0xDD, 4 | 5, _) => {
+            let source = read_st(cpu, i, &mut flags);
+            if compare(cpu, source, flags, false, false) && reg == 5 {
+                pop(cpu);
+            }
+        }
+        _ => return Err(Exception::InvalidOpcode),
+    }
+    Ok(())
+}
+
+// D8-DF, by opcode and ModRM.reg for the memory forms and by the whole ModRM byte for the register forms
+pub fn escape(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let (opcode, reg) = (instruction.opcode, instruction.group_index());
+    // REX.B does not extend the stack register index
+    let i = instruction.modrm.map_or(0, |modrm| modrm.rm) as usize;
+    let memory = match operand::rm_operand(cpu, instruction) {
+        Operand::Memory(address) => Some(address),
+        Operand::Register(_) => None,
+    };
+    // the non-waiting control instructions go ahead with an exception pending: D9 /7 FNSTCW m16, DD /7 FNSTSW m16,
+    // DF E0 FNSTSW AX, DB E2 FNCLEX and DB E3 FNINIT
+    let status = cpu.registers.get_fpu_status();
+    match (opcode, memory, reg, i) {
+        (0xD9, Some(address), 7, _) => return cpu.write_memory::<u16>(address, cpu.registers.get_fpu_control()),
+        (0xDD, Some(address), 7, _) => return cpu.write_memory::<u16>(address, status),
+        (0xDF, None, 4, 0) => {
+            operand::write_gpr(cpu, instruction, 0, OperandSize::Word, status as u64);
+            return Ok(());
+        }
+        (0xDB, None, 4, 2) => {
+            cpu.registers.set_fpu_status(status & !(EXCEPTIONS | STACK_FAULT | ERROR_SUMMARY | BUSY));
+            return Ok(());
+        }
+        (0xDB, None, 4, 3) => {
+            cpu.registers.set_fpu_control(0x037F);
+            cpu.registers.set_fpu_status(0);
+            cpu.registers.set_fpu_tag(0xFFFF);
+            return Ok(());
+        }
+        _ => {}
+    }
+    check_pending(cpu)?;
+    match memory {
+        Some(address) => memory_form(cpu, opcode, reg, address),
+        None => register_form(cpu, opcode, reg, i),
+    }
+}
+
+// 9B FWAIT
+pub fn wait(cpu: &mut Cpu, _instruction: &Instruction) -> Result<(), Exception> {
+    check_pending(cpu)
+}
+
+// the 512-byte FXSAVE area: FCW, FSW, the abridged tag (one bit per physical register, set when not empty), FOP,
+// FIP, FDP, MXCSR and MXCSR_MASK, then ST(0)-ST(7) in 16-byte slots at 32 and XMM0-15 at 160
+const AREA_MXCSR: u64 = 24;
+const AREA_STACK: u64 = 32;
+const AREA_XMM: u64 = 160;
+
+fn save_area(cpu: &Cpu, instruction: &Instruction) -> Result<u64, Exception> {
+    match operand::rm_operand(cpu, instruction) {
+        Operand::Memory(address) if instruction.mandatory_prefix() == MandatoryPrefix::None => {
+            if address % 16 != 0 {
+                return Err(Exception::GeneralProtection(0));
+            }
+            Ok(address)
+        }
+        _ => Err(Exception::InvalidOpcode),
+    }
+}
+
+// 0F AE /0 FXSAVE m512; the last instruction and data pointers are not tracked and save as zero
+pub fn fxsave(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let address = save_area(cpu, instruction)?;
+    let abridged = (0..8).filter(|&physical| tag(cpu, physical) != TAG_EMPTY).fold(0u16, |tags, physical| tags | 1 << physical);
+    cpu.write_memory::<u16>(address, cpu.registers.get_fpu_control())?;
+    cpu.write_memory::<u16>(address + 2, cpu.registers.get_fpu_status())?;
+    cpu.write_memory::<u16>(address + 4, abridged)?;
+    cpu.write_memory::<u16>(address + 6, 0)?;
+    cpu.write_memory::<u128>(address + 8, 0)?;
+    cpu.write_memory::<u32>(address + AREA_MXCSR, cpu.registers.get_mxcsr())?;
+    cpu.write_memory::<u32>(address + AREA_MXCSR + 4, mxcsr::WRITABLE)?;
+    for i in 0..8 {
+        let value = cpu.registers.get_fpu_register(physical(cpu, i));
+        cpu.write_memory::<u128>(address + AREA_STACK + 16 * i as u64, value)?;
+    }
+    for index in 0..16 {
+        let value = sse::read_xmm(cpu, index);
+        cpu.write_memory::<u128>(address + AREA_XMM + 16 * index as u64, value)?;
+    }
+    Ok(())
+}
+
+// 0F AE /1 FXRSTOR m512; the full tag word comes back from the abridged one and the register contents, and
+// reserved MXCSR bits are #GP(0) before anything changes
+pub fn fxrstor(cpu: &mut Cpu, instruction: &Instruction) -> Result<(), Exception> {
+    let address = save_area(cpu, instruction)?;
+    let mxcsr = cpu.read_memory::<u32>(address + AREA_MXCSR)?;
+    if mxcsr & !mxcsr::WRITABLE != 0 {
+        return Err(Exception::GeneralProtection(0));
+    }
+    let control = cpu.read_memory::<u16>(address)?;
+    let status = cpu.read_memory::<u16>(address + 2)?;
+    let abridged = cpu.read_memory::<u8>(address + 4)?;
+    let mut stack = [0u128; 8];
+    for (i, value) in stack.iter_mut().enumerate() {
+        *value = cpu.read_memory::<u128>(address + AREA_STACK + 16 * i as u64)?;
+    }
+    let mut xmm = [0u128; 16];
+    for (index, value) in xmm.iter_mut().enumerate() {
+        *value = cpu.read_memory::<u128>(address + AREA_XMM + 16 * index as u64)?;
+    }
+
+    cpu.registers.set_fpu_control(control);
+    cpu.registers.set_fpu_status(status);
+    let mut tags = 0;
+    for (i, &value) in stack.iter().enumerate() {
+        let physical = physical(cpu, i);
+        cpu.registers.set_fpu_register(physical, value);
+        let tag = if abridged >> physical & 1 == 1 { tag_of(F80::from_raw(value)) } else { TAG_EMPTY };
+        tags |= tag << (2 * physical);
+    }
+    cpu.registers.set_fpu_tag(tags);
+    cpu.registers.set_mxcsr(mxcsr);
+    for (index, &value) in xmm.iter().enumerate() {
+        sse::write_xmm(cpu, index as u8, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::Flag;
+    use crate::registers::GPRName;
+
+    use crate::cpu::StopReason;
+    use crate::cpu::tests::cpu_with_code;
+
+    fn st(cpu: &Cpu, i: usize) -> F80 {
+        F80::from_raw(cpu.registers.get_fpu_register(physical(cpu, i)))
+    }
+
+    #[test]
+    fn loads_arithmetic_and_stores() {
+        // fld qword [0x2000] / fld dword [0x2008] / fmulp st1, st0 / fld1 / faddp st1, st0 / fsqrt /
+        // fmul qword [0x2000] / fstp qword [0x2010]
+        let mut cpu = cpu_with_code(&[
+            0xDD, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xD9, 0x04, 0x25, 0x08, 0x20, 0x00, 0x00,
+            0xDE, 0xC9,
+            0xD9, 0xE8,
+            0xDE, 0xC1,
+            0xD9, 0xFA,
+            0xDC, 0x0C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xDD, 0x1C, 0x25, 0x10, 0x20, 0x00, 0x00,
+        ]);
+        cpu.memory.write::<u64>(0x2000, 1.5f64.to_bits());
+        cpu.memory.write::<u32>(0x2008, 2.0f32.to_bits());
+        cpu.run(3);
+        assert_eq!(top(&cpu), 7);
+        assert_eq!(st(&cpu, 0), F80::from_integer(3));
+        cpu.run(5);
+        // (1.5 * 2 + 1) square rooted and times 1.5, stored and popped off an empty stack again
+        assert_eq!(cpu.memory.read::<u64>(0x2010), 3.0f64.to_bits());
+        assert_eq!((top(&cpu), cpu.registers.get_fpu_tag()), (0, 0xFFFF));
+        assert_eq!(cpu.registers.get_fpu_status() & EXCEPTIONS, 0);
+    }
+
+    #[test]
+    fn precision_and_rounding_control() {
+        // fld tword [0x2000] / fld1 / fadd st0, st1 / fstp tword [0x2010] / fldcw [0x2020] / fld1 / fadd st0, st1 /
+        // fistp dword [0x2030] / fldcw [0x2022] / fild word [0x2040] / fidiv dword [0x2044] / fist dword [0x2034]
+        let mut cpu = cpu_with_code(&[
+            0xDB, 0x2C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xD9, 0xE8,
+            0xD8, 0xC1,
+            0xDB, 0x3C, 0x25, 0x10, 0x20, 0x00, 0x00,
+            0xD9, 0x2C, 0x25, 0x20, 0x20, 0x00, 0x00,
+            0xD9, 0xE8,
+            0xD8, 0xC1,
+            0xDB, 0x1C, 0x25, 0x30, 0x20, 0x00, 0x00,
+            0xD9, 0x2C, 0x25, 0x22, 0x20, 0x00, 0x00,
+            0xDF, 0x04, 0x25, 0x40, 0x20, 0x00, 0x00,
+            0xDA, 0x34, 0x25, 0x44, 0x20, 0x00, 0x00,
+            0xDB, 0x14, 0x25, 0x34, 0x20, 0x00, 0x00,
+        ]);
+        // 2^-63, then 53-bit precision rounding up, then 64-bit precision rounding toward zero
+        cpu.memory.write::<u64>(0x2000, 1 << 63);
+        cpu.memory.write::<u16>(0x2008, 0x3FFF - 63);
+        cpu.memory.write::<u16>(0x2020, 0x0A7F);
+        cpu.memory.write::<u16>(0x2022, 0x0F7F);
+        cpu.memory.write::<u16>(0x2040, (-7i16) as u16);
+        cpu.memory.write::<u32>(0x2044, 2);
+        cpu.run(4);
+        // 1 + 2^-63 takes the full 64-bit significand
+        assert_eq!(cpu.memory.read::<u64>(0x2010), 1 << 63 | 1);
+        assert_eq!(cpu.memory.read::<u16>(0x2018), 0x3FFF);
+        cpu.run(4);
+        // rounded up to 53 bits the sum is 1 + 2^-52, which rounds up again to the integer 2
+        assert_eq!(cpu.memory.read::<u32>(0x2030), 2);
+        assert_eq!(cpu.registers.get_fpu_status() & float80::PRECISION, float80::PRECISION);
+        cpu.run(4);
+        // -7 / 2 truncates to -3
+        assert_eq!(cpu.memory.read::<u32>(0x2034), (-3i32) as u32);
+        assert_eq!(st(&cpu, 0), float80::from_binary((-3.5f64).to_bits(), float80::DOUBLE).0);
+    }
+
+    #[test]
+    fn compares_and_exchanges() {
+        // fld1 / fldz / fcom st1 / fnstsw ax / fcomi st0, st1 / fxch st1 / fcompp
+        let mut cpu = cpu_with_code(&[0xD9, 0xE8, 0xD9, 0xEE, 0xD8, 0xD1, 0xDF, 0xE0, 0xDB, 0xF1, 0xD9, 0xC9, 0xDE, 0xD9]);
+        cpu.run(4);
+        // 0 < 1
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX) as u16 & (C3 | C2 | C0), C0);
+        cpu.run(1);
+        assert!(cpu.registers.get_flag(Flag::CF) && !cpu.registers.get_flag(Flag::ZF) && !cpu.registers.get_flag(Flag::PF));
+        cpu.run(1);
+        assert_eq!((st(&cpu, 0), st(&cpu, 1)), (F80::ONE, F80::zero(false)));
+        // 1 > 0, and both popped
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_fpu_status() & (C3 | C2 | C0), 0);
+        assert_eq!((top(&cpu), cpu.registers.get_fpu_tag()), (0, 0xFFFF));
+    }
+
+    #[test]
+    fn ordered_compares_signal_on_quiet_nans() {
+        // fld dword [0x2000] / fld1 / fucom st1 / fcom st1 / fnclex / fldcw [0x2004] / fcom st1 / fwait
+        let mut cpu = cpu_with_code(&[
+            0xD9, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xD9, 0xE8,
+            0xDD, 0xE1,
+            0xD8, 0xD1,
+            0xDB, 0xE2,
+            0xD9, 0x2C, 0x25, 0x04, 0x20, 0x00, 0x00,
+            0xD8, 0xD1,
+            0x9B,
+        ]);
+        cpu.memory.write::<u32>(0x2000, 0x7FC0_0000);
+        // invalid operation unmasked
+        cpu.memory.write::<u16>(0x2004, 0x037E);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | C3 | C2 | C0), C3 | C2 | C0);
+        cpu.run(1);
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | C3 | C2 | C0), INVALID | C3 | C2 | C0);
+        cpu.run(3);
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | ERROR_SUMMARY), INVALID | ERROR_SUMMARY);
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::FloatingPointError));
+    }
+
+    #[test]
+    fn stack_faults_and_pending_exceptions() {
+        // nine fld1 / fninit / fadd st0, st0 / fnclex / fldcw [0x2000] / fld1 / fldz / fdivp st1, st0 / fnstsw ax /
+        // fwait
+        let mut code = [0xD9, 0xE8].repeat(9);
+        code.extend_from_slice(&[
+            0xDB, 0xE3,
+            0xD8, 0xC0,
+            0xDB, 0xE2,
+            0xD9, 0x2C, 0x25, 0x00, 0x20, 0x00, 0x00,
+            0xD9, 0xE8,
+            0xD9, 0xEE,
+            0xDE, 0xF9,
+            0xDF, 0xE0,
+            0x9B,
+        ]);
+        let mut cpu = cpu_with_code(&code);
+        // divide-by-zero unmasked
+        cpu.memory.write::<u16>(0x2000, 0x037B);
+        cpu.run(9);
+        // the ninth push overflows: C1 set and the indefinite loaded
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | STACK_FAULT | C1 | ERROR_SUMMARY), INVALID | STACK_FAULT | C1);
+        assert_eq!(st(&cpu, 0), F80::INDEFINITE);
+        // an empty stack underflows with C1 clear and computes with the indefinite
+        cpu.run(2);
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | STACK_FAULT | C1 | ERROR_SUMMARY), INVALID | STACK_FAULT);
+        assert_eq!(st(&cpu, 0), F80::INDEFINITE);
+        // the unmasked divide-by-zero leaves the stack as it was and stays pending past FNSTSW until FWAIT
+        cpu.run(6);
+        assert_eq!(cpu.registers.get_fpu_status() & (EXCEPTIONS | ERROR_SUMMARY), DIVIDE_BY_ZERO | ERROR_SUMMARY);
+        assert_eq!((st(&cpu, 0), st(&cpu, 1), top(&cpu)), (F80::zero(false), F80::ONE, 6));
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RAX) as u16, cpu.registers.get_fpu_status());
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::FloatingPointError));
+    }
+
+    #[test]
+    fn fxsave_and_fxrstor() {
+        // fld1 / fldz / fxsave [0x3000] / fninit / fxrstor [0x3000] / fxsave [0x3008]
+        let mut cpu = cpu_with_code(&[
+            0xD9, 0xE8,
+            0xD9, 0xEE,
+            0x0F, 0xAE, 0x04, 0x25, 0x00, 0x30, 0x00, 0x00,
+            0xDB, 0xE3,
+            0x0F, 0xAE, 0x0C, 0x25, 0x00, 0x30, 0x00, 0x00,
+            0x0F, 0xAE, 0x04, 0x25, 0x08, 0x30, 0x00, 0x00,
+        ]);
+        cpu.registers.set_mxcsr(0x1FC0);
+        sse::write_xmm(&mut cpu, 1, 0x1111_2222_3333_4444_5555_6666_7777_8888);
+        cpu.run(3);
+        assert_eq!(cpu.memory.read::<u16>(0x3000), 0x037F);
+        assert_eq!(cpu.memory.read::<u16>(0x3002), 6 << TOP_SHIFT);
+        // R6 and R7 are in use
+        assert_eq!(cpu.memory.read::<u8>(0x3004), 0xC0);
+        assert_eq!(cpu.memory.read::<u32>(0x3018), 0x1FC0);
+        assert_eq!(cpu.memory.read::<u32>(0x301C), mxcsr::WRITABLE);
+        assert_eq!(cpu.memory.read::<u128>(0x3020), 0);
+        assert_eq!(cpu.memory.read::<u128>(0x3030), F80::ONE.raw());
+        assert_eq!(cpu.memory.read::<u128>(0x30B0), 0x1111_2222_3333_4444_5555_6666_7777_8888);
+
+        cpu.registers.set_mxcsr(0x1F80);
+        sse::write_xmm(&mut cpu, 1, 0);
+        cpu.run(2);
+        // the tags come back as zero for R6 and valid for R7
+        assert_eq!((top(&cpu), cpu.registers.get_fpu_tag()), (6, 0x1FFF));
+        assert_eq!((st(&cpu, 0), st(&cpu, 1)), (F80::zero(false), F80::ONE));
+        assert_eq!(cpu.registers.get_mxcsr(), 0x1FC0);
+        assert_eq!(sse::read_xmm(&cpu, 1), 0x1111_2222_3333_4444_5555_6666_7777_8888);
+        // the area must be 16-byte aligned
+        assert_eq!(cpu.run(1), StopReason::Fault(Exception::GeneralProtection(0)));
+    }
+}
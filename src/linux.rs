@@ -0,0 +1,654 @@
+// Linux x86-64 system calls for user-mode emulation
+// reference: arch/x86/entry/syscalls/syscall_64.tbl and the man-pages section 2
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::registers::GPRName;
+
+use crate::memory::Perms;
+
+use crate::paging::PAGE_SIZE;
+
+use crate::cpu::Cpu;
+use crate::cpu::StopReason;
+
+const SYS_READ: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_CLOSE: u64 = 3;
+const SYS_MMAP: u64 = 9;
+const SYS_MUNMAP: u64 = 11;
+const SYS_BRK: u64 = 12;
+const SYS_EXIT: u64 = 60;
+const SYS_UNAME: u64 = 63;
+const SYS_ARCH_PRCTL: u64 = 158;
+const SYS_CLOCK_GETTIME: u64 = 228;
+const SYS_EXIT_GROUP: u64 = 231;
+const SYS_OPENAT: u64 = 257;
+const SYS_GETRANDOM: u64 = 318;
+
+const ENOENT: i64 = 2;
+const EBADF: i64 = 9;
+const ENOMEM: i64 = 12;
+const EACCES: i64 = 13;
+const EFAULT: i64 = 14;
+const EEXIST: i64 = 17;
+const EINVAL: i64 = 22;
+const ENAMETOOLONG: i64 = 36;
+const ENOSYS: i64 = 38;
+
+const AT_FDCWD: i64 = -100;
+const O_ACCMODE: u64 = 3;
+const O_WRONLY: u64 = 1;
+const O_RDWR: u64 = 2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+const PROT_READ: u64 = 1;
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_SHARED: u64 = 0x01;
+const MAP_PRIVATE: u64 = 0x02;
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const ARCH_SET_GS: u64 = 0x1001;
+const ARCH_SET_FS: u64 = 0x1002;
+const ARCH_GET_FS: u64 = 0x1003;
+const ARCH_GET_GS: u64 = 0x1004;
+
+const CLOCK_REALTIME: u64 = 0;
+const CLOCK_REALTIME_COARSE: u64 = 5;
+const CLOCK_BOOTTIME: u64 = 7;
+
+const PATH_MAX: usize = 4096;
+// each struct utsname field is a NUL-padded 65 byte string
+const UTSNAME_LENGTH: usize = 65;
+
+// mmap without MAP_FIXED hands out addresses downwards from here
+pub const MMAP_BASE: u64 = 0x7FFF_F000_0000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum File {
+    Stdin,
+    Stdout,
+    Stderr,
+    // an open in-memory file, contents live in the VFS under the path
+    Vfs { path: String, position: usize, readable: bool, writable: bool, append: bool },
+}
+
+// process state the kernel would keep: file descriptors, files, brk and mmap layout
+pub struct Linux {
+    files: Vec<Option<File>>,
+    vfs: HashMap<String, Vec<u8>>,
+    // paths missing from the VFS are read from the host file system on open
+    host_files: bool,
+    // buffered stdin instead of the host one
+    stdin: Option<Vec<u8>>,
+    // keep stdout and stderr in memory instead of writing them to the host
+    capture: bool,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    brk_start: u64,
+    brk: u64,
+    random_state: u64,
+    boot: Instant,
+}
+
+fn page_up(value: u64) -> Option<u64> {
+    value.checked_add(PAGE_SIZE as u64 - 1).map(|value| value & !(PAGE_SIZE as u64 - 1))
+}
+
+fn prot_perms(prot: u64) -> Perms {
+    let mut perms = Perms::NONE;
+    if prot & PROT_READ != 0 {
+        perms = perms | Perms::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        perms = perms | Perms::WRITE;
+    }
+    if prot & PROT_EXEC != 0 {
+        perms = perms | Perms::EXECUTE;
+    }
+    perms
+}
+
+impl Linux {
+    // `program_break` is the initial brk, usually what the ELF loader reported
+    pub fn new(program_break: u64) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_nanos() as u64);
+        Linux {
+            files: vec![Some(File::Stdin), Some(File::Stdout), Some(File::Stderr)],
+            vfs: HashMap::new(),
+            host_files: false,
+            stdin: None,
+            capture: false,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            brk_start: program_break,
+            brk: program_break,
+            random_state: seed,
+            boot: Instant::now(),
+        }
+    }
+
+    pub fn set_host_files(&mut self, enabled: bool) {
+        self.host_files = enabled;
+    }
+
+    #[cfg(test)]
+    pub fn add_file(&mut self, path: &str, contents: Vec<u8>) {
+        self.vfs.insert(path.to_string(), contents);
+    }
+
+    #[cfg(test)]
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.vfs.get(path).map(Vec::as_slice)
+    }
+
+    #[cfg(test)]
+    pub fn set_stdin(&mut self, input: Vec<u8>) {
+        self.stdin = Some(input);
+    }
+
+    #[cfg(test)]
+    pub fn set_capture(&mut self, enabled: bool) {
+        self.capture = enabled;
+    }
+
+    #[cfg(test)]
+    pub fn stdout(&self) -> &[u8] {
+        &self.stdout
+    }
+
+    // fixed seed for getrandom, makes runs reproducible
+    #[cfg(test)]
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.random_state = seed;
+    }
+
+    // splitmix64
+    pub fn random_bytes(&mut self, buffer: &mut [u8]) {
+        for chunk in buffer.chunks_mut(8) {
+            self.random_state = self.random_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut value = self.random_state;
+            value = (value ^ (value >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            value = (value ^ (value >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            value ^= value >> 31;
+            chunk.copy_from_slice(&value.to_le_bytes()[..chunk.len()]);
+        }
+    }
+
+    // run the CPU, servicing system calls, until it stops for another reason or the process exits
+    pub fn run(&mut self, cpu: &mut Cpu, max_steps: usize) -> StopReason {
+        for _ in 0..max_steps {
+            match cpu.step() {
+                Ok(()) => {}
+                Err(StopReason::Syscall) => {
+                    if let Some(status) = self.syscall(cpu) {
+                        return StopReason::Exit(status);
+                    }
+                }
+                Err(reason) => return reason,
+            }
+        }
+        StopReason::StepLimit
+    }
+
+    // handle the system call in RAX, returns the exit status when the process ends
+    pub fn syscall(&mut self, cpu: &mut Cpu) -> Option<i32> {
+        let number = cpu.registers.get_gpr_value(GPRName::RAX);
+        let args = [
+            cpu.registers.get_gpr_value(GPRName::RDI),
+            cpu.registers.get_gpr_value(GPRName::RSI),
+            cpu.registers.get_gpr_value(GPRName::RDX),
+            cpu.registers.get_gpr_value(GPRName::R10),
+            cpu.registers.get_gpr_value(GPRName::R8),
+            cpu.registers.get_gpr_value(GPRName::R9),
+        ];
+        let result = match number {
+            SYS_READ => self.read(cpu, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(cpu, args[0], args[1], args[2]),
+            SYS_OPENAT => self.openat(cpu, args[0], args[1], args[2]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_BRK => self.brk(cpu, args[0]),
+            SYS_MMAP => self.mmap(cpu, args),
+            SYS_MUNMAP => self.munmap(cpu, args[0], args[1]),
+            SYS_UNAME => self.uname(cpu, args[0]),
+            SYS_ARCH_PRCTL => self.arch_prctl(cpu, args[0], args[1]),
+            SYS_CLOCK_GETTIME => self.clock_gettime(cpu, args[0], args[1]),
+            SYS_GETRANDOM => self.getrandom(cpu, args[0], args[1]),
+            SYS_EXIT | SYS_EXIT_GROUP => return Some((args[0] & 0xFF) as i32),
+            _ => Err(ENOSYS),
+        };
+        // errors come back as -errno
+        let value = match result {
+            Ok(value) => value,
+            Err(errno) => -errno as u64,
+        };
+        cpu.registers.set_gpr_value(GPRName::RAX, value);
+        None
+    }
+
+    fn file_mut(&mut self, fd: u64) -> Result<&mut File, i64> {
+        self.files.get_mut(fd as usize).and_then(Option::as_mut).ok_or(EBADF)
+    }
+
+    fn read(&mut self, cpu: &mut Cpu, fd: u64, buffer: u64, count: u64) -> Result<u64, i64> {
+        let mut data = vec![0u8; count.min(1 << 24) as usize];
+        let file = self.file_mut(fd)?.clone();
+        let length = match &file {
+            File::Stdin => match &self.stdin {
+                Some(input) => {
+                    let length = data.len().min(input.len());
+                    data[..length].copy_from_slice(&input[..length]);
+                    length
+                }
+                None => std::io::stdin().read(&mut data).map_err(|_| EFAULT)?,
+            },
+            File::Vfs { path, position, readable: true, .. } => {
+                let contents = self.vfs.get(path).map_or(&[][..], Vec::as_slice);
+                // another descriptor may have truncated the file below this one's position
+                let start = (*position).min(contents.len());
+                let length = data.len().min(contents.len() - start);
+                data[..length].copy_from_slice(&contents[start..start + length]);
+                length
+            }
+            _ => return Err(EBADF),
+        };
+        cpu.write_bytes(buffer, &data[..length]).map_err(|_| EFAULT)?;
+        // the data is consumed only once it reached the guest buffer
+        match file {
+            File::Stdin => {
+                if let Some(input) = &mut self.stdin {
+                    input.drain(..length);
+                }
+            }
+            _ => {
+                if let File::Vfs { position, .. } = self.file_mut(fd)? {
+                    *position += length;
+                }
+            }
+        }
+        Ok(length as u64)
+    }
+
+    fn write(&mut self, cpu: &mut Cpu, fd: u64, buffer: u64, count: u64) -> Result<u64, i64> {
+        let mut data = vec![0u8; count.min(1 << 24) as usize];
+        cpu.read_bytes(buffer, &mut data).map_err(|_| EFAULT)?;
+        match self.file_mut(fd)?.clone() {
+            File::Stdout if self.capture => self.stdout.extend_from_slice(&data),
+            File::Stderr if self.capture => self.stderr.extend_from_slice(&data),
+            File::Stdout => {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&data).and_then(|_| stdout.flush()).map_err(|_| EFAULT)?;
+            }
+            File::Stderr => std::io::stderr().write_all(&data).map_err(|_| EFAULT)?,
+            File::Vfs { path, position, writable: true, append, .. } => {
+                let contents = self.vfs.entry(path).or_default();
+                let start = if append { contents.len() } else { position };
+                if contents.len() < start + data.len() {
+                    contents.resize(start + data.len(), 0);
+                }
+                contents[start..start + data.len()].copy_from_slice(&data);
+                if let File::Vfs { position, .. } = self.file_mut(fd)? {
+                    *position = start + data.len();
+                }
+            }
+            _ => return Err(EBADF),
+        }
+        Ok(data.len() as u64)
+    }
+
+    fn read_path(&self, cpu: &Cpu, address: u64) -> Result<String, i64> {
+        let mut path = Vec::new();
+        let mut byte = [0u8];
+        loop {
+            cpu.read_bytes(address + path.len() as u64, &mut byte).map_err(|_| EFAULT)?;
+            if byte[0] == 0 {
+                break;
+            }
+            if path.len() == PATH_MAX {
+                return Err(ENAMETOOLONG);
+            }
+            path.push(byte[0]);
+        }
+        String::from_utf8(path).map_err(|_| ENOENT)
+    }
+
+    fn openat(&mut self, cpu: &mut Cpu, directory: u64, path: u64, flags: u64) -> Result<u64, i64> {
+        let path = self.read_path(cpu, path)?;
+        if !path.starts_with('/') && directory as i64 != AT_FDCWD {
+            return Err(EBADF);
+        }
+        if !self.vfs.contains_key(&path) && self.host_files {
+            if let Ok(contents) = std::fs::read(&path) {
+                self.vfs.insert(path.clone(), contents);
+            }
+        }
+        match self.vfs.get_mut(&path) {
+            Some(_) if flags & O_CREAT != 0 && flags & O_EXCL != 0 => return Err(EEXIST),
+            Some(contents) if flags & O_TRUNC != 0 && flags & O_ACCMODE != 0 => contents.clear(),
+            Some(_) => {}
+            None if flags & O_CREAT != 0 => {
+                self.vfs.insert(path.clone(), Vec::new());
+            }
+            None => return Err(ENOENT),
+        }
+        let access = flags & O_ACCMODE;
+        let file = File::Vfs {
+            path,
+            position: 0,
+            readable: access != O_WRONLY,
+            writable: access == O_WRONLY || access == O_RDWR,
+            append: flags & O_APPEND != 0,
+        };
+        // the lowest free descriptor
+        let fd = match self.files.iter().position(Option::is_none) {
+            Some(fd) => {
+                self.files[fd] = Some(file);
+                fd
+            }
+            None => {
+                self.files.push(Some(file));
+                self.files.len() - 1
+            }
+        };
+        Ok(fd as u64)
+    }
+
+    fn close(&mut self, fd: u64) -> Result<u64, i64> {
+        self.file_mut(fd)?;
+        self.files[fd as usize] = None;
+        Ok(0)
+    }
+
+    // failures leave the break where it was, which is how the kernel reports them
+    fn brk(&mut self, cpu: &mut Cpu, address: u64) -> Result<u64, i64> {
+        if address < self.brk_start {
+            return Ok(self.brk);
+        }
+        let (old_end, new_end) = match (page_up(self.brk), page_up(address)) {
+            (Some(old_end), Some(new_end)) => (old_end, new_end),
+            _ => return Ok(self.brk),
+        };
+        let result = if new_end > old_end {
+            // growing never replaces a mapping in the way
+            if !cpu.memory.is_unmapped(old_end as usize, (new_end - old_end) as usize) {
+                return Ok(self.brk);
+            }
+            cpu.memory.map(old_end as usize, (new_end - old_end) as usize, Perms::RW)
+        } else {
+            cpu.memory.unmap(new_end as usize, (old_end - new_end) as usize)
+        };
+        if result.is_ok() {
+            self.brk = address;
+        }
+        Ok(self.brk)
+    }
+
+    fn mmap(&mut self, cpu: &mut Cpu, args: [u64; 6]) -> Result<u64, i64> {
+        let [address, length, prot, flags, fd, offset] = args;
+        let sharing = flags & (MAP_SHARED | MAP_PRIVATE);
+        if length == 0 || sharing == 0 || sharing == MAP_SHARED | MAP_PRIVATE || !offset.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(EINVAL);
+        }
+        let length = page_up(length).ok_or(ENOMEM)?;
+        // file mappings are private copies of the file contents
+        let contents = if flags & MAP_ANONYMOUS != 0 {
+            Vec::new()
+        } else {
+            match self.file_mut(fd)?.clone() {
+                File::Vfs { path, readable: true, .. } => {
+                    let contents = self.vfs.get(&path).map_or(&[][..], Vec::as_slice);
+                    let start = (offset as usize).min(contents.len());
+                    let end = start + (length as usize).min(contents.len() - start);
+                    contents[start..end].to_vec()
+                }
+                File::Vfs { .. } => return Err(EACCES),
+                _ => return Err(EBADF),
+            }
+        };
+
+        let address = if flags & MAP_FIXED != 0 {
+            if !address.is_multiple_of(PAGE_SIZE as u64) {
+                return Err(EINVAL);
+            }
+            address
+        } else {
+            // the highest hole below MMAP_BASE, so unmapped ranges get reused
+            cpu.memory.find_free(MMAP_BASE as usize, length as usize).ok_or(ENOMEM)? as u64
+        };
+        cpu.memory.map(address as usize, length as usize, Perms::RW).map_err(|_| ENOMEM)?;
+        cpu.memory.write_bytes(address as usize, &contents).map_err(|_| ENOMEM)?;
+        cpu.memory.protect(address as usize, length as usize, prot_perms(prot)).map_err(|_| ENOMEM)?;
+        Ok(address)
+    }
+
+    fn munmap(&mut self, cpu: &mut Cpu, address: u64, length: u64) -> Result<u64, i64> {
+        if !address.is_multiple_of(PAGE_SIZE as u64) || length == 0 {
+            return Err(EINVAL);
+        }
+        let length = page_up(length).ok_or(EINVAL)?;
+        cpu.memory.unmap(address as usize, length as usize).map_err(|_| EINVAL)?;
+        Ok(0)
+    }
+
+    fn uname(&mut self, cpu: &mut Cpu, buffer: u64) -> Result<u64, i64> {
+        let fields = ["Linux", "localhost", "6.1.0", "#1 SMP", "x86_64", "(none)"];
+        let mut utsname = vec![0u8; fields.len() * UTSNAME_LENGTH];
+        for (index, field) in fields.iter().enumerate() {
+            let start = index * UTSNAME_LENGTH;
+            utsname[start..start + field.len()].copy_from_slice(field.as_bytes());
+        }
+        cpu.write_bytes(buffer, &utsname).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    fn arch_prctl(&mut self, cpu: &mut Cpu, code: u64, address: u64) -> Result<u64, i64> {
+        match code {
+            ARCH_SET_FS => cpu.registers.set_fs_base(address),
+            ARCH_SET_GS => cpu.registers.set_gs_base(address),
+            ARCH_GET_FS => {
+                let base = cpu.registers.get_fs_base();
+                cpu.write_bytes(address, &base.to_le_bytes()).map_err(|_| EFAULT)?;
+            }
+            ARCH_GET_GS => {
+                let base = cpu.registers.get_gs_base();
+                cpu.write_bytes(address, &base.to_le_bytes()).map_err(|_| EFAULT)?;
+            }
+            _ => return Err(EINVAL),
+        }
+        Ok(0)
+    }
+
+    // real time comes from the host, the other clocks count from when the process started
+    fn clock_gettime(&mut self, cpu: &mut Cpu, clock: u64, timespec: u64) -> Result<u64, i64> {
+        let time = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+            1..=CLOCK_BOOTTIME => self.boot.elapsed(),
+            _ => return Err(EINVAL),
+        };
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&time.as_secs().to_le_bytes());
+        bytes[8..].copy_from_slice(&(time.subsec_nanos() as u64).to_le_bytes());
+        cpu.write_bytes(timespec, &bytes).map_err(|_| EFAULT)?;
+        Ok(0)
+    }
+
+    fn getrandom(&mut self, cpu: &mut Cpu, buffer: u64, length: u64) -> Result<u64, i64> {
+        let mut bytes = vec![0u8; length.min(1 << 25) as usize];
+        self.random_bytes(&mut bytes);
+        cpu.write_bytes(buffer, &bytes).map_err(|_| EFAULT)?;
+        Ok(bytes.len() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::IPName;
+
+    use crate::cpu::tests::cpu_with_code;
+    use crate::cpu::tests::CODE;
+
+    const DATA: u64 = 0x3000;
+
+    fn call(linux: &mut Linux, cpu: &mut Cpu, number: u64, args: &[u64]) -> u64 {
+        let registers = [GPRName::RDI, GPRName::RSI, GPRName::RDX, GPRName::R10, GPRName::R8, GPRName::R9];
+        cpu.registers.set_gpr_value(GPRName::RAX, number);
+        for (register, value) in registers.iter().zip(args) {
+            cpu.registers.set_gpr_value(*register, *value);
+        }
+        assert_eq!(linux.syscall(cpu), None);
+        cpu.registers.get_gpr_value(GPRName::RAX)
+    }
+
+    #[test]
+    fn write_and_exit_from_code() {
+        // mov eax, 1 / mov edi, 1 / lea rsi, [rip+0x13] / mov edx, 3 / syscall / mov edi, 7 / mov eax, 231 / syscall / "hi\n"
+        let mut cpu = cpu_with_code(&[
+            0xB8, 0x01, 0x00, 0x00, 0x00,
+            0xBF, 0x01, 0x00, 0x00, 0x00,
+            0x48, 0x8D, 0x35, 0x13, 0x00, 0x00, 0x00,
+            0xBA, 0x03, 0x00, 0x00, 0x00,
+            0x0F, 0x05,
+            0xBF, 0x07, 0x00, 0x00, 0x00,
+            0xB8, 0xE7, 0x00, 0x00, 0x00,
+            0x0F, 0x05,
+            b'h', b'i', b'\n',
+        ]);
+        let mut linux = Linux::new(0x10000);
+        linux.set_capture(true);
+        assert_eq!(linux.run(&mut cpu, 100), StopReason::Exit(7));
+        assert_eq!(linux.stdout(), b"hi\n");
+        // SYSCALL leaves the return address in RCX
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RCX), CODE as u64 + 36);
+        assert_eq!(cpu.registers.get_ip_value(IPName::RIP), CODE as u64 + 36);
+    }
+
+    #[test]
+    fn files_in_the_vfs() {
+        let mut cpu = cpu_with_code(&[]);
+        let mut linux = Linux::new(0x10000);
+        cpu.memory.write_bytes(DATA as usize, b"/etc/motd\0/tmp/out\0").unwrap();
+        linux.add_file("/etc/motd", b"hello".to_vec());
+
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA, 0]);
+        assert_eq!(fd, 3);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, DATA + 0x100, 3]), 3);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, DATA + 0x103, 10]), 2);
+        assert_eq!(cpu.memory.read_vec::<u8>(DATA as usize + 0x100, 5), b"hello");
+        assert_eq!(call(&mut linux, &mut cpu, SYS_WRITE, &[fd, DATA, 1]), -EBADF as u64);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOSE, &[fd]), 0);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOSE, &[fd]), -EBADF as u64);
+
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA + 10, O_WRONLY | O_CREAT]);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_WRITE, &[fd, DATA + 0x100, 5]), 5);
+        assert_eq!(linux.file("/tmp/out"), Some(&b"hello"[..]));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA + 1, 0]), -ENOENT as u64);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, 0xDEAD_0000, 1]), -EBADF as u64);
+    }
+
+    #[test]
+    fn read_after_truncation_through_another_fd() {
+        let mut cpu = cpu_with_code(&[]);
+        let mut linux = Linux::new(0x10000);
+        cpu.memory.write_bytes(DATA as usize, b"/tmp/log\0").unwrap();
+        linux.add_file("/tmp/log", b"hello".to_vec());
+
+        let reader = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA, 0]);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[reader, DATA + 0x100, 3]), 3);
+        call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA, O_WRONLY | O_TRUNC]);
+        assert_eq!(linux.file("/tmp/log"), Some(&b""[..]));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[reader, DATA + 0x100, 3]), 0);
+    }
+
+    #[test]
+    fn faulting_read_consumes_nothing() {
+        let mut cpu = cpu_with_code(&[]);
+        let mut linux = Linux::new(0x10000);
+        cpu.memory.write_bytes(DATA as usize, b"/etc/motd\0").unwrap();
+        cpu.memory.set_lazy_allocation(false);
+        linux.add_file("/etc/motd", b"hello".to_vec());
+        linux.set_stdin(b"input".to_vec());
+
+        let fd = call(&mut linux, &mut cpu, SYS_OPENAT, &[AT_FDCWD as u64, DATA, 0]);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, 0xDEAD_0000, 3]), -EFAULT as u64);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[fd, DATA + 0x100, 5]), 5);
+        assert_eq!(cpu.memory.read_vec::<u8>(DATA as usize + 0x100, 5), b"hello");
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[0, 0xDEAD_0000, 3]), -EFAULT as u64);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_READ, &[0, DATA + 0x100, 5]), 5);
+        assert_eq!(cpu.memory.read_vec::<u8>(DATA as usize + 0x100, 5), b"input");
+    }
+
+    #[test]
+    fn brk_and_mmap_manage_memory() {
+        let mut cpu = cpu_with_code(&[]);
+        cpu.memory.set_lazy_allocation(false);
+        let mut linux = Linux::new(0x40_0000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[0]), 0x40_0000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[0x40_1800]), 0x40_1800);
+        assert_eq!(cpu.memory.perms(0x40_1FFF), Some(Perms::RW));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[0x40_0800]), 0x40_0800);
+        assert_eq!(cpu.memory.perms(0x40_1000), None);
+        cpu.memory.map(0x40_2000, 0x1000, Perms::READ).unwrap();
+        assert_eq!(call(&mut linux, &mut cpu, SYS_BRK, &[0x40_2800]), 0x40_0800);
+        assert_eq!(cpu.memory.perms(0x40_2000), Some(Perms::READ));
+        assert_eq!(cpu.memory.perms(0x40_1000), None);
+
+        let address = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 0x2001, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, u64::MAX, 0]);
+        assert_eq!(address, MMAP_BASE - 0x3000);
+        assert_eq!(cpu.memory.perms(address as usize + 0x2FFF), Some(Perms::READ));
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MUNMAP, &[address, 0x1000]), 0);
+        assert_eq!(cpu.memory.perms(address as usize), None);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MMAP, &[0, 0, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, u64::MAX, 0]), -EINVAL as u64);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MUNMAP, &[address + 1, 0x1000]), -EINVAL as u64);
+
+        // a fixed mapping in the way of the next hole pushes the mapping below it
+        let fixed = MMAP_BASE - 0x5000;
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MMAP, &[fixed, 0x1000, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS | MAP_FIXED, u64::MAX, 0]), fixed);
+        cpu.memory.write::<u64>(fixed as usize, 0x1234);
+        let below = call(&mut linux, &mut cpu, SYS_MMAP, &[0, 0x3000, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, u64::MAX, 0]);
+        assert_eq!(below, fixed - 0x3000);
+        assert_eq!(cpu.memory.read::<u64>(fixed as usize), 0x1234);
+        // the page freed by munmap is the highest hole left
+        assert_eq!(call(&mut linux, &mut cpu, SYS_MMAP, &[0, 0x1000, PROT_READ, MAP_PRIVATE | MAP_ANONYMOUS, u64::MAX, 0]), address);
+    }
+
+    #[test]
+    fn process_information() {
+        let mut cpu = cpu_with_code(&[]);
+        let mut linux = Linux::new(0x10000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_UNAME, &[DATA]), 0);
+        assert_eq!(cpu.memory.read_vec::<u8>(DATA as usize, 6), b"Linux\0");
+        assert_eq!(cpu.memory.read_vec::<u8>(DATA as usize + 4 * UTSNAME_LENGTH, 7), b"x86_64\0");
+
+        assert_eq!(call(&mut linux, &mut cpu, SYS_ARCH_PRCTL, &[ARCH_SET_FS, 0x7000]), 0);
+        assert_eq!(cpu.registers.get_fs_base(), 0x7000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_ARCH_PRCTL, &[ARCH_GET_FS, DATA]), 0);
+        assert_eq!(cpu.memory.read::<u64>(DATA as usize), 0x7000);
+
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOCK_GETTIME, &[CLOCK_REALTIME, DATA]), 0);
+        assert!(cpu.memory.read::<u64>(DATA as usize) > 1_600_000_000);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_CLOCK_GETTIME, &[99, DATA]), -EINVAL as u64);
+
+        linux.set_random_seed(1);
+        assert_eq!(call(&mut linux, &mut cpu, SYS_GETRANDOM, &[DATA, 16, 0]), 16);
+        assert_ne!(cpu.memory.read::<u128>(DATA as usize), 0);
+        assert_eq!(call(&mut linux, &mut cpu, 1000, &[]), -ENOSYS as u64);
+
+        // only the low byte of the status reaches the parent
+        cpu.registers.set_gpr_value(GPRName::RAX, SYS_EXIT_GROUP);
+        cpu.registers.set_gpr_value(GPRName::RDI, 0x1_0102);
+        assert_eq!(linux.syscall(&mut cpu), Some(2));
+    }
+
+}
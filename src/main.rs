@@ -1,27 +1,139 @@
+#![allow(clippy::upper_case_acronyms)]
+
 extern crate primitive_types;
 use primitive_types::U256 as u256;
 use primitive_types::U512 as u512;
 
 mod registers;
 mod memory;
+mod paging;
 mod utilities;
 mod instructions;
+mod cpu;
+mod flags;
+mod elf;
+mod linux;
+mod stack;
 
 use registers::Registers;
 use registers::VecRegName;
 use registers::GPRName;
-use registers::FLAGSName;
-use registers::IPName;
 
 use memory::Memory;
 
 use utilities::Utilities;
 
-fn main() {
-    let mut registers = Registers::new();
-    let mut memory = Memory::new(0x40000000);
+use cpu::Cpu;
+use cpu::StopReason;
+
+use linux::Linux;
+
+use stack::StackBuilder;
+use stack::DEFAULT_STACK_SIZE;
+
+const USAGE: &str = "usage: CPU [--env NAME=VALUE]... [--inherit-env] [--argv0 NAME] [--stack-size BYTES] \
+[--max-steps N] [--host-files] PROGRAM [ARGS]...";
+
+// command line for running a statically linked Linux program
+struct Options {
+    program: String,
+    args: Vec<String>,
+    env: Vec<String>,
+    argv0: Option<String>,
+    stack_size: u64,
+    max_steps: usize,
+    host_files: bool,
+}
 
-    test(&mut registers, &mut memory);
+impl Options {
+    // options come before the program, everything after it is passed on
+    fn parse(arguments: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            program: String::new(),
+            args: Vec::new(),
+            env: Vec::new(),
+            argv0: None,
+            stack_size: DEFAULT_STACK_SIZE,
+            max_steps: usize::MAX,
+            host_files: false,
+        };
+        let mut arguments = arguments.iter();
+        while let Some(argument) = arguments.next() {
+            let mut value = || arguments.next().cloned().ok_or(format!("{} needs a value", argument));
+            match argument.as_str() {
+                "--env" => {
+                    let variable = value()?;
+                    if !variable.contains('=') {
+                        return Err(format!("--env expects NAME=VALUE, got {}", variable));
+                    }
+                    options.env.push(variable);
+                }
+                "--inherit-env" => options.env.extend(std::env::vars().map(|(name, value)| format!("{}={}", name, value))),
+                "--argv0" => options.argv0 = Some(value()?),
+                "--stack-size" => options.stack_size = value()?.parse().map_err(|_| "--stack-size expects a byte count")?,
+                "--max-steps" => options.max_steps = value()?.parse().map_err(|_| "--max-steps expects a number")?,
+                "--host-files" => options.host_files = true,
+                option if option.starts_with("--") => return Err(format!("unknown option {}", option)),
+                program => {
+                    options.program = program.to_string();
+                    options.args = arguments.cloned().collect();
+                    return Ok(options);
+                }
+            }
+        }
+        Err("no program given".to_string())
+    }
+}
+
+// load, set up the stack and run until exit, returns the process exit status
+fn run(options: &Options) -> Result<i32, String> {
+    let image = std::fs::read(&options.program).map_err(|error| format!("{}: {}", options.program, error))?;
+    let mut cpu = Cpu::new(Registers::new(), Memory::new(0));
+    let loaded = elf::load(&mut cpu, &image).map_err(|error| format!("{}: {:?}", options.program, error))?;
+
+    let mut linux = Linux::new(loaded.program_break);
+    linux.set_host_files(options.host_files);
+    let mut random = [0u8; 16];
+    linux.random_bytes(&mut random);
+    let argv0 = options.argv0.as_ref().unwrap_or(&options.program);
+    let mut stack = StackBuilder::new().arg(argv0).args(&options.args).size(options.stack_size).random(random);
+    for variable in &options.env {
+        stack = stack.env(variable);
+    }
+    stack.build(&mut cpu, &loaded).map_err(|fault| format!("stack setup failed: {:?}", fault))?;
+
+    match linux.run(&mut cpu, options.max_steps) {
+        StopReason::Exit(status) => Ok(status),
+        reason => Err(format!("stopped at RIP=0x{:X}: {:?}", cpu.registers.get_ip_value(registers::IPName::RIP), reason)),
+    }
+}
+
+fn main() {
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
+    // without arguments run the register and memory walkthrough
+    if arguments.is_empty() {
+        let mut registers = Registers::new();
+        let mut memory = Memory::new(0x40000000);
+        memory.set_lazy_allocation(true);
+
+        test(&mut registers, &mut memory);
+        return;
+    }
+
+    let options = match Options::parse(&arguments) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            std::process::exit(2);
+        }
+    };
+    match run(&options) {
+        Ok(status) => std::process::exit(status),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
 }
 
 fn test(registers: &mut Registers, memory: &mut Memory) {
@@ -58,15 +170,11 @@ fn test(registers: &mut Registers, memory: &mut Memory) {
     println!("{:?}", registers.get_by_sections::<u512>(VecRegName::ZMM, 5));
 
     println!("{}", registers.set_by_sections(VecRegName::XMM, 6, Utilities::f32vec_to_u32vec(vec![1.0f32, 2.0f32, 3.0f32, 4.0f32])));
-    println!("{:?}", if let Some(u32vec) = registers.get_by_sections::<u32>(VecRegName::XMM, 6) {
-        Some(Utilities::u32vec_to_f32vec(u32vec))
-    } else { None });
+    println!("{:?}", registers.get_by_sections::<u32>(VecRegName::XMM, 6).map(Utilities::u32vec_to_f32vec));
     println!("{}", registers.set_by_sections(VecRegName::XMM, 7, Utilities::f64vec_to_u64vec(vec![1.0f64, 2.0f64])));
-    println!("{:?}", if let Some(u64vec) = registers.get_by_sections::<u64>(VecRegName::XMM, 7) {
-        Some(Utilities::u64vec_to_f64vec(u64vec))
-    } else { None });
+    println!("{:?}", registers.get_by_sections::<u64>(VecRegName::XMM, 7).map(Utilities::u64vec_to_f64vec));
 
-    println!("0x{:X}", memory.read::<u8>(0x40000000));
+    println!("{:?}", memory.try_read::<u8>(0x40000000));
     memory.write::<u8>(0x40000000, 0x12);
     println!("0x{:X}", memory.read::<u8>(0x40000000));
     memory.write::<u16>(0x40000000, 0x1234);
@@ -90,8 +198,8 @@ fn test(registers: &mut Registers, memory: &mut Memory) {
         0x12345678u32, 0x12345678u32, 0x12345678u32, 0x12345678u32,
     ]);
     println!("{:X?}", registers.get_by_sections::<u32>(VecRegName::XMM, 15));
-    registers.set_by_selector::<u32>(VecRegName::XMM, 15, "[31:0]", 0x00000000u32);
+    println!("{:?}", registers.set_by_selector::<u32>(VecRegName::XMM, 15, "[31:0]", 0x00000000u32));
     println!("{:X?}", registers.get_by_sections::<u32>(VecRegName::XMM, 15));
-    registers.set_by_selector::<u32>(VecRegName::XMM, 15, "[MAX:64]", 0x00000000u32);
+    println!("{:?}", registers.set_by_selector::<u64>(VecRegName::XMM, 15, "qword[1]", 0x00000000u64));
     println!("{:X?}", registers.get_by_sections::<u32>(VecRegName::XMM, 15));
 }
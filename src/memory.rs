@@ -2,41 +2,31 @@ extern crate primitive_types;
 use primitive_types::U256 as u256;
 use primitive_types::U512 as u512;
 
-extern crate byteorder;
-use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::collections::HashMap;
 
-trait MemoryIO {
+use crate::paging::PagingMode;
+use crate::paging::AccessKind;
+use crate::paging::PageFault;
+use crate::paging::PAGE_SIZE;
+
+pub trait MemoryIO {
     fn from_bytes(bytes: &[u8]) -> Self;
     fn to_bytes(&self) -> Vec<u8>;
     fn size() -> usize;
 }
 
 macro_rules! impl_memory_io {
-    ($t:ty, $type_str:ident, $size:expr) => {
+    ($t:ty, $size:expr) => {
         impl MemoryIO for $t {
             fn from_bytes(bytes: &[u8]) -> Self {
-                let mut rdr = std::io::Cursor::new(bytes);
-                match stringify!($type_str) {
-                    "u8" => rdr.read_u8().unwrap() as $t,
-                    "u16" => rdr.read_u16::<LittleEndian>().unwrap() as $t,
-                    "u32" => rdr.read_u32::<LittleEndian>().unwrap() as $t,
-                    "u64" => rdr.read_u64::<LittleEndian>().unwrap() as $t,
-                    "u128" => rdr.read_u128::<LittleEndian>().unwrap() as $t,
-                    _ => panic!("Unsupported type"),
-                }
+                let mut buffer = [0u8; $size];
+                buffer.copy_from_slice(&bytes[..$size]);
+                <$t>::from_le_bytes(buffer)
             }
 
             fn to_bytes(&self) -> Vec<u8> {
-                let mut wtr = vec![];
-                match stringify!($type_str) {
-                    "u8" => wtr.write_u8(*self as u8).unwrap(),
-                    "u16" => wtr.write_u16::<LittleEndian>(*self as u16).unwrap(),
-                    "u32" => wtr.write_u32::<LittleEndian>(*self as u32).unwrap(),
-                    "u64" => wtr.write_u64::<LittleEndian>(*self as u64).unwrap(),
-                    "u128" => wtr.write_u128::<LittleEndian>(*self as u128).unwrap(),
-                    _ => panic!("Unsupported type"),
-                };
-                wtr
+                self.to_le_bytes().to_vec()
             }
 
             fn size() -> usize {
@@ -46,11 +36,11 @@ macro_rules! impl_memory_io {
     };
 }
 
-impl_memory_io!(u8, u8, 1);
-impl_memory_io!(u16, u16, 2);
-impl_memory_io!(u32, u32, 4);
-impl_memory_io!(u64, u64, 8);
-impl_memory_io!(u128, u128, 16);
+impl_memory_io!(u8, 1);
+impl_memory_io!(u16, 2);
+impl_memory_io!(u32, 4);
+impl_memory_io!(u64, 8);
+impl_memory_io!(u128, 16);
 
 impl MemoryIO for u256 {
     fn from_bytes(bytes: &[u8]) -> Self {
@@ -84,101 +74,587 @@ impl MemoryIO for u512 {
     }
 }
 
-const DEFAULT_SIZE: usize = 512; // 512 bytes
+// access rights of a mapped region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Perms(u8);
+
+impl Perms {
+    pub const NONE: Perms = Perms(0);
+    pub const READ: Perms = Perms(1);
+    pub const WRITE: Perms = Perms(2);
+    pub const EXECUTE: Perms = Perms(4);
+    pub const RW: Perms = Perms(1 | 2);
+    #[cfg(test)]
+    pub const RX: Perms = Perms(1 | 4);
+    pub const RWX: Perms = Perms(1 | 2 | 4);
+
+    pub fn contains(self, other: Perms) -> bool {
+        self.0 & other.0 == other.0
+    }
 
-struct MemorySegment {
-    start_address: usize,
-    data: Vec<u8>,
+    fn allows(self, access: AccessKind) -> bool {
+        self.contains(match access {
+            AccessKind::Read => Perms::READ,
+            AccessKind::Write => Perms::WRITE,
+            AccessKind::Execute => Perms::EXECUTE,
+        })
+    }
 }
 
+impl std::ops::BitOr for Perms {
+    type Output = Perms;
+
+    fn bitor(self, other: Perms) -> Perms {
+        Perms(self.0 | other.0)
+    }
+}
+
+// a mapped range [start, end) keyed by its start in Memory::regions
+#[derive(Clone, Copy)]
+struct Region {
+    end: usize,
+    perms: Perms,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFault {
+    // nothing is mapped at the address
+    Unmapped { address: usize, access: AccessKind },
+    // the address is mapped but does not allow the access
+    Permission { address: usize, access: AccessKind },
+    // the address lies below the base or the access wraps the address space
+    OutOfRange { address: usize },
+    // the linear address could not be translated
+    Page(PageFault),
+}
+
+// mapped ranges live in an ordered map for permission checks, their contents in
+// page-sized frames allocated on first write; mapped memory without a frame reads as zero
 pub struct Memory {
-    segments: Vec<MemorySegment>,
+    regions: BTreeMap<usize, Region>,
+    frames: HashMap<usize, Box<[u8; PAGE_SIZE]>>,
     base_address: usize,
+    paging: Option<PagingMode>,
+    // writes to unmapped addresses map the surrounding page read/write/execute instead of faulting
+    lazy_allocation: bool,
+}
+
+impl From<PageFault> for MemoryFault {
+    fn from(fault: PageFault) -> Self {
+        MemoryFault::Page(fault)
+    }
 }
 
 impl Memory {
     pub fn new(base: usize) -> Self {
         Memory {
-            segments: Vec::new(),
+            regions: BTreeMap::new(),
+            frames: HashMap::new(),
             base_address: base,
+            paging: None,
+            lazy_allocation: false,
         }
     }
 
-    fn find_segment(&self, real_address: usize) -> Option<usize> {
-        for (index, segment) in self.segments.iter().enumerate() {
-            if real_address >= segment.start_address && real_address < segment.start_address + segment.data.len() {
-                return Some(index);
+    pub fn set_lazy_allocation(&mut self, enabled: bool) {
+        self.lazy_allocation = enabled;
+    }
+
+    // region containing real_address
+    fn find_region(&self, real_address: usize) -> Option<(usize, Region)> {
+        self.regions.range(..=real_address).next_back()
+            .filter(|(_, region)| real_address < region.end)
+            .map(|(start, region)| (*start, *region))
+    }
+
+    // offset from the base of an access of `size` bytes
+    fn real_address(&self, address: usize, size: usize) -> Result<usize, MemoryFault> {
+        if address < self.base_address || address.checked_add(size).is_none() {
+            return Err(MemoryFault::OutOfRange { address });
+        }
+        Ok(address - self.base_address)
+    }
+
+    // every byte of [real_address, real_address + size) must be mapped and allow the access
+    fn check_access(&self, real_address: usize, size: usize, access: AccessKind) -> Result<(), MemoryFault> {
+        let end = real_address + size;
+        let mut current = real_address;
+        while current < end {
+            let address = current + self.base_address;
+            match self.find_region(current) {
+                Some((_, region)) if !region.perms.allows(access) => return Err(MemoryFault::Permission { address, access }),
+                Some((_, region)) => current = region.end,
+                None if access == AccessKind::Write && self.lazy_allocation => {
+                    // skip the hole, lazy_map fills it before the write lands
+                    current = self.regions.range(current..).next().map_or(end, |(start, _)| *start);
+                }
+                None => return Err(MemoryFault::Unmapped { address, access }),
             }
         }
-        None
-    }
-
-    fn read_byte(&self, address: usize) -> u8 {
-        let real_address = address - self.base_address;
-        if let Some(index) = self.find_segment(real_address) {
-            self.segments[index].data[real_address - self.segments[index].start_address]
-        } else {
-            // return 0 if the address is not found
-            0
-        }
-    }
-
-    fn write_byte(&mut self, address: usize, value: u8) {
-        let real_address = address - self.base_address;
-        if let Some(index) = self.find_segment(real_address) {
-            let start = self.segments[index].start_address;
-            self.segments[index].data[real_address - start] = value;
-        } else {
-            let adjusted_address = (real_address / DEFAULT_SIZE) * DEFAULT_SIZE;
-            let mut new_data = Vec::with_capacity(DEFAULT_SIZE);
-            new_data.resize(DEFAULT_SIZE, 0);
-            new_data[real_address - adjusted_address] = value;
-            let new_segment = MemorySegment {
-                start_address: adjusted_address,
-                data: new_data,
-            };
-            self.segments.push(new_segment);
-            // sort by address
-            self.segments.sort_by(|a, b| a.start_address.cmp(&b.start_address));
-        }
-        // merge segments if they are contiguous
-        let mut i = 0;
-        while i + 1 < self.segments.len() {
-            if self.segments[i].start_address + self.segments[i].data.len() == self.segments[i + 1].start_address {
-                let next = self.segments.remove(i + 1);
-                self.segments[i].data.extend(next.data);
-            } else {
-                i += 1;
+        Ok(())
+    }
+
+    // map the unmapped parts of the pages touched by a lazily allocated write
+    fn lazy_map(&mut self, real_address: usize, size: usize) {
+        let first_page = real_address / PAGE_SIZE * PAGE_SIZE;
+        let last_page = (real_address + size - 1) / PAGE_SIZE * PAGE_SIZE;
+        let mut current = first_page;
+        let end = last_page.saturating_add(PAGE_SIZE);
+        while current < end {
+            match self.find_region(current) {
+                Some((_, region)) => current = region.end,
+                None => {
+                    let hole_end = self.regions.range(current..).next().map_or(end, |(start, _)| (*start).min(end));
+                    self.regions.insert(current, Region { end: hole_end, perms: Perms::RWX });
+                    current = hole_end;
+                }
+            }
+        }
+        self.merge_regions(first_page, end);
+    }
+
+    // merge contiguous regions with equal permissions around [start, end)
+    fn merge_regions(&mut self, start: usize, end: usize) {
+        let first = self.regions.range(..start).next_back().map_or(start, |(first, _)| *first);
+        let starts: Vec<usize> = self.regions.range(first..=end).map(|(start, _)| *start).collect();
+        let mut previous: Option<(usize, Region)> = None;
+        for start in starts {
+            let region = self.regions[&start];
+            match previous {
+                Some((previous_start, previous_region)) if previous_region.end == start && previous_region.perms == region.perms => {
+                    self.regions.remove(&start);
+                    let merged = Region { end: region.end, perms: region.perms };
+                    self.regions.insert(previous_start, merged);
+                    previous = Some((previous_start, merged));
+                }
+                _ => previous = Some((start, region)),
+            }
+        }
+    }
+
+    // make real_address a region boundary
+    fn split_region(&mut self, real_address: usize) {
+        if let Some((start, region)) = self.find_region(real_address) {
+            if start != real_address {
+                self.regions.insert(start, Region { end: real_address, perms: region.perms });
+                self.regions.insert(real_address, region);
+            }
+        }
+    }
+
+    fn range(&self, address: usize, length: usize) -> Result<(usize, usize), MemoryFault> {
+        let start = self.real_address(address, length)?;
+        Ok((start, start + length))
+    }
+
+    // copy mapped contents out, pages without a frame read as zero
+    fn read_raw(&self, real_address: usize, buffer: &mut [u8]) {
+        let mut done = 0;
+        while done < buffer.len() {
+            let current = real_address + done;
+            let offset = current % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - offset).min(buffer.len() - done);
+            match self.frames.get(&(current / PAGE_SIZE)) {
+                Some(frame) => buffer[done..done + chunk].copy_from_slice(&frame[offset..offset + chunk]),
+                None => buffer[done..done + chunk].fill(0),
+            }
+            done += chunk;
+        }
+    }
+
+    fn write_raw(&mut self, real_address: usize, bytes: &[u8]) {
+        let mut done = 0;
+        while done < bytes.len() {
+            let current = real_address + done;
+            let offset = current % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - offset).min(bytes.len() - done);
+            let frame = self.frames.entry(current / PAGE_SIZE).or_insert_with(|| Box::new([0; PAGE_SIZE]));
+            frame[offset..offset + chunk].copy_from_slice(&bytes[done..done + chunk]);
+            done += chunk;
+        }
+    }
+
+    // drop the contents of [start, end), whole frames are freed
+    fn clear_raw(&mut self, start: usize, end: usize) {
+        let mut current = start;
+        while current < end {
+            let offset = current % PAGE_SIZE;
+            let chunk = (PAGE_SIZE - offset).min(end - current);
+            if chunk == PAGE_SIZE {
+                self.frames.remove(&(current / PAGE_SIZE));
+            } else if let Some(frame) = self.frames.get_mut(&(current / PAGE_SIZE)) {
+                frame[offset..offset + chunk].fill(0);
+            }
+            current += chunk;
+        }
+    }
+
+    // map zero-filled memory, replacing whatever was mapped in the range
+    pub fn map(&mut self, address: usize, length: usize, perms: Perms) -> Result<(), MemoryFault> {
+        if length == 0 {
+            return Ok(());
+        }
+        self.unmap(address, length)?;
+        let (start, end) = self.range(address, length)?;
+        self.regions.insert(start, Region { end, perms });
+        self.merge_regions(start, end);
+        Ok(())
+    }
+
+    pub fn unmap(&mut self, address: usize, length: usize) -> Result<(), MemoryFault> {
+        let (start, end) = self.range(address, length)?;
+        self.split_region(start);
+        self.split_region(end);
+        let inside: Vec<usize> = self.regions.range(start..end).map(|(start, _)| *start).collect();
+        for region_start in inside {
+            self.regions.remove(&region_start);
+        }
+        self.clear_raw(start, end);
+        Ok(())
+    }
+
+    // change the permissions of an already mapped range, every byte of it must be mapped
+    pub fn protect(&mut self, address: usize, length: usize, perms: Perms) -> Result<(), MemoryFault> {
+        let (start, end) = self.range(address, length)?;
+        let mut current = start;
+        while current < end {
+            match self.find_region(current) {
+                Some((_, region)) => current = region.end,
+                None => return Err(MemoryFault::Unmapped { address: current + self.base_address, access: AccessKind::Read }),
+            }
+        }
+        self.split_region(start);
+        self.split_region(end);
+        for (_, region) in self.regions.range_mut(start..end) {
+            region.perms = perms;
+        }
+        self.merge_regions(start, end);
+        Ok(())
+    }
+
+    // true when no byte of the range is mapped
+    pub fn is_unmapped(&self, address: usize, length: usize) -> bool {
+        match self.range(address, length) {
+            Ok((start, end)) => self.find_region(start).is_none() && self.regions.range(start..end).next().is_none(),
+            Err(_) => false,
+        }
+    }
+
+    // start of the highest page-aligned unmapped range of `length` bytes that ends at or below `top`
+    pub fn find_free(&self, top: usize, length: usize) -> Option<usize> {
+        let mut end = top.checked_sub(self.base_address)?;
+        for (start, region) in self.regions.range(..end).rev() {
+            match end.checked_sub(length) {
+                Some(candidate) if region.end <= candidate / PAGE_SIZE * PAGE_SIZE => break,
+                _ => end = *start,
             }
         }
+        let start = end.checked_sub(length)? / PAGE_SIZE * PAGE_SIZE;
+        Some(start + self.base_address)
+    }
+
+    // permissions at an address, None when unmapped
+    #[cfg(test)]
+    pub fn perms(&self, address: usize) -> Option<Perms> {
+        let real_address = self.real_address(address, 1).ok()?;
+        self.find_region(real_address).map(|(_, region)| region.perms)
+    }
+
+    pub fn read_bytes(&self, address: usize, buffer: &mut [u8]) -> Result<(), MemoryFault> {
+        let real_address = self.real_address(address, buffer.len())?;
+        self.check_access(real_address, buffer.len(), AccessKind::Read)?;
+        self.read_raw(real_address, buffer);
+        Ok(())
+    }
+
+    // nothing is written unless the whole range is writable
+    pub fn write_bytes(&mut self, address: usize, bytes: &[u8]) -> Result<(), MemoryFault> {
+        let real_address = self.real_address(address, bytes.len())?;
+        self.check_access(real_address, bytes.len(), AccessKind::Write)?;
+        if bytes.is_empty() {
+            return Ok(());
+        }
+        if self.lazy_allocation {
+            self.lazy_map(real_address, bytes.len());
+        }
+        self.write_raw(real_address, bytes);
+        Ok(())
+    }
+
+    // fill a range with one byte value, the memset counterpart of write_bytes
+    #[cfg(test)]
+    pub fn fill_bytes(&mut self, address: usize, length: usize, value: u8) -> Result<(), MemoryFault> {
+        let real_address = self.real_address(address, length)?;
+        self.check_access(real_address, length, AccessKind::Write)?;
+        if length == 0 {
+            return Ok(());
+        }
+        if self.lazy_allocation {
+            self.lazy_map(real_address, length);
+        }
+        let chunk = [value; PAGE_SIZE];
+        let mut done = 0;
+        while done < length {
+            let size = (length - done).min(PAGE_SIZE - (real_address + done) % PAGE_SIZE);
+            self.write_raw(real_address + done, &chunk[..size]);
+            done += size;
+        }
+        Ok(())
+    }
+
+    pub fn try_read<T: MemoryIO>(&self, address: usize) -> Result<T, MemoryFault> {
+        let mut buffer = [0u8; 64];
+        let bytes = &mut buffer[..T::size()];
+        self.read_bytes(address, bytes)?;
+        Ok(T::from_bytes(bytes))
+    }
+
+    pub fn try_write<T: MemoryIO>(&mut self, address: usize, value: T) -> Result<(), MemoryFault> {
+        self.write_bytes(address, &value.to_bytes())
+    }
+
+    pub fn try_fetch(&self, address: usize) -> Result<u8, MemoryFault> {
+        let real_address = self.real_address(address, 1)?;
+        self.check_access(real_address, 1, AccessKind::Execute)?;
+        let mut byte = [0u8];
+        self.read_raw(real_address, &mut byte);
+        Ok(byte[0])
     }
 
+    // unchecked accessors for setup code, a fault here is a bug in the caller
     pub fn read<T: MemoryIO>(&self, address: usize) -> T {
-        let mut bytes = Vec::new();
-        for i in 0..T::size() {
-            bytes.push(self.read_byte(address + i));
+        match self.try_read(address) {
+            Ok(value) => value,
+            Err(fault) => panic!("memory read failed: {:?}", fault),
         }
-        T::from_bytes(&bytes)
     }
 
     pub fn write<T: MemoryIO>(&mut self, address: usize, value: T) {
-        let bytes = value.to_bytes();
-        for (i, byte) in bytes.iter().enumerate() {
-            self.write_byte(address + i, *byte);
+        if let Err(fault) = self.try_write(address, value) {
+            panic!("memory write failed: {:?}", fault);
         }
     }
 
     pub fn read_vec<T: MemoryIO>(&self, address: usize, number_of_value: usize) -> Vec<T> {
-        let mut result: Vec<T> = vec![];
-        for i in 0..number_of_value {
-            result.push(self.read(address + i * T::size()));
+        let mut bytes = vec![0u8; number_of_value * T::size()];
+        if let Err(fault) = self.read_bytes(address, &mut bytes) {
+            panic!("memory read failed: {:?}", fault);
         }
-        result
+        bytes.chunks(T::size()).map(T::from_bytes).collect()
     }
 
     pub fn write_vec<T: MemoryIO + Clone>(&mut self, address: usize, values: Vec<T>) {
-        for (i, value) in values.iter().enumerate() {
-            self.write(address + i * T::size(), value.clone());
+        let bytes: Vec<u8> = values.iter().flat_map(|value| value.to_bytes()).collect();
+        if let Err(fault) = self.write_bytes(address, &bytes) {
+            panic!("memory write failed: {:?}", fault);
+        }
+    }
+
+    // linear addresses equal physical ones while paging is off
+    pub fn set_paging(&mut self, paging: Option<PagingMode>) {
+        self.paging = paging;
+    }
+
+    pub fn paging(&self) -> Option<PagingMode> {
+        self.paging
+    }
+
+    pub fn translate(&self, address: usize, access: AccessKind, user: bool) -> Result<usize, MemoryFault> {
+        match self.paging {
+            Some(paging) => paging.translate(self, address as u64, access, user).map(|physical| physical as usize),
+            None => Ok(address),
+        }
+    }
+
+    // physical (address, length) pieces of an access, one per page touched
+    fn translate_range(&self, address: usize, size: usize, access: AccessKind, user: bool) -> Result<Vec<(usize, usize)>, MemoryFault> {
+        let mut pieces = Vec::new();
+        let mut done = 0;
+        while done < size {
+            let linear = address.wrapping_add(done);
+            let chunk = (PAGE_SIZE - linear % PAGE_SIZE).min(size - done);
+            pieces.push((self.translate(linear, access, user)?, chunk));
+            done += chunk;
+        }
+        Ok(pieces)
+    }
+
+    pub fn read_bytes_linear(&self, address: usize, buffer: &mut [u8], user: bool) -> Result<(), MemoryFault> {
+        if self.paging.is_none() {
+            return self.read_bytes(address, buffer);
+        }
+        let mut done = 0;
+        for (physical, length) in self.translate_range(address, buffer.len(), AccessKind::Read, user)? {
+            self.read_bytes(physical, &mut buffer[done..done + length])?;
+            done += length;
         }
+        Ok(())
+    }
+
+    pub fn write_bytes_linear(&mut self, address: usize, bytes: &[u8], user: bool) -> Result<(), MemoryFault> {
+        if self.paging.is_none() {
+            return self.write_bytes(address, bytes);
+        }
+        // translate and check the whole access first so a fault leaves memory untouched
+        let pieces = self.translate_range(address, bytes.len(), AccessKind::Write, user)?;
+        for (physical, length) in &pieces {
+            let real_address = self.real_address(*physical, *length)?;
+            self.check_access(real_address, *length, AccessKind::Write)?;
+        }
+        let mut done = 0;
+        for (physical, length) in pieces {
+            self.write_bytes(physical, &bytes[done..done + length])?;
+            done += length;
+        }
+        Ok(())
+    }
+
+    pub fn read_linear<T: MemoryIO>(&self, address: usize, user: bool) -> Result<T, MemoryFault> {
+        let mut buffer = [0u8; 64];
+        let bytes = &mut buffer[..T::size()];
+        self.read_bytes_linear(address, bytes, user)?;
+        Ok(T::from_bytes(bytes))
+    }
+
+    pub fn write_linear<T: MemoryIO>(&mut self, address: usize, value: T, user: bool) -> Result<(), MemoryFault> {
+        self.write_bytes_linear(address, &value.to_bytes(), user)
+    }
+
+    pub fn fetch_linear(&self, address: usize, user: bool) -> Result<u8, MemoryFault> {
+        let physical = self.translate(address, AccessKind::Execute, user)?;
+        self.try_fetch(physical)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unmapped_reads_and_writes_fault() {
+        let mut memory = Memory::new(0x1000);
+        assert_eq!(memory.try_read::<u32>(0x1000), Err(MemoryFault::Unmapped { address: 0x1000, access: AccessKind::Read }));
+        assert_eq!(memory.try_write::<u8>(0x1000, 1), Err(MemoryFault::Unmapped { address: 0x1000, access: AccessKind::Write }));
+        assert_eq!(memory.try_fetch(0x1000), Err(MemoryFault::Unmapped { address: 0x1000, access: AccessKind::Execute }));
+    }
+
+    #[test]
+    fn out_of_range_addresses_fault() {
+        let mut memory = Memory::new(0x1000);
+        memory.set_lazy_allocation(true);
+        assert_eq!(memory.try_read::<u8>(0xFFF), Err(MemoryFault::OutOfRange { address: 0xFFF }));
+        assert_eq!(memory.try_write::<u16>(0x0, 1), Err(MemoryFault::OutOfRange { address: 0x0 }));
+        assert_eq!(memory.try_write::<u64>(usize::MAX - 3, 1), Err(MemoryFault::OutOfRange { address: usize::MAX - 3 }));
+    }
+
+    #[test]
+    fn mapped_regions_enforce_permissions() {
+        let mut memory = Memory::new(0);
+        memory.map(0x1000, 0x2000, Perms::RW).unwrap();
+        memory.write::<u32>(0x1FFE, 0x11223344);
+        assert_eq!(memory.try_fetch(0x1000), Err(MemoryFault::Permission { address: 0x1000, access: AccessKind::Execute }));
+
+        memory.protect(0x1000, 0x1000, Perms::RX).unwrap();
+        assert_eq!(memory.perms(0x1FFF), Some(Perms::RX));
+        assert_eq!(memory.perms(0x2000), Some(Perms::RW));
+        assert_eq!(memory.try_fetch(0x1000), Ok(0));
+        // the read-only half rejects the straddling write without touching the writable half
+        assert_eq!(memory.try_write::<u32>(0x1FFE, 0), Err(MemoryFault::Permission { address: 0x1FFE, access: AccessKind::Write }));
+        assert_eq!(memory.try_read::<u32>(0x1FFE), Ok(0x11223344));
+
+        memory.protect(0x2000, 0x1000, Perms::NONE).unwrap();
+        assert_eq!(memory.try_read::<u8>(0x2000), Err(MemoryFault::Permission { address: 0x2000, access: AccessKind::Read }));
+        assert!(memory.protect(0x2000, 0x2000, Perms::RW).is_err());
+    }
+
+    #[test]
+    fn map_and_unmap_split_regions() {
+        let mut memory = Memory::new(0);
+        memory.map(0x1000, 0x3000, Perms::RW).unwrap();
+        memory.write::<u8>(0x1000, 1);
+        memory.write::<u8>(0x3FFF, 2);
+        memory.unmap(0x2000, 0x1000).unwrap();
+        assert_eq!(memory.try_read::<u8>(0x1FFF), Ok(0));
+        assert_eq!(memory.try_read::<u8>(0x2000), Err(MemoryFault::Unmapped { address: 0x2000, access: AccessKind::Read }));
+        assert_eq!(memory.try_read::<u8>(0x3FFF), Ok(2));
+        // mapping over an existing region replaces it with zeroes
+        memory.map(0x0800, 0x1000, Perms::READ).unwrap();
+        assert_eq!(memory.try_read::<u8>(0x1000), Ok(0));
+        assert_eq!(memory.perms(0x17FF), Some(Perms::READ));
+        assert_eq!(memory.perms(0x1800), Some(Perms::RW));
+        // an empty mapping leaves the region it lands in whole
+        memory.map(0x3800, 0, Perms::READ).unwrap();
+        assert_eq!(memory.regions.len(), 3);
+        assert_eq!(memory.try_read::<u8>(0x3FFF), Ok(2));
+    }
+
+    #[test]
+    fn lazy_allocation_is_opt_in() {
+        let mut memory = Memory::new(0);
+        memory.set_lazy_allocation(true);
+        memory.try_write::<u32>(0x1FE, 0xAABBCCDD).unwrap();
+        assert_eq!(memory.try_read::<u32>(0x1FE), Ok(0xAABBCCDD));
+        // the rest of the allocated page reads as zero
+        assert_eq!(memory.try_read::<u64>(0xFF8), Ok(0));
+        assert!(memory.try_read::<u8>(0x1000).is_err());
+
+        memory.set_lazy_allocation(false);
+        // a write straddling into unmapped memory changes nothing
+        assert!(memory.try_write::<u16>(0xFFF, 0xFFFF).is_err());
+        assert_eq!(memory.try_read::<u8>(0xFFF), Ok(0));
+    }
+
+    #[test]
+    fn bulk_accesses_cross_pages() {
+        let mut memory = Memory::new(0);
+        memory.map(0x1000, 0x3000, Perms::RW).unwrap();
+        let bytes: Vec<u8> = (0..0x2100).map(|index| index as u8).collect();
+        memory.write_bytes(0x1F00, &bytes).unwrap();
+        let mut buffer = vec![0u8; bytes.len()];
+        memory.read_bytes(0x1F00, &mut buffer).unwrap();
+        assert_eq!(buffer, bytes);
+        // a u512 straddling a page boundary
+        let value = memory.read::<u512>(0x1FE0);
+        assert_eq!(value.to_bytes(), bytes[0xE0..0x120].to_vec());
+        // the range runs past the mapping, nothing is written
+        assert_eq!(memory.write_bytes(0x3FFF, &[1, 2]), Err(MemoryFault::Unmapped { address: 0x4000, access: AccessKind::Write }));
+        assert_eq!(memory.read::<u8>(0x3FFF), 0xFF);
+        memory.fill_bytes(0x1000, 0x3000, 0xCC).unwrap();
+        assert_eq!(memory.read::<u64>(0x2FFC), 0xCCCCCCCC_CCCCCCCC);
+        // unmapping drops the contents
+        memory.unmap(0x2000, 0x1000).unwrap();
+        memory.map(0x2000, 0x1000, Perms::READ).unwrap();
+        assert_eq!(memory.read::<u64>(0x2800), 0);
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    fn bench(name: &str, iterations: usize, mut body: impl FnMut(usize)) {
+        let start = std::time::Instant::now();
+        for index in 0..iterations {
+            body(index);
+        }
+        let elapsed = start.elapsed();
+        println!("{:<24} {:>10.2} ns/op", name, elapsed.as_nanos() as f64 / iterations as f64);
+    }
+
+    #[test]
+    #[ignore]
+    fn benchmark_accesses() {
+        const SPAN: usize = 16 << 20;
+        let mut memory = Memory::new(0);
+        memory.map(0, SPAN, Perms::RW).unwrap();
+        // scatter the accesses over the span so every page gets a frame
+        let address = |index: usize| (index.wrapping_mul(0x9E37_79B9) % (SPAN - 64)) & !7;
+        bench("write::<u64>", 1_000_000, |index| memory.write::<u64>(address(index), index as u64));
+        bench("read::<u64>", 1_000_000, |index| {
+            std::hint::black_box(memory.read::<u64>(address(index)));
+        });
+        bench("read::<u512>", 1_000_000, |index| {
+            std::hint::black_box(memory.read::<u512>(address(index)));
+        });
+        let block = vec![0x5Au8; 1 << 20];
+        let mut buffer = vec![0u8; 1 << 20];
+        bench("write_bytes 1 MiB", 100, |index| memory.write_bytes((index % 16) << 20, &block).unwrap());
+        bench("read_bytes 1 MiB", 100, |index| memory.read_bytes((index % 16) << 20, &mut buffer).unwrap());
     }
 }
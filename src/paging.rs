@@ -0,0 +1,235 @@
+// x86-64 4-level and 5-level page table walker
+// reference: Intel SDM Vol. 3A, 4.5 "4-Level Paging and 5-Level Paging"
+
+use crate::memory::Memory;
+use crate::memory::MemoryFault;
+
+pub const PAGE_SIZE: usize = 4096;
+
+// page table entry bits
+const PTE_PRESENT: u64 = 1 << 0;
+const PTE_WRITABLE: u64 = 1 << 1;
+const PTE_USER: u64 = 1 << 2;
+const PTE_PAGE_SIZE: u64 = 1 << 7;
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+// bits 51:12 hold the next table or the page frame
+const PTE_ADDRESS_MASK: u64 = 0x000FFFFF_FFFFF000;
+
+// page fault error code bits
+pub const PF_PRESENT: u32 = 1 << 0;
+pub const PF_WRITE: u32 = 1 << 1;
+pub const PF_USER: u32 = 1 << 2;
+pub const PF_RESERVED: u32 = 1 << 3;
+pub const PF_INSTRUCTION: u32 = 1 << 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingLevels {
+    Four, Five
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read, Write, Execute
+}
+
+// the parts of CR0/CR3/CR4/EFER that shape translation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagingMode {
+    pub cr3: u64,
+    pub levels: PagingLevels,
+    // EFER.NXE
+    pub no_execute: bool,
+    // CR0.WP
+    pub write_protect: bool,
+    // CR4.SMEP
+    pub smep: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageFault {
+    // faulting linear address, what CR2 receives
+    pub address: u64,
+    pub error_code: u32,
+}
+
+impl PagingLevels {
+    fn count(self) -> u32 {
+        match self {
+            PagingLevels::Four => 4,
+            PagingLevels::Five => 5,
+        }
+    }
+
+    // width of a canonical linear address
+    pub fn address_bits(self) -> u32 {
+        12 + 9 * self.count()
+    }
+}
+
+impl PagingMode {
+    pub fn new(cr3: u64, levels: PagingLevels) -> Self {
+        PagingMode {
+            cr3,
+            levels,
+            no_execute: false,
+            write_protect: false,
+            smep: false,
+        }
+    }
+
+    // the upper bits must all copy the top implemented bit
+    pub fn is_canonical(&self, address: u64) -> bool {
+        let shift = 64 - self.levels.address_bits();
+        (((address << shift) as i64) >> shift) as u64 == address
+    }
+
+    // page table memory that is not backed surfaces as the underlying memory fault
+    pub fn translate(&self, memory: &Memory, address: u64, access: AccessKind, user: bool) -> Result<u64, MemoryFault> {
+        let mut error_code = 0;
+        if access == AccessKind::Write {
+            error_code |= PF_WRITE;
+        }
+        if user {
+            error_code |= PF_USER;
+        }
+        if access == AccessKind::Execute && (self.no_execute || self.smep) {
+            error_code |= PF_INSTRUCTION;
+        }
+        let fault = |error_code| MemoryFault::Page(PageFault { address, error_code });
+
+        let mut table = self.cr3 & PTE_ADDRESS_MASK;
+        let mut writable = true;
+        let mut user_accessible = true;
+        let mut executable = true;
+        let levels = self.levels.count();
+        for level in (1..=levels).rev() {
+            let shift = 12 + 9 * (level - 1);
+            let index = (address >> shift) & 0x1FF;
+            let entry = memory.try_read::<u64>((table + index * 8) as usize)?;
+            if entry & PTE_PRESENT == 0 {
+                return Err(fault(error_code));
+            }
+            if entry & PTE_NO_EXECUTE != 0 && !self.no_execute {
+                return Err(fault(error_code | PF_PRESENT | PF_RESERVED));
+            }
+            writable &= entry & PTE_WRITABLE != 0;
+            user_accessible &= entry & PTE_USER != 0;
+            executable &= entry & PTE_NO_EXECUTE == 0;
+
+            let large = entry & PTE_PAGE_SIZE != 0 && level > 1;
+            if large && level > 3 {
+                // no large pages above the PDPT
+                return Err(fault(error_code | PF_PRESENT | PF_RESERVED));
+            }
+            if large || level == 1 {
+                let page_mask = (1u64 << shift) - 1;
+                // large page frames must be aligned, bit 12 is PAT
+                if large && entry & PTE_ADDRESS_MASK & page_mask & !(1 << 12) != 0 {
+                    return Err(fault(error_code | PF_PRESENT | PF_RESERVED));
+                }
+                let violation = match access {
+                    AccessKind::Write => !writable && (user || self.write_protect),
+                    AccessKind::Execute => !executable || (self.smep && !user && user_accessible),
+                    AccessKind::Read => false,
+                } || (user && !user_accessible);
+                if violation {
+                    return Err(fault(error_code | PF_PRESENT));
+                }
+                return Ok((entry & PTE_ADDRESS_MASK & !page_mask) | (address & page_mask));
+            }
+            table = entry & PTE_ADDRESS_MASK;
+        }
+        unreachable!()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const P: u64 = PTE_PRESENT;
+    const W: u64 = PTE_WRITABLE;
+    const U: u64 = PTE_USER;
+    const PS: u64 = PTE_PAGE_SIZE;
+    const NX: u64 = PTE_NO_EXECUTE;
+
+    // PML4 at 0x1000, PDPT 0x2000, PD 0x3000, PT 0x4000
+    fn four_level() -> (Memory, PagingMode) {
+        let mut memory = Memory::new(0);
+        memory.set_lazy_allocation(true);
+        memory.write::<u64>(0x1000, 0x2000 | P | W | U);
+        memory.write::<u64>(0x2000, 0x3000 | P | W | U);
+        // 0x4000_0000: 1 GiB page at 0x8000_0000, supervisor only
+        memory.write::<u64>(0x2008, 0x8000_0000 | P | W | PS);
+        memory.write::<u64>(0x3000, 0x4000 | P | W | U);
+        // 0x20_0000: 2 MiB read-only user page at 0x60_0000
+        memory.write::<u64>(0x3008, 0x60_0000 | P | U | PS);
+        // 0x0000, 0x1000: user pages, the second one not executable
+        memory.write::<u64>(0x4000, 0x9000 | P | W | U);
+        memory.write::<u64>(0x4008, 0xA000 | P | W | U | NX);
+        let mut mode = PagingMode::new(0x1000, PagingLevels::Four);
+        mode.no_execute = true;
+        (memory, mode)
+    }
+
+    #[test]
+    fn translates_all_page_sizes() {
+        let (memory, mode) = four_level();
+        assert_eq!(mode.translate(&memory, 0x0123, AccessKind::Read, true), Ok(0x9123));
+        assert_eq!(mode.translate(&memory, 0x1FFF, AccessKind::Write, true), Ok(0xAFFF));
+        assert_eq!(mode.translate(&memory, 0x2F_0010, AccessKind::Read, true), Ok(0x6F_0010));
+        assert_eq!(mode.translate(&memory, 0x4123_4567, AccessKind::Write, false), Ok(0x8123_4567));
+    }
+
+    #[test]
+    fn reports_page_fault_error_codes() {
+        let (memory, mode) = four_level();
+        // not present
+        assert_eq!(mode.translate(&memory, 0x3000, AccessKind::Read, false), Err(MemoryFault::Page(PageFault { address: 0x3000, error_code: 0 })));
+        assert_eq!(mode.translate(&memory, 0x3000, AccessKind::Write, true), Err(MemoryFault::Page(PageFault { address: 0x3000, error_code: PF_WRITE | PF_USER })));
+        // user write to a read-only page
+        assert_eq!(
+            mode.translate(&memory, 0x20_0000, AccessKind::Write, true),
+            Err(MemoryFault::Page(PageFault { address: 0x20_0000, error_code: PF_PRESENT | PF_WRITE | PF_USER }))
+        );
+        // user access to a supervisor page
+        assert_eq!(
+            mode.translate(&memory, 0x4000_0000, AccessKind::Read, true),
+            Err(MemoryFault::Page(PageFault { address: 0x4000_0000, error_code: PF_PRESENT | PF_USER }))
+        );
+        // instruction fetch from an NX page
+        assert_eq!(
+            mode.translate(&memory, 0x1000, AccessKind::Execute, true),
+            Err(MemoryFault::Page(PageFault { address: 0x1000, error_code: PF_PRESENT | PF_USER | PF_INSTRUCTION }))
+        );
+        assert_eq!(mode.translate(&memory, 0x0000, AccessKind::Execute, true), Ok(0x9000));
+    }
+
+    #[test]
+    fn honors_write_protect_and_reserved_bits() {
+        let (memory, mut mode) = four_level();
+        // supervisor writes ignore RW unless CR0.WP is set
+        assert_eq!(mode.translate(&memory, 0x20_0000, AccessKind::Write, false), Ok(0x60_0000));
+        mode.write_protect = true;
+        assert!(mode.translate(&memory, 0x20_0000, AccessKind::Write, false).is_err());
+        // NX with EFER.NXE clear is a reserved bit
+        mode.no_execute = false;
+        assert_eq!(
+            mode.translate(&memory, 0x1000, AccessKind::Read, false),
+            Err(MemoryFault::Page(PageFault { address: 0x1000, error_code: PF_PRESENT | PF_RESERVED }))
+        );
+    }
+
+    #[test]
+    fn five_level_walk_and_canonical_check() {
+        let (mut memory, _) = four_level();
+        // PML5 at 0x5000, entry 1 covers linear bit 48
+        memory.write::<u64>(0x5008, 0x1000 | P | W | U);
+        let mode = PagingMode::new(0x5000, PagingLevels::Five);
+        assert_eq!(mode.translate(&memory, 0x0001_0000_0000_0123, AccessKind::Read, true), Ok(0x9123));
+        assert!(mode.translate(&memory, 0x0123, AccessKind::Read, true).is_err());
+        assert!(mode.is_canonical(0x00FF_FFFF_FFFF_FFFF));
+        assert!(!PagingMode::new(0, PagingLevels::Four).is_canonical(0x0000_8000_0000_0000));
+        assert!(PagingMode::new(0, PagingLevels::Four).is_canonical(0xFFFF_8000_0000_0000));
+    }
+}
@@ -1,25 +1,80 @@
-extern crate bit_vec;
-use bit_vec::BitVec;
-extern crate regex;
-use regex::Regex;
-
-// trait alias and enum
-trait SectionCompatible:
-    From<u8> + Copy + Eq +
-    std::ops::Shl<usize, Output = Self> + std::ops::Shr<usize, Output = Self> +
-    std::ops::BitOr<Output = Self> + std::ops::BitAnd<Output = Self>
-{}
-
-impl<T:
-    From<u8> + Copy + Eq +
-    std::ops::Shl<usize, Output = T> + std::ops::Shr<usize, Output = T> +
-    std::ops::BitOr<Output = T> + std::ops::BitAnd<Output = T>> SectionCompatible for T
-{}
+use primitive_types::U256 as u256;
+use primitive_types::U512 as u512;
+
+/// Types a vector register can be viewed as in place.
+///
+/// # Safety
+///
+/// Implementors must be plain old data with no padding bytes and every bit pattern must be a valid value. The size
+/// must be a power of two up to 64 bytes and `align_of` at most 64, so a whole number of them fills the 64-byte aligned register.
+pub unsafe trait Lane: Copy {}
+
+// u256 and u512 are little-endian u64 limbs, which only matches the register bytes on a little-endian host
+const _: () = assert!(cfg!(target_endian = "little"), "vector register lanes assume a little-endian host");
+
+unsafe impl Lane for u8 {}
+unsafe impl Lane for u16 {}
+unsafe impl Lane for u32 {}
+unsafe impl Lane for u64 {}
+unsafe impl Lane for u128 {}
+unsafe impl Lane for u256 {}
+unsafe impl Lane for u512 {}
+unsafe impl Lane for f32 {}
+unsafe impl Lane for f64 {}
+
+fn bytes_of<T: Lane>(value: &T) -> &[u8] {
+    // a Lane is plain data, every byte of it is initialized
+    unsafe { std::slice::from_raw_parts((value as *const T).cast::<u8>(), std::mem::size_of::<T>()) }
+}
+
+#[cfg(test)]
+fn bytes_of_mut<T: Lane>(value: &mut T) -> &mut [u8] {
+    // any bytes written make a valid Lane
+    unsafe { std::slice::from_raw_parts_mut((value as *mut T).cast::<u8>(), std::mem::size_of::<T>()) }
+}
+
+// copy `count` bits from `source` at bit `from` to `destination` at bit `to`, a destination byte at a time
+fn copy_bits(source: &[u8], from: usize, destination: &mut [u8], to: usize, count: usize) {
+    let mut done = 0;
+    while done < count {
+        let (source_bit, destination_bit) = (from + done, to + done);
+        let take = (8 - destination_bit % 8).min(count - done);
+        let mask = ((1u16 << take) - 1) as u8;
+        // up to eight source bits, possibly spanning two bytes
+        let window = source[source_bit / 8] as u16 | (source.get(source_bit / 8 + 1).copied().unwrap_or(0) as u16) << 8;
+        let bits = (window >> (source_bit % 8)) as u8 & mask;
+        let byte = &mut destination[destination_bit / 8];
+        *byte = *byte & !(mask << (destination_bit % 8)) | bits << (destination_bit % 8);
+        done += take;
+    }
+}
 
 pub enum VecRegName {
     XMM, YMM, ZMM
 }
 
+impl VecRegName {
+    fn bits(&self) -> usize {
+        match self {
+            VecRegName::XMM => 128,
+            VecRegName::YMM => 256,
+            VecRegName::ZMM => 512,
+        }
+    }
+}
+
+// what a vector register write does with the bits above the width it names
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    // legacy SSE: the bits above are left as they are
+    Merge,
+    // VEX and EVEX: the bits above are zeroed up to the full 512 bits
+    ZeroUpper,
+    // the write names the whole 512-bit register
+    Full,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GPRName {
     // 64-bit registers
     RAX, RBX, RCX, RDX, RSI, RDI, RBP, RSP,
@@ -35,143 +90,308 @@ pub enum GPRName {
     R8B, R9B, R10B, R11B, R12B, R13B, R14B, R15B
 }
 
+// the slice of a 64-bit GPR a register name refers to
+#[derive(Clone, Copy)]
+enum GPRPart {
+    Full, Low32, Low16, Low8, High8
+}
+
 pub enum FLAGSName {
     // 64-bit registers
-    RFLAGS,
-    // 32-bit registers
-    EFLAGS,
-    // 16-bit registers
-    FLAGS
+    RFLAGS
 }
 
+// single bits and fields of RFLAGS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    CF, PF, AF, ZF, SF, TF, IF, DF, OF, NT, RF, VM, AC, ID
+}
+
+pub enum CRName {
+    CR0, CR2, CR3, CR4, CR8
+}
+
+// control register bits the emulator acts on
+pub const CR0_WP: u64 = 1 << 16;
+pub const CR0_PG: u64 = 1 << 31;
+pub const CR4_LA57: u64 = 1 << 12;
+pub const CR4_FSGSBASE: u64 = 1 << 16;
+pub const CR4_SMEP: u64 = 1 << 20;
+pub const EFER_NXE: u64 = 1 << 11;
+
+// segment registers in the order of the ModRM Sreg encoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentName {
+    ES, CS, SS, DS, FS, GS
+}
+
+// the hidden part of a segment register, filled from the descriptor its selector picks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SegmentCache {
+    pub base: u64,
+    pub limit: u32,
+    // descriptor bits 40-47 and 52-55: type, S, DPL and P in the low byte, AVL, L, D/B and G from bit 12
+    pub attributes: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Segment {
+    pub selector: u16,
+    pub cache: SegmentCache,
+}
+
+// GDTR and IDTR: the linear base of a descriptor table and its limit in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DescriptorTable {
+    pub base: u64,
+    pub limit: u16,
+}
+
+// flat long mode segments at CPL 0: 64-bit code, and writable data with a 4 GiB limit
+const FLAT_CODE: Segment = Segment { selector: 0x08, cache: SegmentCache { base: 0, limit: 0xFFFF_FFFF, attributes: 0xA09B } };
+const FLAT_DATA: Segment = Segment { selector: 0x10, cache: SegmentCache { base: 0, limit: 0xFFFF_FFFF, attributes: 0xC093 } };
+
 pub enum IPName {
     // 64-bit registers
-    RIP,
-    // 32-bit registers
-    EIP,
-    // 16-bit registers
-    IP
+    RIP
+}
+
+// why a vector register selector was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectorError {
+    // neither `[high:low]` nor `lane[index]` / `lane[first..last]`
+    Syntax,
+    // a bound that is neither a decimal number nor MAX
+    InvalidNumber,
+    // a lane other than byte, word, dword, qword, xmmword or ymmword
+    UnknownLane,
+    // the range is reversed or runs past the width of the register named
+    OutOfRange { low: usize, high: usize, width: usize },
+    // the range holds more bits than the type it is read into or written from
+    TooWide { bits: usize, type_bits: usize },
 }
 
-fn extract_values(s: &str) -> Option<(usize, usize)> {
-    let re = Regex::new(r"\[(.*?):(.*?)\]").unwrap();
-    re.captures(s).map(|cap| {
-        let a_str = cap.get(1).map_or("", |m| m.as_str());
-        let b_str = cap.get(2).map_or("", |m| m.as_str());
-        let a_value = if a_str == "MAX" { 511 } else { a_str.parse::<usize>().unwrap_or(0) };
-        let b_value = if b_str == "MAX" { 511 } else { b_str.parse::<usize>().unwrap_or(0) };
-        (a_value, b_value)
-    })
+// an inclusive range of vector register bits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    pub low: usize,
+    pub high: usize,
 }
 
+fn selector_bound(text: &str, max: usize) -> Result<usize, SelectorError> {
+    match text.trim() {
+        "MAX" => Ok(max),
+        number => number.parse().map_err(|_| SelectorError::InvalidNumber),
+    }
+}
+
+impl BitRange {
+    // `[high:low]` in bits, or `lane[index]` and `lane[first..last]` with both lanes included; MAX is the top bit or
+    // the last lane within the width of `reg_type`
+    pub fn parse(selector: &str, reg_type: &VecRegName) -> Result<BitRange, SelectorError> {
+        let width = reg_type.bits();
+        let selector = selector.trim();
+        let open = selector.find('[').ok_or(SelectorError::Syntax)?;
+        let inner = selector[open + 1..].strip_suffix(']').ok_or(SelectorError::Syntax)?;
+        let (low, high) = match selector[..open].trim() {
+            "" => {
+                let (high, low) = inner.split_once(':').ok_or(SelectorError::Syntax)?;
+                (selector_bound(low, width - 1)?, selector_bound(high, width - 1)?)
+            }
+            lane => {
+                let lane_bits = match lane {
+                    "byte" => 8,
+                    "word" => 16,
+                    "dword" => 32,
+                    "qword" => 64,
+                    "xmmword" => 128,
+                    "ymmword" => 256,
+                    _ => return Err(SelectorError::UnknownLane),
+                };
+                let last_lane = (width / lane_bits).saturating_sub(1);
+                let (first, last) = match inner.split_once("..") {
+                    Some((first, last)) => (selector_bound(first, last_lane)?, selector_bound(last, last_lane)?),
+                    None => {
+                        let index = selector_bound(inner, last_lane)?;
+                        (index, index)
+                    }
+                };
+                (first.saturating_mul(lane_bits), last.saturating_add(1).saturating_mul(lane_bits) - 1)
+            }
+        };
+        if low > high || high >= width {
+            return Err(SelectorError::OutOfRange { low, high, width });
+        }
+        Ok(BitRange { low, high })
+    }
+
+    pub fn bits(&self) -> usize {
+        self.high - self.low + 1
+    }
+
+    fn fits<T: Lane>(self) -> Result<BitRange, SelectorError> {
+        let type_bits = std::mem::size_of::<T>() * 8;
+        if self.bits() > type_bits {
+            return Err(SelectorError::TooWide { bits: self.bits(), type_bits });
+        }
+        Ok(self)
+    }
+}
+
+// 512 bits as 64 little-endian bytes, aligned so every lane type can be viewed in place
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
 struct SIMDRegister {
-    bits: BitVec,
+    bytes: [u8; 64],
 }
 
+#[derive(Clone, Copy)]
 struct GPR {
     value: u64,
 }
 
 pub struct Registers {
-    simd_registers: [SIMDRegister; 16],
+    // XMM/YMM/ZMM 0-31, the upper sixteen are only reachable through EVEX
+    simd_registers: [SIMDRegister; 32],
+    // AVX-512 opmask registers k0-k7
+    opmask_registers: [u64; 8],
+    // SSE control and status: exception flags, DAZ, exception masks, rounding control and FTZ
+    mxcsr: u32,
+    // x87 physical registers R0-R7 as raw 80-bit values, ST(i) is R((TOP + i) mod 8) with TOP in the status word
+    fpu_registers: [u128; 8],
+    fpu_control: u16,
+    fpu_status: u16,
+    // two bits per physical register: valid, zero, special or empty
+    fpu_tag: u16,
     gpr: [GPR; 16],
     rflags: u64,
     rip: u64,
+    control_registers: [u64; 5],
+    efer: u64,
+    // ES, CS, SS, DS, FS and GS; 64-bit mode uses the bases of FS and GS only
+    segments: [Segment; 6],
+    // the GS base SWAPGS exchanges with GS.base
+    kernel_gs_base: u64,
+    gdtr: DescriptorTable,
+    idtr: DescriptorTable,
+    // LDTR and TR hold selectors of system descriptors in the GDT, cached like segment registers
+    ldtr: Segment,
+    tr: Segment,
 }
 
 impl SIMDRegister {
-    fn new(size: usize) -> Self {
+    fn new() -> Self {
         SIMDRegister {
-            bits: BitVec::from_elem(size, false),
+            bytes: [0u8; 64],
         }
     }
 
+    fn lanes<T: Lane>(&self) -> &[T] {
+        // 64 aligned bytes hold a whole number of any Lane
+        unsafe { std::slice::from_raw_parts(self.bytes.as_ptr().cast::<T>(), 64 / std::mem::size_of::<T>()) }
+    }
+
+    fn lanes_mut<T: Lane>(&mut self) -> &mut [T] {
+        unsafe { std::slice::from_raw_parts_mut(self.bytes.as_mut_ptr().cast::<T>(), 64 / std::mem::size_of::<T>()) }
+    }
+
     fn set_bit(&mut self, position: usize, value: bool) {
-        self.bits.set(position, value);
+        let mask = 1u8 << (position % 8);
+        if value {
+            self.bytes[position / 8] |= mask;
+        } else {
+            self.bytes[position / 8] &= !mask;
+        }
     }
 
     fn get_bit(&self, position: usize) -> bool {
-        self.bits[position]
+        self.bytes[position / 8] >> (position % 8) & 1 == 1
     }
 
     fn clear(&mut self) {
-        for i in 0..self.bits.len() {
-            self.set_bit(i, false);
-        }
+        self.bytes = [0u8; 64];
     }
 
-    fn get_sections<T: SectionCompatible>(&self) -> Vec<T> {
-        let mut sections = Vec::new();
-        let type_bits = std::mem::size_of::<T>() * 8;
-        for i in (0..self.bits.len()).step_by(type_bits) {
-            let mut section_value: T = T::from(0u8);
-            for j in 0..type_bits {
-                if i + j >= self.bits.len() {
-                    break;
-                }
-                if self.bits[i + j] {
-                    section_value = section_value | (T::from(1u8) << j);
-                }
-            }
-            sections.push(section_value);
-        }
-        sections
+    // the bits of a range that fits T, zero-extended
+    #[cfg(test)]
+    fn get_by_index<T: Lane>(&self, range: BitRange) -> T {
+        // all zero bytes are a valid Lane
+        let mut value: T = unsafe { std::mem::zeroed() };
+        copy_bits(&self.bytes, range.low, bytes_of_mut(&mut value), 0, range.bits());
+        value
     }
 
-    fn set_by_sections<T: SectionCompatible>(&mut self, sections: Vec<T>) -> bool {
-        let type_bits = std::mem::size_of::<T>() * 8;
-        if type_bits * sections.len() != self.bits.len() {
-            return false;
-        }
-        let mut i = 0;
-        for section in &sections {
-            for j in 0..type_bits {
-                if i + j >= self.bits.len() {
-                    break;
-                }
-                if (*section >> j) & T::from(1u8) == T::from(1u8) {
-                    self.set_bit(i + j, true);
-                }
-            }
-            i += type_bits;
+    // the range from the low bits of value
+    fn set_by_index<T: Lane>(&mut self, range: BitRange, value: T) {
+        copy_bits(bytes_of(&value), 0, &mut self.bytes, range.low, range.bits());
+    }
+}
+
+impl GPRName {
+    // backing 64-bit register index and the part of it this name covers
+    fn alias(self) -> (usize, GPRPart) {
+        let name = self as usize;
+        let (r64, r32, r16, ah, al) = (
+            GPRName::RAX as usize, GPRName::EAX as usize, GPRName::AX as usize,
+            GPRName::AH as usize, GPRName::AL as usize,
+        );
+        if name < r32 {
+            (name - r64, GPRPart::Full)
+        } else if name < r16 {
+            (name - r32, GPRPart::Low32)
+        } else if name < ah {
+            (name - r16, GPRPart::Low16)
+        } else if name < al {
+            // AH, BH, CH, DH
+            (name - ah, GPRPart::High8)
+        } else {
+            // AL, BL, CL, DL, SIL, DIL, BPL, SPL and R8B..R15B follow the 64-bit order
+            (name - al, GPRPart::Low8)
         }
-        true
     }
+}
 
-    fn get_by_index<T: SectionCompatible>(&self, start_index: usize, end_index: usize) -> T {
-        let size = end_index - start_index + 1;
-        let type_bits = std::mem::size_of::<T>() * 8;
-        if type_bits < size {
-            panic!("Invalid T size for getting value from {} to {}", start_index, end_index);
+impl GPRPart {
+    fn mask(self) -> u64 {
+        match self {
+            GPRPart::Full => 0xFFFFFFFF_FFFFFFFF,
+            GPRPart::Low32 => 0x00000000_FFFFFFFF,
+            GPRPart::Low16 => 0x00000000_0000FFFF,
+            GPRPart::Low8 | GPRPart::High8 => 0x00000000_000000FF,
         }
-        let mut value: T = T::from(0u8);
-        for i in start_index..=end_index {
-            if i >= self.bits.len() {
-                break;
-            }
-            if self.bits[i] {
-                value = value | (T::from(1u8) << (i - start_index));
-            }
+    }
+
+    fn shift(self) -> u32 {
+        match self {
+            GPRPart::High8 => 8,
+            _ => 0,
         }
-        value
     }
+}
 
-    fn set_by_index<T: SectionCompatible>(&mut self, start_index: usize, end_index: usize, value: T) {
-        let size = end_index - start_index + 1;
-        let type_bits = std::mem::size_of::<T>() * 8;
-        for i in start_index..=end_index {
-            if i >= self.bits.len() {
-                break;
-            }
-            if i - start_index >= type_bits {
-                self.set_bit(i, false);
-            } else if (value >> (i - start_index)) & T::from(1u8) == T::from(1u8) {
-                self.set_bit(i, true);
-            } else {
-                self.set_bit(i, false);
-            }
+impl Flag {
+    pub const fn bit(self) -> u32 {
+        match self {
+            Flag::CF => 0,
+            Flag::PF => 2,
+            Flag::AF => 4,
+            Flag::ZF => 6,
+            Flag::SF => 7,
+            Flag::TF => 8,
+            Flag::IF => 9,
+            Flag::DF => 10,
+            Flag::OF => 11,
+            Flag::NT => 14,
+            Flag::RF => 16,
+            Flag::VM => 17,
+            Flag::AC => 18,
+            Flag::ID => 21,
         }
     }
+
+    pub const fn mask(self) -> u64 {
+        1u64 << self.bit()
+    }
 }
 
 impl GPR {
@@ -190,34 +410,32 @@ impl GPR {
     }
 }
 
-impl Clone for GPR {
-    fn clone(&self) -> Self {
-        GPR {
-            value: self.value
-        }
-    }
-}
-
-impl Copy for GPR {}
-
 impl Registers {
     pub fn new() -> Self {
         Registers {
-            simd_registers: [
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-                SIMDRegister::new(512), SIMDRegister::new(512),
-            ],
+            simd_registers: [SIMDRegister::new(); 32],
+            opmask_registers: [0u64; 8],
+            // every exception masked, round to nearest
+            mxcsr: 0x1F80u32,
+            // the FNINIT state: every exception masked, 64-bit precision, round to nearest and an empty stack
+            fpu_registers: [0u128; 8],
+            fpu_control: 0x037Fu16,
+            fpu_status: 0u16,
+            fpu_tag: 0xFFFFu16,
             gpr: [
                 GPR::new(); 16
             ],
-            rflags: 0u64,
+            // bit 1 of RFLAGS is reserved and always set
+            rflags: 0x2u64,
             rip: 0u64,
+            control_registers: [0u64; 5],
+            efer: 0u64,
+            segments: [FLAT_DATA, FLAT_CODE, FLAT_DATA, FLAT_DATA, Segment::default(), Segment::default()],
+            kernel_gs_base: 0u64,
+            gdtr: DescriptorTable::default(),
+            idtr: DescriptorTable::default(),
+            ldtr: Segment::default(),
+            tr: Segment::default(),
         }
     }
 
@@ -251,137 +469,76 @@ impl Registers {
         }
     }
 
-    pub fn clear(&mut self, reg_index: usize) {
-        self.simd_registers[reg_index].clear();
+    // the low `reg_type` bits of a register as lanes of T, without copying
+    pub fn lanes<T: Lane>(&self, reg_type: VecRegName, reg_index: usize) -> &[T] {
+        let count = reg_type.bits() / (std::mem::size_of::<T>() * 8);
+        &self.simd_registers[reg_index].lanes()[..count]
     }
 
-    pub fn get_by_sections<T: SectionCompatible>(&self, reg_type: VecRegName, reg_index: usize) -> Option<Vec<T>> {
-        let sections: Vec<T> = self.simd_registers[reg_index].get_sections();
-        match reg_type {
-            VecRegName::XMM => {
-                let n = 128 / (std::mem::size_of::<T>() * 8);
-                let slice = &sections[..n];
-                Some(slice.to_vec())
-            }
-            VecRegName::YMM => {
-                let n = 256 / (std::mem::size_of::<T>() * 8);
-                let slice = &sections[..n];
-                Some(slice.to_vec())
-            }
-            VecRegName::ZMM => {
-                let n = 512 / (std::mem::size_of::<T>() * 8);
-                let slice = &sections[..n];
-                Some(slice.to_vec())
-            }
-        }
+    // writes through the view leave the bits above `reg_type` alone
+    #[cfg(test)]
+    pub fn lanes_mut<T: Lane>(&mut self, reg_type: VecRegName, reg_index: usize) -> &mut [T] {
+        let count = reg_type.bits() / (std::mem::size_of::<T>() * 8);
+        &mut self.simd_registers[reg_index].lanes_mut()[..count]
     }
 
-    pub fn set_by_sections<T: SectionCompatible>(&mut self, reg_type: VecRegName, reg_index: usize, sections: Vec<T>) -> bool {
-        let type_bits = std::mem::size_of::<T>() * 8;
-        let register_bits = type_bits * sections.len();
-        let fill_sections = (512 - register_bits) / type_bits;
-        match reg_type {
-            VecRegName::XMM => {
-                if register_bits != 128 {
-                    return false;
-                }
-                let mut fill = sections;
-                fill.extend(std::iter::repeat(T::from(0u8)).take(fill_sections));
-                self.simd_registers[reg_index].set_by_sections(fill);
-                true
-            }
-            VecRegName::YMM => {
-                if register_bits != 256 {
-                    return false;
-                }
-                let mut fill = sections;
-                fill.extend(std::iter::repeat(T::from(0u8)).take(fill_sections));
-                self.simd_registers[reg_index].set_by_sections(fill);
-                true
-            }
-            VecRegName::ZMM => {
-                if register_bits != 512 {
-                    return false;
-                }
-                let mut fill = sections;
-                fill.extend(std::iter::repeat(T::from(0u8)).take(fill_sections));
-                self.simd_registers[reg_index].set_by_sections(fill);
-                true
-            }
-        }
+    pub fn get_by_sections<T: Lane>(&self, reg_type: VecRegName, reg_index: usize) -> Option<Vec<T>> {
+        Some(self.lanes(reg_type, reg_index).to_vec())
     }
 
-    pub fn get_by_selector<T: SectionCompatible>(&self, _reg_type: VecRegName, reg_index: usize, selector: &str) -> Option<T> {
-        if let Some((a, b)) = extract_values(selector) {
-            Some(self.simd_registers[reg_index].get_by_index(b, a))
-        } else {
-            None
-        }
+    pub fn set_by_sections<T: Lane>(&mut self, reg_type: VecRegName, reg_index: usize, sections: Vec<T>) -> bool {
+        self.write_sections(reg_type, reg_index, sections, WriteMode::ZeroUpper)
     }
 
-    pub fn set_by_selector<T: SectionCompatible>(&mut self, _reg_type: VecRegName, reg_index: usize, selector: &str, value: T) -> bool {
-        if let Some((a, b)) = extract_values(selector) {
-            self.simd_registers[reg_index].set_by_index(b, a, value);
-            true
-        } else {
-            false
+    // overwrite the `reg_type` wide low part of a register, false and nothing written when the sections do not
+    // cover exactly that width or a full write names less than ZMM
+    pub fn write_sections<T: Lane>(&mut self, reg_type: VecRegName, reg_index: usize, sections: Vec<T>, mode: WriteMode) -> bool {
+        let width = reg_type.bits();
+        if std::mem::size_of::<T>() * 8 * sections.len() != width {
+            return false;
+        }
+        let register = &mut self.simd_registers[reg_index];
+        match mode {
+            WriteMode::Full if width != 512 => return false,
+            WriteMode::ZeroUpper | WriteMode::Full => register.clear(),
+            WriteMode::Merge => {}
         }
+        register.lanes_mut()[..sections.len()].copy_from_slice(&sections);
+        true
+    }
+
+    #[cfg(test)]
+    pub fn get_by_selector<T: Lane>(&self, reg_type: VecRegName, reg_index: usize, selector: &str) -> Result<T, SelectorError> {
+        let range = BitRange::parse(selector, &reg_type)?.fits::<T>()?;
+        Ok(self.simd_registers[reg_index].get_by_index(range))
+    }
+
+    pub fn set_by_selector<T: Lane>(&mut self, reg_type: VecRegName, reg_index: usize, selector: &str, value: T) -> Result<(), SelectorError> {
+        let range = BitRange::parse(selector, &reg_type)?.fits::<T>()?;
+        self.simd_registers[reg_index].set_by_index(range, value);
+        Ok(())
     }
 
     pub fn set_gpr_value(&mut self, reg_name: GPRName, value: u64) {
-        match reg_name {
-            GPRName::EAX => {
-                self.gpr[GPRName::RAX as usize].value = value & 0x00000000_FFFFFFFF;
-            }
-            GPRName::AX => {
-                self.gpr[GPRName::RAX as usize].value = (self.gpr[GPRName::RAX as usize].value & 0xFFFFFFFF_FFFF0000) | (value & 0x00000000_0000FFFF);
-            }
-            GPRName::AL => {
-                self.gpr[GPRName::RAX as usize].value = (self.gpr[GPRName::RAX as usize].value & 0xFFFFFFFF_FFFFFF00) | (value & 0x00000000_000000FF);
-            }
-            GPRName::AH => {
-                self.gpr[GPRName::RAX as usize].value = (self.gpr[GPRName::RAX as usize].value & 0xFFFFFFFF_FFFF00FF) | ((value << 8) & 0x00000000_0000FF00);
-            }
-            // TODO: add other registers
-            _ => {
-                let index = reg_name as usize;
-                self.gpr[index].set_value(value);
-            }
-        }
+        let (index, part) = reg_name.alias();
+        let old = self.gpr[index].get_value();
+        let new = match part {
+            // 32-bit writes zero-extend into the full register
+            GPRPart::Full | GPRPart::Low32 => value & part.mask(),
+            _ => (old & !(part.mask() << part.shift())) | ((value & part.mask()) << part.shift()),
+        };
+        self.gpr[index].set_value(new);
     }
 
     pub fn get_gpr_value(&self, reg_name: GPRName) -> u64 {
-        match reg_name {
-            GPRName::EAX => {
-                self.gpr[GPRName::RAX as usize].value & 0x00000000_FFFFFFFF
-            }
-            GPRName::AX => {
-                self.gpr[GPRName::RAX as usize].value & 0x00000000_0000FFFF
-            }
-            GPRName::AL => {
-                self.gpr[GPRName::RAX as usize].value & 0x00000000_000000FF
-            }
-            GPRName::AH => {
-                (self.gpr[GPRName::RAX as usize].value & 0x00000000_0000FF00) >> 8
-            }
-            // TODO: add other registers
-            _ => {
-                let index = reg_name as usize;
-                self.gpr[index].get_value()
-            }
-        }
+        let (index, part) = reg_name.alias();
+        (self.gpr[index].get_value() >> part.shift()) & part.mask()
     }
 
     pub fn set_flags_value(&mut self, reg_name: FLAGSName, value: u64) {
         match reg_name {
             FLAGSName::RFLAGS => {
                 self.rflags = value;
-            },
-            FLAGSName::EFLAGS => {
-                self.rflags = (self.rflags & 0xFFFFFFFF_00000000) | (value & 0x00000000_FFFFFFFF);
-            },
-            FLAGSName::FLAGS => {
-                self.rflags = (self.rflags & 0xFFFFFFFF_FFFF0000) | (value & 0x00000000_0000FFFF);
             }
         }
     }
@@ -390,26 +547,36 @@ impl Registers {
         match reg_name {
             FLAGSName::RFLAGS => {
                 self.rflags
-            },
-            FLAGSName::EFLAGS => {
-                self.rflags & 0x00000000_FFFFFFFF
-            },
-            FLAGSName::FLAGS => {
-                self.rflags & 0x00000000_0000FFFF
             }
         }
     }
 
+    pub fn get_flag(&self, flag: Flag) -> bool {
+        self.rflags & flag.mask() != 0
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, value: bool) {
+        if value {
+            self.rflags |= flag.mask();
+        } else {
+            self.rflags &= !flag.mask();
+        }
+    }
+
+    // I/O privilege level, bits 12-13
+    pub fn get_iopl(&self) -> u8 {
+        ((self.rflags >> 12) & 0b11) as u8
+    }
+
+    #[cfg(test)]
+    pub fn set_iopl(&mut self, value: u8) {
+        self.rflags = (self.rflags & !0x3000) | (((value & 0b11) as u64) << 12);
+    }
+
     pub fn set_ip_value(&mut self, reg_name: IPName, value: u64) {
         match reg_name {
             IPName::RIP => {
                 self.rip = value;
-            },
-            IPName::EIP => {
-                self.rip = value & 0x00000000_FFFFFFFF;
-            },
-            IPName::IP => {
-                self.rip = value & 0x00000000_0000FFFF;
             }
         }
     }
@@ -418,13 +585,436 @@ impl Registers {
         match reg_name {
             IPName::RIP => {
                 self.rip
-            },
-            IPName::EIP => {
-                self.rip & 0x00000000_FFFFFFFF
-            },
-            IPName::IP => {
-                self.rip & 0x00000000_0000FFFF
             }
         }
     }
+
+    pub fn set_cr_value(&mut self, reg_name: CRName, value: u64) {
+        self.control_registers[reg_name as usize] = value;
+    }
+
+    pub fn get_cr_value(&self, reg_name: CRName) -> u64 {
+        self.control_registers[reg_name as usize]
+    }
+
+    pub fn set_efer(&mut self, value: u64) {
+        self.efer = value;
+    }
+
+    pub fn get_efer(&self) -> u64 {
+        self.efer
+    }
+
+    pub fn set_segment(&mut self, name: SegmentName, segment: Segment) {
+        self.segments[name as usize] = segment;
+    }
+
+    pub fn get_segment(&self, name: SegmentName) -> Segment {
+        self.segments[name as usize]
+    }
+
+    pub fn set_fs_base(&mut self, value: u64) {
+        self.segments[SegmentName::FS as usize].cache.base = value;
+    }
+
+    pub fn get_fs_base(&self) -> u64 {
+        self.segments[SegmentName::FS as usize].cache.base
+    }
+
+    pub fn set_gs_base(&mut self, value: u64) {
+        self.segments[SegmentName::GS as usize].cache.base = value;
+    }
+
+    pub fn get_gs_base(&self) -> u64 {
+        self.segments[SegmentName::GS as usize].cache.base
+    }
+
+    pub fn set_kernel_gs_base(&mut self, value: u64) {
+        self.kernel_gs_base = value;
+    }
+
+    pub fn get_kernel_gs_base(&self) -> u64 {
+        self.kernel_gs_base
+    }
+
+    pub fn set_gdtr(&mut self, value: DescriptorTable) {
+        self.gdtr = value;
+    }
+
+    pub fn get_gdtr(&self) -> DescriptorTable {
+        self.gdtr
+    }
+
+    pub fn set_idtr(&mut self, value: DescriptorTable) {
+        self.idtr = value;
+    }
+
+    pub fn get_idtr(&self) -> DescriptorTable {
+        self.idtr
+    }
+
+    pub fn set_ldtr(&mut self, value: Segment) {
+        self.ldtr = value;
+    }
+
+    pub fn get_ldtr(&self) -> Segment {
+        self.ldtr
+    }
+
+    pub fn set_tr(&mut self, value: Segment) {
+        self.tr = value;
+    }
+
+    pub fn get_tr(&self) -> Segment {
+        self.tr
+    }
+
+    pub fn set_opmask(&mut self, index: usize, value: u64) {
+        self.opmask_registers[index] = value;
+    }
+
+    pub fn get_opmask(&self, index: usize) -> u64 {
+        self.opmask_registers[index]
+    }
+
+    pub fn set_mxcsr(&mut self, value: u32) {
+        self.mxcsr = value;
+    }
+
+    pub fn get_mxcsr(&self) -> u32 {
+        self.mxcsr
+    }
+
+    pub fn set_fpu_register(&mut self, index: usize, value: u128) {
+        self.fpu_registers[index] = value & ((1 << 80) - 1);
+    }
+
+    pub fn get_fpu_register(&self, index: usize) -> u128 {
+        self.fpu_registers[index]
+    }
+
+    pub fn set_fpu_control(&mut self, value: u16) {
+        self.fpu_control = value;
+    }
+
+    pub fn get_fpu_control(&self) -> u16 {
+        self.fpu_control
+    }
+
+    pub fn set_fpu_status(&mut self, value: u16) {
+        self.fpu_status = value;
+    }
+
+    pub fn get_fpu_status(&self) -> u16 {
+        self.fpu_status
+    }
+
+    pub fn set_fpu_tag(&mut self, value: u16) {
+        self.fpu_tag = value;
+    }
+
+    pub fn get_fpu_tag(&self) -> u16 {
+        self.fpu_tag
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const R64: [GPRName; 16] = [
+        GPRName::RAX, GPRName::RBX, GPRName::RCX, GPRName::RDX, GPRName::RSI, GPRName::RDI, GPRName::RBP, GPRName::RSP,
+        GPRName::R8, GPRName::R9, GPRName::R10, GPRName::R11, GPRName::R12, GPRName::R13, GPRName::R14, GPRName::R15,
+    ];
+    const R32: [GPRName; 16] = [
+        GPRName::EAX, GPRName::EBX, GPRName::ECX, GPRName::EDX, GPRName::ESI, GPRName::EDI, GPRName::EBP, GPRName::ESP,
+        GPRName::R8D, GPRName::R9D, GPRName::R10D, GPRName::R11D, GPRName::R12D, GPRName::R13D, GPRName::R14D, GPRName::R15D,
+    ];
+    const R16: [GPRName; 16] = [
+        GPRName::AX, GPRName::BX, GPRName::CX, GPRName::DX, GPRName::SI, GPRName::DI, GPRName::BP, GPRName::SP,
+        GPRName::R8W, GPRName::R9W, GPRName::R10W, GPRName::R11W, GPRName::R12W, GPRName::R13W, GPRName::R14W, GPRName::R15W,
+    ];
+    const R8: [GPRName; 16] = [
+        GPRName::AL, GPRName::BL, GPRName::CL, GPRName::DL, GPRName::SIL, GPRName::DIL, GPRName::BPL, GPRName::SPL,
+        GPRName::R8B, GPRName::R9B, GPRName::R10B, GPRName::R11B, GPRName::R12B, GPRName::R13B, GPRName::R14B, GPRName::R15B,
+    ];
+    const R8H: [GPRName; 4] = [GPRName::AH, GPRName::BH, GPRName::CH, GPRName::DH];
+
+    const DIRTY: u64 = 0x11223344_55667788;
+
+    fn dirty_registers() -> Registers {
+        let mut registers = Registers::new();
+        for (i, name) in R64.iter().enumerate() {
+            registers.set_gpr_value(*name, DIRTY ^ i as u64);
+        }
+        registers
+    }
+
+    // every vector register starts all ones
+    fn dirty_simd_registers() -> Registers {
+        let mut registers = Registers::new();
+        for index in 0..32 {
+            for bit in 0..512 {
+                registers.set_bit(VecRegName::ZMM, index, bit, true);
+            }
+        }
+        registers
+    }
+
+    // every other register must keep its dirty value
+    fn assert_others_untouched(registers: &Registers, skip: usize) {
+        for (i, name) in R64.iter().enumerate() {
+            if i != skip {
+                assert_eq!(registers.get_gpr_value(*name), DIRTY ^ i as u64, "{:?} was clobbered", name);
+            }
+        }
+    }
+
+    #[test]
+    fn gpr64_round_trip() {
+        for (i, name) in R64.iter().enumerate() {
+            let mut registers = dirty_registers();
+            registers.set_gpr_value(*name, 0xFEDCBA98_76543210);
+            assert_eq!(registers.get_gpr_value(*name), 0xFEDCBA98_76543210);
+            assert_others_untouched(&registers, i);
+        }
+    }
+
+    #[test]
+    fn gpr32_zero_extends() {
+        for (i, name) in R32.iter().enumerate() {
+            let mut registers = dirty_registers();
+            assert_eq!(registers.get_gpr_value(*name), (DIRTY ^ i as u64) & 0xFFFFFFFF);
+            registers.set_gpr_value(*name, 0xFFFFFFFF_89ABCDEF);
+            assert_eq!(registers.get_gpr_value(*name), 0x89ABCDEF);
+            assert_eq!(registers.get_gpr_value(R64[i]), 0x00000000_89ABCDEF);
+            assert_others_untouched(&registers, i);
+        }
+    }
+
+    #[test]
+    fn gpr16_merges() {
+        for (i, name) in R16.iter().enumerate() {
+            let mut registers = dirty_registers();
+            assert_eq!(registers.get_gpr_value(*name), (DIRTY ^ i as u64) & 0xFFFF);
+            registers.set_gpr_value(*name, 0xFFFF_ABCD);
+            assert_eq!(registers.get_gpr_value(*name), 0xABCD);
+            assert_eq!(registers.get_gpr_value(R64[i]), ((DIRTY ^ i as u64) & !0xFFFF) | 0xABCD);
+            assert_others_untouched(&registers, i);
+        }
+    }
+
+    #[test]
+    fn gpr8_low_merges() {
+        for (i, name) in R8.iter().enumerate() {
+            let mut registers = dirty_registers();
+            assert_eq!(registers.get_gpr_value(*name), (DIRTY ^ i as u64) & 0xFF);
+            registers.set_gpr_value(*name, 0xFFA5);
+            assert_eq!(registers.get_gpr_value(*name), 0xA5);
+            assert_eq!(registers.get_gpr_value(R64[i]), ((DIRTY ^ i as u64) & !0xFF) | 0xA5);
+            assert_others_untouched(&registers, i);
+        }
+    }
+
+    #[test]
+    fn gpr8_high_merges() {
+        for (i, name) in R8H.iter().enumerate() {
+            let mut registers = dirty_registers();
+            assert_eq!(registers.get_gpr_value(*name), ((DIRTY ^ i as u64) >> 8) & 0xFF);
+            registers.set_gpr_value(*name, 0xFF5A);
+            assert_eq!(registers.get_gpr_value(*name), 0x5A);
+            assert_eq!(registers.get_gpr_value(R64[i]), ((DIRTY ^ i as u64) & !0xFF00) | 0x5A00);
+            assert_eq!(registers.get_gpr_value(R8[i]), (DIRTY ^ i as u64) & 0xFF);
+            assert_others_untouched(&registers, i);
+        }
+    }
+
+    #[test]
+    fn set_by_sections_clears_stale_bits() {
+        let mut registers = dirty_simd_registers();
+        assert!(registers.set_by_sections::<u32>(VecRegName::XMM, 15, vec![0, 0x8000_0000, 0, 1]));
+        assert_eq!(registers.get_by_sections::<u32>(VecRegName::XMM, 15), Some(vec![0, 0x8000_0000, 0, 1]));
+        // the padding above the named width is written as zeros too
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 15).unwrap()[2..], [0; 6]);
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 14), Some(vec![u64::MAX; 8]));
+    }
+
+    #[test]
+    fn write_modes() {
+        let mut registers = dirty_simd_registers();
+        assert!(registers.write_sections::<u64>(VecRegName::XMM, 0, vec![1, 2], WriteMode::Merge));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 0), Some([vec![1, 2], vec![u64::MAX; 6]].concat()));
+        assert!(registers.write_sections::<u128>(VecRegName::YMM, 1, vec![0, 3], WriteMode::Merge));
+        assert_eq!(registers.get_by_sections::<u128>(VecRegName::ZMM, 1), Some(vec![0, 3, u128::MAX, u128::MAX]));
+
+        assert!(registers.write_sections::<u64>(VecRegName::XMM, 2, vec![0, 4], WriteMode::ZeroUpper));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 2), Some([vec![0, 4], vec![0; 6]].concat()));
+        assert!(registers.write_sections::<u32>(VecRegName::YMM, 3, vec![5; 8], WriteMode::ZeroUpper));
+        assert_eq!(registers.get_by_sections::<u32>(VecRegName::ZMM, 3), Some([vec![5; 8], vec![0; 8]].concat()));
+
+        assert!(registers.write_sections::<u64>(VecRegName::ZMM, 4, vec![0, 6, 0, 6, 0, 6, 0, 6], WriteMode::Full));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 4), Some(vec![0, 6, 0, 6, 0, 6, 0, 6]));
+
+        // a width mismatch or a partial full write is refused and leaves the register alone
+        assert!(!registers.write_sections::<u64>(VecRegName::XMM, 5, vec![0; 4], WriteMode::Merge));
+        assert!(!registers.write_sections::<u64>(VecRegName::YMM, 5, vec![0; 4], WriteMode::Full));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 5), Some(vec![u64::MAX; 8]));
+    }
+
+    #[test]
+    fn lane_views_and_selectors() {
+        let mut registers = dirty_simd_registers();
+        registers.lanes_mut::<u32>(VecRegName::XMM, 2).copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(registers.lanes::<u64>(VecRegName::XMM, 2), [2 << 32 | 1, 4 << 32 | 3]);
+        assert_eq!(registers.lanes::<u128>(VecRegName::YMM, 2)[1], u128::MAX);
+        registers.lanes_mut::<f64>(VecRegName::ZMM, 2)[7] = 1.5;
+        assert_eq!(registers.lanes::<u8>(VecRegName::ZMM, 2)[56..], 1.5f64.to_bits().to_le_bytes());
+        assert_eq!(registers.lanes::<u256>(VecRegName::ZMM, 2)[0], u256::from(4u128 << 96 | 3 << 64 | 2 << 32 | 1) | u256::MAX << 128);
+        assert_eq!(registers.lanes::<f32>(VecRegName::XMM, 2).len(), 4);
+
+        // bit ranges need not be lane aligned
+        assert_eq!(registers.get_by_selector::<u32>(VecRegName::XMM, 2, "[67:36]"), Ok(0x3000_0000));
+        assert_eq!(registers.set_by_selector::<u16>(VecRegName::XMM, 2, "[19:4]", 0xABCD), Ok(()));
+        assert_eq!(registers.lanes::<u32>(VecRegName::XMM, 2)[0], 0xA_BCD1);
+        assert_eq!(registers.set_by_selector::<u16>(VecRegName::ZMM, 2, "[MAX:500]", 0xFF), Ok(()));
+        assert_eq!(registers.lanes::<u64>(VecRegName::ZMM, 2)[7], 1.5f64.to_bits() & 0x000F_FFFF_FFFF_FFFF | 0xFF << 52);
+        assert_eq!(registers.get_by_selector::<u64>(VecRegName::XMM, 2, "dword[1..2]"), Ok(3 << 32 | 2));
+        assert_eq!(registers.set_by_selector::<u8>(VecRegName::YMM, 2, "byte[MAX]", 0x5A), Ok(()));
+        assert_eq!(registers.lanes::<u8>(VecRegName::ZMM, 2)[31..33], [0x5A, 0xFF]);
+    }
+
+    #[test]
+    fn selector_parsing() {
+        let parse = BitRange::parse;
+        let range = |low, high| Ok(BitRange { low, high });
+        assert_eq!(parse("dword[3]", &VecRegName::XMM), range(96, 127));
+        assert_eq!(parse("qword[1..2]", &VecRegName::YMM), range(64, 191));
+        assert_eq!(parse("byte[7]", &VecRegName::XMM), range(56, 63));
+        assert_eq!(parse(" xmmword[ MAX ] ", &VecRegName::ZMM), range(384, 511));
+        // MAX follows the width of the register named
+        assert_eq!(parse("[MAX:64]", &VecRegName::XMM), range(64, 127));
+        assert_eq!(parse("[MAX:64]", &VecRegName::ZMM), range(64, 511));
+        assert_eq!(parse("qword[1..2]", &VecRegName::XMM), Err(SelectorError::OutOfRange { low: 64, high: 191, width: 128 }));
+        assert_eq!(parse("[3:7]", &VecRegName::XMM), Err(SelectorError::OutOfRange { low: 7, high: 3, width: 128 }));
+        assert_eq!(parse("ymmword[0]", &VecRegName::XMM), Err(SelectorError::OutOfRange { low: 0, high: 255, width: 128 }));
+        assert_eq!(parse("[12:x]", &VecRegName::XMM), Err(SelectorError::InvalidNumber));
+        assert_eq!(parse("word[-1]", &VecRegName::XMM), Err(SelectorError::InvalidNumber));
+        assert_eq!(parse("nibble[1]", &VecRegName::XMM), Err(SelectorError::UnknownLane));
+        assert_eq!(parse("dword 3", &VecRegName::XMM), Err(SelectorError::Syntax));
+        assert_eq!(parse("[31-0]", &VecRegName::XMM), Err(SelectorError::Syntax));
+        // a range wider than the type is refused before anything is read or written
+        let mut registers = dirty_simd_registers();
+        assert_eq!(registers.get_by_selector::<u32>(VecRegName::XMM, 0, "qword[1]"), Err(SelectorError::TooWide { bits: 64, type_bits: 32 }));
+        assert_eq!(registers.set_by_selector::<u8>(VecRegName::XMM, 0, "[MAX:0]", 0), Err(SelectorError::TooWide { bits: 128, type_bits: 8 }));
+        assert_eq!(registers.lanes::<u128>(VecRegName::XMM, 0)[0], u128::MAX);
+    }
+
+    #[test]
+    fn upper_zmm_and_opmasks() {
+        let mut registers = Registers::new();
+        assert!(registers.set_by_sections::<u64>(VecRegName::ZMM, 31, (1..=8).collect()));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 31), Some((1..=8).collect()));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::XMM, 31), Some(vec![1, 2]));
+        assert_eq!(registers.get_by_sections::<u64>(VecRegName::ZMM, 15), Some(vec![0; 8]));
+        for k in 0..8 {
+            registers.set_opmask(k, 1 << (k * 8));
+        }
+        assert_eq!(registers.get_opmask(7), 1 << 56);
+        assert_eq!(registers.get_opmask(0), 1);
+    }
+
+    #[test]
+    fn segment_defaults_and_bases() {
+        let mut registers = Registers::new();
+        let cs = registers.get_segment(SegmentName::CS);
+        assert_eq!((cs.selector, cs.cache.attributes), (0x08, 0xA09B));
+        assert_eq!(registers.get_segment(SegmentName::SS).selector, 0x10);
+        // the FS/GS base accessors go through the hidden caches
+        registers.set_fs_base(0x7000);
+        let mut gs = registers.get_segment(SegmentName::GS);
+        gs.cache.base = 0x8000;
+        registers.set_segment(SegmentName::GS, gs);
+        assert_eq!(registers.get_segment(SegmentName::FS).cache.base, 0x7000);
+        assert_eq!(registers.get_gs_base(), 0x8000);
+        assert_eq!(registers.get_kernel_gs_base(), 0);
+    }
+
+    // the BitVec register this representation replaced, kept to benchmark against
+    struct BitVecRegister {
+        bits: bit_vec::BitVec,
+    }
+
+    impl BitVecRegister {
+        fn get_sections(&self) -> Vec<u64> {
+            let mut sections = Vec::new();
+            for i in (0..self.bits.len()).step_by(64) {
+                let mut section = 0u64;
+                for j in 0..64 {
+                    if self.bits[i + j] {
+                        section |= 1 << j;
+                    }
+                }
+                sections.push(section);
+            }
+            sections
+        }
+
+        fn set_by_sections(&mut self, sections: Vec<u64>) {
+            for (i, section) in sections.iter().enumerate() {
+                for j in 0..64 {
+                    self.bits.set(i * 64 + j, section >> j & 1 == 1);
+                }
+            }
+        }
+
+        fn get_by_index(&self, start_index: usize, end_index: usize) -> u32 {
+            let mut value = 0u32;
+            for i in start_index..=end_index {
+                if self.bits[i] {
+                    value |= 1 << (i - start_index);
+                }
+            }
+            value
+        }
+    }
+
+    // cargo test --release -- --ignored --nocapture
+    fn bench(name: &str, iterations: usize, mut body: impl FnMut(usize)) {
+        let start = std::time::Instant::now();
+        for index in 0..iterations {
+            body(index);
+        }
+        let elapsed = start.elapsed();
+        println!("{:<32} {:>10.2} ns/op", name, elapsed.as_nanos() as f64 / iterations as f64);
+    }
+
+    #[test]
+    #[ignore]
+    fn benchmark_vector_registers() {
+        let mut old = BitVecRegister { bits: bit_vec::BitVec::from_elem(512, false) };
+        let mut registers = Registers::new();
+        bench("bitvec set_by_sections::<u64>", 100_000, |index| old.set_by_sections(vec![index as u64; 8]));
+        bench("lanes set_by_sections::<u64>", 100_000, |index| {
+            registers.set_by_sections(VecRegName::ZMM, 0, vec![index as u64; 8]);
+        });
+        bench("bitvec get_sections::<u64>", 100_000, |_| {
+            std::hint::black_box(old.get_sections());
+        });
+        bench("lanes get_by_sections::<u64>", 100_000, |_| {
+            std::hint::black_box(registers.get_by_sections::<u64>(VecRegName::ZMM, 0));
+        });
+        bench("lanes lanes::<u64>", 100_000, |index| {
+            std::hint::black_box(registers.lanes::<u64>(VecRegName::ZMM, 0)[index & 7]);
+        });
+        bench("bitvec get_by_index [95:64]", 100_000, |_| {
+            std::hint::black_box(old.get_by_index(64, 95));
+        });
+        bench("lanes get_by_index [95:64]", 100_000, |_| {
+            std::hint::black_box(registers.simd_registers[0].get_by_index::<u32>(BitRange { low: 64, high: 95 }));
+        });
+        // with the selector parsed on every call
+        bench("lanes get_by_selector [95:64]", 100_000, |_| {
+            std::hint::black_box(registers.get_by_selector::<u32>(VecRegName::ZMM, 0, "[95:64]").unwrap());
+        });
+    }
 }
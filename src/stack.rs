@@ -0,0 +1,228 @@
+// initial process stack for a loaded program
+// reference: System V ABI x86-64 supplement, 3.4.1 "Initial Stack and Register State"
+
+use crate::registers::GPRName;
+
+use crate::memory::MemoryFault;
+use crate::memory::Perms;
+
+use crate::paging::PAGE_SIZE;
+use crate::paging::AccessKind;
+
+use crate::cpu::Cpu;
+
+use crate::elf::LoadedElf;
+
+// auxiliary vector entry types
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_FLAGS: u64 = 8;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_UID: u64 = 11;
+pub const AT_EUID: u64 = 12;
+pub const AT_GID: u64 = 13;
+pub const AT_EGID: u64 = 14;
+pub const AT_PLATFORM: u64 = 15;
+pub const AT_HWCAP: u64 = 16;
+pub const AT_CLKTCK: u64 = 17;
+pub const AT_SECURE: u64 = 23;
+pub const AT_RANDOM: u64 = 25;
+pub const AT_EXECFN: u64 = 31;
+
+// the stack ends one page below the top of the lower canonical half, like on Linux
+pub const STACK_TOP: u64 = 0x7FFF_FFFF_F000;
+pub const DEFAULT_STACK_SIZE: u64 = 8 << 20;
+
+// copy bytes below the cursor, returns where they start
+fn push_bytes(cpu: &mut Cpu, cursor: &mut u64, bytes: &[u8]) -> Result<u64, MemoryFault> {
+    *cursor = cursor.checked_sub(bytes.len() as u64).ok_or(MemoryFault::OutOfRange { address: 0 })?;
+    cpu.memory.write_bytes(*cursor as usize, bytes)?;
+    Ok(*cursor)
+}
+
+fn push_string(cpu: &mut Cpu, cursor: &mut u64, string: &str) -> Result<u64, MemoryFault> {
+    let mut bytes = string.as_bytes().to_vec();
+    bytes.push(0);
+    push_bytes(cpu, cursor, &bytes)
+}
+
+// lays out argc, argv, envp and the auxiliary vector, top of the stack first:
+// strings, AT_RANDOM bytes, padding, auxv, envp, argv, argc <- RSP
+pub struct StackBuilder {
+    args: Vec<String>,
+    env: Vec<String>,
+    size: u64,
+    random: [u8; 16],
+}
+
+impl StackBuilder {
+    pub fn new() -> Self {
+        StackBuilder {
+            args: Vec::new(),
+            env: Vec::new(),
+            size: DEFAULT_STACK_SIZE,
+            random: [0; 16],
+        }
+    }
+
+    pub fn arg(mut self, arg: &str) -> Self {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn args<S: AsRef<str>>(mut self, args: &[S]) -> Self {
+        self.args.extend(args.iter().map(|arg| arg.as_ref().to_string()));
+        self
+    }
+
+    // a NAME=VALUE string
+    pub fn env(mut self, variable: &str) -> Self {
+        self.env.push(variable.to_string());
+        self
+    }
+
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    // the 16 bytes AT_RANDOM points at, libc seeds its stack protector from them
+    pub fn random(mut self, random: [u8; 16]) -> Self {
+        self.random = random;
+        self
+    }
+
+    // map the stack, fill it in and point RSP at argc, returns RSP
+    pub fn build(&self, cpu: &mut Cpu, elf: &LoadedElf) -> Result<u64, MemoryFault> {
+        let size = self.size.max(PAGE_SIZE as u64).next_multiple_of(PAGE_SIZE as u64);
+        let bottom = STACK_TOP.checked_sub(size).ok_or(MemoryFault::OutOfRange { address: STACK_TOP as usize })?;
+        cpu.memory.map(bottom as usize, size as usize, Perms::RW)?;
+
+        let mut cursor = STACK_TOP;
+        let execfn = push_string(cpu, &mut cursor, self.args.first().map_or("", String::as_str))?;
+        let mut env = Vec::with_capacity(self.env.len());
+        for variable in self.env.iter().rev() {
+            env.push(push_string(cpu, &mut cursor, variable)?);
+        }
+        env.reverse();
+        let mut args = Vec::with_capacity(self.args.len());
+        for arg in self.args.iter().rev() {
+            args.push(push_string(cpu, &mut cursor, arg)?);
+        }
+        args.reverse();
+        let platform = push_string(cpu, &mut cursor, "x86_64")?;
+        let random = push_bytes(cpu, &mut cursor, &self.random)?;
+
+        let auxv = [
+            (AT_PHDR, elf.phdr),
+            (AT_PHENT, elf.phent),
+            (AT_PHNUM, elf.phnum),
+            (AT_PAGESZ, PAGE_SIZE as u64),
+            (AT_BASE, 0),
+            (AT_FLAGS, 0),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_PLATFORM, platform),
+            (AT_HWCAP, 0),
+            (AT_CLKTCK, 100),
+            (AT_SECURE, 0),
+            (AT_RANDOM, random),
+            (AT_EXECFN, execfn),
+            (AT_NULL, 0),
+        ];
+
+        let mut words = vec![args.len() as u64];
+        words.extend_from_slice(&args);
+        words.push(0);
+        words.extend_from_slice(&env);
+        words.push(0);
+        for (kind, value) in auxv {
+            words.extend_from_slice(&[kind, value]);
+        }
+        // RSP must be 16-byte aligned at the entry point
+        let rsp = (cursor - words.len() as u64 * 8) & !15;
+        if rsp < bottom {
+            return Err(MemoryFault::Unmapped { address: rsp as usize, access: AccessKind::Write });
+        }
+        let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        cpu.memory.write_bytes(rsp as usize, &bytes)?;
+        cpu.registers.set_gpr_value(GPRName::RSP, rsp);
+        Ok(rsp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::registers::Registers;
+
+    use crate::memory::Memory;
+
+    fn loaded() -> LoadedElf {
+        LoadedElf { entry: 0x401000, base: 0, phdr: 0x400040, phent: 56, phnum: 2, program_break: 0x404000 }
+    }
+
+    fn read_string(cpu: &Cpu, address: u64) -> String {
+        let mut bytes = Vec::new();
+        while cpu.memory.read::<u8>(address as usize + bytes.len()) != 0 {
+            bytes.push(cpu.memory.read::<u8>(address as usize + bytes.len()));
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn lays_out_argv_envp_and_auxv() {
+        let mut cpu = Cpu::new(Registers::new(), Memory::new(0));
+        let rsp = StackBuilder::new()
+            .args(&["/bin/true", "-x"])
+            .env("HOME=/root")
+            .random([7; 16])
+            .build(&mut cpu, &loaded())
+            .unwrap();
+        assert_eq!(cpu.registers.get_gpr_value(GPRName::RSP), rsp);
+        assert_eq!(rsp % 16, 0);
+        assert_eq!(cpu.memory.perms(STACK_TOP as usize - 1), Some(Perms::RW));
+        assert_eq!(cpu.memory.perms((STACK_TOP - DEFAULT_STACK_SIZE) as usize), Some(Perms::RW));
+
+        let word = |index: u64| cpu.memory.read::<u64>((rsp + index * 8) as usize);
+        assert_eq!(word(0), 2);
+        assert_eq!(read_string(&cpu, word(1)), "/bin/true");
+        assert_eq!(read_string(&cpu, word(2)), "-x");
+        assert_eq!(word(3), 0);
+        assert_eq!(read_string(&cpu, word(4)), "HOME=/root");
+        assert_eq!(word(5), 0);
+
+        let mut auxv = Vec::new();
+        let mut index = 6;
+        loop {
+            auxv.push((word(index), word(index + 1)));
+            if word(index) == AT_NULL {
+                break;
+            }
+            index += 2;
+        }
+        let lookup = |kind| auxv.iter().find(|(entry, _)| *entry == kind).map(|(_, value)| *value).unwrap();
+        assert_eq!(lookup(AT_PHDR), 0x400040);
+        assert_eq!(lookup(AT_PHNUM), 2);
+        assert_eq!(lookup(AT_ENTRY), 0x401000);
+        assert_eq!(lookup(AT_PAGESZ), 4096);
+        assert_eq!(cpu.memory.read::<u128>(lookup(AT_RANDOM) as usize), u128::from_le_bytes([7; 16]));
+        assert_eq!(read_string(&cpu, lookup(AT_EXECFN)), "/bin/true");
+        assert_eq!(read_string(&cpu, lookup(AT_PLATFORM)), "x86_64");
+    }
+
+    #[test]
+    fn rejects_a_stack_too_small_for_its_strings() {
+        let mut cpu = Cpu::new(Registers::new(), Memory::new(0));
+        let long = "A".repeat(PAGE_SIZE);
+        assert!(StackBuilder::new().size(PAGE_SIZE as u64).arg(&long).build(&mut cpu, &loaded()).is_err());
+    }
+}